@@ -0,0 +1,90 @@
+//! # Build script
+//!
+//! This script exposes build metadata to the crate through environment
+//! variables, see the `/version` endpoint of the HTTP server
+
+use std::{
+    env, fs,
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// -----------------------------------------------------------------------------
+// Constants
+
+/// Value of metadata that could not be retrieved
+const UNKNOWN: &str = "unknown";
+
+// -----------------------------------------------------------------------------
+// Main
+
+fn main() {
+    let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap_or_else(|_| ".".to_string());
+    let manifest_dir = Path::new(&manifest_dir);
+
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-changed=Cargo.lock");
+
+    // The commit may be given by the environment, e.g. when building from an
+    // archive of the sources without the git directory
+    let commit = env::var("GIT_COMMIT")
+        .ok()
+        .filter(|commit| !commit.is_empty())
+        .or_else(|| {
+            Command::new("git")
+                .args(["rev-parse", "HEAD"])
+                .current_dir(manifest_dir)
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .and_then(|output| String::from_utf8(output.stdout).ok())
+                .map(|commit| commit.trim().to_string())
+        })
+        .unwrap_or_else(|| UNKNOWN.to_string());
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs().to_string())
+        .unwrap_or_else(|_| UNKNOWN.to_string());
+
+    let lock = fs::read_to_string(manifest_dir.join("Cargo.lock")).unwrap_or_default();
+
+    println!("cargo:rustc-env=BUILD_GIT_COMMIT={commit}");
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={timestamp}");
+    println!(
+        "cargo:rustc-env=BUILD_SOZU_CLIENT_VERSION={}",
+        locked_version(&lock, "sozu-client")
+    );
+    println!(
+        "cargo:rustc-env=BUILD_SOZU_COMMAND_LIB_VERSION={}",
+        locked_version(&lock, "sozu-command-lib")
+    );
+}
+
+// -----------------------------------------------------------------------------
+// Helpers
+
+/// Retrieve the version of the given package from the lock file, the version
+/// follows the name of the package in its section
+fn locked_version(lock: &str, package: &str) -> String {
+    let name = format!("name = \"{package}\"");
+    let mut lines = lock.lines();
+    while let Some(line) = lines.next() {
+        if line.trim() != name {
+            continue;
+        }
+
+        if let Some(version) = lines
+            .next()
+            .and_then(|line| line.trim().strip_prefix("version = \""))
+            .and_then(|version| version.strip_suffix('"'))
+        {
+            return version.to_string();
+        }
+    }
+
+    UNKNOWN.to_string()
+}
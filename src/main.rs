@@ -2,16 +2,26 @@
 //!
 //! This application retrieve pki on a directory and load them into Sōzu
 
-use std::{path::PathBuf, sync::Arc};
+use std::{path::PathBuf, process::ExitCode, sync::Arc, time::Duration};
 
 use clap::{ArgAction, Parser};
-use tracing::{error, info};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, watch},
+    time::timeout,
+};
+use tracing::{error, info, warn};
 
 use crate::svc::{
-    certificates::watcher,
+    certificates::{
+        self,
+        watcher::{self, Inventory},
+    },
     config::{self, ConnectorConfiguration},
-    http,
-    logging::{self, LoggingInitGuard},
+    health::Health,
+    http::{self, server::Context},
+    logging,
+    pid::{self, PidFile},
 };
 
 pub mod svc;
@@ -27,10 +37,14 @@ pub enum Error {
     Logging(logging::Error),
     #[error("failed to create handler on termination signal, {0}")]
     Termination(std::io::Error),
+    #[error("failed to create handler on reload signal, {0}")]
+    Reload(std::io::Error),
     #[error("failed to serve http server, {0}")]
     HttpServer(http::server::Error),
     #[error("failed to watch pki directory, {0}")]
     Watcher(watcher::Error),
+    #[error("failed to create pid file, {0}")]
+    PidFile(pid::Error),
 }
 
 // -----------------------------------------------------------------------------
@@ -47,6 +61,20 @@ pub struct Args {
     /// Path to the configuration file of the prometheus connector,
     #[clap(short = 'c', long = "config")]
     pub config: Option<PathBuf>,
+    /// Look up the pki directory a single time and exit, the exit code is 1 on
+    /// configuration or connection error and 2 if some certificates failed
+    /// to be sent to Sōzu
+    #[clap(long = "once")]
+    pub once: bool,
+    /// Log requests that would be sent to Sōzu instead of sending them,
+    /// override the `dry-run` configuration option
+    #[clap(long = "dry-run")]
+    pub dry_run: bool,
+    /// Check the configuration and load every certificate of the pki
+    /// directories without connecting to Sōzu, then exit with 1 if some could
+    /// not be loaded
+    #[clap(long = "validate", alias = "check")]
+    pub validate: bool,
 }
 
 impl paw::ParseArgs for Args {
@@ -57,45 +85,185 @@ impl paw::ParseArgs for Args {
     }
 }
 
+// -----------------------------------------------------------------------------
+// Constants
+
+/// Exit code used in `--once` mode when some certificates failed to be sent
+pub const EXIT_PARTIAL_FAILURE: u8 = 2;
+
+/// Maximum number of pending requests of immediate lookups
+pub const SYNC_REQUESTS_CAPACITY: usize = 64;
+
 // -----------------------------------------------------------------------------
 // main
 
+// The runtime runs on a single thread, work which is bound to the cpu, e.g.
+// parsing certificates or verifying chains, is offloaded to blocking threads,
+// so that the HTTP server and signals are served while a large pki is scanned
 #[paw::main]
 #[tokio::main(flavor = "current_thread")]
-pub async fn main(args: Args) -> Result<(), Error> {
+pub async fn main(args: Args) -> Result<ExitCode, Error> {
     // -------------------------------------------------------------------------
     // Retrieve configuration
-    let config = Arc::new(match &args.config {
-        Some(path) => {
-            ConnectorConfiguration::try_from(path.to_owned()).map_err(Error::Configuration)?
-        }
-        None => ConnectorConfiguration::try_new().map_err(Error::Configuration)?,
-    });
+    let config = Arc::new(configuration(&args).map_err(Error::Configuration)?);
 
     // -------------------------------------------------------------------------
     // Initialize logging system
     let _guard = match &config.sentry {
-        Some(sentry_ctx) => {
-            logging::initialize_with_sentry(args.verbosity as usize, sentry_ctx.to_owned())
-                .map_err(Error::Logging)?
-        }
-        None => logging::initialize(args.verbosity as usize)
-            .map(|_| LoggingInitGuard::default())
-            .map_err(Error::Logging)?,
+        Some(sentry_ctx) => logging::initialize_with_sentry(
+            args.verbosity as usize,
+            sentry_ctx.to_owned(),
+            config.logging.format,
+            &config.telemetry,
+        )
+        .map_err(Error::Logging)?,
+        None => logging::initialize(
+            args.verbosity as usize,
+            config.logging.format,
+            &config.telemetry,
+        )
+        .map_err(Error::Logging)?,
     };
 
+    // -------------------------------------------------------------------------
+    // Validate the pki directories without connecting to Sōzu, if asked to
+    if args.validate {
+        let validation = certificates::validate(&config).await;
+        for (path, reason) in &validation.failures {
+            error!(
+                path = path.display().to_string(),
+                error = reason,
+                "Certificate directory could not be loaded"
+            );
+        }
+
+        if !validation.failures.is_empty() {
+            error!(
+                directories = validation.directories,
+                failures = validation.failures.len(),
+                "Some certificate directories could not be loaded"
+            );
+
+            return Ok(ExitCode::FAILURE);
+        }
+
+        info!(
+            directories = validation.directories,
+            "Successfully validated configuration and certificate directories"
+        );
+
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // -------------------------------------------------------------------------
+    // Look up the pki directory a single time, if asked to
+    if args.once {
+        return match watcher::lookup_once(config).await {
+            Ok(summary) if 0 != summary.failed => {
+                warn!(
+                    sent = summary.sent,
+                    failed = summary.failed,
+                    "Some certificates requests failed to be applied by Sōzu"
+                );
+
+                Ok(ExitCode::from(EXIT_PARTIAL_FAILURE))
+            }
+            Ok(summary) => {
+                info!(
+                    sent = summary.sent,
+                    "Successfully looked up pki directory a single time"
+                );
+
+                Ok(ExitCode::SUCCESS)
+            }
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "Could not look up pki directory a single time"
+                );
+
+                Err(Error::Watcher(err))
+            }
+        };
+    }
+
+    // -------------------------------------------------------------------------
+    // Write the pid file, it is removed once dropped on shutdown
+    let _pid_file = config
+        .pid_file
+        .as_deref()
+        .map(PidFile::create)
+        .transpose()
+        .map_err(Error::PidFile)?;
+
     // -------------------------------------------------------------------------
     // Start HTTP server and listener to termination signals concurrently and
     // not in parallel
 
-    let result = tokio::select! {
-        r = tokio::signal::ctrl_c() => r.map_err(Error::Termination),
-        r = http::server::serve(config.to_owned()) => r.map_err(Error::HttpServer),
-        r = watcher::lookup_every(config) => r.map_err(Error::Watcher),
+    let health = Arc::new(Health::default());
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let shutdown_timeout = Duration::from_millis(config.shutdown_timeout);
+
+    let (config_tx, config_rx) = watch::channel(config.to_owned());
+    let (sync_tx, sync_rx) = mpsc::channel(SYNC_REQUESTS_CAPACITY);
+    let inventory = Inventory::default();
+    let context = Context {
+        health: health.to_owned(),
+        inventory: inventory.to_owned(),
+        syncs: sync_tx,
+        metrics: Arc::new(config.metrics.to_owned()),
     };
 
+    let watcher = watcher::lookup_every(config_rx, health, inventory, shutdown_rx, sync_rx);
+    tokio::pin!(watcher);
+
+    // Metrics are still collected, there is just nobody to scrape them
+    if !config.http.enabled {
+        info!("HTTP server is disabled");
+    }
+
+    // The reason tells which branch stopped the connector, for post-mortems
+    let (reason, result) = tokio::select! {
+        r = termination() => match r {
+            Ok(reason) => {
+                // -------------------------------------------------------------
+                // Let the watcher finish to send requests in flight
+                info!(
+                    reason = reason,
+                    timeout = shutdown_timeout.as_millis(),
+                    "Received termination signal, wait for requests in flight"
+                );
+
+                // The receiver lives as long as the watcher, which is still
+                // pending at this point.
+                let _ = shutdown_tx.send(true);
+                let result = match timeout(shutdown_timeout, &mut watcher).await {
+                    Ok(r) => r.map_err(Error::Watcher),
+                    Err(_) => {
+                        warn!("Requests in flight did not complete in time, abandon them");
+                        Ok(())
+                    }
+                };
+
+                (reason, result)
+            }
+            Err(err) => ("signal_error", Err(Error::Termination(err))),
+        },
+        r = reload(&args, &config_tx) => ("reload_error", r.map_err(Error::Reload)),
+        r = http::server::serve(config.to_owned(), context), if config.http.enabled => {
+            let reason = if r.is_ok() { "http_stopped" } else { "http_error" };
+            (reason, r.map_err(Error::HttpServer))
+        }
+        r = &mut watcher => {
+            let reason = if r.is_ok() { "watcher_stopped" } else { "watcher_error" };
+            (reason, r.map_err(Error::Watcher))
+        }
+    };
+
+    // Errors embed the ones they are caused by, so the message holds the chain
     if let Err(err) = result {
         error!(
+            reason = reason,
             error = err.to_string(),
             "Could not execute {} properly",
             env!("CARGO_PKG_NAME")
@@ -104,6 +272,74 @@ pub async fn main(args: Args) -> Result<(), Error> {
         return Err(err);
     }
 
-    info!("Gracefully halted {}!", env!("CARGO_PKG_NAME"));
-    Ok(())
+    info!(
+        reason = reason,
+        "Gracefully halted {}!",
+        env!("CARGO_PKG_NAME")
+    );
+    Ok(ExitCode::SUCCESS)
+}
+
+// -----------------------------------------------------------------------------
+// Helpers
+
+/// Load the configuration from the given path or the default locations and
+/// apply overrides of command line arguments
+fn configuration(args: &Args) -> Result<ConnectorConfiguration, config::Error> {
+    let mut config = match &args.config {
+        Some(path) => ConnectorConfiguration::try_from(path.to_owned())?,
+        None => ConnectorConfiguration::try_new()?,
+    };
+
+    config.dry_run |= args.dry_run;
+    Ok(config)
+}
+
+/// Reload the configuration on SIGHUP and forward it to the watcher, the
+/// current configuration is kept if the new one could not be loaded
+#[tracing::instrument(skip_all)]
+async fn reload(
+    args: &Args,
+    tx: &watch::Sender<Arc<ConnectorConfiguration>>,
+) -> Result<(), std::io::Error> {
+    let mut sighup = signal(SignalKind::hangup())?;
+
+    loop {
+        if sighup.recv().await.is_none() {
+            // The signal stream has been closed, nothing will be reloaded
+            return std::future::pending().await;
+        }
+
+        info!("Received reload signal, reload configuration");
+        match configuration(args) {
+            Ok(config) if **tx.borrow() == config => {
+                info!("Configuration did not change");
+            }
+            Ok(config) => {
+                info!("Successfully reloaded configuration, apply it");
+
+                // The receiver lives as long as the watcher, if it has been
+                // dropped we are shutting down anyway.
+                let _ = tx.send(Arc::new(config));
+            }
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "Could not reload configuration, keep the current one"
+                );
+            }
+        }
+    }
+}
+
+/// Wait for a termination signal, either SIGINT or SIGTERM, and returns the
+/// shutdown reason naming it
+#[tracing::instrument]
+async fn termination() -> Result<&'static str, std::io::Error> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        r = tokio::signal::ctrl_c() => r.map(|_| "signal:SIGINT"),
+        _ = sigterm.recv() => Ok("signal:SIGTERM"),
+    }
 }
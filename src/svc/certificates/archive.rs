@@ -0,0 +1,557 @@
+//! # Archive module
+//!
+//! This module provides the extraction of pki directories given as `.tar.gz`
+//! or `.zip` archives, so that they are looked up as any other pki directory
+
+use std::{
+    collections::HashMap,
+    fs::{self, DirBuilder, File, OpenOptions},
+    io::{self, Read},
+    os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt},
+    path::{Component, Path, PathBuf},
+    time::SystemTime,
+};
+
+use flate2::read::GzDecoder;
+use tokio::task::{spawn_blocking as blocking, JoinError};
+use tracing::{debug, info, warn};
+use zip::{result::ZipError, ZipArchive};
+
+use crate::svc::config::{Archive, ConnectorConfiguration};
+
+// -------------------------------------------------------------------------------------
+// Constants
+
+/// Mask of the file type bits of a unix mode
+const S_IFMT: u32 = 0o170_000;
+
+/// File type bits of a symbolic link
+const S_IFLNK: u32 = 0o120_000;
+
+// -------------------------------------------------------------------------------------
+// Error
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to read archive '{0}', {1}")]
+    Read(PathBuf, io::Error),
+    #[error("failed to read zip archive '{0}', {1}")]
+    Zip(PathBuf, ZipError),
+    #[error("archive '{0}' holds entry '{1}' which is outside of the archive")]
+    Traversal(PathBuf, String),
+    #[error("archive '{0}' extracts to more than {1} bytes")]
+    TooLarge(PathBuf, u64),
+    #[error("archive '{0}' holds more than {1} entries")]
+    TooManyEntries(PathBuf, usize),
+    #[error("failed to write '{0}', {1}")]
+    Write(PathBuf, io::Error),
+    #[error("directory '{0}' must be owned by the connector and only accessible to it")]
+    Insecure(PathBuf),
+    #[error("failed to wait for extraction of archive, {0}")]
+    Join(JoinError),
+}
+
+// -------------------------------------------------------------------------------------
+// Extractions
+
+/// Archives extracted so far, with the directory they were extracted to and
+/// the modification time and size of their file, so that they are only
+/// extracted again once they change
+#[derive(Clone, Debug, Default)]
+pub struct Extractions(HashMap<PathBuf, Extraction>);
+
+#[derive(Clone, Debug)]
+struct Extraction {
+    destination: PathBuf,
+    stamp: Option<(SystemTime, u64)>,
+}
+
+impl Extractions {
+    /// Extract the archives of the pki directories which changed since their
+    /// last extraction, the previous extraction of an archive is kept if the
+    /// new one fails. Extractions of archives which are no longer pki
+    /// directories, or were extracted elsewhere, are removed
+    #[tracing::instrument(skip_all)]
+    pub async fn refresh(&mut self, config: &ConnectorConfiguration) -> Vec<(PathBuf, Error)> {
+        let mut failures = vec![];
+        for path in config
+            .sozu
+            .pki
+            .iter()
+            .filter(|path| Archive::is_archive(path))
+        {
+            let destination = config.archive.destination(path);
+            let stamp = match tokio::fs::metadata(path).await {
+                Ok(metadata) => metadata
+                    .modified()
+                    .ok()
+                    .map(|mtime| (mtime, metadata.len())),
+                Err(err) => {
+                    failures.push((path.to_owned(), Error::Read(path.to_owned(), err)));
+                    continue;
+                }
+            };
+
+            if stamp.is_some()
+                && self.0.get(path).is_some_and(|extraction| {
+                    extraction.destination == destination && extraction.stamp == stamp
+                })
+                && destination.is_dir()
+            {
+                continue;
+            }
+
+            let (archive, target, limits) = (
+                path.to_owned(),
+                destination.to_owned(),
+                config.archive.to_owned(),
+            );
+
+            let result = blocking(move || replace(&archive, &target, &limits))
+                .await
+                .map_err(Error::Join)
+                .and_then(|result| result);
+
+            match result {
+                Ok(entries) => {
+                    info!(
+                        path = path.display().to_string(),
+                        entries = entries,
+                        "Extracted pki archive"
+                    );
+
+                    let extraction = Extraction { destination, stamp };
+                    if let Some(previous) = self.0.insert(path.to_owned(), extraction) {
+                        remove_stale(path, &previous.destination, config);
+                    }
+                }
+                Err(err) => failures.push((path.to_owned(), err)),
+            }
+        }
+
+        self.0.retain(|path, extraction| {
+            let kept = config.sozu.pki.contains(path)
+                && config.archive.destination(path) == extraction.destination;
+
+            if !kept {
+                remove_stale(path, &extraction.destination, config);
+            }
+
+            kept
+        });
+
+        failures
+    }
+
+    /// Returns true if the given pki directory is where an archive is
+    /// extracted to, but the archive has never been extracted successfully
+    pub fn is_missing(&self, root: &Path, config: &ConnectorConfiguration) -> bool {
+        config.sozu.pki.iter().any(|path| {
+            Archive::is_archive(path)
+                && config.archive.destination(path) == root
+                && !self.0.contains_key(path)
+        })
+    }
+}
+
+// -------------------------------------------------------------------------------------
+// Helpers
+
+/// Remove a previous extraction of the given archive, unless it is still
+/// where the archive is extracted
+fn remove_stale(archive: &Path, destination: &Path, config: &ConnectorConfiguration) {
+    if config.sozu.pki.iter().any(|path| path == archive)
+        && config.archive.destination(archive) == destination
+    {
+        return;
+    }
+
+    match fs::remove_dir_all(destination) {
+        Ok(()) => info!(
+            path = archive.display().to_string(),
+            destination = destination.display().to_string(),
+            "Removed stale extraction of pki archive"
+        ),
+        Err(err) if io::ErrorKind::NotFound == err.kind() => {}
+        Err(err) => warn!(
+            path = archive.display().to_string(),
+            destination = destination.display().to_string(),
+            error = err.to_string(),
+            "Could not remove stale extraction of pki archive"
+        ),
+    }
+}
+
+/// Extract the given archive next to the destination, then swap it with the
+/// previous extraction, returns the number of extracted entries
+fn replace(archive: &Path, destination: &Path, limits: &Archive) -> Result<usize, Error> {
+    private_directory(&limits.directory)?;
+
+    let mut staging = destination.as_os_str().to_owned();
+    staging.push(".partial");
+    let staging = PathBuf::from(staging);
+    if staging.exists() {
+        fs::remove_dir_all(&staging).map_err(|err| Error::Write(staging.to_owned(), err))?;
+    }
+
+    let result = extract(archive, &staging, limits);
+    if result.is_err() {
+        let _ = fs::remove_dir_all(&staging);
+        return result;
+    }
+
+    if destination.exists() {
+        fs::remove_dir_all(destination).map_err(|err| Error::Write(destination.to_owned(), err))?;
+    }
+
+    fs::rename(&staging, destination).map_err(|err| Error::Write(destination.to_owned(), err))?;
+    result
+}
+
+/// Create the given directory, only accessible to the connector, and check
+/// that an existing one was not created by someone else
+fn private_directory(path: &Path) -> Result<(), Error> {
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(path)
+        .map_err(|err| Error::Write(path.to_owned(), err))?;
+
+    let metadata = fs::symlink_metadata(path).map_err(|err| Error::Write(path.to_owned(), err))?;
+
+    // SAFETY: geteuid has no preconditions and cannot fail
+    let uid = unsafe { libc::geteuid() };
+    if !metadata.is_dir() || metadata.uid() != uid || 0 != metadata.mode() & 0o077 {
+        return Err(Error::Insecure(path.to_owned()));
+    }
+
+    Ok(())
+}
+
+/// Extract the entries of the given archive into the given directory,
+/// symbolic links and special files are skipped
+fn extract(archive: &Path, directory: &Path, limits: &Archive) -> Result<usize, Error> {
+    let file = File::open(archive).map_err(|err| Error::Read(archive.to_owned(), err))?;
+    let mut writer = Writer::new(archive, directory, limits)?;
+
+    if archive.to_string_lossy().to_lowercase().ends_with(".zip") {
+        let mut zip = ZipArchive::new(file).map_err(|err| Error::Zip(archive.to_owned(), err))?;
+        for idx in 0..zip.len() {
+            let entry = zip
+                .by_index(idx)
+                .map_err(|err| Error::Zip(archive.to_owned(), err))?;
+
+            let name = PathBuf::from(entry.name());
+            if entry.is_dir() {
+                writer.directory(&name)?;
+            } else if entry
+                .unix_mode()
+                .is_some_and(|mode| S_IFLNK == mode & S_IFMT)
+            {
+                writer.skip(&name)?;
+            } else {
+                writer.file(&name, entry)?;
+            }
+        }
+    } else {
+        let mut tar = tar::Archive::new(GzDecoder::new(file));
+        let entries = tar
+            .entries()
+            .map_err(|err| Error::Read(archive.to_owned(), err))?;
+
+        for entry in entries {
+            let entry = entry.map_err(|err| Error::Read(archive.to_owned(), err))?;
+            let name = entry
+                .path()
+                .map_err(|err| Error::Read(archive.to_owned(), err))?
+                .into_owned();
+
+            let kind = entry.header().entry_type();
+            if kind.is_dir() {
+                writer.directory(&name)?;
+            } else if kind.is_file() {
+                writer.file(&name, entry)?;
+            } else {
+                writer.skip(&name)?;
+            }
+        }
+    }
+
+    Ok(writer.entries)
+}
+
+/// Write the entries of an archive within a directory, keeping track of the
+/// limits of the archive
+struct Writer<'a> {
+    archive: &'a Path,
+    directory: &'a Path,
+    limits: &'a Archive,
+    size: u64,
+    entries: usize,
+}
+
+impl<'a> Writer<'a> {
+    fn new(archive: &'a Path, directory: &'a Path, limits: &'a Archive) -> Result<Self, Error> {
+        DirBuilder::new()
+            .mode(0o700)
+            .create(directory)
+            .map_err(|err| Error::Write(directory.to_owned(), err))?;
+
+        Ok(Self {
+            archive,
+            directory,
+            limits,
+            size: 0,
+            entries: 0,
+        })
+    }
+
+    /// Resolve the name of an entry within the directory, names which are
+    /// absolute or hold parent components are refused
+    fn resolve(&mut self, name: &Path) -> Result<PathBuf, Error> {
+        self.entries += 1;
+        if self.entries > self.limits.max_entries {
+            return Err(Error::TooManyEntries(
+                self.archive.to_owned(),
+                self.limits.max_entries,
+            ));
+        }
+
+        let mut path = self.directory.to_owned();
+        for component in name.components() {
+            match component {
+                Component::Normal(component) => path.push(component),
+                Component::CurDir => {}
+                Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                    return Err(Error::Traversal(
+                        self.archive.to_owned(),
+                        name.display().to_string(),
+                    ));
+                }
+            }
+        }
+
+        Ok(path)
+    }
+
+    fn skip(&mut self, name: &Path) -> Result<(), Error> {
+        self.resolve(name)?;
+        debug!(
+            path = self.archive.display().to_string(),
+            entry = name.display().to_string(),
+            "Skip entry of archive which is neither a file nor a directory"
+        );
+
+        Ok(())
+    }
+
+    fn directory(&mut self, name: &Path) -> Result<(), Error> {
+        let path = self.resolve(name)?;
+        DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&path)
+            .map_err(|err| Error::Write(path, err))
+    }
+
+    /// Write the content of a file, which is read up to the remaining size so
+    /// that sizes announced by the archive are not trusted
+    fn file(&mut self, name: &Path, reader: impl Read) -> Result<(), Error> {
+        let path = self.resolve(name)?;
+        if let Some(parent) = path.parent() {
+            DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(parent)
+                .map_err(|err| Error::Write(parent.to_owned(), err))?;
+        }
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)
+            .map_err(|err| Error::Write(path.to_owned(), err))?;
+
+        let remaining = self.limits.max_size - self.size;
+        let written = io::copy(&mut reader.take(remaining.saturating_add(1)), &mut file)
+            .map_err(|err| Error::Read(self.archive.to_owned(), err))?;
+
+        if written > remaining {
+            return Err(Error::TooLarge(
+                self.archive.to_owned(),
+                self.limits.max_size,
+            ));
+        }
+
+        self.size += written;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+    use zip::{write::FileOptions, ZipWriter};
+
+    use super::*;
+    use crate::svc::config::tests::configuration;
+
+    /// Files of an archive, by name
+    pub type Files<'a> = &'a [(&'a str, &'a [u8])];
+
+    /// Write an archive of the given files at the given path
+    type Build = fn(&Path, Files);
+
+    /// Write a `.tar.gz` archive holding the given files, names are written
+    /// as is so that malicious ones can be built
+    pub fn tarball(path: &Path, files: Files) {
+        let encoder = GzEncoder::new(File::create(path).expect("archive"), Compression::fast());
+        let mut builder = tar::Builder::new(encoder);
+        for (name, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(content.len() as u64);
+            header.set_mode(0o600);
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_cksum();
+            builder.append(&header, *content).expect("entry");
+        }
+
+        builder
+            .into_inner()
+            .and_then(GzEncoder::finish)
+            .expect("archive");
+    }
+
+    /// Write a `.zip` archive holding the given files
+    fn zip(path: &Path, files: Files) {
+        let mut writer = ZipWriter::new(File::create(path).expect("archive"));
+        for (name, content) in files {
+            writer
+                .start_file(*name, FileOptions::default())
+                .expect("entry");
+            writer.write_all(content).expect("entry");
+        }
+
+        writer.finish().expect("archive");
+    }
+
+    fn limits(directory: &Path) -> Archive {
+        Archive {
+            directory: directory.to_owned(),
+            ..Archive::default()
+        }
+    }
+
+    #[test]
+    fn entries_outside_of_the_archive_are_refused() {
+        let dir = tempfile::tempdir().expect("temporary directory to be created");
+        let limits = limits(&dir.path().join("extractions"));
+        let writers: [(&str, Build); 2] = [("pki.tar.gz", tarball), ("pki.zip", zip)];
+
+        for name in ["../escaped.key", "nested/../../escaped.key", "/escaped.key"] {
+            for (file, write) in writers {
+                let archive = dir.path().join(file);
+                write(
+                    &archive,
+                    &[("example.com/example.com.crt", b"crt"), (name, b"key")],
+                );
+
+                let destination = limits.destination(&archive);
+                let result = replace(&archive, &destination, &limits);
+                assert!(
+                    matches!(&result, Err(Error::Traversal(_, entry)) if name == entry),
+                    "{name} in {}: {result:?}",
+                    archive.display()
+                );
+
+                assert!(!dir.path().join("escaped.key").exists());
+                assert!(!Path::new("/escaped.key").exists());
+                assert!(!destination.exists());
+            }
+        }
+    }
+
+    #[test]
+    fn archives_exceeding_the_limits_are_refused_and_keep_their_extraction() {
+        let dir = tempfile::tempdir().expect("temporary directory to be created");
+        let archive = dir.path().join("pki.tar.gz");
+        let limits = Archive {
+            max_size: 8,
+            max_entries: 2,
+            ..limits(&dir.path().join("extractions"))
+        };
+
+        let destination = limits.destination(&archive);
+        tarball(&archive, &[("a.crt", b"1234"), ("a.key", b"5678")]);
+        assert_eq!(
+            2,
+            replace(&archive, &destination, &limits).expect("extraction")
+        );
+
+        tarball(&archive, &[("a.crt", b"1234"), ("a.key", b"56789")]);
+        assert!(matches!(
+            replace(&archive, &destination, &limits),
+            Err(Error::TooLarge(_, 8))
+        ));
+
+        tarball(
+            &archive,
+            &[("a.crt", b"1"), ("a.key", b"2"), ("b.crt", b"3")],
+        );
+        assert!(matches!(
+            replace(&archive, &destination, &limits),
+            Err(Error::TooManyEntries(_, 2))
+        ));
+
+        // The previous extraction is kept and the partial ones are removed
+        assert_eq!(
+            b"5678".as_slice(),
+            fs::read(destination.join("a.key")).expect("key")
+        );
+        let entries = fs::read_dir(&limits.directory)
+            .expect("extractions")
+            .count();
+        assert_eq!(1, entries);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn extractions_of_archives_leaving_the_pki_are_removed() {
+        let dir = tempfile::tempdir().expect("temporary directory to be created");
+        let archive = dir.path().join("pki.zip");
+        zip(&archive, &[("example.com/example.com.crt", b"crt")]);
+
+        let keys = format!(
+            "archive = {{ directory = \"{}\" }}",
+            dir.path().join("extractions").display()
+        );
+
+        let config = configuration(&archive, &keys);
+        let destination = config.archive.destination(&archive);
+
+        let mut extractions = Extractions::default();
+        assert!(extractions.refresh(&config).await.is_empty());
+        assert!(destination.join("example.com/example.com.crt").is_file());
+
+        // The archive moves to another extraction directory on reload
+        let moved = configuration(
+            &archive,
+            &format!(
+                "archive = {{ directory = \"{}\" }}",
+                dir.path().join("moved").display()
+            ),
+        );
+
+        assert!(extractions.refresh(&moved).await.is_empty());
+        assert!(!destination.exists());
+        assert!(moved.archive.destination(&archive).is_dir());
+
+        // The archive is no longer a pki directory
+        let plain = configuration(&dir.path().join("pki"), &keys);
+        assert!(extractions.refresh(&plain).await.is_empty());
+        assert!(!moved.archive.destination(&archive).exists());
+    }
+}
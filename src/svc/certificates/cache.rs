@@ -0,0 +1,191 @@
+//! # Cache module
+//!
+//! This module provides a cache of certificates read from disk, indexed by the
+//! modification time of their files
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::Metadata,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use sozu_command_lib::proto::command::CertificateAndKey;
+use tokio::fs;
+
+use crate::svc::{
+    certificates::{self, wipe},
+    config::Layout,
+};
+
+// -------------------------------------------------------------------------------------
+// Stamp
+
+/// Modification time and size of the files of a certificate directory, and
+/// their mode if asked to.
+///
+/// Stamps are only compared for equality, so a clock going backwards still
+/// invalidates the cache. A change of mode does not change the modification
+/// time, so the mode is part of the stamp when permissions are enforced, for a
+/// key which is not readable by others anymore to be read again.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Stamp(Vec<Option<(SystemTime, u64, Option<u32>)>>);
+
+impl Stamp {
+    #[tracing::instrument(skip(layout))]
+    pub async fn new(path: &Path, layout: &Layout, permissions: bool) -> Self {
+        let mut acc = vec![];
+        let mut templates = vec![
+            layout.certificate(),
+            layout.key(),
+            layout.options(),
+            layout.ocsp(),
+            layout.dh(),
+        ];
+        templates.extend(layout.pkcs12());
+        templates.extend(layout.chain());
+
+        for template in templates {
+            acc.push(
+                fs::metadata(layout.file(path, template))
+                    .await
+                    .ok()
+                    .and_then(|metadata| {
+                        Some((
+                            metadata.modified().ok()?,
+                            metadata.len(),
+                            Some(mode(&metadata)).filter(|_| permissions),
+                        ))
+                    }),
+            );
+        }
+
+        Self(acc)
+    }
+}
+
+/// Returns the permission bits of the given file, which are only known on unix
+#[cfg(unix)]
+fn mode(metadata: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+
+    metadata.permissions().mode() & 0o777
+}
+
+#[cfg(not(unix))]
+fn mode(_metadata: &Metadata) -> u32 {
+    0
+}
+
+// -------------------------------------------------------------------------------------
+// Entry
+
+/// Cached certificate directory, its private key is wiped from memory when
+/// dropped, so it does not implement `Debug`
+#[derive(Clone)]
+pub struct Entry {
+    pub stamp: Stamp,
+    pub certificate_and_key: CertificateAndKey,
+    pub metadata: certificates::Metadata,
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        wipe(&mut self.certificate_and_key);
+    }
+}
+
+// -------------------------------------------------------------------------------------
+// Cache
+
+#[derive(Clone, Default)]
+pub struct Cache {
+    entries: HashMap<PathBuf, Entry>,
+    /// Directories whose certificate file holds no certificate, with the stamp
+    /// of their files, so that they are only reported once until they change
+    empty: HashMap<PathBuf, Stamp>,
+}
+
+impl Cache {
+    /// Retrieve the entry of the given directory, if its files did not change
+    pub fn get(&self, path: &Path, stamp: &Stamp) -> Option<&Entry> {
+        self.entries.get(path).filter(|entry| &entry.stamp == stamp)
+    }
+
+    /// Retrieve the entry of the given directory, whether its files changed
+    /// or not
+    pub fn peek(&self, path: &Path) -> Option<&Entry> {
+        self.entries.get(path)
+    }
+
+    /// Returns true if the certificate file of the given directory was already
+    /// known to be empty, and the files did not change since
+    pub fn is_known_empty(&self, path: &Path, stamp: &Stamp) -> bool {
+        self.empty.get(path) == Some(stamp)
+    }
+
+    /// Remember that the certificate file of the given directory is empty
+    pub fn insert_empty(&mut self, path: PathBuf, stamp: Stamp) {
+        self.entries.remove(&path);
+        self.empty.insert(path, stamp);
+    }
+
+    pub fn insert(
+        &mut self,
+        path: PathBuf,
+        stamp: Stamp,
+        certificate_and_key: CertificateAndKey,
+        metadata: certificates::Metadata,
+    ) {
+        self.empty.remove(&path);
+        self.entries.insert(
+            path,
+            Entry {
+                stamp,
+                certificate_and_key,
+                metadata,
+            },
+        );
+    }
+
+    /// Forget the given directory and the ones nested in it
+    pub fn remove(&mut self, path: &Path) {
+        self.entries.retain(|entry, _| !entry.starts_with(path));
+        self.empty.retain(|entry, _| !entry.starts_with(path));
+    }
+
+    /// Forget directories which are not in the given set
+    pub fn retain(&mut self, paths: &HashSet<PathBuf>) {
+        self.entries.retain(|path, _| paths.contains(path));
+        self.empty.retain(|path, _| paths.contains(path));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::svc::certificates::tests::{self_signed, write_directory};
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn change_of_mode_invalidates_the_stamp_if_permissions_are_strict() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let root = tempfile::tempdir().expect("temporary directory to be created");
+        let (cert, key) = self_signed(None, &["example.com"]);
+        let path = write_directory(root.path(), "example", &cert, &key);
+        let layout = Layout::default();
+        let key = path.join("example.key");
+        std::fs::set_permissions(&key, std::fs::Permissions::from_mode(0o600))
+            .expect("permissions to be changed");
+
+        let strict = Stamp::new(&path, &layout, true).await;
+        let lax = Stamp::new(&path, &layout, false).await;
+
+        assert_eq!(strict, Stamp::new(&path, &layout, true).await);
+        std::fs::set_permissions(&key, std::fs::Permissions::from_mode(0o640))
+            .expect("permissions to be changed");
+        assert_ne!(strict, Stamp::new(&path, &layout, true).await);
+        assert_eq!(lax, Stamp::new(&path, &layout, false).await);
+    }
+}
@@ -0,0 +1,63 @@
+//! # Chain module
+//!
+//! This module provides helpers to check that a certificate chain is ordered
+//! and complete
+
+use sozu_command_lib::certificate::{parse_pem, parse_x509};
+use x509_parser::certificate::X509Certificate;
+
+// -------------------------------------------------------------------------------------
+// Error
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to parse certificate at link {0}, {1}")]
+    Parse(usize, String),
+    #[error("certificate at link {0} is issued by '{1}', but the next one is '{2}'")]
+    Issuer(usize, String, String),
+    #[error("certificate at link {0} is not signed by the next one, {1}")]
+    Signature(usize, String),
+}
+
+// -------------------------------------------------------------------------------------
+// Helpers
+
+/// Check that each certificate of the chain is issued and signed by the next
+/// one, the link 0 being the leaf certificate.
+///
+/// Returns whether the chain ends with a self-signed certificate, which is not
+/// required as clients usually know the root.
+pub fn verify(leaf: &X509Certificate, chain: &[String]) -> Result<bool, Error> {
+    let mut pems = vec![];
+    for (idx, certificate) in chain.iter().enumerate() {
+        pems.push(
+            parse_pem(certificate.as_bytes())
+                .map_err(|err| Error::Parse(idx + 1, err.to_string()))?,
+        );
+    }
+
+    let mut certificates = vec![];
+    for (idx, pem) in pems.iter().enumerate() {
+        certificates
+            .push(parse_x509(&pem.contents).map_err(|err| Error::Parse(idx + 1, err.to_string()))?);
+    }
+
+    let mut current = leaf;
+    for (idx, issuer) in certificates.iter().enumerate() {
+        if current.issuer() != issuer.subject() {
+            return Err(Error::Issuer(
+                idx,
+                current.issuer().to_string(),
+                issuer.subject().to_string(),
+            ));
+        }
+
+        current
+            .verify_signature(Some(issuer.public_key()))
+            .map_err(|err| Error::Signature(idx, err.to_string()))?;
+
+        current = issuer;
+    }
+
+    Ok(current.issuer() == current.subject())
+}
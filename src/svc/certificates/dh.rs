@@ -0,0 +1,113 @@
+//! # Dh module
+//!
+//! This module provides helpers to check pem encoded Diffie-Hellman parameters
+//! stored next to certificates, see PKCS#3. Sōzu cannot receive them, so they
+//! are only checked and never sent.
+
+use x509_parser::{
+    der_parser::asn1_rs::{FromDer, Integer, Sequence},
+    pem::parse_x509_pem,
+};
+
+// -------------------------------------------------------------------------------------
+// Constants
+
+/// Label of the pem block holding Diffie-Hellman parameters
+pub const LABEL: &str = "DH PARAMETERS";
+
+/// Minimum size in bits of the prime, smaller ones are considered weak
+pub const MIN_BITS: usize = 2048;
+
+// -------------------------------------------------------------------------------------
+// Error
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to parse pem, {0}")]
+    Pem(String),
+    #[error("pem block is labelled '{0}', expected '{LABEL}'")]
+    Label(String),
+    #[error("failed to parse der, {0}")]
+    Parse(String),
+}
+
+// -------------------------------------------------------------------------------------
+// Helpers
+
+/// Parse the given pem encoded Diffie-Hellman parameters and return the size
+/// in bits of their prime
+pub fn prime_bits(data: &[u8]) -> Result<usize, Error> {
+    let (_, pem) = parse_x509_pem(data).map_err(|err| Error::Pem(err.to_string()))?;
+    if LABEL != pem.label {
+        return Err(Error::Label(pem.label));
+    }
+
+    // DHParameter ::= SEQUENCE { prime INTEGER, base INTEGER,
+    // privateValueLength INTEGER OPTIONAL }
+    let (_, prime) = Sequence::from_der_and_then(&pem.contents, Integer::from_der)
+        .map_err(|err| Error::Parse(err.to_string()))?;
+
+    // The integer is big endian and may be padded with a leading zero
+    let bytes = prime.as_ref();
+    let bits = match bytes.iter().position(|byte| 0 != *byte) {
+        Some(idx) => (bytes.len() - idx) * 8 - bytes[idx].leading_zeros() as usize,
+        None => 0,
+    };
+
+    Ok(bits)
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    use super::*;
+
+    /// Encode parameters whose prime has the given size in bits, the prime is
+    /// not an actual one as only its size matters
+    fn parameters(bits: usize) -> String {
+        let mut prime = vec![0xff; bits / 8];
+        prime.insert(0, 0);
+
+        let mut integer = vec![0x02];
+        integer.extend(length(prime.len()));
+        integer.extend(prime);
+        integer.extend([0x02, 0x01, 0x02]);
+
+        let mut sequence = vec![0x30];
+        sequence.extend(length(integer.len()));
+        sequence.extend(integer);
+
+        format!(
+            "-----BEGIN {LABEL}-----\n{}\n-----END {LABEL}-----\n",
+            STANDARD.encode(sequence)
+        )
+    }
+
+    /// Der encoding of the given length
+    fn length(len: usize) -> Vec<u8> {
+        match len {
+            0..=0x7f => vec![len as u8],
+            0x80..=0xff => vec![0x81, len as u8],
+            _ => vec![0x82, (len >> 8) as u8, len as u8],
+        }
+    }
+
+    #[test]
+    fn size_of_the_prime_is_returned() {
+        assert_eq!(
+            Ok(1024),
+            prime_bits(parameters(1024).as_bytes()).map_err(|_| ())
+        );
+        assert_eq!(
+            Ok(2048),
+            prime_bits(parameters(2048).as_bytes()).map_err(|_| ())
+        );
+    }
+
+    #[test]
+    fn other_pem_blocks_are_refused() {
+        let pem = parameters(2048).replace(LABEL, "CERTIFICATE");
+        assert!(matches!(prime_bits(pem.as_bytes()), Err(Error::Label(_))));
+    }
+}
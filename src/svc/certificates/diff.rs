@@ -8,12 +8,14 @@ use std::{
     path::PathBuf,
 };
 
+use serde::{Serialize, Serializer};
+
 use crate::svc::certificates::Metadata;
 
 // -------------------------------------------------------------------------------------
 // Diff
 
-#[derive(Debug, Clone)]
+#[derive(Serialize, Debug, Clone)]
 pub struct Diff<T>
 where
     T: PartialEq + Eq + Debug + Clone,
@@ -21,17 +23,27 @@ where
     pub added: HashSet<T>,
     pub deleted: HashSet<T>,
     pub modified: HashSet<T>,
+    /// Deleted entries whose content has been found in an added one, as
+    /// couples of old and new entries
+    #[serde(serialize_with = "serialize_couples")]
+    pub renamed: HashMap<T, T>,
 }
 
 impl<T> Diff<T>
 where
     T: PartialEq + Eq + Debug + Clone,
 {
-    pub fn new(added: HashSet<T>, modified: HashSet<T>, deleted: HashSet<T>) -> Diff<T> {
+    pub fn new(
+        added: HashSet<T>,
+        modified: HashSet<T>,
+        deleted: HashSet<T>,
+        renamed: HashMap<T, T>,
+    ) -> Diff<T> {
         Self {
             added,
             modified,
             deleted,
+            renamed,
         }
     }
 }
@@ -39,6 +51,16 @@ where
 // -------------------------------------------------------------------------------------
 // Helpers
 
+/// Serialize a map as a list of couples, so that its keys do not have to be
+/// strings
+fn serialize_couples<T, S>(map: &HashMap<T, T>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Serialize,
+    S: Serializer,
+{
+    serializer.collect_seq(map.iter())
+}
+
 #[tracing::instrument(skip_all)]
 pub fn create(
     current: &HashMap<PathBuf, Metadata>,
@@ -47,21 +69,77 @@ pub fn create(
     let current_keys: HashSet<&PathBuf> = current.keys().collect();
     let new_keys: HashSet<&PathBuf> = new.keys().collect();
 
-    let deleted_keys: HashSet<PathBuf> = current_keys
+    let mut deleted_keys: HashSet<PathBuf> = current_keys
         .difference(&new_keys)
         .map(|path| path.to_path_buf())
         .collect();
 
-    let added_keys: HashSet<PathBuf> = new_keys
+    let mut added_keys: HashSet<PathBuf> = new_keys
         .difference(&current_keys)
         .map(|path| path.to_path_buf())
         .collect();
 
+    let renamed = renames(current, new, &deleted_keys, &added_keys);
+    for (old, new) in &renamed {
+        deleted_keys.remove(old);
+        added_keys.remove(new);
+    }
+
     let modified_keys: HashSet<PathBuf> = current_keys
         .intersection(&new_keys)
         .filter(|path| current.get(**path) != new.get(**path))
         .map(|path| path.to_path_buf())
         .collect();
 
-    Diff::new(added_keys, modified_keys, deleted_keys)
+    Diff::new(added_keys, modified_keys, deleted_keys, renamed)
+}
+
+/// Pair deleted and added directories which carry the same certificate, so
+/// that a renamed directory does not remove the certificate from the proxy.
+///
+/// Each deleted directory is paired with at most one added directory, in path
+/// order, to keep the pairing deterministic when several directories hold the
+/// same certificate.
+#[tracing::instrument(skip_all)]
+fn renames(
+    current: &HashMap<PathBuf, Metadata>,
+    new: &HashMap<PathBuf, Metadata>,
+    deleted: &HashSet<PathBuf>,
+    added: &HashSet<PathBuf>,
+) -> HashMap<PathBuf, PathBuf> {
+    let mut deleted = deleted.iter().collect::<Vec<_>>();
+    deleted.sort();
+
+    let mut index: HashMap<_, Vec<&PathBuf>> = HashMap::new();
+    for path in deleted {
+        if let Some(metadata) = current.get(path) {
+            index.entry(&metadata.fingerprint).or_default().push(path);
+        }
+    }
+
+    let mut added = added.iter().collect::<Vec<_>>();
+    added.sort();
+
+    let mut renamed = HashMap::new();
+    for path in added {
+        let Some(metadata) = new.get(path) else {
+            continue;
+        };
+
+        let Some(candidates) = index.get_mut(&metadata.fingerprint) else {
+            continue;
+        };
+
+        let position = candidates.iter().position(|candidate| {
+            current
+                .get(*candidate)
+                .is_some_and(|candidate| candidate.is_same_certificate(metadata))
+        });
+
+        if let Some(position) = position {
+            renamed.insert(candidates.remove(position).to_owned(), path.to_owned());
+        }
+    }
+
+    renamed
 }
@@ -0,0 +1,521 @@
+//! # Events module
+//!
+//! This module provides a listener on filesystem events of the pki directories
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use notify::{
+    recommended_watcher, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher as _,
+};
+use tokio::{
+    fs,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver},
+    time::Instant,
+};
+use tracing::{debug, info, trace, warn};
+
+use crate::svc::config::Layout;
+
+// -------------------------------------------------------------------------------------
+// Constants
+
+/// Delay between two attempts to watch a pki directory once it has been
+/// removed, see [`EventListener::rewatch`]
+pub const REWATCH_DELAY: Duration = Duration::from_secs(1);
+
+// -------------------------------------------------------------------------------------
+// Error
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to create filesystem watcher, {0}")]
+    CreateWatcher(notify::Error),
+    #[error("failed to watch path '{0}', {1}")]
+    Watch(PathBuf, notify::Error),
+    #[error("failed to receive filesystem events, channel is closed")]
+    Closed,
+}
+
+// -------------------------------------------------------------------------------------
+// Change
+
+/// A change observed in the pki directories
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Change {
+    /// Certificate directories that have been created, modified or removed
+    Directories(HashSet<PathBuf>),
+    /// A pki directory has been (re-)created, everything should be looked up
+    All,
+}
+
+impl Change {
+    /// Merge the given change into this one
+    pub fn merge(&mut self, other: Self) {
+        match (self, other) {
+            (Self::Directories(directories), Self::Directories(others)) => {
+                directories.extend(others);
+            }
+            (this, Self::All) => *this = Self::All,
+            (Self::All, _) => {}
+        }
+    }
+}
+
+// -------------------------------------------------------------------------------------
+// EventListener
+
+pub struct EventListener {
+    /// Paths to the watched pki directories
+    roots: Vec<PathBuf>,
+    /// Depth down to which certificate directories are searched
+    max_depth: usize,
+    /// Layout of certificate directories, to map files of a flat layout to
+    /// their certificate
+    layout: Layout,
+    /// Filesystem watcher, inotify on Linux
+    watcher: RecommendedWatcher,
+    /// Receiver of filesystem events
+    rx: UnboundedReceiver<notify::Result<Event>>,
+    /// Pki directories that have been removed and are not watched anymore
+    removed: HashSet<PathBuf>,
+}
+
+impl EventListener {
+    #[tracing::instrument]
+    pub fn try_new(roots: Vec<PathBuf>, max_depth: usize, layout: Layout) -> Result<Self, Error> {
+        let (tx, rx) = unbounded_channel();
+        let mut watcher = recommended_watcher(move |res| {
+            // The receiver is only dropped with the listener, there is nothing
+            // left to notify at this point.
+            let _ = tx.send(res);
+        })
+        .map_err(Error::CreateWatcher)?;
+
+        for root in &roots {
+            info!(
+                path = root.display().to_string(),
+                "Watch pki directory for filesystem events"
+            );
+
+            watcher
+                .watch(root, RecursiveMode::Recursive)
+                .map_err(|err| Error::Watch(root.to_owned(), err))?;
+        }
+
+        Ok(Self {
+            roots,
+            max_depth: max_depth.max(1),
+            layout,
+            watcher,
+            rx,
+            removed: HashSet::new(),
+        })
+    }
+
+    /// Wait for the next change in the pki directories. A removed pki
+    /// directory is not watched anymore until [`Self::rewatch`] finds it
+    /// again, this future may be dropped at any point without losing track of
+    /// it.
+    #[tracing::instrument(skip_all)]
+    pub async fn next(&mut self) -> Result<Change, Error> {
+        loop {
+            let event = match self.rx.recv().await {
+                Some(Ok(event)) => event,
+                Some(Err(err)) => {
+                    warn!(
+                        error = err.to_string(),
+                        "Could not receive filesystem event, ask for a full lookup"
+                    );
+
+                    return Ok(Change::All);
+                }
+                None => return Err(Error::Closed),
+            };
+
+            trace!(
+                kind = format!("{:?}", event.kind),
+                paths = format!("{:?}", event.paths),
+                "Received filesystem event"
+            );
+
+            if event.need_rescan() {
+                debug!("Filesystem events have been missed, ask for a full lookup");
+                return Ok(Change::All);
+            }
+
+            if matches!(event.kind, EventKind::Access(_)) {
+                continue;
+            }
+
+            let mut removed = None;
+            for root in &self.roots {
+                if event.paths.iter().any(|path| path == root)
+                    && !fs::metadata(root)
+                        .await
+                        .is_ok_and(|metadata| metadata.is_dir())
+                {
+                    removed = Some(root.to_owned());
+                    break;
+                }
+            }
+
+            if let Some(root) = removed {
+                self.unwatch(root);
+                continue;
+            }
+
+            let mut directories = HashSet::new();
+            for path in &event.paths {
+                if let Some(directory) = self.directory(path).await {
+                    directories.insert(directory);
+                }
+            }
+
+            if !directories.is_empty() {
+                return Ok(Change::Directories(directories));
+            }
+        }
+    }
+
+    /// Retrieve the directory to look up for the given path, which is the
+    /// deepest directory holding it down to the maximum depth, or the
+    /// shallowest one that does not exist anymore.
+    ///
+    /// For a flat layout, it is the certificate the file belongs to, or the
+    /// directory holding the path if it is not a certificate file.
+    async fn directory(&self, path: &Path) -> Option<PathBuf> {
+        let root = self.roots.iter().find(|root| path.starts_with(root))?;
+        if self.layout.is_flat() {
+            let directory = match fs::metadata(path).await {
+                Ok(metadata) if metadata.is_dir() => path,
+                _ => path.parent()?,
+            };
+
+            let depth = directory.strip_prefix(root).ok()?.components().count();
+            if depth >= self.max_depth {
+                return None;
+            }
+
+            let name = path
+                .file_name()
+                .and_then(|name| self.layout.name_of(&name.to_string_lossy()));
+
+            return match name {
+                Some(name) if directory != path => Some(directory.join(name)),
+                _ => Some(directory.to_owned()),
+            };
+        }
+        let components = path.strip_prefix(root).ok()?.components();
+
+        let mut directory = None;
+        let mut candidate = root.to_owned();
+        for component in components.take(self.max_depth) {
+            candidate.push(component);
+            match fs::metadata(&candidate).await {
+                Ok(metadata) if metadata.is_dir() => directory = Some(candidate.to_owned()),
+                Ok(_) => break,
+                Err(_) => return Some(candidate),
+            }
+        }
+
+        directory
+    }
+
+    /// Stop to watch the given pki directory which has been removed, until it
+    /// is re-created
+    #[tracing::instrument(skip(self))]
+    fn unwatch(&mut self, root: PathBuf) {
+        warn!(
+            path = root.display().to_string(),
+            "Watched pki directory has been removed, wait for it to be re-created"
+        );
+
+        // The watch may already have been dropped by the kernel, so an error
+        // here is expected.
+        let _ = self.watcher.unwatch(&root);
+        self.removed.insert(root);
+    }
+
+    /// Returns true if a pki directory has been removed and is waiting to be
+    /// watched again
+    pub fn is_removed(&self) -> bool {
+        !self.removed.is_empty()
+    }
+
+    /// Watch again the removed pki directories which have been re-created,
+    /// to be called every [`REWATCH_DELAY`]. Returns a change asking for a
+    /// full lookup if any of them is watched again.
+    #[tracing::instrument(skip_all)]
+    pub async fn rewatch(&mut self) -> Option<Change> {
+        let mut rewatched = vec![];
+        for root in &self.removed {
+            if !fs::metadata(root)
+                .await
+                .is_ok_and(|metadata| metadata.is_dir())
+            {
+                continue;
+            }
+
+            match self.watcher.watch(root, RecursiveMode::Recursive) {
+                Ok(_) => {
+                    info!(
+                        path = root.display().to_string(),
+                        "Watch re-created pki directory for filesystem events"
+                    );
+
+                    rewatched.push(root.to_owned());
+                }
+                Err(err) => {
+                    warn!(
+                        error = err.to_string(),
+                        path = root.display().to_string(),
+                        "Could not watch re-created pki directory, retry later"
+                    );
+                }
+            }
+        }
+
+        for root in &rewatched {
+            self.removed.remove(root);
+        }
+
+        (!rewatched.is_empty()).then_some(Change::All)
+    }
+}
+
+// -------------------------------------------------------------------------------------
+// Coalescer
+
+/// Gather changes of all certificate directories over a window starting at the
+/// first change, so that a burst of events yields a single set of directories
+#[derive(Clone, Debug)]
+pub struct Coalescer {
+    /// Duration of the window
+    window: Duration,
+    /// Changes gathered so far with the instant of the first one
+    pending: Option<(Instant, Change)>,
+}
+
+impl Coalescer {
+    #[tracing::instrument]
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            pending: None,
+        }
+    }
+
+    /// Record a change
+    #[tracing::instrument(skip_all)]
+    pub fn push(&mut self, change: Change) {
+        match &mut self.pending {
+            Some((_, pending)) => pending.merge(change),
+            None => self.pending = Some((Instant::now(), change)),
+        }
+    }
+
+    /// Forget gathered changes, typically when a full lookup happens
+    pub fn clear(&mut self) {
+        self.pending = None;
+    }
+
+    /// Returns the instant at which the window closes, if any change has been
+    /// gathered
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending.as_ref().map(|(first, _)| *first + self.window)
+    }
+
+    /// Remove and return gathered changes once the window is closed
+    #[tracing::instrument(skip_all)]
+    pub fn due(&mut self) -> Option<Change> {
+        if self.deadline()? > Instant::now() {
+            return None;
+        }
+
+        let (_, change) = self.pending.take()?;
+        if let Change::Directories(directories) = &change {
+            debug!(
+                number = directories.len(),
+                "Coalesced filesystem events of certificate directories"
+            );
+        }
+
+        Some(change)
+    }
+}
+
+// -------------------------------------------------------------------------------------
+// Debouncer
+
+/// Delay the lookup of certificate directories until they are quiet for a
+/// while, each directory is debounced independently.
+#[derive(Clone, Debug)]
+pub struct Debouncer {
+    /// Quiet period to wait after the last change of a directory
+    debounce: Duration,
+    /// Maximum delay between the first change of a directory and its lookup
+    max_debounce: Duration,
+    /// Pending directories with the instant of their first and last change
+    pending: HashMap<PathBuf, (Instant, Instant)>,
+}
+
+impl Debouncer {
+    #[tracing::instrument]
+    pub fn new(debounce: Duration, max_debounce: Duration) -> Self {
+        Self {
+            debounce,
+            max_debounce,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Record a change on the given directories
+    #[tracing::instrument(skip_all)]
+    pub fn push(&mut self, paths: HashSet<PathBuf>) {
+        let now = Instant::now();
+        for path in paths {
+            self.pending
+                .entry(path)
+                .and_modify(|(_, last)| *last = now)
+                .or_insert((now, now));
+        }
+    }
+
+    /// Forget all pending directories, typically when a full lookup happens
+    #[tracing::instrument(skip_all)]
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    /// Returns the instant at which the next directory will be ready, if any
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .map(|(first, last)| self.deadline_of(*first, *last))
+            .min()
+    }
+
+    /// Remove and return directories that are ready to be looked up, the ones
+    /// still within their quiet period are left pending
+    #[tracing::instrument(skip_all)]
+    pub fn due(&mut self) -> HashSet<PathBuf> {
+        let now = Instant::now();
+        let due: HashSet<_> = self
+            .pending
+            .iter()
+            .filter(|(_, (first, last))| self.deadline_of(*first, *last) <= now)
+            .map(|(path, _)| path.to_owned())
+            .collect();
+
+        self.pending.retain(|path, _| !due.contains(path));
+        due
+    }
+
+    fn deadline_of(&self, first: Instant, last: Instant) -> Instant {
+        (last + self.debounce).min(first + self.max_debounce)
+    }
+}
+
+// -------------------------------------------------------------------------------------
+// Tests
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, path::PathBuf, time::Duration};
+
+    use tokio::time::{advance, pause};
+
+    use super::{Change, Coalescer, Debouncer};
+
+    fn directories(paths: &[&str]) -> HashSet<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[tokio::test]
+    async fn coalescer_gathers_changes_over_its_window() {
+        pause();
+        let mut coalescer = Coalescer::new(Duration::from_millis(100));
+        assert_eq!(None, coalescer.deadline());
+        assert_eq!(None, coalescer.due());
+
+        // The window starts at the first change and is not extended by others
+        coalescer.push(Change::Directories(directories(&["a"])));
+        let deadline = coalescer.deadline();
+        advance(Duration::from_millis(60)).await;
+        coalescer.push(Change::Directories(directories(&["b"])));
+        assert_eq!(deadline, coalescer.deadline());
+        assert_eq!(None, coalescer.due());
+
+        advance(Duration::from_millis(40)).await;
+        assert_eq!(
+            Some(Change::Directories(directories(&["a", "b"]))),
+            coalescer.due()
+        );
+        assert_eq!(None, coalescer.deadline());
+
+        // A re-created pki directory supersedes any directory
+        coalescer.push(Change::Directories(directories(&["a"])));
+        coalescer.push(Change::All);
+        coalescer.push(Change::Directories(directories(&["c"])));
+        advance(Duration::from_millis(100)).await;
+        assert_eq!(Some(Change::All), coalescer.due());
+    }
+
+    #[tokio::test]
+    async fn debouncer_waits_for_the_quiet_period() {
+        pause();
+        let mut debouncer = Debouncer::new(Duration::from_millis(100), Duration::from_secs(1));
+        debouncer.push(directories(&["a"]));
+
+        // Each change restarts the quiet period
+        advance(Duration::from_millis(80)).await;
+        debouncer.push(directories(&["a"]));
+        advance(Duration::from_millis(80)).await;
+        assert!(debouncer.due().is_empty());
+
+        advance(Duration::from_millis(20)).await;
+        assert_eq!(directories(&["a"]), debouncer.due());
+        assert_eq!(None, debouncer.deadline());
+    }
+
+    #[tokio::test]
+    async fn debouncer_caps_the_delay_of_busy_directories() {
+        pause();
+        let mut debouncer = Debouncer::new(Duration::from_millis(100), Duration::from_millis(250));
+
+        // A directory which never quiets down is looked up anyway
+        for _ in 0..4 {
+            debouncer.push(directories(&["a"]));
+            assert!(debouncer.due().is_empty());
+            advance(Duration::from_millis(60)).await;
+        }
+
+        advance(Duration::from_millis(10)).await;
+        assert_eq!(directories(&["a"]), debouncer.due());
+    }
+
+    #[tokio::test]
+    async fn debouncer_handles_directories_independently() {
+        pause();
+        let mut debouncer = Debouncer::new(Duration::from_millis(100), Duration::from_secs(1));
+        debouncer.push(directories(&["a"]));
+        advance(Duration::from_millis(50)).await;
+        debouncer.push(directories(&["b"]));
+
+        // The second directory is still within its quiet period when the first
+        // one is ready, it is not looked up early
+        advance(Duration::from_millis(50)).await;
+        assert_eq!(directories(&["a"]), debouncer.due());
+        assert!(debouncer.due().is_empty());
+
+        advance(Duration::from_millis(49)).await;
+        assert!(debouncer.due().is_empty());
+
+        advance(Duration::from_millis(1)).await;
+        assert_eq!(directories(&["b"]), debouncer.due());
+    }
+}
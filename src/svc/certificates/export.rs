@@ -0,0 +1,146 @@
+//! # Export module
+//!
+//! This module provides the export of the diff computed at each lookup as a
+//! JSON line, for external tooling, e.g. a change management system
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+
+use crate::svc::certificates::{diff::Diff, Metadata};
+
+// -------------------------------------------------------------------------------------
+// Constants
+
+/// Path of the diff sink which writes to the standard output
+pub const STDOUT: &str = "-";
+
+// -------------------------------------------------------------------------------------
+// Error
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to serialize diff, {0}")]
+    Serialize(serde_json::Error),
+    #[error("failed to write diff to '{0}', {1}")]
+    Write(PathBuf, io::Error),
+}
+
+// -------------------------------------------------------------------------------------
+// Entry
+
+/// Certificate directory of an exported diff
+#[derive(Serialize, PartialEq, Eq, Hash, Debug, Clone)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub fingerprint: String,
+    pub names: Vec<String>,
+}
+
+impl From<&Metadata> for Entry {
+    fn from(metadata: &Metadata) -> Self {
+        let mut names = metadata.names.iter().cloned().collect::<Vec<_>>();
+        names.sort();
+
+        Self {
+            path: metadata.path.to_owned(),
+            fingerprint: metadata.fingerprint.to_string(),
+            names,
+        }
+    }
+}
+
+// -------------------------------------------------------------------------------------
+// Record
+
+/// Exported diff, with the unix timestamp in milliseconds at which it has
+/// been computed
+#[derive(Serialize, Debug, Clone)]
+pub struct Record {
+    pub timestamp: i64,
+    #[serde(flatten)]
+    pub diff: Diff<Entry>,
+}
+
+impl Record {
+    /// Describe the entries of the diff with their metadata, the current one
+    /// for deleted directories and the new one for the others
+    pub fn new(
+        diff: &Diff<PathBuf>,
+        current: &HashMap<PathBuf, Metadata>,
+        new: &HashMap<PathBuf, Metadata>,
+    ) -> Self {
+        let renamed = diff
+            .renamed
+            .iter()
+            .filter_map(|(old, renamed)| {
+                Some((
+                    Entry::from(current.get(old)?),
+                    Entry::from(new.get(renamed)?),
+                ))
+            })
+            .collect();
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as i64)
+            .unwrap_or_default();
+
+        Self {
+            timestamp,
+            diff: Diff::new(
+                entries(&diff.added, new),
+                entries(&diff.modified, new),
+                entries(&diff.deleted, current),
+                renamed,
+            ),
+        }
+    }
+}
+
+// -------------------------------------------------------------------------------------
+// Helpers
+
+/// Describe the given directories with their metadata
+fn entries(paths: &HashSet<PathBuf>, metadata: &HashMap<PathBuf, Metadata>) -> HashSet<Entry> {
+    paths
+        .iter()
+        .filter_map(|path| metadata.get(path).map(Entry::from))
+        .collect()
+}
+
+/// Append the record as a single JSON line to the given file, or to the
+/// standard output if the path is [`STDOUT`]. The file is opened at each write,
+/// so that it may be rotated by moving it away.
+#[tracing::instrument(skip(record))]
+pub async fn write(path: &Path, record: &Record) -> Result<(), Error> {
+    let mut line = serde_json::to_vec(record).map_err(Error::Serialize)?;
+    line.push(b'\n');
+
+    // The standard output is locked, as for logs, so that the line is not
+    // interleaved with them
+    if Path::new(STDOUT) == path {
+        let mut stdout = io::stdout().lock();
+        return stdout
+            .write_all(&line)
+            .and_then(|_| stdout.flush())
+            .map_err(|err| Error::Write(path.to_owned(), err));
+    }
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .map_err(|err| Error::Write(path.to_owned(), err))?;
+
+    file.write_all(&line)
+        .await
+        .map_err(|err| Error::Write(path.to_owned(), err))
+}
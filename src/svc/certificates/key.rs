@@ -0,0 +1,290 @@
+//! # Key module
+//!
+//! This module provides helpers to check that a private key belongs to a
+//! certificate and to detect changes of a private key without keeping it
+
+use std::fmt::{self, Debug, Formatter};
+
+use p256::elliptic_curve::sec1::ToEncodedPoint;
+use rsa::{
+    pkcs1::{DecodeRsaPrivateKey, EncodeRsaPublicKey},
+    pkcs8::DecodePrivateKey,
+    RsaPrivateKey,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x509_parser::{
+    certificate::X509Certificate,
+    oid_registry::{
+        OID_EC_P256, OID_KEY_TYPE_EC_PUBLIC_KEY, OID_NIST_EC_P384, OID_NIST_EC_P521,
+        OID_PKCS1_RSAENCRYPTION, OID_SIG_ED25519, OID_SIG_ED448,
+    },
+    pem::parse_x509_pem,
+    public_key::PublicKey,
+};
+use zeroize::Zeroize;
+
+use crate::svc::config::{Curve, KeyAlgorithm, KeyPolicy};
+
+// -------------------------------------------------------------------------------------
+// Error
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to encode public key, {0}")]
+    Encode(rsa::pkcs1::Error),
+}
+
+// -------------------------------------------------------------------------------------
+// KeyDigest
+
+/// SHA-256 digest of a private key, the digest is never displayed to avoid
+/// leaking anything about the key in logs.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+pub struct KeyDigest([u8; 32]);
+
+impl Debug for KeyDigest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("KeyDigest(<redacted>)")
+    }
+}
+
+impl KeyDigest {
+    /// Compute the digest of the given pem encoded private key.
+    ///
+    /// The digest is computed on the decoded key material when possible, so
+    /// that whitespaces or line endings do not change it.
+    pub fn new(key: &str) -> Self {
+        let digest = match parse_x509_pem(key.as_bytes()) {
+            Ok((_, mut pem)) => {
+                let digest = Sha256::digest(&pem.contents);
+                pem.contents.zeroize();
+                digest
+            }
+            Err(_) => Sha256::digest(key.trim().as_bytes()),
+        };
+
+        Self(digest.into())
+    }
+}
+
+// -------------------------------------------------------------------------------------
+// Helpers
+
+/// Derive the public key of the given pem encoded private key, as it is
+/// encoded in the subject public key of a certificate.
+///
+/// Returns `None` if the kind of the private key is not supported, at the
+/// moment RSA (PKCS#1 and PKCS#8) and ECDSA P-256/P-384 (SEC1 and PKCS#8).
+pub fn public_key(key: &str) -> Result<Option<Vec<u8>>, Error> {
+    if let Ok(private_key) =
+        RsaPrivateKey::from_pkcs1_pem(key).or_else(|_| RsaPrivateKey::from_pkcs8_pem(key))
+    {
+        return private_key
+            .to_public_key()
+            .to_pkcs1_der()
+            .map(|document| Some(document.into_vec()))
+            .map_err(Error::Encode);
+    }
+
+    if let Ok(private_key) =
+        p256::SecretKey::from_sec1_pem(key).or_else(|_| p256::SecretKey::from_pkcs8_pem(key))
+    {
+        return Ok(Some(
+            private_key
+                .public_key()
+                .to_encoded_point(false)
+                .as_bytes()
+                .to_vec(),
+        ));
+    }
+
+    if let Ok(private_key) =
+        p384::SecretKey::from_sec1_pem(key).or_else(|_| p384::SecretKey::from_pkcs8_pem(key))
+    {
+        return Ok(Some(
+            private_key
+                .public_key()
+                .to_encoded_point(false)
+                .as_bytes()
+                .to_vec(),
+        ));
+    }
+
+    Ok(None)
+}
+
+/// Check that the public key of the certificate complies with the given
+/// policy, returns the reason why it does not, if so
+pub fn violation(x509: &X509Certificate, policy: &KeyPolicy) -> Option<String> {
+    let spki = x509.public_key();
+    let oid = &spki.algorithm.algorithm;
+    let algorithm = if OID_PKCS1_RSAENCRYPTION == *oid {
+        KeyAlgorithm::Rsa
+    } else if OID_KEY_TYPE_EC_PUBLIC_KEY == *oid {
+        KeyAlgorithm::Ecdsa
+    } else if OID_SIG_ED25519 == *oid {
+        KeyAlgorithm::Ed25519
+    } else if OID_SIG_ED448 == *oid {
+        KeyAlgorithm::Ed448
+    } else {
+        return Some(format!("algorithm {oid} is not supported"));
+    };
+
+    if !policy.algorithms.contains(&algorithm) {
+        return Some(format!("algorithm {algorithm:?} is not allowed"));
+    }
+
+    match algorithm {
+        KeyAlgorithm::Rsa => match spki.parsed() {
+            Ok(PublicKey::RSA(rsa)) if rsa.key_size() >= policy.rsa_min_bits => None,
+            Ok(PublicKey::RSA(rsa)) => Some(format!(
+                "RSA key of {} bits is smaller than {} bits",
+                rsa.key_size(),
+                policy.rsa_min_bits
+            )),
+            _ => Some("RSA key could not be parsed".to_string()),
+        },
+        KeyAlgorithm::Ecdsa => {
+            let curve = spki
+                .algorithm
+                .parameters
+                .as_ref()
+                .and_then(|parameters| parameters.as_oid().ok());
+
+            let curve = match curve {
+                Some(curve) if OID_EC_P256 == curve => Curve::P256,
+                Some(curve) if OID_NIST_EC_P384 == curve => Curve::P384,
+                Some(curve) if OID_NIST_EC_P521 == curve => Curve::P521,
+                Some(curve) => return Some(format!("curve {curve} is not supported")),
+                None => return Some("curve of ECDSA key is unknown".to_string()),
+            };
+
+            if policy.curves.contains(&curve) {
+                None
+            } else {
+                Some(format!("curve {curve:?} is not allowed"))
+            }
+        }
+        KeyAlgorithm::Ed25519 | KeyAlgorithm::Ed448 => None,
+    }
+}
+
+/// Check that the given pem encoded private key belongs to the certificate.
+///
+/// Returns `None` if the kind of the private key is not supported.
+pub fn matches(x509: &X509Certificate, key: &str) -> Result<Option<bool>, Error> {
+    Ok(public_key(key)?
+        .map(|public_key| public_key.as_slice() == x509.public_key().subject_public_key.as_ref()))
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{
+        Certificate, CertificateParams, KeyPair, RemoteKeyPair, SignatureAlgorithm,
+        PKCS_ECDSA_P256_SHA256, PKCS_ECDSA_P384_SHA384, PKCS_RSA_SHA256,
+    };
+    use rsa::RsaPublicKey;
+    use x509_parser::pem::parse_x509_pem;
+
+    use super::*;
+
+    /// Public RSA key of a certificate signed by another one, its private key
+    /// is never used
+    struct PublicRsaKey(Vec<u8>);
+
+    impl RemoteKeyPair for PublicRsaKey {
+        fn public_key(&self) -> &[u8] {
+            &self.0
+        }
+
+        fn sign(&self, _: &[u8]) -> Result<Vec<u8>, rcgen::Error> {
+            Err(rcgen::Error::RemoteKeyError)
+        }
+
+        fn algorithm(&self) -> &'static SignatureAlgorithm {
+            &PKCS_RSA_SHA256
+        }
+    }
+
+    /// Generate a certificate holding the given key pair signed by a throwaway
+    /// authority, as keys that are too weak cannot sign anything
+    fn certificate(key_pair: KeyPair) -> String {
+        let authority = Certificate::from_params(CertificateParams::new(vec![]))
+            .expect("authority to be generated");
+
+        let mut params = CertificateParams::new(vec!["example.com".to_string()]);
+        params.alg = key_pair.algorithm();
+        params.key_pair = Some(key_pair);
+
+        Certificate::from_params(params)
+            .expect("certificate to be generated")
+            .serialize_pem_with_signer(&authority)
+            .expect("certificate to be signed")
+    }
+
+    fn rsa(bits: usize) -> KeyPair {
+        let private_key =
+            RsaPrivateKey::new(&mut rand::thread_rng(), bits).expect("RSA key to be generated");
+        let public_key = RsaPublicKey::from(&private_key)
+            .to_pkcs1_der()
+            .expect("RSA key to be encoded");
+
+        KeyPair::from_remote(Box::new(PublicRsaKey(public_key.into_vec())))
+            .expect("key pair to be created")
+    }
+
+    fn ecdsa(algorithm: &'static SignatureAlgorithm) -> KeyPair {
+        KeyPair::generate(algorithm).expect("ECDSA key to be generated")
+    }
+
+    fn check(certificate: &str, policy: &KeyPolicy) -> Option<String> {
+        let (_, pem) = parse_x509_pem(certificate.as_bytes()).expect("pem to be parsed");
+        let x509 = pem.parse_x509().expect("certificate to be parsed");
+        violation(&x509, policy)
+    }
+
+    #[test]
+    fn weak_rsa_key_is_refused() {
+        let reason = check(&certificate(rsa(1024)), &KeyPolicy::default());
+        assert_eq!(
+            Some("RSA key of 1024 bits is smaller than 2048 bits".to_string()),
+            reason
+        );
+    }
+
+    #[test]
+    fn compliant_rsa_key_passes() {
+        assert_eq!(None, check(&certificate(rsa(2048)), &KeyPolicy::default()));
+    }
+
+    #[test]
+    fn curve_must_be_allowed() {
+        let policy = KeyPolicy {
+            curves: vec![Curve::P256],
+            ..KeyPolicy::default()
+        };
+
+        assert_eq!(
+            None,
+            check(&certificate(ecdsa(&PKCS_ECDSA_P256_SHA256)), &policy)
+        );
+        assert_eq!(
+            Some("curve P384 is not allowed".to_string()),
+            check(&certificate(ecdsa(&PKCS_ECDSA_P384_SHA384)), &policy)
+        );
+    }
+
+    #[test]
+    fn algorithm_must_be_allowed() {
+        let policy = KeyPolicy {
+            algorithms: vec![KeyAlgorithm::Ecdsa],
+            ..KeyPolicy::default()
+        };
+
+        assert_eq!(
+            Some("algorithm Rsa is not allowed".to_string()),
+            check(&certificate(rsa(2048)), &policy)
+        );
+    }
+}
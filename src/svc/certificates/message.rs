@@ -2,14 +2,22 @@
 //!
 //! This module provides helpers to generate messages to send to Sōzu
 
-use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::PathBuf,
+};
 
+use sozu_command_lib::certificate::Fingerprint;
 use sozu_command_lib::proto::command::{
     request::RequestType, AddCertificate, CertificateAndKey, RemoveCertificate, ReplaceCertificate,
 };
-use tracing::{trace, Level};
+use tracing::{debug, trace, Level};
 
-use crate::svc::certificates::{self, Metadata};
+use crate::svc::{
+    certificates::{self, diff::Diff, Metadata},
+    config::{RequestOrder, SendPriority},
+};
 
 // -------------------------------------------------------------------------------------
 // Error
@@ -25,98 +33,398 @@ pub enum Error {
 // -------------------------------------------------------------------------------------
 // Helpers
 
+/// Requests to send to Sōzu alongside the certificate directory they come from
+pub type Requests = Vec<(PathBuf, RequestType)>;
+
+/// Returns the listeners of a certificate, the one of its directory overrides
+/// the given ones
+pub fn listeners_of(metadata: &Metadata, https_listeners: &[SocketAddr]) -> Vec<SocketAddr> {
+    metadata
+        .listener
+        .map_or_else(|| https_listeners.to_vec(), |listener| vec![listener])
+}
+
+/// Create requests to send to Sōzu to go from the current to the new
+/// certificates, alongside the diff they come from.
+///
+/// Certificates are counted by fingerprint, including the ones of the other
+/// directories which do not change: a certificate is only added if no
+/// directory held it before and only removed if no directory holds it after,
+/// and a single request is created per fingerprint.
 #[tracing::instrument(skip_all)]
 pub fn create(
-    https_listener: SocketAddr,
+    https_listeners: &[SocketAddr],
+    order: RequestOrder,
+    priority: SendPriority,
     current: &HashMap<PathBuf, Metadata>,
     new: &HashMap<PathBuf, Metadata>,
+    others: &HashMap<PathBuf, Metadata>,
     pki: &HashMap<PathBuf, CertificateAndKey>,
-) -> Result<Vec<(PathBuf, RequestType)>, Error> {
+) -> Result<(Diff<PathBuf>, Requests), Error> {
     let diff = certificates::diff::create(current, new);
 
+    let before: HashSet<&Fingerprint> = current
+        .values()
+        .chain(others.values())
+        .map(|metadata| &metadata.fingerprint)
+        .collect();
+
+    let after: HashSet<&Fingerprint> = new
+        .values()
+        .chain(others.values())
+        .map(|metadata| &metadata.fingerprint)
+        .collect();
+
+    let listeners_of = |metadata: &Metadata| listeners_of(metadata, https_listeners);
+
+    // Fingerprints for which a request has already been created
+    let mut added_fingerprints = before.to_owned();
+    let mut removed_fingerprints = after.to_owned();
+
+    for (old, new) in &diff.renamed {
+        debug!(
+            old = old.display().to_string(),
+            new = new.display().to_string(),
+            "Certificate directory has been renamed, nothing to send to the proxy"
+        );
+    }
+
     // ---------------------------------------------------------------------------------
     // Create messages to add new certificates
-    let mut acc = vec![];
-    for added in diff.added.into_iter() {
+    let mut additions = vec![];
+    let mut added = diff.added.iter().collect::<Vec<_>>();
+    added.sort();
+
+    for added in added {
         let metadata = new
-            .get(&added)
+            .get(added)
             .ok_or_else(|| Error::NoMetadataFor(added.to_owned()))?;
 
+        if !added_fingerprints.insert(&metadata.fingerprint) {
+            debug!(
+                path = added.display().to_string(),
+                fingerprint = metadata.fingerprint.to_string(),
+                "Certificate is already provided by another directory, nothing to add"
+            );
+
+            continue;
+        }
+
+        let certificate = pki
+            .get(added)
+            .ok_or_else(|| Error::NoPKIAt(added.to_owned()))?;
+
         let names = metadata.names.iter().cloned().collect::<Vec<_>>();
-        trace!(
-            address = https_listener.to_string(),
-            names = names.join(", "),
-            fingerprint = metadata.fingerprint.to_string(),
-            "Create a message to add certificate to proxy for the given listener"
-        );
+        for https_listener in &listeners_of(metadata) {
+            trace!(
+                address = https_listener.to_string(),
+                names = names.join(", "),
+                fingerprint = metadata.fingerprint.to_string(),
+                serial = metadata.serial,
+                issuer = metadata.issuer,
+                "Create a message to add certificate to proxy for the given listener"
+            );
 
-        let request_type = RequestType::AddCertificate(AddCertificate {
-            address: https_listener.into(),
-            certificate: pki
-                .get(&added)
-                .ok_or_else(|| Error::NoPKIAt(added.to_owned()))?
-                .to_owned(),
-            expired_at: None,
-        });
+            let request_type = RequestType::AddCertificate(AddCertificate {
+                address: (*https_listener).into(),
+                certificate: certificate.to_owned(),
+                expired_at: metadata.expires_at,
+            });
 
-        acc.push((added, request_type))
+            additions.push((added.to_owned(), request_type))
+        }
     }
 
     // ---------------------------------------------------------------------------------
     // Create messages to delete old certificates
-    for deleted in diff.deleted.into_iter() {
+    let mut removals = vec![];
+    let mut deleted = diff.deleted.iter().collect::<Vec<_>>();
+    deleted.sort();
+
+    for deleted in deleted {
         let metadata = current
-            .get(&deleted)
+            .get(deleted)
             .ok_or_else(|| Error::NoMetadataFor(deleted.to_owned()))?;
 
-        trace!(
-            address = https_listener.to_string(),
-            fingerprint = metadata.fingerprint.to_string(),
-            "Create a message to delete certificate from proxy for the given listener"
-        );
+        if !removed_fingerprints.insert(&metadata.fingerprint) {
+            debug!(
+                path = deleted.display().to_string(),
+                fingerprint = metadata.fingerprint.to_string(),
+                "Certificate is still provided by another directory, nothing to remove"
+            );
+
+            continue;
+        }
+
+        for https_listener in &listeners_of(metadata) {
+            trace!(
+                address = https_listener.to_string(),
+                fingerprint = metadata.fingerprint.to_string(),
+                "Create a message to delete certificate from proxy for the given listener"
+            );
 
-        let request_type = RequestType::RemoveCertificate(RemoveCertificate {
-            address: https_listener.into(),
-            fingerprint: metadata.fingerprint.to_string(),
-        });
+            let request_type = RequestType::RemoveCertificate(RemoveCertificate {
+                address: (*https_listener).into(),
+                fingerprint: metadata.fingerprint.to_string(),
+            });
 
-        acc.push((deleted, request_type))
+            removals.push((deleted.to_owned(), request_type))
+        }
     }
 
     // -----------------------------------------------------------------------------
     // Create messages to replace modified certificates
-    for modified in diff.modified {
+    let mut replacements = vec![];
+    let mut modified = diff.modified.iter().collect::<Vec<_>>();
+    modified.sort();
+
+    for modified in modified {
         let metadata = current
-            .get(&modified)
+            .get(modified)
             .ok_or_else(|| Error::NoMetadataFor(modified.to_owned()))?;
 
-        let new_names = metadata.names.iter().cloned().collect::<Vec<_>>();
-        if tracing::enabled!(Level::TRACE) {
-            trace!(
-                address = https_listener.to_string(),
-                names = new_names.join(", "),
-                new_fingerprint = new
-                    .get(&modified)
-                    .ok_or_else(|| Error::NoMetadataFor(modified.to_owned()))?
-                    .fingerprint
-                    .to_string(),
-                old_fingerprint = metadata.fingerprint.to_string(),
-                "Create a message to replace certificate of proxy for the given listener"
+        let new_metadata = new
+            .get(modified)
+            .ok_or_else(|| Error::NoMetadataFor(modified.to_owned()))?;
+
+        let new_certificate = pki
+            .get(modified)
+            .ok_or_else(|| Error::NoPKIAt(modified.to_owned()))?;
+
+        // The same certificate is replaced in place, e.g. on a key rotation or
+        // when only its chain changed, otherwise the old one may still be
+        // provided by another directory and the new one may already be
+        // provided by another directory.
+        //
+        // Sōzu removes the old fingerprint before inserting the new
+        // certificate on a replacement, so replacing a certificate by itself
+        // does update its chain and key, whereas an addition of a fingerprint
+        // it already knows is skipped.
+        let (add, remove) = if metadata.fingerprint == new_metadata.fingerprint {
+            if metadata.chain_fingerprints != new_metadata.chain_fingerprints {
+                debug!(
+                    path = modified.display().to_string(),
+                    fingerprint = metadata.fingerprint.to_string(),
+                    "Only the chain of certificate changed, replace it in place"
+                );
+            }
+
+            (true, true)
+        } else {
+            (
+                added_fingerprints.insert(&new_metadata.fingerprint),
+                removed_fingerprints.insert(&metadata.fingerprint),
+            )
+        };
+
+        if !add && !remove {
+            debug!(
+                path = modified.display().to_string(),
+                "Certificates are provided by other directories, nothing to replace"
             );
+
+            continue;
         }
 
-        let request_type = RequestType::ReplaceCertificate(ReplaceCertificate {
-            address: https_listener.into(),
-            new_certificate: pki
-                .get(&modified)
-                .ok_or_else(|| Error::NoPKIAt(modified.to_owned()))?
-                .to_owned(),
-            old_fingerprint: metadata.fingerprint.to_string(),
-            new_expired_at: None,
-        });
-
-        acc.push((modified, request_type))
+        // The certificate moves to other listeners, it is added to the new
+        // ones and removed from the old ones rather than replaced
+        let (old_listeners, new_listeners) = (listeners_of(metadata), listeners_of(new_metadata));
+        if old_listeners != new_listeners {
+            debug!(
+                path = modified.display().to_string(),
+                old_listeners = format!("{old_listeners:?}"),
+                new_listeners = format!("{new_listeners:?}"),
+                "Listeners of certificate changed, move it"
+            );
+
+            for https_listener in new_listeners.iter().filter(|_| add) {
+                let request_type = RequestType::AddCertificate(AddCertificate {
+                    address: (*https_listener).into(),
+                    certificate: new_certificate.to_owned(),
+                    expired_at: new_metadata.expires_at,
+                });
+
+                additions.push((modified.to_owned(), request_type));
+            }
+
+            for https_listener in old_listeners.iter().filter(|_| remove) {
+                let request_type = RequestType::RemoveCertificate(RemoveCertificate {
+                    address: (*https_listener).into(),
+                    fingerprint: metadata.fingerprint.to_string(),
+                });
+
+                removals.push((modified.to_owned(), request_type));
+            }
+
+            continue;
+        }
+
+        let new_names = new_metadata.names.iter().cloned().collect::<Vec<_>>();
+        for https_listener in &new_listeners {
+            if !remove {
+                let request_type = RequestType::AddCertificate(AddCertificate {
+                    address: (*https_listener).into(),
+                    certificate: new_certificate.to_owned(),
+                    expired_at: new_metadata.expires_at,
+                });
+
+                additions.push((modified.to_owned(), request_type));
+                continue;
+            }
+
+            if !add {
+                let request_type = RequestType::RemoveCertificate(RemoveCertificate {
+                    address: (*https_listener).into(),
+                    fingerprint: metadata.fingerprint.to_string(),
+                });
+
+                removals.push((modified.to_owned(), request_type));
+                continue;
+            }
+
+            if tracing::enabled!(Level::TRACE) {
+                trace!(
+                    address = https_listener.to_string(),
+                    names = new_names.join(", "),
+                    new_fingerprint = new_metadata.fingerprint.to_string(),
+                    old_fingerprint = metadata.fingerprint.to_string(),
+                    new_serial = new_metadata.serial,
+                    old_serial = metadata.serial,
+                    issuer = new_metadata.issuer,
+                    "Create a message to replace certificate of proxy for the given listener"
+                );
+            }
+
+            let request_type = RequestType::ReplaceCertificate(ReplaceCertificate {
+                address: (*https_listener).into(),
+                new_certificate: new_certificate.to_owned(),
+                old_fingerprint: metadata.fingerprint.to_string(),
+                new_expired_at: new_metadata.expires_at,
+            });
+
+            replacements.push((modified.to_owned(), request_type))
+        }
     }
 
-    Ok(acc)
+    // ---------------------------------------------------------------------------------
+    // Order messages, certificates without a validity period come last
+    if SendPriority::Expiry == priority {
+        let expiry = |(path, _): &(PathBuf, RequestType)| {
+            new.get(path)
+                .and_then(|metadata| metadata.expires_at)
+                .unwrap_or(i64::MAX)
+        };
+
+        additions.sort_by_key(expiry);
+        replacements.sort_by_key(expiry);
+    }
+
+    let acc = match order {
+        RequestOrder::AddFirst => [additions, replacements, removals],
+        RequestOrder::RemoveFirst => [removals, additions, replacements],
+    }
+    .concat();
+
+    Ok((diff, acc))
+}
+
+/// Merge requests created for several listeners, see [`create`], so that they
+/// are ordered as if they had been created for all listeners at once: by kind
+/// in the given order, then by expiry if asked to and by directory
+pub fn merge(
+    requests: Vec<Requests>,
+    order: RequestOrder,
+    priority: SendPriority,
+    new: &HashMap<PathBuf, Metadata>,
+) -> Requests {
+    let rank = |request: &RequestType| match (order, request) {
+        (RequestOrder::AddFirst, RequestType::AddCertificate(_))
+        | (RequestOrder::RemoveFirst, RequestType::RemoveCertificate(_)) => 0,
+        (RequestOrder::AddFirst, RequestType::ReplaceCertificate(_))
+        | (RequestOrder::RemoveFirst, RequestType::AddCertificate(_)) => 1,
+        _ => 2,
+    };
+
+    let expiry = |path: &PathBuf, request: &RequestType| match request {
+        RequestType::AddCertificate(_) | RequestType::ReplaceCertificate(_)
+            if SendPriority::Expiry == priority =>
+        {
+            new.get(path)
+                .and_then(|metadata| metadata.expires_at)
+                .unwrap_or(i64::MAX)
+        }
+        _ => 0,
+    };
+
+    // The sort is stable, so requests of the same directory keep the order of
+    // their listeners
+    let mut requests = requests.concat();
+    requests.sort_by_cached_key(|(path, request)| {
+        (rank(request), expiry(path, request), path.to_owned())
+    });
+
+    requests
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::date_time_ymd;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::svc::{
+        certificates::tests::{self_signed_with, write_directory},
+        config::tests::configuration,
+    };
+
+    #[tokio::test]
+    async fn requests_carry_the_expiration_of_certificates() {
+        let pki = TempDir::new().expect("pki directory to be created");
+        let config = configuration(pki.path(), "");
+        let listeners = ["127.0.0.1:8443".parse().expect("address to be valid")];
+
+        let expire = |year| {
+            move |params: &mut rcgen::CertificateParams| {
+                params.not_after = date_time_ymd(year, 1, 1);
+            }
+        };
+
+        let (certificate, key) = self_signed_with(None, &["example.com"], expire(2035));
+        let path = write_directory(pki.path(), "example.com", &certificate, &key);
+        let (old_certificate, old) = certificates::load(path.to_owned(), &config)
+            .await
+            .expect("certificate to be loaded");
+
+        let (certificate, key) = self_signed_with(None, &["example.com"], expire(2036));
+        write_directory(pki.path(), "example.com", &certificate, &key);
+        let (new_certificate, new) = certificates::load(path.to_owned(), &config)
+            .await
+            .expect("certificate to be loaded");
+
+        let (order, priority) = (RequestOrder::AddFirst, SendPriority::Path);
+        let (current, others) = (HashMap::new(), HashMap::new());
+        let added = HashMap::from([(path.to_owned(), old)]);
+        let pki = HashMap::from([(path.to_owned(), old_certificate)]);
+        let (_, requests) = create(&listeners, order, priority, &current, &added, &others, &pki)
+            .expect("requests to be created");
+
+        let expected = date_time_ymd(2035, 1, 1).unix_timestamp();
+        assert!(matches!(
+            &requests[..],
+            [(_, RequestType::AddCertificate(add))] if Some(expected) == add.expired_at
+        ));
+
+        let replaced = HashMap::from([(path.to_owned(), new)]);
+        let pki = HashMap::from([(path.to_owned(), new_certificate)]);
+        let (_, requests) = create(
+            &listeners, order, priority, &added, &replaced, &others, &pki,
+        )
+        .expect("requests to be created");
+
+        let expected = date_time_ymd(2036, 1, 1).unix_timestamp();
+        assert!(matches!(
+            &requests[..],
+            [(_, RequestType::ReplaceCertificate(replace))] if Some(expected) == replace.new_expired_at
+        ));
+    }
 }
@@ -4,13 +4,26 @@
 
 use std::{
     collections::{HashMap, HashSet},
-    io,
-    path::PathBuf,
+    env, fmt, io,
+    net::{AddrParseError, SocketAddr},
+    ops::{Deref, DerefMut},
+    path::{Path, PathBuf},
+    process, str,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
+use once_cell::sync::Lazy;
+use p12_keystore::KeyStore;
+use prometheus::{register_int_counter, register_int_counter_vec, IntCounter, IntCounterVec};
+use rsa::pkcs8::{
+    der::pem::{self, encode_string, LineEnding},
+    PrivateKeyInfo,
+};
+use serde::{Deserialize, Serialize};
 use sozu_command_lib::{
     certificate::{
-        calculate_fingerprint, get_cn_and_san_attributes, parse_pem, parse_x509, split_certificate_chain, CertificateError, Fingerprint
+        calculate_fingerprint, get_cn_and_san_attributes, parse_pem, parse_x509,
+        split_certificate_chain, CertificateError, Fingerprint,
     },
     proto::command::CertificateAndKey,
 };
@@ -18,12 +31,142 @@ use tokio::{
     fs,
     task::{spawn_blocking as blocking, JoinError},
 };
-use tracing::{debug, warn};
+use tracing::{debug, trace, warn, Span};
+use x509_parser::{
+    error::X509Error, extensions::GeneralName, pem::Pem, prelude::parse_x509_certificate,
+};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::svc::{
+    certificates::{archive::Extractions, key::KeyDigest},
+    config::{Archive, ChainVerification, ConnectorConfiguration, Layout, LayoutKind},
+};
 
+pub mod archive;
+pub mod cache;
+pub mod chain;
+pub mod dh;
 pub mod diff;
+pub mod events;
+pub mod export;
+pub mod key;
 pub mod message;
+pub mod ocsp;
+pub mod sink;
+pub mod state;
 pub mod watcher;
 
+// -------------------------------------------------------------------------------------
+// Constants
+
+/// Environment variable holding the passphrase of PKCS#12 bundles
+pub const PKCS12_PASSPHRASE_ENV: &str = "SOZU_PKI_CONNECTOR_PKCS12_PASSPHRASE";
+
+// -------------------------------------------------------------------------------------
+// Telemetry
+
+static CERTIFICATE_INSECURE_KEY: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "certificate_insecure_key_total",
+        "Number of private keys readable by group or others read by the certificate daemon"
+    )
+    .expect("'certificate_insecure_key_total' to not be already registered")
+});
+
+static CERTIFICATE_SCAN_ERROR: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "certificate_scan_error_total",
+        "Number of certificate directories skipped by the certificate daemon because of an error",
+        &["kind"]
+    )
+    .expect("'certificate_scan_error_total' to not be already registered")
+});
+
+static CERTIFICATE_OCSP_STALE: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "certificate_ocsp_stale_total",
+        "Number of stale OCSP responses read by the certificate daemon"
+    )
+    .expect("'certificate_ocsp_stale_total' to not be already registered")
+});
+
+static CERTIFICATE_DH_WEAK: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "certificate_dh_weak_total",
+        "Number of Diffie-Hellman parameters with a prime smaller than 2048 bits read by the certificate daemon"
+    )
+    .expect("'certificate_dh_weak_total' to not be already registered")
+});
+
+static CERTIFICATE_OPTIONS_PARSE_ERROR: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "certificate_options_parse_error_total",
+        "Number of options files that the certificate daemon failed to parse",
+        &["directory"]
+    )
+    .expect("'certificate_options_parse_error_total' to not be already registered")
+});
+
+static CERTIFICATE_NO_NAMES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "certificate_no_names_total",
+        "Number of certificates without any name read by the certificate daemon",
+        &["directory"]
+    )
+    .expect("'certificate_no_names_total' to not be already registered")
+});
+
+static CERTIFICATE_SKIPPED_NO_CERT: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "certificate_skipped_no_cert_total",
+        "Number of certificate directories without certificate skipped by the certificate daemon",
+        &["directory"]
+    )
+    .expect("'certificate_skipped_no_cert_total' to not be already registered")
+});
+
+static CERTIFICATE_SKIPPED_EMPTY: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "certificate_skipped_empty_total",
+        "Number of certificate directories with a certificate file without certificate skipped by the certificate daemon",
+        &["directory"]
+    )
+    .expect("'certificate_skipped_empty_total' to not be already registered")
+});
+
+static CERTIFICATE_SKIPPED_NO_KEY: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "certificate_skipped_no_key_total",
+        "Number of certificate directories without private key skipped by the certificate daemon",
+        &["directory"]
+    )
+    .expect("'certificate_skipped_no_key_total' to not be already registered")
+});
+
+static CERTIFICATE_SCAN_DIRECTORIES_VISITED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "certificate_scan_directories_visited_total",
+        "Number of directories visited by the certificate daemon while searching for certificate directories"
+    )
+    .expect("'certificate_scan_directories_visited_total' to not be already registered")
+});
+
+static CERTIFICATE_SCAN_FILES_READ: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "certificate_scan_files_read_total",
+        "Number of files read by the certificate daemon in certificate directories"
+    )
+    .expect("'certificate_scan_files_read_total' to not be already registered")
+});
+
+static CERTIFICATE_SCAN_BYTES_READ: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "certificate_scan_bytes_read_total",
+        "Number of bytes read by the certificate daemon in certificate directories"
+    )
+    .expect("'certificate_scan_bytes_read_total' to not be already registered")
+});
+
 // -------------------------------------------------------------------------------------
 // Error
 
@@ -37,14 +180,84 @@ pub enum Error {
     DirectoryName(PathBuf),
     #[error("failed to read path '{0}', {1}")]
     Read(PathBuf, io::Error),
-    #[error("failed to parse pem, '{0}'")]
-    ParsePem(CertificateError),
-    #[error("failed to parse x509 from pem, '{0}'")]
-    ParseX509(CertificateError),
-    #[error("failed to compute fingerprint, {0}")]
-    Fingerprint(Box<dyn std::error::Error + Send + Sync>),
+    #[error("certificate file '{0}' does not hold any certificate")]
+    EmptyCertificate(PathBuf),
+    #[error("failed to parse pem of {1} certificate in '{0}', '{2}'")]
+    ParsePem(PathBuf, Block, CertificateError),
+    #[error("failed to parse x509 from pem of {1} certificate in '{0}', '{2}'")]
+    ParseX509(PathBuf, Block, CertificateError),
+    #[error("failed to compute fingerprint of {1} certificate of '{0}', {2}")]
+    Fingerprint(PathBuf, Block, Box<dyn std::error::Error + Send + Sync>),
     #[error("failed to join on task, {0}")]
     Join(JoinError),
+    #[error("failed to derive public key from private key, {0}")]
+    PublicKey(key::Error),
+    #[error("certificate '{0}' has neither a common name nor a subject alternative name")]
+    NoNames(PathBuf),
+    #[error("private key '{0}' does not match the certificate")]
+    KeyCertificateMismatch(PathBuf),
+    #[error("public key of certificate '{0}' does not comply with the key policy, {1}")]
+    KeyPolicy(PathBuf, String),
+    #[error("certificate chain of '{0}' does not verify, {1}")]
+    InvalidChain(PathBuf, chain::Error),
+    #[error("failed to parse PKCS#12 bundle '{0}', {1}")]
+    ParsePkcs12(PathBuf, p12_keystore::error::Error),
+    #[error("PKCS#12 bundle '{0}' does not contain a private key and its certificate")]
+    EmptyPkcs12(PathBuf),
+    #[error("failed to encode PKCS#12 bundle '{0}' as pem, {1}")]
+    EncodePkcs12(PathBuf, pem::Error),
+    #[error("failed to decode '{0}' as pem or der, {1}")]
+    Decode(PathBuf, String),
+    #[error("failed to parse der certificate '{0}', {1}")]
+    ParseDer(PathBuf, x509_parser::nom::Err<X509Error>),
+    #[error("der private key '{0}' is neither PKCS#8, PKCS#1 nor SEC1")]
+    UnsupportedDerKey(PathBuf),
+    #[error("failed to encode '{0}' as pem, {1}")]
+    EncodePem(PathBuf, pem::Error),
+    #[error("failed to parse pem blocks of '{0}', {1}")]
+    ParseCombined(PathBuf, String),
+    #[error("file '{0}' holds {1} private keys, only one is expected")]
+    MultipleKeys(PathBuf, usize),
+    #[error("private key '{0}' is readable by group or others, mode {1:o}")]
+    InsecureKeyPermissions(PathBuf, u32),
+    #[error("failed to parse options '{0}' at '{1}', {2}")]
+    ParseOptions(PathBuf, String, serde_json::Error),
+    #[error("options '{0}' hold listener '{1}' which is not a socket address, {2}")]
+    InvalidListener(PathBuf, String, AddrParseError),
+    #[error("certificate '{0}' does not exist")]
+    MissingCertificate(PathBuf),
+    #[error("private key '{0}' does not exist")]
+    MissingKey(PathBuf),
+}
+
+impl Error {
+    /// Short name of the error, used as a label of metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::ReadDir(..) | Self::ReadEntry(_) | Self::DirectoryName(_) => "directory",
+            Self::Read(..) => "read",
+            Self::MissingCertificate(_) => "missing_certificate",
+            Self::MissingKey(_) => "missing_key",
+            Self::EmptyCertificate(_) => "empty",
+            Self::ParseOptions(..) | Self::InvalidListener(..) => "options",
+            Self::ParsePem(..)
+            | Self::ParseX509(..)
+            | Self::Decode(..)
+            | Self::ParseDer(..)
+            | Self::UnsupportedDerKey(_)
+            | Self::EncodePem(..)
+            | Self::ParseCombined(..)
+            | Self::MultipleKeys(..) => "parse",
+            Self::ParsePkcs12(..) | Self::EmptyPkcs12(_) | Self::EncodePkcs12(..) => "pkcs12",
+            Self::Fingerprint(..) => "fingerprint",
+            Self::Join(_) => "join",
+            Self::PublicKey(_) | Self::KeyCertificateMismatch(_) => "key_mismatch",
+            Self::KeyPolicy(..) => "key_policy",
+            Self::NoNames(_) => "no_names",
+            Self::InvalidChain(..) => "chain",
+            Self::InsecureKeyPermissions(..) => "permissions",
+        }
+    }
 }
 
 impl From<JoinError> for Error {
@@ -53,17 +266,150 @@ impl From<JoinError> for Error {
     }
 }
 
+// -------------------------------------------------------------------------------------
+// Block
+
+/// Position of a certificate, to tell which pem block of a file is broken
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Block {
+    /// Leaf certificate
+    Leaf,
+    /// Certificate of the chain at the given index, starting at 0
+    Chain(usize),
+}
+
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Leaf => write!(f, "leaf"),
+            Self::Chain(idx) => write!(f, "chain[{idx}]"),
+        }
+    }
+}
+
+// -------------------------------------------------------------------------------------
+// Pki
+
+/// Certificates and private keys read from disk, indexed by directory. Private
+/// keys are wiped from memory when dropped, so it does not implement `Debug`.
+#[derive(Clone, Default)]
+pub struct Pki(HashMap<PathBuf, CertificateAndKey>);
+
+impl Deref for Pki {
+    type Target = HashMap<PathBuf, CertificateAndKey>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for Pki {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+impl FromIterator<(PathBuf, CertificateAndKey)> for Pki {
+    fn from_iter<T: IntoIterator<Item = (PathBuf, CertificateAndKey)>>(iter: T) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl Drop for Pki {
+    fn drop(&mut self) {
+        self.0.values_mut().for_each(wipe);
+    }
+}
+
+// -------------------------------------------------------------------------------------
+// Usage
+
+/// Resources used to scan the pki directories since the process started
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct Usage {
+    /// Number of directories visited
+    pub directories: u64,
+    /// Number of files read
+    pub files: u64,
+    /// Number of bytes read
+    pub bytes: u64,
+}
+
+impl Usage {
+    /// Retrieve the resources used so far
+    pub fn now() -> Self {
+        Self {
+            directories: CERTIFICATE_SCAN_DIRECTORIES_VISITED.get(),
+            files: CERTIFICATE_SCAN_FILES_READ.get(),
+            bytes: CERTIFICATE_SCAN_BYTES_READ.get(),
+        }
+    }
+
+    /// Returns the resources used since the given ones
+    pub fn since(&self, before: &Self) -> Self {
+        Self {
+            directories: self.directories.saturating_sub(before.directories),
+            files: self.files.saturating_sub(before.files),
+            bytes: self.bytes.saturating_sub(before.bytes),
+        }
+    }
+}
+
 // -------------------------------------------------------------------------------------
 // Metadata
 
-#[derive(PartialEq, Eq, Debug, Clone)]
+/// Metadata of a certificate directory, the serial number and the issuer are
+/// derived from the certificate, so they are left out of comparisons and
+/// state files written before they existed do not trigger a replacement
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Metadata {
     pub fingerprint: Fingerprint,
+    /// Names of the certificate, normalized so that they compare regardless of
+    /// their case, a trailing dot or punycode, see [`normalize_name`]
+    #[serde(deserialize_with = "deserialize_names")]
     pub names: HashSet<String>,
     pub path: PathBuf,
     pub chain_fingerprints: HashSet<Fingerprint>,
+    /// Unix timestamp of the start of validity of the certificate (notBefore)
+    pub not_before: Option<i64>,
+    /// Unix timestamp of the end of validity of the certificate (notAfter)
+    pub expires_at: Option<i64>,
+    /// Digest of the private key, to detect a key rotation without a new
+    /// certificate
+    pub key_digest: KeyDigest,
+    /// Serial number of the certificate, as uppercase hexadecimal without
+    /// separators, the way `openssl x509 -serial` renders it
+    #[serde(default)]
+    pub serial: String,
+    /// Distinguished name of the issuer of the certificate
+    #[serde(default)]
+    pub issuer: String,
+    /// HTTPS listener given by the options of the directory, which overrides
+    /// the listeners of the configuration
+    #[serde(default)]
+    pub listener: Option<SocketAddr>,
+    /// Name the certificate is known by, its common name or else its first
+    /// subject alternative name. It is not persisted, so it is empty for a
+    /// certificate restored from the state file until it is read again.
+    #[serde(skip)]
+    pub primary_name: String,
+}
+
+impl PartialEq for Metadata {
+    fn eq(&self, other: &Self) -> bool {
+        self.fingerprint == other.fingerprint
+            && self.names == other.names
+            && self.path == other.path
+            && self.chain_fingerprints == other.chain_fingerprints
+            && self.not_before == other.not_before
+            && self.expires_at == other.expires_at
+            && self.key_digest == other.key_digest
+            && self.listener == other.listener
+    }
 }
 
+impl Eq for Metadata {}
+
 impl Metadata {
     #[tracing::instrument]
     pub fn new(
@@ -71,169 +417,1812 @@ impl Metadata {
         fingerprint: Fingerprint,
         names: HashSet<String>,
         chain_fingerprints: HashSet<Fingerprint>,
+        not_before: Option<i64>,
+        expires_at: Option<i64>,
+        key_digest: KeyDigest,
     ) -> Self {
         Self {
             path,
-            names,
+            names: names.iter().map(|name| normalize_name(name)).collect(),
             fingerprint,
             chain_fingerprints,
+            not_before,
+            expires_at,
+            key_digest,
+            serial: String::new(),
+            issuer: String::new(),
+            listener: None,
+            primary_name: String::new(),
         }
     }
+
+    /// Returns true if both metadata describe the same certificate material,
+    /// regardless of the directory that holds it
+    pub fn is_same_certificate(&self, other: &Self) -> bool {
+        self.fingerprint == other.fingerprint
+            && self.chain_fingerprints == other.chain_fingerprints
+            && self.key_digest == other.key_digest
+    }
+}
+
+// -------------------------------------------------------------------------------------
+// Options
+
+/// Options file of a certificate directory, either the list of its TLS
+/// versions or an object which may also hold the HTTPS listener to send the
+/// certificate to and the passphrase of its PKCS#12 bundle. It is not `Debug`,
+/// so that the passphrase never ends up in logs.
+enum Options {
+    Versions(Vec<i32>),
+    Object(ObjectOptions),
+}
+
+/// Options file of a certificate directory written as an object
+#[derive(Deserialize)]
+struct ObjectOptions {
+    #[serde(default)]
+    versions: Option<Vec<i32>>,
+    #[serde(default)]
+    listener: Option<String>,
+    /// Passphrase of the PKCS#12 bundle, which wins over the file
+    #[serde(default)]
+    passphrase: Option<String>,
+    /// File holding the passphrase of the PKCS#12 bundle, relative to the
+    /// certificate directory
+    #[serde(rename = "passphrase-file", default)]
+    passphrase_file: Option<PathBuf>,
+}
+
+impl Options {
+    /// Parse the given options, the error comes with the path of the offending
+    /// value, e.g. `versions[1]`, or `.` if it is the whole document
+    fn parse(content: &str) -> Result<Self, (String, serde_json::Error)> {
+        let value: serde_json::Value =
+            serde_json::from_str(content).map_err(|err| (".".to_string(), err))?;
+
+        // The shape is told by the document itself, so that the error points
+        // at the offending value rather than at the mismatch of both shapes
+        let result = if value.is_array() {
+            serde_path_to_error::deserialize(value).map(Self::Versions)
+        } else {
+            serde_path_to_error::deserialize(value).map(Self::Object)
+        };
+
+        result.map_err(|err| (err.path().to_string(), err.into_inner()))
+    }
+}
+
+// -------------------------------------------------------------------------------------
+// Validation
+
+/// Outcome of the validation of the pki directories
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct Validation {
+    /// Number of certificate directories found
+    pub directories: usize,
+    /// Directories that could not be loaded, with the reason
+    pub failures: Vec<(PathBuf, String)>,
 }
 
 // -------------------------------------------------------------------------------------
 // Helpers
 
-#[tracing::instrument]
-pub async fn find(path: &PathBuf) -> Result<HashMap<PathBuf, CertificateAndKey>, Error> {
-    let mut scanner = fs::read_dir(path)
+/// Retrieve certificate directories within the pki directory, down to the
+/// given depth.
+///
+/// Directories at the maximum depth are always certificate directories, while
+/// the ones above are only if they hold a certificate and its key or a PKCS#12
+/// bundle, the others are searched for nested certificate directories.
+/// Directories which are not included by the configured patterns are skipped.
+///
+/// Symbolic links are followed, a dangling one is skipped and a directory
+/// reached a second time, e.g. through a symbolic link cycle, is only searched
+/// once. Only an unreadable pki directory fails the search, a nested directory
+/// which cannot be read is skipped with a warning.
+#[tracing::instrument(skip(config))]
+pub async fn directories(
+    path: &PathBuf,
+    config: &ConnectorConfiguration,
+    max_depth: usize,
+) -> Result<Vec<PathBuf>, Error> {
+    let layout = &config.layout;
+    let max_depth = max_depth.max(1);
+    let root = fs::canonicalize(path)
         .await
         .map_err(|err| Error::ReadDir(path.to_owned(), err))?;
 
-    let mut acc = HashMap::new();
-    while let Some(entry) = scanner.next_entry().await.map_err(Error::ReadEntry)? {
-        let path = entry.path();
+    let mut visited = HashSet::from([root.to_owned()]);
+    let mut pending = vec![(path.to_owned(), root, 1)];
+    let mut acc = vec![];
 
-        if path.is_dir() {
-            debug!(
-                path = path.display().to_string(),
-                "Found certificate directory"
-            );
+    while let Some((parent, canonical_parent, depth)) = pending.pop() {
+        // Only the pki directory itself must be readable, a nested directory
+        // which is not is skipped along with what it holds
+        let mut scanner = match fs::read_dir(&parent).await {
+            Ok(scanner) => scanner,
+            Err(err) if 1 == depth => return Err(Error::ReadDir(parent.to_owned(), err)),
+            Err(err) => {
+                unreadable(Error::ReadDir(parent.to_owned(), err));
+                continue;
+            }
+        };
+
+        CERTIFICATE_SCAN_DIRECTORIES_VISITED.inc();
+
+        // Names of the certificates of a flat layout found in the directory
+        let mut names = HashSet::new();
+        while let Some(entry) = match scanner.next_entry().await {
+            Ok(entry) => entry,
+            Err(err) if 1 == depth => return Err(Error::ReadEntry(err)),
+            Err(err) => {
+                unreadable(Error::ReadDir(parent.to_owned(), err));
+                None
+            }
+        } {
+            let path = entry.path();
+
+            // Follow symbolic links, the entry may also have been removed since
+            let is_dir = match fs::metadata(&path).await {
+                Ok(metadata) => metadata.is_dir(),
+                Err(err) => {
+                    warn!(
+                        error = err.to_string(),
+                        path = path.display().to_string(),
+                        "Skip path in pki directory which cannot be resolved, e.g. a dangling symbolic link"
+                    );
+
+                    continue;
+                }
+            };
+
+            if !is_dir && layout.is_flat() {
+                match layout.name_of(&entry.file_name().to_string_lossy()) {
+                    Some(name) => {
+                        names.insert(name);
+                    }
+                    None => {
+                        trace!(
+                            path = path.display().to_string(),
+                            "Skip a path in pki directory which is not a certificate file"
+                        );
+                    }
+                }
+
+                continue;
+            }
 
-            // Read certificates and key from path
-            let certificate_and_key = match read(path.to_owned()).await {
-                Ok(Some(certificate_and_key)) => certificate_and_key,
-                Ok(None) => {
+            if !is_dir {
+                if 1 == max_depth {
                     warn!(
                         path = path.display().to_string(),
-                        "Could not read certificates and key"
+                        "Found a path in certificate directory which is not a directory"
+                    );
+                } else {
+                    trace!(
+                        path = path.display().to_string(),
+                        "Skip a path in pki directory which is not a directory"
+                    );
+                }
+
+                continue;
+            }
+
+            if config.exclude.matches(relative(config, &path)) {
+                trace!(path = path.display().to_string(), "Skip excluded directory");
+
+                continue;
+            }
+
+            // Files of a flat layout are only searched down to the depth
+            if layout.is_flat() && depth >= max_depth {
+                trace!(
+                    path = path.display().to_string(),
+                    "Skip directory below the maximum depth"
+                );
+
+                continue;
+            }
+
+            if !layout.is_flat()
+                && (depth >= max_depth || is_certificate_directory(&path, layout).await)
+            {
+                if !is_included(config, &path) {
+                    trace!(
+                        path = path.display().to_string(),
+                        "Skip certificate directory which is not included"
                     );
 
                     continue;
                 }
+
+                debug!(
+                    path = path.display().to_string(),
+                    "Found certificate directory"
+                );
+
+                acc.push(path);
+                continue;
+            }
+
+            // Only directories that are searched are tracked, certificate
+            // directories are named after their files and a symbolic link to
+            // one is another certificate directory
+            let canonical = match fs::canonicalize(&path).await {
+                Ok(canonical) => canonical,
                 Err(err) => {
                     warn!(
                         error = err.to_string(),
                         path = path.display().to_string(),
-                        "Could not read certificates and key"
+                        "Skip directory which cannot be resolved"
                     );
 
                     continue;
                 }
             };
 
-            // Compute there metadata
-            acc.insert(path, certificate_and_key);
-        } else {
-            warn!(
-                path = path.display().to_string(),
-                "Found a path in certificate directory which is not a directory"
-            );
+            if !visited.insert(canonical.to_owned()) {
+                if canonical_parent.starts_with(&canonical) {
+                    warn!(
+                        path = path.display().to_string(),
+                        target = canonical.display().to_string(),
+                        "Skip symbolic link to a parent directory, it would create a cycle"
+                    );
+                } else {
+                    debug!(
+                        path = path.display().to_string(),
+                        target = canonical.display().to_string(),
+                        "Skip directory which has already been scanned through another path"
+                    );
+                }
+
+                continue;
+            }
+
+            pending.push((path, canonical, depth + 1));
+        }
+
+        let mut names: Vec<_> = names.into_iter().collect();
+        names.sort();
+        for name in names {
+            let path = parent.join(name);
+            if !is_certificate_directory(&path, layout).await {
+                warn!(
+                    path = path.display().to_string(),
+                    "Skip certificate files which are not paired, e.g. a certificate without its key"
+                );
+
+                continue;
+            }
+
+            if !is_included(config, &path) {
+                trace!(
+                    path = path.display().to_string(),
+                    "Skip certificate which is not included"
+                );
+
+                continue;
+            }
+
+            debug!(path = path.display().to_string(), "Found certificate");
+            acc.push(path);
         }
     }
 
     Ok(acc)
 }
 
-#[tracing::instrument]
-pub async fn read(path: PathBuf) -> Result<Option<CertificateAndKey>, Error> {
-    // ---------------------------------------------------------------------------------
-    // Retrieve name of the current directory
-    let name = path
-        .file_name()
-        .ok_or_else(|| Error::DirectoryName(path.to_owned()))?
-        .to_string_lossy();
+/// Report a nested directory of a pki directory which cannot be read
+fn unreadable(err: Error) {
+    warn!(
+        error = err.to_string(),
+        "Skip directory of pki directory which cannot be read"
+    );
 
-    // ---------------------------------------------------------------------------------
-    // Compute path to certificate and key
-    let certificates_path = path.join(format!("{name}.crt"));
-    let key_path = path.join(format!("{name}.key"));
-    let tls_path = path.join("options.json");
+    CERTIFICATE_SCAN_ERROR
+        .with_label_values(&[err.kind()])
+        .inc();
+}
 
-    // ---------------------------------------------------------------------------------
-    // Load certificates, key and optional options
-    let certificates = split_certificate_chain(
-        fs::read_to_string(&certificates_path)
+/// Returns true if the given certificate directory matches the include
+/// patterns, all if there is none, and none of the exclude patterns
+pub fn is_included(config: &ConnectorConfiguration, path: &Path) -> bool {
+    let relative = relative(config, path);
+
+    (config.include.is_empty() || config.include.matches(relative))
+        && !config.exclude.matches(relative)
+}
+
+/// Returns the first name of the given certificate which is denied or not
+/// allowed by the configured domain name patterns, denial wins over allowance
+pub fn forbidden_name<'a>(
+    config: &ConnectorConfiguration,
+    metadata: &'a Metadata,
+) -> Option<&'a str> {
+    let mut names: Vec<_> = metadata.names.iter().collect();
+    names.sort();
+
+    names
+        .into_iter()
+        .find(|name| {
+            config.san_deny.matches(name)
+                || (!config.san_allow.is_empty() && !config.san_allow.matches(name))
+        })
+        .map(String::as_str)
+}
+
+/// Returns the given path relative to the pki directory holding it, or the
+/// path itself if it is not within one
+fn relative<'a>(config: &ConnectorConfiguration, path: &'a Path) -> &'a Path {
+    config
+        .roots()
+        .iter()
+        .find_map(|root| path.strip_prefix(root).ok())
+        .unwrap_or(path)
+}
+
+/// Check that the der encoded OCSP response at the given path is fresh, a stale
+/// or invalid response is only logged
+#[tracing::instrument]
+async fn check_ocsp(path: &Path) {
+    let validities = match read_file(path).await {
+        Ok(data) => blocking(move || ocsp::validities(&data))
             .await
-            .map_err(|err| Error::Read(certificates_path, err))?,
-    );
+            .map_err(|err| err.to_string())
+            .and_then(|result| result.map_err(|err| err.to_string())),
+        Err(err) => Err(err.to_string()),
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default();
 
-    // Skip if there is no certificate
-    let (certificate, certificate_chain) = match certificates.len() {
-        0 => {
+    match validities {
+        Ok(validities) if validities.iter().any(|validity| validity.is_stale(now)) => {
             warn!(
-                error = "there is no certificate",
                 path = path.display().to_string(),
-                "Could not parse certificates"
+                "OCSP response is stale, it should be refreshed"
             );
 
-            return Ok(None);
+            CERTIFICATE_OCSP_STALE.inc();
+        }
+        Ok(_) => {
+            debug!(path = path.display().to_string(), "OCSP response is fresh");
+        }
+        Err(err) => {
+            warn!(
+                error = err,
+                path = path.display().to_string(),
+                "Could not check the OCSP response"
+            );
         }
-        1 => (certificates[0].to_string(), vec![]),
-        _ => (certificates[0].to_string(), certificates[1..].to_vec()),
+    }
+}
+
+/// Check that the pem encoded Diffie-Hellman parameters at the given path are
+/// not weak, they are ignored anyway as Sōzu cannot receive them
+#[tracing::instrument]
+async fn check_dh(path: &Path) {
+    let bits = match read_file(path).await {
+        Ok(data) => blocking(move || dh::prime_bits(&data))
+            .await
+            .map_err(|err| err.to_string())
+            .and_then(|result| result.map_err(|err| err.to_string())),
+        Err(err) => Err(err.to_string()),
     };
 
-    let key = fs::read_to_string(&key_path)
+    match bits {
+        Ok(bits) if bits < dh::MIN_BITS => {
+            warn!(
+                path = path.display().to_string(),
+                bits = bits,
+                "Diffie-Hellman parameters are weak, their prime is smaller than 2048 bits"
+            );
+
+            CERTIFICATE_DH_WEAK.inc();
+        }
+        Ok(bits) => {
+            debug!(
+                path = path.display().to_string(),
+                bits = bits,
+                "Diffie-Hellman parameters are strong enough"
+            );
+        }
+        Err(err) => {
+            warn!(
+                error = err,
+                path = path.display().to_string(),
+                "Could not check the Diffie-Hellman parameters"
+            );
+        }
+    }
+
+    warn!(
+        path = path.display().to_string(),
+        "Ignore Diffie-Hellman parameters, Sōzu cannot receive them"
+    );
+}
+
+/// Read the whole file at the given path and record it in the usage of scans
+async fn read_file(path: &Path) -> Result<Vec<u8>, Error> {
+    let data = fs::read(path)
         .await
-        .map_err(|err| Error::Read(key_path, err))?;
+        .map_err(|err| Error::Read(path.to_owned(), err))?;
 
-    // Check if the path exists, see [std::path::Path::exists] method
-    let mut versions = vec![];
-    if fs::metadata(&tls_path).await.is_ok() {
-        let options = fs::read_to_string(&tls_path)
-            .await
-            .map_err(|err| Error::Read(tls_path.to_owned(), err))?;
+    CERTIFICATE_SCAN_FILES_READ.inc();
+    CERTIFICATE_SCAN_BYTES_READ.inc_by(data.len() as u64);
+    Ok(data)
+}
 
-        match blocking(move || serde_json::from_str(&options)).await? {
-            Ok(options) => {
-                versions = options;
-            }
-            Err(err) => {
-                warn!(
-                    error = err.to_string(),
-                    path = tls_path.display().to_string(),
-                    "Could not deserialize TLS versions, skip it.."
-                );
-            }
+/// Normalize a name of a certificate so that names which designate the same
+/// domains are equal, whatever their case or a trailing dot. Internationalized
+/// names are encoded in punycode and wildcards are kept as is, names which are
+/// not valid domains are only lowercased.
+pub fn normalize_name(name: &str) -> String {
+    let name = name.trim().trim_end_matches('.');
+    idna::domain_to_ascii(name).unwrap_or_else(|_| name.to_lowercase())
+}
+
+/// Deserialize names of a certificate and normalize them, so that names of a
+/// state file written by a previous version compare with the current ones
+fn deserialize_names<'de, D>(deserializer: D) -> Result<HashSet<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let names = HashSet::<String>::deserialize(deserializer)?;
+    Ok(names.iter().map(|name| normalize_name(name)).collect())
+}
+
+/// Returns the name of the given certificate directory
+pub fn directory_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+/// Wipe the private key of the given certificate from memory
+pub fn wipe(certificate_and_key: &mut CertificateAndKey) {
+    certificate_and_key.key.zeroize();
+}
+
+/// Convert a failure to read a file which does not exist into the given error
+fn missing(err: Error, into: fn(PathBuf) -> Error) -> Error {
+    match err {
+        Error::Read(path, err) if io::ErrorKind::NotFound == err.kind() => into(path),
+        err => err,
+    }
+}
+
+/// Returns true if the given directory holds a certificate and its key or a
+/// PKCS#12 bundle, named after the layout
+pub async fn is_certificate_directory(path: &Path, layout: &Layout) -> bool {
+    let exists = |template: &str| fs::metadata(layout.file(path, template));
+
+    if exists(layout.certificate()).await.is_ok() && exists(layout.key()).await.is_ok() {
+        return true;
+    }
+
+    for template in layout.pkcs12() {
+        if exists(template).await.is_ok() {
+            return true;
         }
     }
 
-    // ---------------------------------------------------------------------------------
-    // Parse certificate to retrieve SAN and CN attributes from pem
-    let pem = parse_pem(certificate.as_bytes()).map_err(Error::ParsePem)?;
-    let x509 = parse_x509(&pem.contents).map_err(Error::ParseX509)?;
-    let names = get_cn_and_san_attributes(&x509);
+    false
+}
+
+/// Read certificates and key of the given directory and compute their
+/// metadata, failures are logged and returned so that the directory is skipped
+#[tracing::instrument(skip(config))]
+pub async fn load(
+    path: PathBuf,
+    config: &ConnectorConfiguration,
+) -> Result<(CertificateAndKey, Metadata), Error> {
+    let (certificate_and_key, listener) = match read(path.to_owned(), config).await {
+        Ok(read) => read,
+        Err(err) => {
+            warn!(
+                error = err.to_string(),
+                path = path.display().to_string(),
+                "Could not read certificates and key"
+            );
+
+            CERTIFICATE_SCAN_ERROR
+                .with_label_values(&[err.kind()])
+                .inc();
+
+            match err {
+                Error::MissingCertificate(_) => CERTIFICATE_SKIPPED_NO_CERT
+                    .with_label_values(&[&directory_name(&path)])
+                    .inc(),
+                Error::MissingKey(_) => CERTIFICATE_SKIPPED_NO_KEY
+                    .with_label_values(&[&directory_name(&path)])
+                    .inc(),
+                Error::EmptyCertificate(_) => CERTIFICATE_SKIPPED_EMPTY
+                    .with_label_values(&[&directory_name(&path)])
+                    .inc(),
+                _ => {}
+            }
+
+            return Err(err);
+        }
+    };
+
+    match metadata(path.to_owned(), &certificate_and_key, listener).await {
+        Ok(metadata) => Ok((certificate_and_key, metadata)),
+        Err(err) => {
+            warn!(
+                error = err.to_string(),
+                path = path.display().to_string(),
+                "Could not compute metadata of certificate"
+            );
 
-    Ok(Some(CertificateAndKey {
-        certificate,
-        certificate_chain,
-        key,
-        versions,
-        names: names.into_iter().collect(),
-    }))
+            CERTIFICATE_SCAN_ERROR
+                .with_label_values(&[err.kind()])
+                .inc();
+            Err(err)
+        }
+    }
 }
 
-#[tracing::instrument(skip(certificate_and_key))]
-pub async fn metadata(
+/// Read certificates and key of the given directory, alongside the listener
+/// given by its options, if any
+#[tracing::instrument(skip(config))]
+pub async fn read(
     path: PathBuf,
-    certificate_and_key: &CertificateAndKey,
-) -> Result<Metadata, Error> {
-    let names = certificate_and_key.names.iter().cloned().collect();
+    config: &ConnectorConfiguration,
+) -> Result<(CertificateAndKey, Option<SocketAddr>), Error> {
+    let layout = &config.layout;
 
     // ---------------------------------------------------------------------------------
-    // Compute fingerprints
-    let fingerprint = calculate_fingerprint(certificate_and_key.certificate.as_bytes())
-        .map(Fingerprint)
-        .map_err(|err| Error::Fingerprint(err.into()))?;
-
-    let mut chain_fingerprints = HashSet::new();
-    for certificate in &certificate_and_key.certificate_chain {
-        chain_fingerprints.insert(
-            calculate_fingerprint(certificate.as_bytes())
-                .map(Fingerprint)
-                .map_err(|err| Error::Fingerprint(err.into()))?,
-        );
+    // Check that the current directory has a name, files are named after it
+    if path.file_name().is_none() {
+        return Err(Error::DirectoryName(path.to_owned()));
     }
 
-    Ok(Metadata::new(path, fingerprint, names, chain_fingerprints))
+    // ---------------------------------------------------------------------------------
+    // Compute path to certificate and key
+    let certificates_path = layout.file(&path, layout.certificate());
+    let key_path = layout.file(&path, layout.key());
+    let tls_path = layout.file(&path, layout.options());
+
+    // ---------------------------------------------------------------------------------
+    // Read the options of the directory, if any, they override the default TLS
+    // versions and listeners and may hold the passphrase of a PKCS#12 bundle
+    let mut versions: Vec<i32> = config
+        .default_tls_versions
+        .iter()
+        .map(|version| *version as i32)
+        .collect();
+    let mut listener = None;
+    let mut passphrase = None;
+    if fs::metadata(&tls_path).await.is_ok() {
+        // The raw options may hold the passphrase, they are zeroed once parsed
+        // as well as if they are not valid UTF-8
+        let options = String::from_utf8(read_file(&tls_path).await?)
+            .map(Zeroizing::new)
+            .map_err(|err| {
+                let message = err.to_string();
+                drop(Zeroizing::new(err.into_bytes()));
+                Error::Decode(tls_path.to_owned(), message)
+            })?;
+
+        match blocking(move || Options::parse(&options)).await? {
+            Ok(Options::Versions(options)) => {
+                versions = options;
+            }
+            Ok(Options::Object(ObjectOptions {
+                versions: options,
+                listener: address,
+                passphrase: secret,
+                passphrase_file,
+            })) => {
+                if let Some(options) = options {
+                    versions = options;
+                }
+
+                if let Some(address) = address {
+                    listener = Some(address.parse::<SocketAddr>().map_err(|err| {
+                        Error::InvalidListener(tls_path.to_owned(), address.to_owned(), err)
+                    })?);
+                }
+
+                // The file is relative to the certificate directory
+                passphrase = match (secret, passphrase_file) {
+                    (Some(secret), _) => Some(Zeroizing::new(secret)),
+                    (None, Some(file)) => {
+                        let file = path.join(file);
+                        let data = Zeroizing::new(read_file(&file).await?);
+                        let secret = std::str::from_utf8(&data)
+                            .map_err(|err| Error::Decode(file.to_owned(), err.to_string()))?;
+
+                        Some(Zeroizing::new(
+                            secret.trim_end_matches(['\r', '\n']).to_string(),
+                        ))
+                    }
+                    (None, None) => None,
+                };
+            }
+            Err((at, err)) => {
+                CERTIFICATE_OPTIONS_PARSE_ERROR
+                    .with_label_values(&[&directory_name(&path)])
+                    .inc();
+
+                if config.strict_options {
+                    return Err(Error::ParseOptions(tls_path, at, err));
+                }
+
+                warn!(
+                    error = err.to_string(),
+                    at = at,
+                    line = err.line(),
+                    column = err.column(),
+                    path = tls_path.display().to_string(),
+                    "Could not deserialize options, expected a list of TLS versions or an object, use the default ones"
+                );
+            }
+        }
+    }
+
+    // ---------------------------------------------------------------------------------
+    // Load certificates and key, either from a PKCS#12 bundle or pem files
+    let mut bundle = None;
+    for template in layout.pkcs12() {
+        let bundle_path = layout.file(&path, template);
+        if fs::metadata(&bundle_path).await.is_ok() {
+            bundle = Some(bundle_path);
+            break;
+        }
+    }
+
+    // Files holding the leaf certificate and the chain, to tell which one is
+    // broken
+    let mut certificate_source = certificates_path.to_owned();
+    let mut chain_source = certificates_path.to_owned();
+    let (certificate, certificate_chain, key, key_path) = match bundle {
+        Some(bundle_path) => {
+            let (certificate, certificate_chain, key) =
+                read_pkcs12(&bundle_path, passphrase).await?;
+            certificate_source.clone_from(&bundle_path);
+            chain_source.clone_from(&bundle_path);
+            (certificate, certificate_chain, key, bundle_path)
+        }
+        None if LayoutKind::Combined == layout.kind => {
+            let (certificate, certificate_chain, key) = read_combined(&certificates_path)
+                .await
+                .map_err(|err| missing(err, Error::MissingCertificate))?;
+
+            (certificate, certificate_chain, key, certificates_path)
+        }
+        None => {
+            let certificates = split_certificate_chain(
+                read_pem(&certificates_path, Content::Certificates)
+                    .await
+                    .map_err(|err| missing(err, Error::MissingCertificate))?
+                    .to_string(),
+            );
+
+            // Skip if there is no certificate
+            let (certificate, mut certificate_chain) = match certificates.len() {
+                0 => return Err(Error::EmptyCertificate(certificates_path)),
+                1 => (certificates[0].to_string(), vec![]),
+                _ => (certificates[0].to_string(), certificates[1..].to_vec()),
+            };
+
+            // Read the chain from its own file, if any
+            if let Some(template) = layout.chain() {
+                let chain_path = layout.file(&path, template);
+                if fs::metadata(&chain_path).await.is_ok() {
+                    if !certificate_chain.is_empty() {
+                        debug!(
+                            path = chain_path.display().to_string(),
+                            "Chain file exists, ignore the chain within the certificate file"
+                        );
+                    }
+
+                    certificate_chain = split_certificate_chain(
+                        read_pem(&chain_path, Content::Certificates)
+                            .await?
+                            .to_string(),
+                    );
+                    chain_source = chain_path;
+                }
+            }
+
+            let key = read_pem(&key_path, Content::Key)
+                .await
+                .map_err(|err| missing(err, Error::MissingKey))?;
+
+            (certificate, certificate_chain, key, key_path)
+        }
+    };
+
+    check_permissions(&key_path, config.strict_permissions).await?;
+
+    // ---------------------------------------------------------------------------------
+    // Check the freshness of the OCSP response, if any. Requests to Sōzu do not
+    // carry OCSP responses, so it is only checked and never sent.
+    let ocsp_path = layout.file(&path, layout.ocsp());
+    if fs::metadata(&ocsp_path).await.is_ok() {
+        check_ocsp(&ocsp_path).await;
+    }
+
+    // Requests to Sōzu do not carry Diffie-Hellman parameters either, they are
+    // checked so that weak ones are reported
+    let dh_path = layout.file(&path, layout.dh());
+    if fs::metadata(&dh_path).await.is_ok() {
+        check_dh(&dh_path).await;
+    }
+
+    // ---------------------------------------------------------------------------------
+    // Parse and check the certificate, its chain and the key outside of the
+    // runtime, as signatures of chains are costly to verify, see [`metadata`]
+    let span = Span::current();
+    let verify_chain = config.verify_chain;
+    let key_policy = config.key_policy.to_owned();
+    let strict_names = config.strict_names;
+    let (certificate, certificate_chain, mut key, names) = blocking(move || {
+        let _entered = span.enter();
+
+        // -----------------------------------------------------------------------------
+        // Parse certificate to retrieve SAN and CN attributes from pem
+        let pem = parse_pem(certificate.as_bytes())
+            .map_err(|err| Error::ParsePem(certificate_source.to_owned(), Block::Leaf, err))?;
+        let x509 = parse_x509(&pem.contents)
+            .map_err(|err| Error::ParseX509(certificate_source.to_owned(), Block::Leaf, err))?;
+        let names = get_cn_and_san_attributes(&x509);
+
+        // Sōzu selects certificates by name, a certificate without any is
+        // never served
+        if names.is_empty() {
+            CERTIFICATE_NO_NAMES
+                .with_label_values(&[&directory_name(&path)])
+                .inc();
+
+            if strict_names {
+                return Err(Error::NoNames(certificate_source));
+            }
+
+            warn!(
+                path = path.display().to_string(),
+                fingerprint = calculate_fingerprint(certificate.as_bytes())
+                    .map(|fingerprint| Fingerprint(fingerprint).to_string())
+                    .unwrap_or_default(),
+                "Certificate has neither a common name nor a subject alternative name, Sōzu will never serve it"
+            );
+        }
+
+        // Certificates of the chain are parsed as well, so that a broken one is
+        // reported with its position rather than refused by Sōzu
+        for (idx, certificate) in certificate_chain.iter().enumerate() {
+            let pem = parse_pem(certificate.as_bytes())
+                .map_err(|err| Error::ParsePem(chain_source.to_owned(), Block::Chain(idx), err))?;
+            parse_x509(&pem.contents)
+                .map_err(|err| Error::ParseX509(chain_source.to_owned(), Block::Chain(idx), err))?;
+        }
+
+        // -----------------------------------------------------------------------------
+        // Check that the public key of the certificate complies with the policy
+        if let Some(reason) = key_policy.and_then(|policy| key::violation(&x509, &policy)) {
+            return Err(Error::KeyPolicy(certificate_source, reason));
+        }
+
+        // -----------------------------------------------------------------------------
+        // Check that the certificate chain is ordered and signed, if asked to
+        if ChainVerification::Off != verify_chain {
+            match chain::verify(&x509, &certificate_chain) {
+                Ok(true) => {}
+                Ok(false) => {
+                    debug!(
+                        path = path.display().to_string(),
+                        "Certificate chain does not end with a root certificate"
+                    );
+                }
+                Err(err) if ChainVerification::Reject == verify_chain => {
+                    return Err(Error::InvalidChain(path, err));
+                }
+                Err(err) => {
+                    warn!(
+                        error = err.to_string(),
+                        path = path.display().to_string(),
+                        "Certificate chain does not verify"
+                    );
+                }
+            }
+        }
+
+        // -----------------------------------------------------------------------------
+        // Check that the private key belongs to the certificate
+        match key::matches(&x509, &key).map_err(Error::PublicKey)? {
+            Some(true) => {}
+            Some(false) => return Err(Error::KeyCertificateMismatch(key_path)),
+            None => {
+                debug!(
+                    path = key_path.display().to_string(),
+                    "Kind of private key is not supported, skip the check against the certificate"
+                );
+            }
+        }
+
+        Ok::<_, Error>((certificate, certificate_chain, key, names))
+    })
+    .await??;
+
+    // The key is moved out of its wrapper rather than copied, the certificate
+    // and key are then wiped by their holders, see [`Pki`]
+    Ok((
+        CertificateAndKey {
+            certificate,
+            certificate_chain,
+            key: std::mem::take(&mut *key),
+            versions,
+            names: names.into_iter().collect(),
+        },
+        listener,
+    ))
+}
+
+#[tracing::instrument(skip(certificate_and_key))]
+pub async fn metadata(
+    path: PathBuf,
+    certificate_and_key: &CertificateAndKey,
+    listener: Option<SocketAddr>,
+) -> Result<Metadata, Error> {
+    let names = certificate_and_key.names.iter().cloned().collect();
+
+    // ---------------------------------------------------------------------------------
+    // Compute fingerprints and parse the certificate, this is done outside of
+    // the runtime which would otherwise stall, e.g. the HTTP server, while a
+    // large pki is scanned
+    let certificate = certificate_and_key.certificate.to_owned();
+    let chain = certificate_and_key.certificate_chain.to_owned();
+    let owned = path.to_owned();
+    let (fingerprint, chain_fingerprints, validity, identity) = blocking(move || {
+        let fingerprint = calculate_fingerprint(certificate.as_bytes())
+            .map(Fingerprint)
+            .map_err(|err| Error::Fingerprint(owned.to_owned(), Block::Leaf, err.into()))?;
+
+        let mut chain_fingerprints = HashSet::new();
+        for (idx, certificate) in chain.iter().enumerate() {
+            chain_fingerprints.insert(
+                calculate_fingerprint(certificate.as_bytes())
+                    .map(Fingerprint)
+                    .map_err(|err| {
+                        Error::Fingerprint(owned.to_owned(), Block::Chain(idx), err.into())
+                    })?,
+            );
+        }
+
+        Ok::<_, Error>((
+            fingerprint,
+            chain_fingerprints,
+            validity(&certificate),
+            identity(&certificate),
+        ))
+    })
+    .await??;
+
+    // ---------------------------------------------------------------------------------
+    // Check the validity period of the certificate
+    if validity.is_none() {
+        warn!(
+            path = path.display().to_string(),
+            "Could not retrieve the validity period of the certificate"
+        );
+    }
+
+    let (serial, issuer, primary_name) = identity.unwrap_or_default();
+    Ok(Metadata {
+        serial,
+        issuer,
+        primary_name,
+        listener,
+        ..Metadata::new(
+            path,
+            fingerprint,
+            names,
+            chain_fingerprints,
+            validity.map(|(not_before, _)| not_before),
+            validity.map(|(_, not_after)| not_after),
+            KeyDigest::new(&certificate_and_key.key),
+        )
+    })
+}
+
+/// Leaf certificate, its chain and the private key, all pem encoded
+pub type Material = (String, Vec<String>, Zeroizing<String>);
+
+/// Read a PKCS#12 bundle and convert its leaf certificate, chain and private
+/// key to pem, the passphrase defaults to the one of the
+/// [`PKCS12_PASSPHRASE_ENV`] environment variable, else to an empty one.
+#[tracing::instrument(skip(passphrase))]
+pub async fn read_pkcs12(
+    path: &Path,
+    passphrase: Option<Zeroizing<String>>,
+) -> Result<Material, Error> {
+    let data = Zeroizing::new(read_file(path).await?);
+
+    let passphrase = passphrase
+        .unwrap_or_else(|| Zeroizing::new(env::var(PKCS12_PASSPHRASE_ENV).unwrap_or_default()));
+    let owned = path.to_owned();
+    blocking(move || {
+        let keystore = KeyStore::from_pkcs12(&data, &passphrase)
+            .map_err(|err| Error::ParsePkcs12(owned.to_owned(), err))?;
+
+        let (_, chain) = keystore
+            .private_key_chain()
+            .ok_or_else(|| Error::EmptyPkcs12(owned.to_owned()))?;
+
+        let mut certificates = vec![];
+        for certificate in chain.chain() {
+            certificates.push(
+                encode_string("CERTIFICATE", LineEnding::LF, certificate.as_der())
+                    .map_err(|err| Error::EncodePkcs12(owned.to_owned(), err))?,
+            );
+        }
+
+        if certificates.is_empty() {
+            return Err(Error::EmptyPkcs12(owned));
+        }
+
+        let key = encode_string("PRIVATE KEY", LineEnding::LF, chain.key())
+            .map(Zeroizing::new)
+            .map_err(|err| Error::EncodePkcs12(owned.to_owned(), err))?;
+
+        let certificate = certificates.remove(0);
+        Ok((certificate, certificates, key))
+    })
+    .await?
+}
+
+/// Read a single pem file holding the certificate, its chain and the private
+/// key, blocks are told apart by their pem label. The first certificate is the
+/// leaf one, the others its chain.
+///
+/// Fails if there is no certificate or no private key.
+#[tracing::instrument]
+async fn read_combined(path: &Path) -> Result<Material, Error> {
+    let data = Zeroizing::new(read_file(path).await?);
+
+    let mut certificates = vec![];
+    let mut keys = vec![];
+    for block in Pem::iter_from_buffer(&data) {
+        let mut block =
+            block.map_err(|err| Error::ParseCombined(path.to_owned(), err.to_string()))?;
+        let encoded = Zeroizing::new(
+            encode_string(&block.label, LineEnding::LF, &block.contents)
+                .map_err(|err| Error::EncodePem(path.to_owned(), err))?,
+        );
+
+        // Decoded blocks are not wiped on drop, whatever they hold
+        block.contents.zeroize();
+
+        if "CERTIFICATE" == block.label {
+            certificates.push(encoded.to_string());
+        } else if block.label.ends_with("PRIVATE KEY") {
+            keys.push(encoded);
+        } else {
+            debug!(
+                path = path.display().to_string(),
+                label = block.label,
+                "Skip pem block which is neither a certificate nor a private key"
+            );
+        }
+    }
+
+    if 1 < keys.len() {
+        return Err(Error::MultipleKeys(path.to_owned(), keys.len()));
+    }
+
+    let Some(key) = keys.pop() else {
+        return Err(Error::MissingKey(path.to_owned()));
+    };
+
+    if certificates.is_empty() {
+        return Err(Error::EmptyCertificate(path.to_owned()));
+    }
+
+    let certificate = certificates.remove(0);
+    Ok((certificate, certificates, key))
+}
+
+/// Content of a file that may be either pem or der encoded
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum Content {
+    /// One or more certificates
+    Certificates,
+    /// A private key
+    Key,
+}
+
+/// Read the given file as pem, der encoded content is converted to pem.
+///
+/// The content is pem if it starts with a pem header, der otherwise. It is
+/// wiped from memory when dropped, as it may be a private key.
+#[tracing::instrument]
+async fn read_pem(path: &Path, content: Content) -> Result<Zeroizing<String>, Error> {
+    let data = Zeroizing::new(read_file(path).await?);
+
+    let start = data
+        .iter()
+        .position(|byte| !byte.is_ascii_whitespace())
+        .unwrap_or(data.len());
+
+    if data[start..].starts_with(b"-----BEGIN") {
+        return str::from_utf8(&data)
+            .map(|content| Zeroizing::new(content.to_owned()))
+            .map_err(|err| Error::Decode(path.to_owned(), err.to_string()));
+    }
+
+    debug!(
+        path = path.display().to_string(),
+        "File is not pem encoded, decode it as der"
+    );
+
+    let mut acc = Zeroizing::new(String::new());
+    match content {
+        Content::Certificates => {
+            // Certificates may be concatenated, the whole file must be consumed
+            let mut rest = data.as_slice();
+            while !rest.is_empty() {
+                let (remaining, _) = parse_x509_certificate(rest)
+                    .map_err(|err| Error::ParseDer(path.to_owned(), err))?;
+
+                let der = &rest[..rest.len() - remaining.len()];
+                acc.push_str(
+                    &encode_string("CERTIFICATE", LineEnding::LF, der)
+                        .map_err(|err| Error::EncodePem(path.to_owned(), err))?,
+                );
+
+                rest = remaining;
+            }
+        }
+        Content::Key => {
+            let label = if PrivateKeyInfo::try_from(data.as_slice()).is_ok() {
+                "PRIVATE KEY"
+            } else if rsa::pkcs1::RsaPrivateKey::try_from(data.as_slice()).is_ok() {
+                "RSA PRIVATE KEY"
+            } else if p256::SecretKey::from_sec1_der(&data).is_ok()
+                || p384::SecretKey::from_sec1_der(&data).is_ok()
+            {
+                "EC PRIVATE KEY"
+            } else {
+                return Err(Error::UnsupportedDerKey(path.to_owned()));
+            };
+
+            acc = encode_string(label, LineEnding::LF, &data)
+                .map(Zeroizing::new)
+                .map_err(|err| Error::EncodePem(path.to_owned(), err))?;
+        }
+    }
+
+    Ok(acc)
+}
+
+/// Check that the given private key is not readable by group or others, it is
+/// only logged unless strict is set
+#[cfg(unix)]
+#[tracing::instrument]
+async fn check_permissions(path: &Path, strict: bool) -> Result<(), Error> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = fs::metadata(path)
+        .await
+        .map_err(|err| Error::Read(path.to_owned(), err))?
+        .permissions()
+        .mode()
+        & 0o777;
+
+    if 0 == mode & 0o044 {
+        return Ok(());
+    }
+
+    CERTIFICATE_INSECURE_KEY.inc();
+    if strict {
+        return Err(Error::InsecureKeyPermissions(path.to_owned(), mode));
+    }
+
+    warn!(
+        path = path.display().to_string(),
+        mode = format!("{mode:o}"),
+        "Private key is readable by group or others"
+    );
+
+    Ok(())
+}
+
+/// Permissions are only checked on unix
+#[cfg(not(unix))]
+async fn check_permissions(_path: &Path, _strict: bool) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Read and check every certificate directory of the pki directories, without
+/// connecting to Sōzu. Certificate chains are always verified.
+#[tracing::instrument(skip_all)]
+pub async fn validate(config: &ConnectorConfiguration) -> Validation {
+    // Archives are extracted aside the ones of a running connector
+    let archive = Archive {
+        directory: config
+            .archive
+            .directory
+            .join(format!("validate-{}", process::id())),
+        ..config.archive.to_owned()
+    };
+
+    let config = ConnectorConfiguration {
+        verify_chain: ChainVerification::Reject,
+        archive,
+        ..config.to_owned()
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as i64)
+        .unwrap_or_default();
+
+    let mut validation = Validation::default();
+    let mut unavailable = HashSet::new();
+    for (path, err) in Extractions::default().refresh(&config).await {
+        unavailable.insert(config.archive.destination(&path));
+        validation.failures.push((path, err.to_string()));
+    }
+
+    for root in &config.roots() {
+        if unavailable.contains(root) {
+            continue;
+        }
+
+        let directories = match directories(root, &config, config.max_depth).await {
+            Ok(directories) => directories,
+            Err(err) => {
+                validation.failures.push((root.to_owned(), err.to_string()));
+                continue;
+            }
+        };
+
+        validation.directories += directories.len();
+        for path in directories {
+            let (certificate_and_key, listener) = match read(path.to_owned(), &config).await {
+                Ok(read) => read,
+                Err(err) => {
+                    validation.failures.push((path, err.to_string()));
+                    continue;
+                }
+            };
+
+            match metadata(path.to_owned(), &certificate_and_key, listener).await {
+                Ok(meta)
+                    if config.skip_expired
+                        && is_expired(&meta, now, config.clock_skew_grace as i64) =>
+                {
+                    let reason = "certificate is expired and would be skipped".to_string();
+                    validation.failures.push((path, reason));
+                }
+                Ok(meta) if is_not_yet_valid(&meta, now, config.clock_skew_grace as i64) => {
+                    let reason = "certificate is not yet valid and would be skipped".to_string();
+                    validation.failures.push((path, reason));
+                }
+                Ok(_) => {}
+                Err(err) => validation.failures.push((path, err.to_string())),
+            }
+        }
+    }
+
+    if config.sozu.pki.iter().any(|path| Archive::is_archive(path)) {
+        let _ = fs::remove_dir_all(&config.archive.directory).await;
+    }
+
+    validation
+}
+
+/// Returns true if the certificate is expired at the given unix timestamp in
+/// milliseconds, tolerating the given clock skew in milliseconds
+pub fn is_expired(metadata: &Metadata, now: i64, grace: i64) -> bool {
+    metadata
+        .expires_at
+        .is_some_and(|expires_at| expires_at.saturating_mul(1000).saturating_add(grace) < now)
+}
+
+/// Returns true if the certificate is not valid yet at the given unix
+/// timestamp in milliseconds, tolerating the given clock skew in milliseconds
+pub fn is_not_yet_valid(metadata: &Metadata, now: i64, grace: i64) -> bool {
+    metadata
+        .not_before
+        .is_some_and(|not_before| not_before.saturating_mul(1000).saturating_sub(grace) > now)
+}
+
+/// Returns the serial number, as uppercase hexadecimal, the distinguished name
+/// of the issuer and the primary name, the common name or else the first
+/// subject alternative name, of the given pem encoded certificate, if it could
+/// be parsed
+pub fn identity(certificate: &str) -> Option<(String, String, String)> {
+    let pem = parse_pem(certificate.as_bytes()).ok()?;
+    let x509 = parse_x509(&pem.contents).ok()?;
+    let serial = x509
+        .serial
+        .to_bytes_be()
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect();
+
+    let primary_name = x509
+        .subject()
+        .iter_common_name()
+        .find_map(|name| name.as_str().ok().map(str::to_owned))
+        .or_else(|| {
+            x509.subject_alternative_name()
+                .ok()
+                .flatten()
+                .and_then(|san| {
+                    san.value.general_names.iter().find_map(|name| match name {
+                        GeneralName::DNSName(name) => Some(name.to_string()),
+                        _ => None,
+                    })
+                })
+        })
+        .map(|name| normalize_name(&name))
+        .unwrap_or_default();
+
+    Some((serial, x509.issuer().to_string(), primary_name))
+}
+
+/// Returns the unix timestamps of the start (notBefore) and the end of
+/// validity (notAfter) of the given pem encoded certificate, if it could be
+/// parsed
+pub fn validity(certificate: &str) -> Option<(i64, i64)> {
+    let pem = parse_pem(certificate.as_bytes()).ok()?;
+    let x509 = parse_x509(&pem.contents).ok()?;
+    let validity = x509.validity();
+
+    Some((
+        validity.not_before.timestamp(),
+        validity.not_after.timestamp(),
+    ))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use rcgen::{date_time_ymd, Certificate, CertificateParams, DistinguishedName, DnType};
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::svc::{config::tests::configuration, http::server};
+
+    /// Generate a self-signed certificate with the given common name, if any,
+    /// and subject alternative names, returns it and its private key as pem
+    pub fn self_signed(common_name: Option<&str>, names: &[&str]) -> (String, String) {
+        self_signed_with(common_name, names, |_| {})
+    }
+
+    /// Generate a self-signed certificate with the given names, which is not
+    /// valid before the given time, see [`self_signed`]
+    pub fn self_signed_from(names: &[&str], not_before: SystemTime) -> (String, String) {
+        let since_epoch = not_before
+            .duration_since(UNIX_EPOCH)
+            .expect("time to be after the epoch");
+
+        self_signed_with(None, names, |params| {
+            params.not_before = date_time_ymd(1970, 1, 1) + since_epoch;
+        })
+    }
+
+    pub fn self_signed_with(
+        common_name: Option<&str>,
+        names: &[&str],
+        customize: impl FnOnce(&mut CertificateParams),
+    ) -> (String, String) {
+        let mut params = CertificateParams::new(
+            names
+                .iter()
+                .map(|name| name.to_string())
+                .collect::<Vec<_>>(),
+        );
+
+        params.distinguished_name = DistinguishedName::new();
+        if let Some(common_name) = common_name {
+            params
+                .distinguished_name
+                .push(DnType::CommonName, common_name);
+        }
+
+        customize(&mut params);
+        let certificate = Certificate::from_params(params).expect("certificate to be generated");
+        (
+            certificate
+                .serialize_pem()
+                .expect("certificate to be encoded"),
+            certificate.serialize_private_key_pem(),
+        )
+    }
+
+    /// Write a certificate directory of the default layout with the given
+    /// certificate and key, returns its path
+    pub fn write_directory(root: &Path, name: &str, certificate: &str, key: &str) -> PathBuf {
+        let path = root.join(name);
+        std::fs::create_dir_all(&path).expect("certificate directory to be created");
+        std::fs::write(path.join(format!("{name}.crt")), certificate)
+            .expect("certificate to be written");
+        std::fs::write(path.join(format!("{name}.key")), key).expect("key to be written");
+        path
+    }
+
+    #[test]
+    fn names_are_normalized() {
+        assert_eq!("example.com", normalize_name(" Example.COM. "));
+        assert_eq!("*.example.com", normalize_name("*.EXAMPLE.com."));
+        assert_eq!("xn--bcher-kva.example", normalize_name("Bücher.example"));
+        assert_eq!(
+            "xn--bcher-kva.example",
+            normalize_name("xn--bcher-kva.example.")
+        );
+        assert_eq!(
+            "*.xn--bcher-kva.example",
+            normalize_name("*.BÜCHER.example")
+        );
+    }
+
+    #[test]
+    fn metadata_compare_regardless_of_how_names_are_written() {
+        let names = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        let metadata = Metadata::new(
+            PathBuf::from("example"),
+            Fingerprint(vec![1]),
+            names(&["*.Example.COM.", "Bücher.example"]),
+            HashSet::new(),
+            None,
+            None,
+            KeyDigest::new("key"),
+        );
+
+        let read = Metadata {
+            names: names(&["xn--bcher-kva.example", "*.example.com"]),
+            ..metadata.to_owned()
+        };
+
+        assert_eq!(metadata, read);
+
+        // A state file written before names were normalized
+        let mut persisted = serde_json::to_value(&metadata).expect("metadata to be serialized");
+        persisted["names"] = serde_json::json!(["BÜCHER.example.", "*.EXAMPLE.com"]);
+        let restored: Metadata =
+            serde_json::from_value(persisted).expect("metadata to be deserialized");
+        assert_eq!(metadata, restored);
+    }
+
+    #[test]
+    fn identity_prefers_the_common_name() {
+        let (certificate, _) = self_signed(Some("Example.COM"), &["www.example.com"]);
+        let (_, _, primary_name) = identity(&certificate).expect("certificate to be parsed");
+        assert_eq!("example.com", primary_name);
+    }
+
+    #[test]
+    fn identity_falls_back_to_the_first_subject_alternative_name() {
+        let (certificate, _) = self_signed(None, &["www.example.com", "api.example.com"]);
+        let (_, _, primary_name) = identity(&certificate).expect("certificate to be parsed");
+        assert_eq!("www.example.com", primary_name);
+    }
+
+    #[tokio::test]
+    async fn certificate_without_names_is_loaded_with_a_warning() {
+        let pki = TempDir::new().expect("pki directory to be created");
+        let (certificate, key) = self_signed(None, &[]);
+        let path = write_directory(pki.path(), "nameless-lenient", &certificate, &key);
+
+        let (_, metadata) = load(path, &configuration(pki.path(), ""))
+            .await
+            .expect("certificate to be loaded");
+
+        assert!(metadata.names.is_empty());
+        assert_eq!(
+            1,
+            CERTIFICATE_NO_NAMES
+                .with_label_values(&["nameless-lenient"])
+                .get()
+        );
+    }
+
+    #[tokio::test]
+    async fn certificate_without_names_is_skipped_if_strict() {
+        let pki = TempDir::new().expect("pki directory to be created");
+        let (certificate, key) = self_signed(None, &[]);
+        let path = write_directory(pki.path(), "nameless-strict", &certificate, &key);
+
+        let result = load(path, &configuration(pki.path(), "strict-names = true")).await;
+
+        assert!(matches!(result, Err(Error::NoNames(_))));
+        assert_eq!(
+            1,
+            CERTIFICATE_NO_NAMES
+                .with_label_values(&["nameless-strict"])
+                .get()
+        );
+    }
+
+    #[tokio::test]
+    async fn nested_certificate_directories_are_found_down_to_the_maximum_depth() {
+        let pki = TempDir::new().expect("pki directory to be created");
+        let (certificate, key) = self_signed(Some("example.com"), &["example.com"]);
+        let leaves = [
+            "example.com",
+            "tenant-a/a.com",
+            "tenant-a/www.a.com",
+            "tenant-b/eu/b.eu",
+            "tenant-b/us/b.us",
+        ];
+
+        for leaf in leaves {
+            let (parent, name) = leaf.rsplit_once('/').unwrap_or(("", leaf));
+            write_directory(&pki.path().join(parent), name, &certificate, &key);
+        }
+
+        // Files next to certificate directories are not certificate files
+        std::fs::write(pki.path().join("tenant-b/README"), "").expect("file to be written");
+
+        let config = configuration(pki.path(), "max-depth = 3");
+        let mut found = directories(&pki.path().to_owned(), &config, config.max_depth)
+            .await
+            .expect("directories to be found");
+        found.sort();
+
+        let expected: Vec<_> = leaves.iter().map(|leaf| pki.path().join(leaf)).collect();
+        assert_eq!(expected, found);
+    }
+
+    #[tokio::test]
+    async fn unreadable_nested_directories_are_skipped() {
+        use std::os::unix::fs::PermissionsExt;
+
+        // Permissions do not apply to root
+        // SAFETY: geteuid has no preconditions and cannot fail
+        if 0 == unsafe { libc::geteuid() } {
+            return;
+        }
+
+        let pki = TempDir::new().expect("pki directory to be created");
+        let (certificate, key) = self_signed(Some("example.com"), &["example.com"]);
+        for leaf in ["example.com", "tenant-a/a.com", "tenant-b/b.com"] {
+            let (parent, name) = leaf.rsplit_once('/').unwrap_or(("", leaf));
+            write_directory(&pki.path().join(parent), name, &certificate, &key);
+        }
+
+        let permissions = |path: &Path, mode| {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+                .expect("permissions to be set")
+        };
+
+        let errors = CERTIFICATE_SCAN_ERROR
+            .with_label_values(&["directory"])
+            .get();
+        permissions(&pki.path().join("tenant-b"), 0o000);
+
+        let config = configuration(pki.path(), "max-depth = 2");
+        let found = directories(&pki.path().to_owned(), &config, config.max_depth).await;
+        permissions(&pki.path().join("tenant-b"), 0o700);
+
+        let mut found = found.expect("directories to be found");
+        found.sort();
+        assert_eq!(
+            vec![
+                pki.path().join("example.com"),
+                pki.path().join("tenant-a/a.com")
+            ],
+            found
+        );
+
+        assert!(
+            CERTIFICATE_SCAN_ERROR
+                .with_label_values(&["directory"])
+                .get()
+                > errors
+        );
+
+        // Whereas an unreadable pki directory fails the whole search
+        permissions(pki.path(), 0o000);
+        let result = directories(&pki.path().to_owned(), &config, config.max_depth).await;
+        permissions(pki.path(), 0o700);
+        assert!(matches!(result, Err(Error::ReadDir(..))));
+    }
+
+    #[tokio::test]
+    async fn symbolic_links_are_followed_without_looping() {
+        use std::os::unix::fs::symlink;
+
+        let dir = TempDir::new().expect("directory to be created");
+        let (pki, archive) = (dir.path().join("pki"), dir.path().join("archive"));
+        let (certificate, key) = self_signed(Some("example.com"), &["example.com"]);
+        write_directory(&archive, "files", &certificate, &key);
+
+        // Certbot keeps a directory of links to the files of an archive
+        let live = pki.join("tenant/example.com");
+        std::fs::create_dir_all(&live).expect("directory to be created");
+        for extension in ["crt", "key"] {
+            symlink(
+                archive.join(format!("files/files.{extension}")),
+                live.join(format!("example.com.{extension}")),
+            )
+            .expect("link to be created");
+        }
+
+        // A linked certificate directory, a dangling link and a cycle
+        symlink(&live, pki.join("example.com")).expect("link to be created");
+        symlink(dir.path().join("gone"), pki.join("gone.com")).expect("link to be created");
+        symlink(&pki, pki.join("tenant/loop")).expect("link to be created");
+
+        let config = configuration(&pki, "max-depth = 3");
+        let mut found = directories(&pki, &config, config.max_depth)
+            .await
+            .expect("directories to be found");
+        found.sort();
+
+        assert_eq!(vec![pki.join("example.com"), live.to_owned()], found);
+        for path in found {
+            let (certificate_and_key, metadata) =
+                load(path, &config).await.expect("certificate to be loaded");
+
+            assert_eq!(certificate.trim(), certificate_and_key.certificate.trim());
+            assert!(metadata.names.contains("example.com"));
+        }
+    }
+
+    #[tokio::test]
+    async fn excluded_directories_and_their_descendants_are_skipped() {
+        let pki = TempDir::new().expect("pki directory to be created");
+        let (certificate, key) = self_signed(Some("example.com"), &["example.com"]);
+        for name in [
+            "example.com",
+            "archive/old.com",
+            "backup-1",
+            "tenant/www.com",
+        ] {
+            let (parent, name) = name.rsplit_once('/').unwrap_or(("", name));
+            write_directory(&pki.path().join(parent), name, &certificate, &key);
+        }
+
+        // A certificate directory at the maximum depth named like the excluded
+        // directory
+        std::fs::create_dir_all(pki.path().join("tenant/archive"))
+            .expect("directory to be created");
+
+        for exclude in [
+            r#"["**/archive/**", "**/backup*"]"#,
+            r#"["**/archive", "**/backup*"]"#,
+        ] {
+            let config = configuration(pki.path(), &format!("max-depth = 2\nexclude = {exclude}"));
+
+            let mut found = directories(&pki.path().to_owned(), &config, config.max_depth)
+                .await
+                .expect("directories to be found");
+            found.sort();
+
+            assert_eq!(
+                vec![
+                    pki.path().join("example.com"),
+                    pki.path().join("tenant/www.com")
+                ],
+                found,
+                "{exclude}"
+            );
+
+            // Directories changed on disk are checked one by one in the events
+            // mode
+            assert!(!is_included(&config, &pki.path().join("archive")));
+            assert!(!is_included(&config, &pki.path().join("archive/old.com")));
+            assert!(!is_included(&config, &pki.path().join("tenant/archive")));
+            assert!(is_included(&config, &pki.path().join("tenant/www.com")));
+        }
+    }
+
+    /// Returns the error of the given options, which must be invalid
+    fn options_error(content: &str) -> (String, serde_json::Error) {
+        match Options::parse(content) {
+            Ok(_) => panic!("options '{content}' to be invalid"),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn options_errors_point_at_the_offending_value() {
+        assert_eq!("[1]", options_error(r#"[771, "TLS1.3"]"#).0);
+        assert_eq!(
+            "versions[1]",
+            options_error(r#"{"versions": [771, "TLS1.3"]}"#).0
+        );
+        assert_eq!("listener", options_error(r#"{"listener": 8443}"#).0);
+
+        let (at, err) = options_error("[771,");
+        assert_eq!(".", at);
+        assert_eq!(1, err.line());
+    }
+
+    #[test]
+    fn options_are_parsed_in_both_shapes() {
+        assert!(matches!(
+            Options::parse("[771, 772]"),
+            Ok(Options::Versions(versions)) if versions == [771, 772]
+        ));
+
+        assert!(matches!(
+            Options::parse(r#"{"listener": "127.0.0.1:8443"}"#),
+            Ok(Options::Object(ObjectOptions {
+                versions: None,
+                listener: Some(_),
+                ..
+            }))
+        ));
+    }
+
+    /// Write a PKCS#12 bundle protected by the given passphrase in a new
+    /// certificate directory of the default layout, returns its path
+    fn write_bundle(root: &Path, name: &str, passphrase: &str) -> PathBuf {
+        let certificate = Certificate::from_params(CertificateParams::new(vec![name.to_string()]))
+            .expect("certificate to be generated");
+
+        let mut keystore = KeyStore::new();
+        keystore.add_entry(
+            name,
+            p12_keystore::KeyStoreEntry::PrivateKeyChain(p12_keystore::PrivateKeyChain::new(
+                certificate.serialize_private_key_der(),
+                [1],
+                [p12_keystore::Certificate::from_der(
+                    &certificate
+                        .serialize_der()
+                        .expect("certificate to be encoded"),
+                )
+                .expect("certificate to be parsed")],
+            )),
+        );
+
+        let path = root.join(name);
+        std::fs::create_dir_all(&path).expect("certificate directory to be created");
+        std::fs::write(
+            path.join(format!("{name}.p12")),
+            keystore
+                .writer(passphrase)
+                .write()
+                .expect("bundle to be written"),
+        )
+        .expect("bundle to be written");
+
+        path
+    }
+
+    #[tokio::test]
+    async fn bundle_passphrase_is_read_from_the_options() {
+        let pki = TempDir::new().expect("pki directory to be created");
+        let config = configuration(pki.path(), "");
+
+        let path = write_bundle(pki.path(), "inline.example.com", "s3cr3t");
+        assert!(matches!(
+            read(path.to_owned(), &config).await,
+            Err(Error::ParsePkcs12(..))
+        ));
+
+        std::fs::write(path.join("options.json"), r#"{"passphrase": "s3cr3t"}"#)
+            .expect("options to be written");
+        let (certificate_and_key, _) = read(path, &config).await.expect("bundle to be read");
+        assert!(certificate_and_key
+            .names
+            .contains(&"inline.example.com".to_string()));
+
+        let path = write_bundle(pki.path(), "file.example.com", "s3cr3t");
+        std::fs::write(path.join("passphrase"), "s3cr3t\n").expect("passphrase to be written");
+        std::fs::write(
+            path.join("options.json"),
+            r#"{"passphrase-file": "passphrase"}"#,
+        )
+        .expect("options to be written");
+        let (certificate_and_key, _) = read(path, &config).await.expect("bundle to be read");
+        assert!(certificate_and_key
+            .names
+            .contains(&"file.example.com".to_string()));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn fingerprints_are_computed_outside_of_the_runtime() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        let (certificate, key) = self_signed(None, &["example.com"]);
+        let certificate_and_key = CertificateAndKey {
+            certificate: certificate.to_owned(),
+            certificate_chain: vec![certificate; 2_000],
+            key,
+            ..Default::default()
+        };
+
+        // The ticker only runs if the runtime is free while hashing
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticker = tokio::spawn({
+            let ticks = ticks.to_owned();
+            async move {
+                loop {
+                    tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+                    ticks.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+
+        let metadata = metadata(PathBuf::from("example.com"), &certificate_and_key, None)
+            .await
+            .expect("metadata to be computed");
+
+        ticker.abort();
+        assert_eq!(1, metadata.chain_fingerprints.len());
+        assert!(1 < ticks.load(Ordering::Relaxed));
+    }
+
+    /// Send a request to the health endpoint of the HTTP server listening on
+    /// the given address, returns true if it answered with a success
+    async fn healthz(addr: SocketAddr) -> bool {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let Ok(mut stream) = tokio::net::TcpStream::connect(addr).await else {
+            return false;
+        };
+
+        let request = "GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+        let mut response = vec![];
+        stream.write_all(request.as_bytes()).await.is_ok()
+            && stream.read_to_end(&mut response).await.is_ok()
+            && response.starts_with(b"HTTP/1.1 200")
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn http_server_answers_promptly_during_a_large_scan() {
+        const DIRECTORIES: usize = 8;
+
+        // Long chains make the work done for a single directory noticeable
+        let pki = TempDir::new().expect("pki directory to be created");
+        let (certificate, key) = self_signed(None, &["example.com"]);
+        let chain = certificate.repeat(500);
+        for idx in 0..DIRECTORIES {
+            write_directory(pki.path(), &format!("example-{idx}"), &chain, &key);
+        }
+
+        let mut config = configuration(pki.path(), "");
+        config.listening_address = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("port to be available");
+
+        let (syncs, _rx) = tokio::sync::mpsc::channel(1);
+        let context = server::Context {
+            health: Default::default(),
+            inventory: Default::default(),
+            syncs,
+            metrics: std::sync::Arc::new(config.metrics.to_owned()),
+        };
+
+        let addr = config.listening_address;
+        tokio::spawn(server::serve(
+            std::sync::Arc::new(config.to_owned()),
+            context,
+        ));
+        while !healthz(addr).await {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        // The scan shares the only thread of the runtime with the server, which
+        // must keep answering while certificates are parsed and hashed
+        let scan = tokio::spawn(async move { validate(&config).await });
+        let mut latencies = vec![];
+        while !scan.is_finished() {
+            let begin = std::time::Instant::now();
+            assert!(healthz(addr).await);
+            latencies.push(begin.elapsed());
+        }
+
+        let validation = scan.await.expect("scan to complete");
+        assert_eq!(DIRECTORIES, validation.directories);
+
+        latencies.sort();
+        assert!(1 < latencies.len(), "{latencies:?}");
+        assert!(
+            latencies[latencies.len() - 1] < std::time::Duration::from_millis(250),
+            "{latencies:?}"
+        );
+    }
 }
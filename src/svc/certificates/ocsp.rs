@@ -0,0 +1,143 @@
+//! # Ocsp module
+//!
+//! This module provides helpers to check the freshness of der encoded OCSP
+//! responses stored next to certificates, see RFC 6960
+
+use x509_parser::der_parser::asn1_rs::{Any, Class, Enumerated, FromDer, GeneralizedTime, Tag};
+
+// -------------------------------------------------------------------------------------
+// Error
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to parse der, {0}")]
+    Parse(String),
+    #[error("unexpected structure, {0}")]
+    Structure(&'static str),
+    #[error("response status is not successful, got {0}")]
+    Status(u32),
+}
+
+// -------------------------------------------------------------------------------------
+// Validity
+
+/// Validity period of a single response, as unix timestamps
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Validity {
+    /// Time at which the status is known to be correct (thisUpdate)
+    pub this_update: i64,
+    /// Time at or before which newer information will be available
+    /// (nextUpdate), the responder always has newer information if unset
+    pub next_update: Option<i64>,
+}
+
+impl Validity {
+    /// Returns true if the response is stale at the given unix timestamp in
+    /// seconds
+    pub fn is_stale(&self, now: i64) -> bool {
+        self.next_update
+            .map_or(true, |next_update| next_update < now)
+    }
+}
+
+// -------------------------------------------------------------------------------------
+// Helpers
+
+/// Parse the given der encoded OCSP response and return the validity period of
+/// each of its single responses
+pub fn validities(data: &[u8]) -> Result<Vec<Validity>, Error> {
+    // OCSPResponse ::= SEQUENCE { responseStatus, [0] EXPLICIT ResponseBytes }
+    let response = children(parse(data)?.data)?;
+    let status = response
+        .first()
+        .ok_or(Error::Structure("missing response status"))?;
+
+    let status = Enumerated::try_from(status).map_err(|err| Error::Parse(err.to_string()))?;
+    if 0 != status.0 {
+        return Err(Error::Status(status.0));
+    }
+
+    // ResponseBytes ::= SEQUENCE { responseType, response OCTET STRING }
+    let bytes = response
+        .get(1)
+        .filter(|any| is_context(any, 0))
+        .ok_or(Error::Structure("missing response bytes"))?;
+
+    let bytes = children(parse(bytes.data)?.data)?;
+    let basic = bytes
+        .get(1)
+        .filter(|any| Tag::OctetString == any.tag())
+        .ok_or(Error::Structure("missing basic response"))?;
+
+    // BasicOCSPResponse ::= SEQUENCE { tbsResponseData, signatureAlgorithm, ... }
+    let basic = children(parse(basic.data)?.data)?;
+    let data = basic
+        .first()
+        .ok_or(Error::Structure("missing response data"))?;
+
+    // ResponseData ::= SEQUENCE { [0] version, responderID, producedAt,
+    // responses, [1] extensions }, the version and the responder are context
+    // specific
+    let responses = children(data.data)?
+        .into_iter()
+        .filter(|any| Class::Universal == any.class())
+        .find(|any| Tag::Sequence == any.tag())
+        .ok_or(Error::Structure("missing responses"))?;
+
+    let mut acc = vec![];
+    for single in children(responses.data)? {
+        // SingleResponse ::= SEQUENCE { certID, certStatus, thisUpdate,
+        // [0] EXPLICIT nextUpdate, [1] extensions }
+        let fields = children(single.data)?;
+        let this_update = fields
+            .iter()
+            .find(|any| Class::Universal == any.class() && Tag::GeneralizedTime == any.tag())
+            .ok_or(Error::Structure("missing this update"))?;
+
+        let next_update = match fields
+            .iter()
+            .skip_while(|any| Tag::GeneralizedTime != any.tag())
+            .find(|any| is_context(any, 0))
+        {
+            Some(any) => Some(timestamp(&parse(any.data)?)?),
+            None => None,
+        };
+
+        acc.push(Validity {
+            this_update: timestamp(this_update)?,
+            next_update,
+        });
+    }
+
+    Ok(acc)
+}
+
+/// Parse a single der object, trailing data is ignored
+fn parse(data: &[u8]) -> Result<Any<'_>, Error> {
+    Any::from_der(data)
+        .map(|(_, any)| any)
+        .map_err(|err| Error::Parse(err.to_string()))
+}
+
+/// Parse the consecutive der objects of the given content
+fn children(mut data: &[u8]) -> Result<Vec<Any<'_>>, Error> {
+    let mut acc = vec![];
+    while !data.is_empty() {
+        let (rest, any) = Any::from_der(data).map_err(|err| Error::Parse(err.to_string()))?;
+        acc.push(any);
+        data = rest;
+    }
+
+    Ok(acc)
+}
+
+fn is_context(any: &Any<'_>, tag: u32) -> bool {
+    Class::ContextSpecific == any.class() && Tag(tag) == any.tag()
+}
+
+fn timestamp(any: &Any<'_>) -> Result<i64, Error> {
+    GeneralizedTime::try_from(any)
+        .and_then(|time| time.utc_datetime())
+        .map(|time| time.unix_timestamp())
+        .map_err(|err| Error::Parse(err.to_string()))
+}
@@ -0,0 +1,59 @@
+//! # Sink module
+//!
+//! This module provides the abstraction over the Sōzu client used by the
+//! watcher to send requests, so that the watcher does not depend on a real
+//! command socket
+
+use sozu_client::{Client, Sender};
+use sozu_command_lib::proto::command::{request::RequestType, Response};
+
+use crate::svc::{
+    certificates::watcher::{self, Error},
+    config::Instance,
+};
+
+// -----------------------------------------------------------------------------
+// CertificateSink
+
+/// Receiver of the requests of the watcher, errors are the ones of the Sōzu
+/// client so that failures and dead connections are told apart the same way
+#[async_trait::async_trait]
+pub trait CertificateSink: Send + Sync {
+    /// Send a single request and wait for its answer
+    async fn send(&self, request: RequestType) -> Result<Response, sozu_client::Error>;
+
+    /// Send the given requests as a single batch and wait for its answer
+    async fn send_all(&self, requests: &[RequestType]) -> Result<Response, sozu_client::Error>;
+}
+
+#[async_trait::async_trait]
+impl CertificateSink for Client {
+    async fn send(&self, request: RequestType) -> Result<Response, sozu_client::Error> {
+        Sender::send(self, request).await
+    }
+
+    async fn send_all(&self, requests: &[RequestType]) -> Result<Response, sozu_client::Error> {
+        Sender::send_all(self, requests).await
+    }
+}
+
+// -----------------------------------------------------------------------------
+// SinkFactory
+
+/// Creator of the sink of a Sōzu instance, used to create it again once its
+/// connection is dead
+#[async_trait::async_trait]
+pub trait SinkFactory: Send + Sync {
+    async fn connect(&self, instance: &Instance) -> Result<Box<dyn CertificateSink>, Error>;
+}
+
+/// Factory of Sōzu clients, see [`watcher::connect`]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClientFactory;
+
+#[async_trait::async_trait]
+impl SinkFactory for ClientFactory {
+    async fn connect(&self, instance: &Instance) -> Result<Box<dyn CertificateSink>, Error> {
+        Ok(Box::new(watcher::connect(instance).await?))
+    }
+}
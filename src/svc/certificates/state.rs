@@ -0,0 +1,244 @@
+//! # State module
+//!
+//! This module provides helpers to persist the current state of certificates
+//! across restarts, without any key material.
+//!
+//! The state is written in a compact binary format, prefixed by [`MAGIC`] and
+//! the version of its schema. State files written by previous versions, as
+//! JSON or with the first version of the schema, are still read.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use tokio::{fs, io::AsyncWriteExt};
+
+use crate::svc::certificates::Metadata;
+
+// -------------------------------------------------------------------------------------
+// Constants
+
+/// Leading bytes of a binary state file
+pub const MAGIC: &[u8; 4] = b"SPKS";
+
+/// Version of the schema of binary state files, to be bumped on any change of
+/// [`Metadata`] as the binary format does not describe its fields
+pub const VERSION: u16 = 2;
+
+/// Version of the schema which only held the certificates of every listener
+const GLOBAL_VERSION: u16 = 1;
+
+// -------------------------------------------------------------------------------------
+// Types
+
+/// Certificates held by each listener of each Sōzu instance, by instance name
+/// and listener address
+pub type Applied = HashMap<String, HashMap<SocketAddr, HashMap<PathBuf, Metadata>>>;
+
+/// State of certificates read from a state file
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum State {
+    /// Certificates held by each listener of each Sōzu instance
+    Applied(Applied),
+    /// Certificates held by every listener of every Sōzu instance, as written
+    /// by previous versions
+    Global(HashMap<PathBuf, Metadata>),
+}
+
+/// Certificates held by a listener of a Sōzu instance, as written in the file
+type Entry = (String, SocketAddr, Vec<Metadata>);
+
+// -------------------------------------------------------------------------------------
+// Error
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to read state file '{0}', {1}")]
+    Read(PathBuf, io::Error),
+    #[error("failed to parse state file '{0}', {1}")]
+    Parse(PathBuf, serde_json::Error),
+    #[error("failed to decode state file '{0}', {1}")]
+    Decode(PathBuf, bincode::Error),
+    #[error("state file '{0}' has version {1} of the schema, expected {VERSION}")]
+    Version(PathBuf, u16),
+    #[error("failed to encode state, {0}")]
+    Encode(bincode::Error),
+    #[error("failed to write state file '{0}', {1}")]
+    Write(PathBuf, io::Error),
+    #[error("failed to rename state file '{0}' to '{1}', {2}")]
+    Rename(PathBuf, PathBuf, io::Error),
+}
+
+impl Error {
+    /// Returns true if the state file does not exist yet
+    pub fn is_not_found(&self) -> bool {
+        matches!(self, Self::Read(_, err) if err.kind() == io::ErrorKind::NotFound)
+    }
+}
+
+// -------------------------------------------------------------------------------------
+// Helpers
+
+/// Load the state of certificates from the given file
+#[tracing::instrument]
+pub async fn load(path: &Path) -> Result<State, Error> {
+    let content = fs::read(path)
+        .await
+        .map_err(|err| Error::Read(path.to_owned(), err))?;
+
+    let metadata: Vec<Metadata> = match content.strip_prefix(MAGIC) {
+        Some(content) => {
+            let Some(&[low, high]) = content.get(..2) else {
+                return Err(Error::Version(path.to_owned(), 0));
+            };
+
+            let (version, content) = (u16::from_le_bytes([low, high]), &content[2..]);
+            match version {
+                VERSION => {
+                    let entries: Vec<Entry> = bincode::deserialize(content)
+                        .map_err(|err| Error::Decode(path.to_owned(), err))?;
+
+                    let mut applied = Applied::new();
+                    for (instance, listener, metadata) in entries {
+                        applied.entry(instance).or_default().insert(
+                            listener,
+                            metadata
+                                .into_iter()
+                                .map(|metadata| (metadata.path.to_owned(), metadata))
+                                .collect(),
+                        );
+                    }
+
+                    return Ok(State::Applied(applied));
+                }
+                GLOBAL_VERSION => bincode::deserialize(content)
+                    .map_err(|err| Error::Decode(path.to_owned(), err))?,
+                _ => return Err(Error::Version(path.to_owned(), version)),
+            }
+        }
+        // State files written by previous versions are JSON, they are written
+        // in the binary format on the next save
+        None => {
+            serde_json::from_slice(&content).map_err(|err| Error::Parse(path.to_owned(), err))?
+        }
+    };
+
+    Ok(State::Global(
+        metadata
+            .into_iter()
+            .map(|metadata| (metadata.path.to_owned(), metadata))
+            .collect(),
+    ))
+}
+
+/// Save the state of certificates to the given file, the state is written to a
+/// temporary file which is then renamed, so a crash never leaves a truncated
+/// state behind
+#[tracing::instrument(skip(applied))]
+pub async fn save(path: &Path, applied: &Applied) -> Result<(), Error> {
+    let mut entries: Vec<(&String, &SocketAddr, Vec<&Metadata>)> = vec![];
+    for (instance, listeners) in applied {
+        for (listener, metadata) in listeners {
+            let mut metadata = metadata.values().collect::<Vec<_>>();
+            metadata.sort_by(|a, b| a.path.cmp(&b.path));
+            entries.push((instance, listener, metadata));
+        }
+    }
+
+    entries.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+
+    let mut content = MAGIC.to_vec();
+    content.extend_from_slice(&VERSION.to_le_bytes());
+    bincode::serialize_into(&mut content, &entries).map_err(Error::Encode)?;
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let mut file = fs::File::create(&tmp)
+        .await
+        .map_err(|err| Error::Write(tmp.to_owned(), err))?;
+
+    file.write_all(&content)
+        .await
+        .map_err(|err| Error::Write(tmp.to_owned(), err))?;
+
+    file.sync_all()
+        .await
+        .map_err(|err| Error::Write(tmp.to_owned(), err))?;
+
+    fs::rename(&tmp, path)
+        .await
+        .map_err(|err| Error::Rename(tmp, path.to_owned(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use sozu_command_lib::certificate::Fingerprint;
+
+    use super::*;
+    use crate::svc::certificates::key::KeyDigest;
+
+    fn metadata(path: &str, fingerprint: u8) -> Metadata {
+        Metadata::new(
+            PathBuf::from(path),
+            Fingerprint(vec![fingerprint; 32]),
+            HashSet::from(["example.com".to_string()]),
+            HashSet::new(),
+            None,
+            None,
+            KeyDigest::new("key"),
+        )
+    }
+
+    #[tokio::test]
+    async fn certificates_of_each_listener_are_saved_and_loaded() {
+        let dir = tempfile::tempdir().expect("temporary directory to be created");
+        let path = dir.path().join("state");
+
+        let (a, b) = (metadata("/pki/a", 1), metadata("/pki/b", 2));
+        let mut applied = Applied::new();
+        applied.entry("default".to_string()).or_default().insert(
+            "127.0.0.1:8443".parse().expect("address"),
+            HashMap::from([(a.path.to_owned(), a.to_owned())]),
+        );
+        applied.entry("green".to_string()).or_default().insert(
+            "127.0.0.1:9443".parse().expect("address"),
+            HashMap::from([(a.path.to_owned(), a), (b.path.to_owned(), b)]),
+        );
+
+        save(&path, &applied).await.expect("state to be saved");
+        assert_eq!(
+            State::Applied(applied),
+            load(&path).await.expect("state to be loaded")
+        );
+    }
+
+    #[tokio::test]
+    async fn state_of_previous_versions_is_loaded() {
+        let dir = tempfile::tempdir().expect("temporary directory to be created");
+        let a = metadata("/pki/a", 1);
+        let expected = State::Global(HashMap::from([(a.path.to_owned(), a.to_owned())]));
+
+        let json = dir.path().join("state.json");
+        let content = serde_json::to_vec(&[&a]).expect("state to be encoded");
+        fs::write(&json, content)
+            .await
+            .expect("state to be written");
+        assert_eq!(expected, load(&json).await.expect("state to be loaded"));
+
+        let binary = dir.path().join("state");
+        let mut content = MAGIC.to_vec();
+        content.extend_from_slice(&GLOBAL_VERSION.to_le_bytes());
+        bincode::serialize_into(&mut content, &vec![&a]).expect("state to be encoded");
+        fs::write(&binary, content)
+            .await
+            .expect("state to be written");
+        assert_eq!(expected, load(&binary).await.expect("state to be loaded"));
+    }
+}
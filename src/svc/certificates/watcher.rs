@@ -2,22 +2,72 @@
 //!
 //! This module provides a watcher to handle certificates refreshment
 
-use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use futures::stream::{self, StreamExt};
 use once_cell::sync::Lazy;
-use prometheus::{register_int_counter_vec, IntCounterVec};
-use sozu_client::{
-    channel::ConnectionProperties, config::canonicalize_command_socket, Client, Sender,
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+};
+use rand::Rng;
+use serde::Serialize;
+use sozu_client::{channel::ConnectionProperties, config::canonicalize_command_socket, Client};
+use sozu_command_lib::{
+    certificate::Fingerprint,
+    proto::{
+        command::{
+            request::RequestType, response_content::ContentType, CertificateAndKey,
+            CertificatesWithFingerprints, ListListeners, ListOfCertificatesByAddress,
+            ListenersList, QueryCertificatesFilters, ResponseContent, WorkerResponses,
+        },
+        display::format_request_type,
+    },
 };
-use sozu_command_lib::proto::display::format_request_type;
-use tokio::time::interval;
-use tracing::{debug, error, info, trace, warn};
+use tokio::{
+    net::{lookup_host, UnixStream},
+    sync::watch,
+    sync::{mpsc, oneshot},
+    time::{interval, sleep, sleep_until, timeout, Instant, Interval, MissedTickBehavior},
+};
+use tracing::{debug, error, info, info_span, trace, warn, Instrument};
 
 use crate::svc::{
-    certificates::{self, message, Metadata},
-    config::ConnectorConfiguration,
+    certificates::{
+        self,
+        archive::Extractions,
+        cache::{Cache, Stamp},
+        events::{self, Change, Coalescer, Debouncer, EventListener},
+        export, message,
+        sink::{CertificateSink, ClientFactory, SinkFactory},
+        state::{self, Applied, State},
+        Metadata, Pki, Usage,
+    },
+    config::{Archive, ConnectorConfiguration, Endpoint, Instance, Mode, Operation, WatchMode},
+    health::Health,
+    leader::LeaderLock,
 };
 
+// -----------------------------------------------------------------------------
+// Constants
+
+/// Delay before the first retry to connect to Sōzu on startup, doubled on each
+/// attempt
+const STARTUP_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Maximum delay between two attempts to connect to Sōzu on startup
+const STARTUP_MAX_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Delay between two refreshes of the gauges of the time until certificates
+/// expire, as lookups may not happen anymore in events mode
+const EXPIRY_REFRESH_DELAY: Duration = Duration::from_secs(30);
+
 // -----------------------------------------------------------------------------
 // Telemetry
 
@@ -25,7 +75,7 @@ static CERTIFICATE_REQUEST_EMITTED: Lazy<IntCounterVec> = Lazy::new(|| {
     register_int_counter_vec!(
         "proxy_manager_certificate_request_emitted",
         "Number of request emitted by the certificate daemon",
-        &["kind"]
+        &["instance", "kind", "source"]
     )
     .expect("'proxy_manager_certificate_request_emitted' to not be already registered")
 });
@@ -34,11 +84,186 @@ static CERTIFICATE_REQUEST_EMITTED_ERROR: Lazy<IntCounterVec> = Lazy::new(|| {
     register_int_counter_vec!(
         "proxy_manager_certificate_request_emitted_error",
         "Number of request emitted by the certificate daemon in error",
-        &["kind"]
+        &["instance", "kind", "source"]
     )
     .expect("'proxy_manager_certificate_request_emitted_error' to not be already registered")
 });
 
+static CERTIFICATE_SKIPPED_EXPIRED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "certificate_skipped_expired_total",
+        "Number of expired certificates skipped by the certificate daemon",
+        &["directory"]
+    )
+    .expect("'certificate_skipped_expired_total' to not be already registered")
+});
+
+static CERTIFICATE_SKIPPED_NOT_YET_VALID: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "certificate_skipped_not_yet_valid_total",
+        "Number of not yet valid certificates skipped by the certificate daemon",
+        &["directory"]
+    )
+    .expect("'certificate_skipped_not_yet_valid_total' to not be already registered")
+});
+
+static CERTIFICATE_SKIPPED_NAME: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "certificate_skipped_name_total",
+        "Number of certificates with names which are not allowed skipped by the certificate daemon",
+        &["directory"]
+    )
+    .expect("'certificate_skipped_name_total' to not be already registered")
+});
+
+static CERTIFICATE_SCAN_DIRECTORIES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "certificate_scan_directories",
+        "Number of directories visited by the last full lookup of the certificate daemon"
+    )
+    .expect("'certificate_scan_directories' to not be already registered")
+});
+
+static CERTIFICATE_SCAN_FILES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "certificate_scan_files",
+        "Number of files read by the last full lookup of the certificate daemon"
+    )
+    .expect("'certificate_scan_files' to not be already registered")
+});
+
+static CERTIFICATE_REQUEST_DRYRUN: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "certificate_request_dryrun_total",
+        "Number of request that the certificate daemon would have emitted in dry-run mode",
+        &["kind"]
+    )
+    .expect("'certificate_request_dryrun_total' to not be already registered")
+});
+
+static CERTIFICATE_DIFF: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "certificate_diff_total",
+        "Number of certificates added, modified, deleted or renamed on disk seen by the certificate daemon",
+        &["kind"]
+    )
+    .expect("'certificate_diff_total' to not be already registered")
+});
+
+static CERTIFICATE_MANAGED: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "certificate_managed",
+        "Number of certificates currently managed by the certificate daemon"
+    )
+    .expect("'certificate_managed' to not be already registered")
+});
+
+// It has no label, so that its only series can be removed when no managed
+// certificate has a known expiration date
+static CERTIFICATE_MIN_EXPIRY: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "certificate_min_expiry_seconds",
+        "Number of seconds until the soonest managed certificate expires, negative if already expired",
+        &[]
+    )
+    .expect("'certificate_min_expiry_seconds' to not be already registered")
+});
+
+static CERTIFICATE_EXPIRY: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "certificate_expiry_seconds",
+        "Number of seconds until a managed certificate expires, negative if already expired",
+        &["name"]
+    )
+    .expect("'certificate_expiry_seconds' to not be already registered")
+});
+
+static CERTIFICATE_LOOKUP_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "certificate_lookup_duration_seconds",
+        "Duration of the lookup of the pki directory by the certificate daemon",
+        &["phase"],
+        vec![0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 25.0, 60.0]
+    )
+    .expect("'certificate_lookup_duration_seconds' to not be already registered")
+});
+
+static CERTIFICATE_QUARANTINED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "certificate_quarantined_total",
+        "Number of certificates quarantined by the certificate daemon after failing too many times"
+    )
+    .expect("'certificate_quarantined_total' to not be already registered")
+});
+
+static CERTIFICATE_SAN_CONFLICT: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "certificate_san_conflict_total",
+        "Number of names covered by several certificates found by the certificate daemon"
+    )
+    .expect("'certificate_san_conflict_total' to not be already registered")
+});
+
+static CERTIFICATE_REQUEST_SUPPRESSED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "certificate_request_suppressed_total",
+        "Number of request that the certificate daemon did not emit as their kind is not allowed",
+        &["kind"]
+    )
+    .expect("'certificate_request_suppressed_total' to not be already registered")
+});
+
+static CERTIFICATE_REJECTED_BY_PROXY: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "certificate_rejected_by_proxy_total",
+        "Number of certificates that Sōzu rejected as invalid, quarantined by the certificate daemon",
+        &["instance"]
+    )
+    .expect("'certificate_rejected_by_proxy_total' to not be already registered")
+});
+
+static CERTIFICATE_VERIFY_FAILED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "certificate_verify_failed_total",
+        "Number of certificates sent by the certificate daemon that Sōzu does not serve once sent",
+        &["instance"]
+    )
+    .expect("'certificate_verify_failed_total' to not be already registered")
+});
+
+static SOZU_CLIENT_RECONNECTION: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "sozu_client_reconnection_total",
+        "Number of attempts to recreate the Sōzu client by the certificate daemon"
+    )
+    .expect("'sozu_client_reconnection_total' to not be already registered")
+});
+
+static SOZU_CLIENT_CONNECTED: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "sozu_client_connected",
+        "Whether the last exchange of the certificate daemon with Sōzu succeeded (1) or not (0)",
+        &["instance"]
+    )
+    .expect("'sozu_client_connected' to not be already registered")
+});
+
+static SOZU_CIRCUIT_OPEN: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "sozu_circuit_open",
+        "Whether the certificate daemon paused sending requests to Sōzu as they keep failing (1) or not (0)"
+    )
+    .expect("'sozu_circuit_open' to not be already registered")
+});
+
+static LEADER: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "certificate_daemon_leader",
+        "Whether the certificate daemon holds the leader lock and sends requests to Sōzu (1) or not (0)"
+    )
+    .expect("'certificate_daemon_leader' to not be already registered")
+});
+
 // -----------------------------------------------------------------------------
 // Error
 
@@ -46,8 +271,8 @@ static CERTIFICATE_REQUEST_EMITTED_ERROR: Lazy<IntCounterVec> = Lazy::new(|| {
 pub enum Error {
     #[error("failed to find certificates at '{0}', {1}")]
     FindCertificates(PathBuf, certificates::Error),
-    #[error("failed to compute metadata from certificate '{0}', {1}")]
-    ComputeMetadata(PathBuf, certificates::Error),
+    #[error("pki directory '{0}' is absent or is not a directory, keep current certificates")]
+    PkiUnavailable(PathBuf),
     #[error("failed to compute message, {0}")]
     ComputeMessage(message::Error),
     #[error("failed to send request to update Sōzu certificate, {0}")]
@@ -58,182 +283,4725 @@ pub enum Error {
     CreateClient(sozu_client::Error),
     #[error("failed to canonicalize path to command socket, {0}")]
     CanonicalizeSocket(sozu_client::config::Error),
+    #[error("failed to use Sōzu instance '{0}', its name is already used")]
+    DuplicateInstance(String),
+    #[error("failed to use Sōzu endpoint, only a 'unix' socket is supported")]
+    InvalidEndpoint,
+    #[error("failed to listen to filesystem events, {0}")]
+    Events(events::Error),
+    #[error("failed to query certificates installed in Sōzu, {0}")]
+    QueryCertificates(sozu_client::Error),
+    #[error("failed to query certificates installed in Sōzu, got an unexpected response")]
+    UnexpectedResponse,
+    #[error("failed to resolve listener '{0}', {1}")]
+    ResolveListener(String, std::io::Error),
+    #[error("failed to resolve listener '{0}', there is no address")]
+    NoListenerAddress(String),
+    #[error("Sōzu configuration '{0}' has no HTTPS listener, set one explicitly")]
+    NoHttpsListener(PathBuf),
+    #[error("failed to reach Sōzu command socket '{0}', {1}")]
+    Unreachable(PathBuf, std::io::Error),
+    #[error("failed to list listeners of Sōzu, {0}")]
+    ListListeners(sozu_client::Error),
+    #[error("failed to use listener '{0}', it is a {1} listener, not an HTTPS one")]
+    NotHttpsListener(SocketAddr, &'static str),
+    #[error("failed to use listener '{0}', Sōzu has no such listener")]
+    UnknownListener(SocketAddr),
+}
+
+impl Error {
+    /// Returns true if the error may go away on its own, e.g. Sōzu is not
+    /// started yet or its configuration is not provisioned yet
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::SozuConfiguration(_)
+                | Self::CreateClient(_)
+                | Self::CanonicalizeSocket(_)
+                | Self::Unreachable(..)
+                | Self::ResolveListener(..)
+                | Self::NoListenerAddress(_)
+                | Self::ListListeners(_)
+                | Self::UnknownListener(_)
+        )
+    }
 }
 
 // -----------------------------------------------------------------------------
 // Watcher
 
-pub struct Watcher {
-    /// Configuration of the connector
-    config: Arc<ConnectorConfiguration>,
-    /// Sōzu client
-    client: Client,
+/// Summary of changes found on disk and requests sent to Sōzu
+#[derive(Serialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct Summary {
+    /// Number of certificates added on disk
+    pub added: usize,
+    /// Number of certificates modified on disk
+    pub modified: usize,
+    /// Number of certificates deleted on disk
+    pub deleted: usize,
+    /// Number of requests successfully sent
+    pub sent: usize,
+    /// Number of requests that Sōzu failed to apply
+    pub failed: usize,
+    /// Errors returned by Sōzu for requests that it failed to apply
+    pub errors: Vec<String>,
+    /// Directories of requests that Sōzu failed to apply
+    #[serde(skip)]
+    pub rejected: HashSet<PathBuf>,
+    /// Directories whose certificate Sōzu rejected as invalid, which is not
+    /// worth retrying until it changes
+    #[serde(skip)]
+    pub invalid: HashSet<PathBuf>,
+    /// Sōzu instances whose client must be recreated, because the connection
+    /// is dead or a request did not get an answer in time, which may still be
+    /// received later
+    #[serde(skip)]
+    pub disconnected: HashSet<String>,
+    /// Directories whose requests were not applied, e.g. failed ones or ones
+    /// held back by the circuit breaker, which the next lookup sends again
+    #[serde(skip)]
+    pub pending: HashSet<PathBuf>,
+}
+
+/// State of the circuit breaker which pauses sending requests to Sōzu when
+/// they keep failing
+#[derive(Serialize, PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum Circuit {
+    /// Requests are sent
+    #[default]
+    #[serde(rename = "closed")]
+    Closed,
+    /// Requests are not sent until the end of the cooldown
+    #[serde(rename = "open")]
+    Open,
+    /// A single request is sent to probe Sōzu
+    #[serde(rename = "half-open")]
+    HalfOpen,
+}
+
+/// Outcome of the last full lookup and schedule of the next one, timestamps
+/// are in milliseconds since the epoch
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Status {
+    /// Duration in milliseconds between two full lookups
+    pub interval: u64,
+    pub last_scan_started_at: Option<i64>,
+    pub last_scan_ended_at: Option<i64>,
+    /// Duration in milliseconds of the last full lookup
+    pub last_scan_duration: Option<u64>,
+    /// Error of the last full lookup, if it failed
+    pub last_scan_error: Option<String>,
+    /// Changes found and requests sent by the last full lookup that succeeded
+    pub last_summary: Option<Summary>,
+    /// Expected start of the next full lookup, unknown if only filesystem
+    /// events trigger lookups
+    pub next_scan_at: Option<i64>,
+    /// State of the circuit breaker as of the last lookup
+    pub circuit: Circuit,
+    /// End of the cooldown of the circuit breaker, if it has been opened
+    pub circuit_cooldown_until: Option<i64>,
+}
+
+/// Certificates known by the watcher and its status
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+    /// Certificates currently managed
+    pub managed: HashMap<PathBuf, Metadata>,
+    /// Certificates skipped until their content changes, as Sōzu kept
+    /// rejecting them
+    pub quarantined: HashMap<PathBuf, Metadata>,
+    /// Outcome of the last full lookup
+    pub status: Status,
+}
+
+/// Snapshot of certificates known by the watcher shared with the HTTP server
+pub type Inventory = Arc<RwLock<Snapshot>>;
+
+/// Request of an immediate lookup, answered with its summary once done
+pub type SyncRequest = oneshot::Sender<Result<Summary, String>>;
+
+/// Outcome of the reading of a certificate directory
+enum Outcome {
+    /// Files did not change since the previous scan
+    Cached,
+    /// Directory does not contain a loadable certificate
+    Skipped,
+    /// Certificate file holds no certificate, e.g. a placeholder
+    Empty,
+    /// Files are still being written
+    Unstable,
+    /// Certificate and key have been read from disk
+    Loaded(Box<(CertificateAndKey, Metadata)>),
+}
+
+/// Certificates and keys read from disk with their metadata
+type Scan = (Pki, HashMap<PathBuf, Metadata>);
+
+/// Certificates served on each listener of a Sōzu instance, by fingerprint
+type Served = HashMap<SocketAddr, HashMap<Fingerprint, Metadata>>;
+
+/// Certificates served by each Sōzu instance that answered, by instance name
+type Installed = HashMap<String, Served>;
+
+/// Addresses of the HTTPS, HTTP and TCP listeners of a Sōzu instance
+type Listeners = (
+    HashSet<SocketAddr>,
+    HashSet<SocketAddr>,
+    HashSet<SocketAddr>,
+);
+
+/// Certificates held by a listener of a Sōzu instance
+#[derive(Default)]
+struct Held {
+    /// Certificates of the looked up directories, before requests are sent
+    before: HashMap<PathBuf, Metadata>,
+    /// Certificates of the looked up directories, once requests are applied
+    after: HashMap<PathBuf, Metadata>,
+    /// Certificates of the other directories, which do not change
+    others: HashMap<PathBuf, Metadata>,
+}
+
+/// Certificates of a Sōzu instance and of all of them, before and once its
+/// requests are applied
+struct Transition<'a> {
     /// Current state of certificates
-    metadata: HashMap<PathBuf, Metadata>,
+    current: &'a HashMap<PathBuf, Metadata>,
+    /// New state of certificates, the ones held by every instance
+    metadata: &'a mut HashMap<PathBuf, Metadata>,
+    /// Certificates held by each listener of the instance
+    listeners: HashMap<SocketAddr, Held>,
+    /// Directories whose requests were reverted and are still to be applied
+    pending: HashSet<PathBuf>,
 }
 
-impl Watcher {
-    #[tracing::instrument(skip_all)]
-    pub async fn try_new(config: Arc<ConnectorConfiguration>) -> Result<Self, Error> {
-        // -------------------------------------------------------------------------
-        // Load Sōzu configuration
-        info!(
-            path = config.sozu.configuration.display().to_string(),
-            "Load Sōzu configuration"
-        );
+impl Transition<'_> {
+    /// Restore the current state of the given directory, on the given listener
+    /// and for every instance, see [`revert`], its requests are still to be
+    /// applied
+    fn revert(&mut self, path: &Path, listener: Option<SocketAddr>) {
+        self.suppress(path, listener);
+        self.pending.insert(path.to_owned());
+    }
 
-        let sozu_config = Arc::new(
-            sozu_client::config::try_from(&config.sozu.configuration)
-                .map_err(Error::SozuConfiguration)?,
-        );
+    /// Restore the current state of the given directory as [`Self::revert`]
+    /// does, for requests which are not to be applied
+    fn suppress(&mut self, path: &Path, listener: Option<SocketAddr>) {
+        revert(self.current, self.metadata, path);
+        if let Some(held) = listener.and_then(|listener| self.listeners.get_mut(&listener)) {
+            revert(&held.before, &mut held.after, path);
+        }
+    }
 
-        // -------------------------------------------------------------------------
-        // Create Sōzu client
-        info!("Create Sōzu client");
-        let mut opts = ConnectionProperties::from(&*sozu_config);
-        if opts.socket.is_relative() {
-            opts.socket = canonicalize_command_socket(&config.sozu.configuration, &sozu_config)
-                .map_err(Error::CanonicalizeSocket)?;
+    /// Returns the certificates held by each listener once requests are
+    /// applied, listeners that hold none are left out
+    fn into_applied(self) -> HashMap<SocketAddr, HashMap<PathBuf, Metadata>> {
+        self.listeners
+            .into_iter()
+            .map(|(listener, mut held)| {
+                held.after.extend(held.others);
+                (listener, held.after)
+            })
+            .filter(|(_, metadata)| !metadata.is_empty())
+            .collect()
+    }
+}
+
+/// A Sōzu instance receiving certificates
+pub struct Target {
+    /// Configuration of the instance
+    instance: Instance,
+    /// Receiver of requests, the Sōzu client
+    client: Box<dyn CertificateSink>,
+    /// Creator of the receiver of requests, once its connection is dead
+    factory: Arc<dyn SinkFactory>,
+    /// Resolved addresses of the HTTPS listeners
+    listeners: Vec<SocketAddr>,
+    /// HTTPS listeners of Sōzu as it last listed them, if it did
+    https: Option<HashSet<SocketAddr>>,
+}
+
+impl Target {
+    #[tracing::instrument(skip_all, fields(instance = instance.name))]
+    pub async fn try_new(instance: Instance) -> Result<Self, Error> {
+        let listeners = listeners(&instance).await?;
+        let mut target = Self::with_factory(instance, Arc::new(ClientFactory), listeners).await?;
+        target.check_listeners().await?;
+        target.set_connected(true);
+        Ok(target)
+    }
+
+    /// Check that every listener is an HTTPS listener of Sōzu, certificates
+    /// sent to another kind of listener or to an unknown one would all fail.
+    /// A listener may still be unknown while Sōzu is starting.
+    #[tracing::instrument(skip_all, fields(instance = self.instance.name))]
+    async fn check_listeners(&mut self) -> Result<(), Error> {
+        let (https, http, tcp) = self.list_listeners().await?;
+        for listener in &self.listeners {
+            if https.contains(listener) {
+                continue;
+            }
+
+            if http.contains(listener) {
+                return Err(Error::NotHttpsListener(*listener, "HTTP"));
+            }
+
+            if tcp.contains(listener) {
+                return Err(Error::NotHttpsListener(*listener, "TCP"));
+            }
+
+            return Err(Error::UnknownListener(*listener));
         }
 
-        let client = Client::try_new(opts).await.map_err(Error::CreateClient)?;
+        debug!(
+            number = self.listeners.len(),
+            "Listeners are HTTPS listeners of Sōzu"
+        );
+
+        self.https = Some(https);
+        Ok(())
+    }
+
+    /// List the addresses of the HTTPS, HTTP and TCP listeners of Sōzu
+    #[tracing::instrument(skip_all, fields(instance = self.instance.name))]
+    async fn list_listeners(&self) -> Result<Listeners, Error> {
+        let response = self
+            .client
+            .send(RequestType::ListListeners(ListListeners {}))
+            .await
+            .map_err(Error::ListListeners)?;
+
+        let ListenersList {
+            http_listeners,
+            https_listeners,
+            tcp_listeners,
+        } = match response.content {
+            Some(ResponseContent {
+                content_type: Some(ContentType::ListenersList(list)),
+            }) => list,
+            _ => return Err(Error::UnexpectedResponse),
+        };
+
+        let https: HashSet<SocketAddr> = https_listeners
+            .into_values()
+            .map(|listener| listener.address.into())
+            .collect();
+        let http: HashSet<SocketAddr> = http_listeners
+            .into_values()
+            .map(|listener| listener.address.into())
+            .collect();
+        let tcp: HashSet<SocketAddr> = tcp_listeners
+            .into_values()
+            .map(|listener| listener.address.into())
+            .collect();
+
+        Ok((https, http, tcp))
+    }
+
+    /// Returns the listeners of this instance that the given certificate
+    /// belongs on. The listener of its directory overrides the configured
+    /// ones, on the instances that have such an HTTPS listener only, or on
+    /// every instance whose listeners are not known.
+    fn listeners_of(&self, metadata: &Metadata) -> Vec<SocketAddr> {
+        match (metadata.listener, &self.https) {
+            (Some(listener), Some(https)) if !https.contains(&listener) => vec![],
+            _ => message::listeners_of(metadata, &self.listeners),
+        }
+    }
 
+    /// Create a target sending requests to the sinks of the given factory,
+    /// e.g. ones which are not Sōzu clients. Listeners are not checked.
+    pub async fn with_factory(
+        instance: Instance,
+        factory: Arc<dyn SinkFactory>,
+        listeners: Vec<SocketAddr>,
+    ) -> Result<Self, Error> {
+        let client = factory.connect(&instance).await?;
         Ok(Self {
-            config,
+            instance,
             client,
-            metadata: HashMap::new(),
+            factory,
+            listeners,
+            https: None,
         })
     }
 
-    #[tracing::instrument(skip_all)]
-    pub async fn lookup(&mut self) -> Result<(), Error> {
-        // -----------------------------------------------------------------------------
-        // Retrieve certificates and keys on disk
-        info!(
-            path = self.config.sozu.pki.to_string_lossy().to_string(),
-            "Load pki from disk"
-        );
+    /// Create the target, retrying with an exponential backoff until the given
+    /// deadline while Sōzu or its configuration is not available
+    #[tracing::instrument(skip_all, fields(instance = instance.name))]
+    pub async fn try_new_until(instance: Instance, deadline: Instant) -> Result<Self, Error> {
+        let mut attempts = 0;
+        loop {
+            let err = match Self::try_new(instance.to_owned()).await {
+                Ok(target) => return Ok(target),
+                Err(err) if err.is_transient() => err,
+                Err(err) => return Err(err),
+            };
 
-        let pki = certificates::find(&self.config.sozu.pki)
-            .await
-            .map_err(|err| Error::FindCertificates(self.config.sozu.pki.to_owned(), err))?;
+            // The last attempt happens at the deadline
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(err);
+            }
 
-        info!(number = pki.len(), "Compute metadata for pki");
-        let mut metadata = HashMap::new();
-        for (path, certificate_and_key) in &pki {
-            metadata.insert(
-                path.to_owned(),
-                certificates::metadata(path.to_owned(), certificate_and_key)
-                    .await
-                    .map_err(|err| Error::ComputeMetadata(self.config.sozu.pki.to_owned(), err))?,
+            let delay =
+                backoff(STARTUP_RETRY_DELAY, attempts, STARTUP_MAX_RETRY_DELAY).min(deadline - now);
+
+            attempts += 1;
+            warn!(
+                error = err.to_string(),
+                attempt = attempts,
+                delay = delay.as_millis(),
+                "Sōzu is not available yet, retry later"
             );
+
+            sleep(delay).await;
         }
+    }
 
-        // -----------------------------------------------------------------------------
-        // Create messages to update Sōzu and send them
-        debug!("Create diff and messages to send to the proxy");
-        let requests = message::create(self.config.sozu.listener, &self.metadata, &metadata, &pki)
-            .map_err(Error::ComputeMessage)?;
+    /// Record whether the last exchange with this Sōzu instance succeeded, an
+    /// answer of Sōzu counts as a success even if it is a failure
+    fn set_connected(&self, connected: bool) {
+        SOZU_CLIENT_CONNECTED
+            .with_label_values(&[&self.instance.name])
+            .set(i64::from(connected));
+    }
 
-        let len = requests.len();
-        debug!(number = len, "Number of requests to send to the proxy");
+    /// Recreate the Sōzu client
+    #[tracing::instrument(skip_all, fields(instance = self.instance.name))]
+    async fn reconnect(&mut self) {
+        SOZU_CLIENT_RECONNECTION.inc();
+        self.set_connected(false);
+        warn!("Connection to Sōzu is dead, recreate the client");
 
-        if !requests.is_empty() {
-            info!(number = len, "Send certificates requests to the proxy");
-            for (idx, (path, request)) in requests.into_iter().enumerate() {
-                trace!(
-                    number = idx + 1,
-                    total = len,
-                    "Send certificate request to Sōzu"
+        match self.factory.connect(&self.instance).await {
+            Ok(client) => {
+                info!("Successfully recreated Sōzu client");
+                self.client = client;
+                self.set_connected(true);
+            }
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "Could not recreate Sōzu client, retry on next iteration"
                 );
+            }
+        }
+    }
+}
 
-                match self.client.send(request.to_owned()).await {
-                    Ok(_) => {
-                        let kind = format_request_type(&request);
-                        CERTIFICATE_REQUEST_EMITTED
-                            .with_label_values(&[&kind])
-                            .inc();
+pub struct Watcher {
+    /// Configuration of the connector
+    config: Arc<ConnectorConfiguration>,
+    /// Sōzu instances receiving certificates
+    targets: Vec<Target>,
+    /// Current state of certificates, the ones held by every listener they
+    /// belong on of every Sōzu instance
+    metadata: HashMap<PathBuf, Metadata>,
+    /// Certificates held by each listener of each Sōzu instance, requests are
+    /// computed from them so that only the listeners which failed to apply a
+    /// request receive it again
+    applied: Applied,
+    /// Certificates held by each listener last written to the state file
+    persisted: Applied,
+    /// Certificates installed in Sōzu on startup, used to seed the current
+    /// state of certificates on the first lookup
+    installed: Installed,
+    /// Certificates read from disk during previous scans
+    cache: Cache,
+    /// Number of consecutive lookups in which requests could not be sent
+    failures: u32,
+    /// End of the cooldown of the circuit breaker, if it has been opened
+    circuit: Option<Instant>,
+    /// Directories whose files were still changing during the last scan
+    unstable: HashSet<PathBuf>,
+    /// Number of consecutive failures of a certificate, by directory
+    retries: HashMap<PathBuf, (Fingerprint, u32)>,
+    /// Certificates skipped until their content changes
+    quarantined: HashMap<PathBuf, Metadata>,
+    /// Health state shared with the HTTP server
+    health: Arc<Health>,
+    /// Snapshot of the current state of certificates shared with the HTTP
+    /// server
+    inventory: Inventory,
+    /// Shutdown signal, no new requests are sent once it is set
+    shutdown: watch::Receiver<bool>,
+    /// Lock shared with other replicas, only its holder sends requests
+    leader: Option<LeaderLock>,
+    /// Whether the leader lock was held on the previous check, unknown before
+    /// the first one
+    leading: Option<bool>,
+    /// Whether requests of the previous lookups are still to be applied
+    pending: bool,
+    /// Certificates skipped as not valid yet, with the time in milliseconds
+    /// since the epoch from which they are accepted
+    activations: HashMap<PathBuf, i64>,
+    /// Archives of pki directories extracted so far
+    extractions: Extractions,
+}
 
-                        if 0 == idx % 1000 {
-                            info!(
-                                number = idx + 1,
-                                total = len,
-                                "Successfully sent request to Sōzu"
-                            );
-                        }
+impl Watcher {
+    #[tracing::instrument(skip_all)]
+    pub async fn try_new(
+        config: Arc<ConnectorConfiguration>,
+        health: Arc<Health>,
+        inventory: Inventory,
+        shutdown: watch::Receiver<bool>,
+    ) -> Result<Self, Error> {
+        let deadline = Instant::now() + Duration::from_millis(config.startup_timeout);
+        let mut targets: Vec<Target> = vec![];
+        for instance in config.sozu.instances() {
+            if targets
+                .iter()
+                .any(|target| target.instance.name == instance.name)
+            {
+                return Err(Error::DuplicateInstance(instance.name));
+            }
 
-                        trace!(
-                            number = idx + 1,
-                            total = len,
-                            "Successfully sent request to Sōzu"
-                        );
-                    }
-                    Err(err) if matches!(err, sozu_client::Error::Failure(..)) => {
-                        // This will be retried in the next iteration
-                        match self.metadata.get(&path) {
-                            Some(meta) => {
-                                metadata.insert(path.to_owned(), meta.to_owned());
-                            }
-                            None => {
-                                metadata.remove(&path);
-                            }
-                        }
+            targets.push(Target::try_new_until(instance, deadline).await?);
+        }
 
-                        let kind = format_request_type(&request);
-                        CERTIFICATE_REQUEST_EMITTED_ERROR
-                            .with_label_values(&[&kind])
-                            .inc();
+        Ok(Self::with_targets(config, targets, health, inventory, shutdown).await)
+    }
 
-                        error!(
-                            error = err.to_string(),
-                            number = idx + 1,
-                            total = len,
-                            path = path.display().to_string(),
-                            kind = kind,
-                            "Could not send certificate request to Sōzu"
-                        );
-                    }
-                    Err(err) => {
-                        return Err(Error::Send(err));
-                    }
-                }
-            }
+    /// Create a watcher sending requests to the given targets, e.g. ones with
+    /// a sink which is not a Sōzu client, see [`Target::with_factory`]
+    #[tracing::instrument(skip_all)]
+    pub async fn with_targets(
+        config: Arc<ConnectorConfiguration>,
+        targets: Vec<Target>,
+        health: Arc<Health>,
+        inventory: Inventory,
+        shutdown: watch::Receiver<bool>,
+    ) -> Self {
+        // -------------------------------------------------------------------------
+        // Retrieve certificates already installed in Sōzu
+        let (installed, connected) = Self::installed_everywhere(&targets).await;
+        health.set_connected(connected);
 
-            info!(
-                number = len,
-                "Successfully sent certificates requests to the proxy"
-            );
+        // -------------------------------------------------------------------------
+        // Restore the state of certificates persisted before the restart
+        let applied = match &config.state_file {
+            Some(path) => Self::restore(path, &installed, &targets).await,
+            None => Applied::new(),
+        };
+
+        let leader = config.leader_lock.as_deref().map(LeaderLock::new);
+        Self {
+            config,
+            metadata: everywhere(&applied, &targets),
+            targets,
+            persisted: applied.to_owned(),
+            applied,
+            installed,
+            cache: Cache::default(),
+            extractions: Extractions::default(),
+            failures: 0,
+            circuit: None,
+            unstable: HashSet::new(),
+            retries: HashMap::new(),
+            quarantined: HashMap::new(),
+            health,
+            inventory,
+            shutdown,
+            leader,
+            leading: None,
+            pending: false,
+            activations: HashMap::new(),
         }
+    }
 
-        // -----------------------------------------------------------------------------
-        // Update the current metadata
-        self.metadata = metadata;
+    /// Query certificates installed on the listeners of the given Sōzu
+    /// instances, instances that did not answer are left out. Also returns
+    /// whether any instance answered.
+    #[tracing::instrument(skip_all)]
+    async fn installed_everywhere(targets: &[Target]) -> (Installed, bool) {
+        info!("Retrieve certificates already installed in Sōzu");
+        let mut installed = Installed::new();
+        let mut connected = false;
+        for target in targets {
+            match Self::installed(target.client.as_ref()).await {
+                Ok(served) => {
+                    info!(
+                        instance = target.instance.name,
+                        number = served.values().map(HashMap::len).sum::<usize>(),
+                        "Retrieved certificates already installed in Sōzu"
+                    );
 
-        Ok(())
+                    connected = true;
+                    target.set_connected(true);
+                    installed.insert(target.instance.name.to_owned(), served);
+                }
+                Err(err) => {
+                    warn!(
+                        error = err.to_string(),
+                        instance = target.instance.name,
+                        "Could not retrieve certificates already installed in Sōzu, assume there is none"
+                    );
+
+                    // Sōzu answered, even if the response could not be used
+                    let answered = !matches!(
+                        &err,
+                        Error::QueryCertificates(err) if !err.is_recoverable()
+                    );
+
+                    connected |= answered;
+                    target.set_connected(answered);
+                }
+            }
+        }
+
+        (installed, connected)
     }
-}
 
-// -----------------------------------------------------------------------------
-// helpers
+    /// Query certificates installed in Sōzu and compute their metadata, by
+    /// listener which serves them. The state of Sōzu holds certificates, and
+    /// its workers tell the listeners that serve them.
+    #[tracing::instrument(skip_all)]
+    async fn installed(client: &dyn CertificateSink) -> Result<Served, Error> {
+        let response = client
+            .send(RequestType::QueryCertificatesFromTheState(
+                QueryCertificatesFilters::default(),
+            ))
+            .await
+            .map_err(Error::QueryCertificates)?;
 
-#[tracing::instrument(skip_all)]
-pub async fn lookup_every(config: Arc<ConnectorConfiguration>) -> Result<(), Error> {
-    // -------------------------------------------------------------------------
-    // Start the watcher
-    let mut ticker = interval(Duration::from_millis(config.interval));
-    let mut watcher = Watcher::try_new(config).await?;
+        let certs = match response.content {
+            Some(ResponseContent {
+                content_type:
+                    Some(ContentType::CertificatesWithFingerprints(CertificatesWithFingerprints {
+                        certs,
+                    })),
+            }) => certs,
+            _ => return Err(Error::UnexpectedResponse),
+        };
 
-    loop {
-        if let Err(err) = watcher.lookup().await {
-            warn!(
-                error = err.to_string(),
-                "Could not lookup into pki directory and send updates to Sōzu"
-            );
+        let mut installed = HashMap::new();
+        for (fingerprint, certificate_and_key) in certs {
+            match certificates::metadata(PathBuf::new(), &certificate_and_key, None).await {
+                Ok(metadata) => {
+                    installed.insert(metadata.fingerprint.to_owned(), metadata);
+                }
+                Err(err) => {
+                    warn!(
+                        error = err.to_string(),
+                        fingerprint = fingerprint,
+                        "Could not compute metadata of certificate installed in Sōzu"
+                    );
+                }
+            }
         }
 
-        // -----------------------------------------------------------------------------
-        // Wait for the next iteration to come
-        info!("Waiting for next iteration to lookup certificates directory");
-        ticker.tick().await;
+        let response = client
+            .send(RequestType::QueryCertificatesFromWorkers(
+                QueryCertificatesFilters::default(),
+            ))
+            .await
+            .map_err(Error::QueryCertificates)?;
+
+        let served = served(response.content.as_ref()).ok_or(Error::UnexpectedResponse)?;
+        Ok(served
+            .into_iter()
+            .map(|(address, fingerprints)| {
+                let certificates = installed
+                    .iter()
+                    .filter(|(fingerprint, _)| fingerprints.contains(&fingerprint.to_string()))
+                    .map(|(fingerprint, meta)| (fingerprint.to_owned(), meta.to_owned()))
+                    .collect();
+
+                (address, certificates)
+            })
+            .collect())
+    }
+
+    /// Load the state of certificates from the state file, certificates that
+    /// are not served on their listener anymore are dropped, so that they will
+    /// be added again. Instances that are not configured anymore are forgotten,
+    /// as are the ones that did not answer, since what they hold is unknown.
+    #[tracing::instrument(skip(installed, targets))]
+    async fn restore(path: &Path, installed: &Installed, targets: &[Target]) -> Applied {
+        let mut applied = match state::load(path).await {
+            Ok(State::Applied(applied)) => applied,
+            // Previous versions only knew the certificates held by every
+            // listener they belong on
+            Ok(State::Global(metadata)) => {
+                let mut applied = Applied::new();
+                for target in targets {
+                    let listeners = applied.entry(target.instance.name.to_owned()).or_default();
+                    for (path, meta) in &metadata {
+                        for listener in target.listeners_of(meta) {
+                            listeners
+                                .entry(listener)
+                                .or_default()
+                                .insert(path.to_owned(), meta.to_owned());
+                        }
+                    }
+                }
+
+                applied
+            }
+            Err(err) if err.is_not_found() => {
+                info!(
+                    path = path.display().to_string(),
+                    "There is no state file yet, start fresh"
+                );
+
+                return Applied::new();
+            }
+            Err(err) => {
+                warn!(
+                    error = err.to_string(),
+                    "Could not restore the state of certificates, start fresh"
+                );
+
+                return Applied::new();
+            }
+        };
+
+        applied.retain(|instance, _| {
+            targets
+                .iter()
+                .any(|target| &target.instance.name == instance)
+        });
+
+        applied.retain(|instance, _| {
+            let answered = installed.contains_key(instance);
+            if !answered {
+                warn!(
+                    instance = instance,
+                    "Sōzu instance did not answer, do not trust its persisted state"
+                );
+            }
+
+            answered
+        });
+
+        for (instance, listeners) in &mut applied {
+            let served = &installed[instance];
+            for (listener, metadata) in listeners.iter_mut() {
+                metadata.retain(|_, meta| {
+                    served
+                        .get(listener)
+                        .is_some_and(|served| served.contains_key(&meta.fingerprint))
+                });
+            }
+
+            listeners.retain(|_, metadata| !metadata.is_empty());
+        }
+
+        info!(
+            number = applied
+                .values()
+                .flat_map(HashMap::values)
+                .map(HashMap::len)
+                .sum::<usize>(),
+            path = path.display().to_string(),
+            "Restored the state of certificates"
+        );
+
+        applied
+    }
+
+    /// Write the state of certificates to the state file, if it changed since
+    /// the last write. Nothing is written in dry-run, as Sōzu did not receive
+    /// the requests.
+    #[tracing::instrument(skip_all)]
+    async fn persist(&mut self) {
+        let Some(path) = &self.config.state_file else {
+            return;
+        };
+
+        if self.config.dry_run || self.persisted == self.applied {
+            return;
+        }
+
+        match state::save(path, &self.applied).await {
+            Ok(()) => {
+                debug!(
+                    number = self.metadata.len(),
+                    path = path.display().to_string(),
+                    "Persisted the state of certificates"
+                );
+
+                self.persisted.clone_from(&self.applied);
+            }
+            Err(err) => {
+                warn!(
+                    error = err.to_string(),
+                    "Could not persist the state of certificates"
+                );
+            }
+        }
+    }
+
+    /// Seed the current state of certificates with the ones installed in Sōzu
+    /// that are also on disk, so that they will not be added again. Only the
+    /// listeners that a certificate belongs on and which serve it are seeded.
+    #[tracing::instrument(skip_all)]
+    fn reconcile(&mut self, metadata: &HashMap<PathBuf, Metadata>) {
+        let installed = std::mem::take(&mut self.installed);
+        if installed.is_empty() {
+            return;
+        }
+
+        let mut number = 0;
+        for target in &self.targets {
+            let Some(served) = installed.get(&target.instance.name) else {
+                continue;
+            };
+
+            for (path, meta) in metadata {
+                for listener in target.listeners_of(meta) {
+                    let Some(found) = served
+                        .get(&listener)
+                        .and_then(|served| served.get(&meta.fingerprint))
+                    else {
+                        continue;
+                    };
+
+                    let held = self
+                        .applied
+                        .entry(target.instance.name.to_owned())
+                        .or_default()
+                        .entry(listener)
+                        .or_default();
+
+                    if !held.contains_key(path) {
+                        let meta = Metadata {
+                            path: path.to_owned(),
+                            ..found.to_owned()
+                        };
+
+                        held.insert(path.to_owned(), meta);
+                        number += 1;
+                    }
+                }
+            }
+        }
+
+        self.metadata = everywhere(&self.applied, &self.targets);
+
+        info!(
+            number = number,
+            "Reconciled certificates on disk with the ones installed in Sōzu"
+        );
+    }
+
+    /// Look up the whole pki directories and send updates to Sōzu, its outcome
+    /// is shared with the HTTP server
+    #[tracing::instrument(skip_all)]
+    pub async fn lookup(&mut self) -> Result<Summary, Error> {
+        let started_at = SystemTime::now();
+        let result = self.lookup_all().await;
+        let ended_at = SystemTime::now();
+
+        self.report(|status| {
+            status.last_scan_started_at = Some(millis(started_at));
+            status.last_scan_ended_at = Some(millis(ended_at));
+            status.last_scan_duration = ended_at
+                .duration_since(started_at)
+                .map(|duration| duration.as_millis() as u64)
+                .ok();
+
+            match &result {
+                Ok(summary) => {
+                    status.last_scan_error = None;
+                    status.last_summary = Some(summary.to_owned());
+                }
+                Err(err) => status.last_scan_error = Some(err.to_string()),
+            }
+        });
+
+        match &result {
+            Ok(summary) => self.pending = !summary.pending.is_empty(),
+            Err(_) => {
+                self.pending = true;
+                self.health.set_synced(false);
+            }
+        }
+
+        result
+    }
+
+    /// Record when the next full lookup is expected, if any
+    pub fn schedule(&self, delay: Option<Duration>) {
+        self.report(|status| {
+            status.next_scan_at = delay.map(|delay| millis(SystemTime::now() + delay));
+        });
+    }
+
+    /// Update the status shared with the HTTP server
+    fn report(&self, update: impl FnOnce(&mut Status)) {
+        match self.inventory.write() {
+            Ok(mut inventory) => {
+                inventory.status.interval = self.config.interval;
+                update(&mut inventory.status);
+            }
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "Could not share the status of the watcher"
+                );
+            }
+        }
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn lookup_all(&mut self) -> Result<Summary, Error> {
+        if Mode::CleanupOnly == self.config.mode {
+            return self.cleanup().await;
+        }
+
+        // The first synchronization may send every certificate, it is spread
+        // over time if asked to
+        let rate_limit = Some(self.config.initial_rate_limit)
+            .filter(|rate| 0 != *rate && self.metadata.is_empty());
+
+        // -----------------------------------------------------------------------------
+        // Retrieve certificates and keys on disk
+        let begin = Instant::now();
+        let before = Usage::now();
+        for (path, err) in self.extractions.refresh(&self.config).await {
+            error!(
+                path = path.display().to_string(),
+                error = err.to_string(),
+                "Could not extract pki archive, keep its previous extraction if any"
+            );
+        }
+
+        let mut directories = vec![];
+        for root in &self.config.roots() {
+            info!(path = root.display().to_string(), "Load pki from disk");
+
+            // An archive which has never been extracted has no certificate
+            // yet, it must not hold back the other pki directories
+            if !root.is_dir() && self.extractions.is_missing(root, &self.config) {
+                warn!(
+                    path = root.display().to_string(),
+                    "Skip pki archive which has not been extracted yet"
+                );
+                continue;
+            }
+
+            // A pki directory that vanished, e.g. an unmounted volume, must
+            // not be mistaken for an empty one, which would remove everything
+            if !root.is_dir() {
+                return Err(Error::PkiUnavailable(root.to_owned()));
+            }
+
+            directories.extend(
+                certificates::directories(root, &self.config, self.config.max_depth)
+                    .await
+                    .map_err(|err| Error::FindCertificates(root.to_owned(), err))?,
+            );
+        }
+
+        self.cache
+            .retain(&directories.iter().cloned().collect::<HashSet<_>>());
+
+        info!(number = directories.len(), "Compute metadata for pki");
+        let (mut pki, metadata) = self.scan(directories).await?;
+        self.activations.clear();
+        let metadata = self.filter(&mut pki, metadata, &HashMap::new());
+        let metadata = self.quarantine(&mut pki, metadata, None);
+        Self::conflicts(&metadata);
+
+        // A standby replica keeps scanning, so that its cache is warm once it
+        // takes over
+        let leading = self.lead(true).await;
+        if leading {
+            self.reconcile(&metadata);
+        }
+        CERTIFICATE_LOOKUP_DURATION
+            .with_label_values(&["scan"])
+            .observe(begin.elapsed().as_secs_f64());
+
+        // Unchanged directories are served by the cache, their files are not
+        // read again
+        let usage = Usage::now().since(&before);
+        CERTIFICATE_SCAN_DIRECTORIES.set(usage.directories as i64);
+        CERTIFICATE_SCAN_FILES.set(usage.files as i64);
+        info!(
+            directories = usage.directories,
+            files = usage.files,
+            bytes = usage.bytes,
+            "Scanned pki directories"
+        );
+
+        if !leading {
+            self.health.set_synced(true);
+            self.publish();
+            return Ok(Summary::default());
+        }
+
+        if self.is_shutting_down() {
+            return Ok(Summary::default());
+        }
+
+        // -----------------------------------------------------------------------------
+        // Create messages to update Sōzu and send them, then update the current
+        // metadata
+        let begin = Instant::now();
+        let attempted = metadata.to_owned();
+        let result = self
+            .apply(&self.metadata, metadata, None, &pki, rate_limit)
+            .await;
+        CERTIFICATE_LOOKUP_DURATION
+            .with_label_values(&["send"])
+            .observe(begin.elapsed().as_secs_f64());
+
+        let (metadata, applied, summary) = self.settle(result).await?;
+        self.track(&attempted, &metadata, &summary);
+        if !self.lead(false).await {
+            self.publish();
+            return Ok(summary);
+        }
+
+        self.metadata = metadata;
+        self.applied = applied;
+        self.health.set_synced(true);
+        self.publish();
+        self.persist().await;
+
+        Ok(summary)
+    }
+
+    /// Look up only the given directories and the certificate directories
+    /// nested in them, a directory which does not exist anymore will be removed
+    /// from the proxy. Certificates of other directories are left untouched,
+    /// they are only used to resolve collisions and renames.
+    pub async fn lookup_paths(&mut self, paths: &HashSet<PathBuf>) -> Result<Summary, Error> {
+        let result = self.lookup_some(paths).await;
+        match &result {
+            Ok(summary) => self.pending |= !summary.pending.is_empty(),
+            Err(_) => {
+                self.pending = true;
+                self.health.set_synced(false);
+            }
+        }
+
+        result
+    }
+
+    /// Returns when the first certificate skipped as not valid yet becomes
+    /// valid, if any
+    pub fn activation(&self) -> Option<Instant> {
+        let activation = *self.activations.values().min()?;
+        let now = millis(SystemTime::now());
+
+        // Milliseconds are truncated, so that the deadline is never early
+        let delay = Duration::from_millis(activation.saturating_sub(now).max(0) as u64);
+        Some(Instant::now() + delay)
+    }
+
+    /// Returns and forget the directories of the certificates skipped as not
+    /// valid yet which are valid by now
+    pub fn take_activated(&mut self) -> HashSet<PathBuf> {
+        let now = millis(SystemTime::now());
+        let activated: HashSet<_> = self
+            .activations
+            .iter()
+            .filter(|(_, activation)| **activation <= now)
+            .map(|(path, _)| path.to_owned())
+            .collect();
+
+        self.activations.retain(|path, _| !activated.contains(path));
+        activated
+    }
+
+    /// Returns true if requests of the previous lookups are still to be
+    /// applied, which only a full lookup does if no directory changes
+    pub fn is_pending(&self) -> bool {
+        self.pending
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn lookup_some(&mut self, paths: &HashSet<PathBuf>) -> Result<Summary, Error> {
+        // -----------------------------------------------------------------------------
+        // Retrieve certificates and keys on disk
+        if Mode::CleanupOnly == self.config.mode {
+            return Ok(Summary::default());
+        }
+
+        info!(number = paths.len(), "Load pki of directories from disk");
+
+        // Directories of a pki directory that vanished are not removed, see
+        // [`Self::lookup`]
+        let roots = self.config.roots();
+        for root in &roots {
+            if paths.iter().any(|path| path.starts_with(root)) && !root.is_dir() {
+                return Err(Error::PkiUnavailable(root.to_owned()));
+            }
+        }
+
+        let mut directories = vec![];
+        for path in paths {
+            // Only certificates of the pki directories are managed, whoever
+            // asks for the lookup
+            if !roots.iter().any(|root| path.starts_with(root)) {
+                warn!(
+                    path = path.display().to_string(),
+                    "Directory is not within a pki directory, skip it"
+                );
+
+                continue;
+            }
+
+            // Certificates of a flat layout are named after their files
+            if self.config.layout.is_flat() && !path.is_dir() {
+                if certificates::is_certificate_directory(path, &self.config.layout).await {
+                    if certificates::is_included(&self.config, path) {
+                        directories.push(path.to_owned());
+                    }
+                } else {
+                    debug!(
+                        path = path.display().to_string(),
+                        "Certificate files do not exist anymore"
+                    );
+
+                    self.cache.remove(path);
+                }
+
+                continue;
+            }
+
+            if !path.is_dir() {
+                debug!(
+                    path = path.display().to_string(),
+                    "Certificate directory does not exist anymore"
+                );
+
+                self.cache.remove(path);
+                continue;
+            }
+
+            let depth = roots
+                .iter()
+                .find_map(|root| Some(path.strip_prefix(root).ok()?.components().count()))
+                .unwrap_or_default();
+
+            if self.config.layout.is_flat() && depth >= self.config.max_depth {
+                continue;
+            }
+
+            if !self.config.layout.is_flat()
+                && (depth >= self.config.max_depth
+                    || certificates::is_certificate_directory(path, &self.config.layout).await)
+            {
+                if certificates::is_included(&self.config, path) {
+                    directories.push(path.to_owned());
+                }
+
+                continue;
+            }
+
+            match certificates::directories(path, &self.config, self.config.max_depth - depth).await
+            {
+                Ok(nested) => directories.extend(nested),
+                Err(err) => {
+                    warn!(
+                        error = err.to_string(),
+                        path = path.display().to_string(),
+                        "Could not search for nested certificate directories"
+                    );
+                }
+            }
+        }
+
+        let (current, others): (HashMap<_, _>, HashMap<_, _>) = self
+            .metadata
+            .iter()
+            .map(|(path, metadata)| (path.to_owned(), metadata.to_owned()))
+            .partition(|(path, _)| within(paths, path));
+
+        let (mut pki, metadata) = self.scan(directories).await?;
+        self.activations.retain(|path, _| !within(paths, path));
+        let metadata = self.filter(&mut pki, metadata, &others);
+        let metadata = self.quarantine(&mut pki, metadata, Some(paths));
+
+        // The leader lock is only acquired by full lookups, which also
+        // reconcile certificates installed by the previous leader
+        if !self.lead(false).await || self.is_shutting_down() {
+            return Ok(Summary::default());
+        }
+
+        // -----------------------------------------------------------------------------
+        // Create messages to update Sōzu for the given directories and send them
+        let attempted = metadata.to_owned();
+        let result = self
+            .apply(&current, metadata, Some(paths), &pki, None)
+            .await;
+        let (metadata, applied, summary) = self.settle(result).await?;
+        self.track(&attempted, &metadata, &summary);
+        if !self.lead(false).await {
+            self.publish();
+            return Ok(summary);
+        }
+
+        // -----------------------------------------------------------------------------
+        // Update the current metadata of the given directories
+        self.metadata.retain(|path, _| !within(paths, path));
+        self.metadata.extend(metadata);
+        self.applied = applied;
+        self.publish();
+        self.persist().await;
+
+        Ok(summary)
+    }
+
+    /// Remove every certificate that the connector installed, as recorded in
+    /// the state file, whether it is still on disk or not. Certificates
+    /// installed by others are never known, so they are left untouched.
+    #[tracing::instrument(skip_all)]
+    async fn cleanup(&mut self) -> Result<Summary, Error> {
+        if !self.lead(true).await || self.is_shutting_down() {
+            return Ok(Summary::default());
+        }
+
+        let number = self
+            .applied
+            .values()
+            .flat_map(HashMap::values)
+            .map(HashMap::len)
+            .sum::<usize>();
+
+        if 0 == number {
+            info!("There is no certificate installed by the connector to remove");
+            self.health.set_synced(true);
+            return Ok(Summary::default());
+        }
+
+        info!(
+            number = number,
+            "Remove certificates installed by the connector"
+        );
+
+        let current = self.metadata.to_owned();
+        let result = self
+            .apply(&current, HashMap::new(), None, &HashMap::new(), None)
+            .await;
+
+        let (metadata, applied, summary) = self.settle(result).await?;
+        if !self.lead(false).await {
+            self.publish();
+            return Ok(summary);
+        }
+
+        self.metadata = metadata;
+        self.applied = applied;
+        self.health.set_synced(true);
+        self.publish();
+        self.persist().await;
+
+        Ok(summary)
+    }
+
+    /// Read certificates and keys of the given directories and compute their
+    /// metadata, directories whose files did not change since the previous scan
+    /// are retrieved from the cache.
+    #[tracing::instrument(skip_all)]
+    async fn scan(&mut self, directories: Vec<PathBuf>) -> Result<Scan, Error> {
+        // -----------------------------------------------------------------------------
+        // Read directories concurrently, the concurrency limit also bounds the
+        // number of opened files.
+        let config = &self.config;
+        let layout = &config.layout;
+        let cache = &self.cache;
+        let window = Duration::from_millis(config.stability_window);
+        let permissions = config.strict_permissions;
+        let outcomes: Vec<_> = stream::iter(directories)
+            .map(|path| async move {
+                let stamp = Stamp::new(&path, layout, permissions).await;
+                if cache.get(&path, &stamp).is_some() {
+                    return (path, stamp, Outcome::Cached);
+                }
+
+                // It has already been reported when its files were read
+                if cache.is_known_empty(&path, &stamp) {
+                    trace!(
+                        path = path.display().to_string(),
+                        "Certificate file is still empty, skip it"
+                    );
+
+                    return (path, stamp, Outcome::Empty);
+                }
+
+                // Files changed, make sure that they are not being written
+                if !window.is_zero() {
+                    sleep(window).await;
+                    if Stamp::new(&path, layout, permissions).await != stamp {
+                        return (path, stamp, Outcome::Unstable);
+                    }
+                }
+
+                match certificates::load(path.to_owned(), config).await {
+                    Ok(loaded) => (path, stamp, Outcome::Loaded(Box::new(loaded))),
+                    Err(certificates::Error::EmptyCertificate(_)) => (path, stamp, Outcome::Empty),
+                    Err(_) => (path, stamp, Outcome::Skipped),
+                }
+            })
+            .buffer_unordered(self.config.scan_concurrency.max(1))
+            .collect()
+            .await;
+
+        // -----------------------------------------------------------------------------
+        // Merge results and update the cache
+        let mut pki = Pki::default();
+        let mut metadata = HashMap::new();
+        for (path, stamp, outcome) in outcomes {
+            match outcome {
+                Outcome::Cached => {
+                    trace!(
+                        path = path.display().to_string(),
+                        "Files of certificate directory did not change, use cached metadata"
+                    );
+
+                    if let Some(entry) = self.cache.get(&path, &stamp) {
+                        pki.insert(path.to_owned(), entry.certificate_and_key.to_owned());
+                        metadata.insert(path, entry.metadata.to_owned());
+                    }
+                }
+                Outcome::Skipped => self.cache.remove(&path),
+                Outcome::Empty => self.cache.insert_empty(path, stamp),
+                Outcome::Unstable => {
+                    debug!(
+                        path = path.display().to_string(),
+                        "Files of certificate directory are still changing, look it up later"
+                    );
+
+                    // Keep the current state of the directory until it is stable
+                    if let Some(meta) = self.metadata.get(&path) {
+                        metadata.insert(path.to_owned(), meta.to_owned());
+                    }
+
+                    self.unstable.insert(path);
+                }
+                Outcome::Loaded(loaded) => {
+                    let (certificate_and_key, meta) = *loaded;
+
+                    self.cache.insert(
+                        path.to_owned(),
+                        stamp,
+                        certificate_and_key.to_owned(),
+                        meta.to_owned(),
+                    );
+
+                    pki.insert(path.to_owned(), certificate_and_key);
+                    metadata.insert(path, meta);
+                }
+            }
+        }
+
+        Ok((pki, metadata))
+    }
+
+    /// Remove certificates that should not be installed from the pki and the
+    /// metadata, `others` are the certificates of directories that are not
+    /// looked up, which may collide with the ones of another pki directory.
+    #[tracing::instrument(skip_all)]
+    fn filter(
+        &mut self,
+        pki: &mut HashMap<PathBuf, CertificateAndKey>,
+        mut metadata: HashMap<PathBuf, Metadata>,
+        others: &HashMap<PathBuf, Metadata>,
+    ) -> HashMap<PathBuf, Metadata> {
+        let now = millis(SystemTime::now());
+        let grace = self.config.clock_skew_grace as i64;
+        let mut activations = vec![];
+        metadata.retain(|path, meta| {
+            if self.config.skip_expired
+                && certificates::is_expired(meta, now, self.config.clock_skew_grace as i64)
+            {
+                warn!(
+                    path = path.display().to_string(),
+                    fingerprint = meta.fingerprint.to_string(),
+                    expires_at = meta.expires_at,
+                    "Skip expired certificate"
+                );
+
+                CERTIFICATE_SKIPPED_EXPIRED
+                    .with_label_values(&[&certificates::directory_name(path)])
+                    .inc();
+
+                return false;
+            }
+
+            if certificates::is_not_yet_valid(meta, now, self.config.clock_skew_grace as i64) {
+                warn!(
+                    path = path.display().to_string(),
+                    fingerprint = meta.fingerprint.to_string(),
+                    not_before = meta.not_before,
+                    "Skip not yet valid certificate until it becomes valid"
+                );
+
+                CERTIFICATE_SKIPPED_NOT_YET_VALID
+                    .with_label_values(&[&certificates::directory_name(path)])
+                    .inc();
+
+                if let Some(not_before) = meta.not_before {
+                    activations.push((path.to_owned(), not_before.saturating_mul(1000) - grace));
+                }
+
+                return false;
+            }
+
+            if let Some(name) = certificates::forbidden_name(&self.config, meta) {
+                warn!(
+                    path = path.display().to_string(),
+                    fingerprint = meta.fingerprint.to_string(),
+                    name = name,
+                    "Skip certificate with a name which is not allowed"
+                );
+
+                CERTIFICATE_SKIPPED_NAME
+                    .with_label_values(&[&certificates::directory_name(path)])
+                    .inc();
+
+                return false;
+            }
+
+            true
+        });
+
+        self.activations.extend(activations);
+
+        // -----------------------------------------------------------------------------
+        // Resolve collisions between pki directories, the first one wins
+        let mut owners: HashMap<&Fingerprint, (usize, &PathBuf)> = HashMap::new();
+        for (path, meta) in others.iter().chain(metadata.iter()) {
+            let root = self.root_of(path);
+            owners
+                .entry(&meta.fingerprint)
+                .and_modify(|owner| {
+                    if (root, path) < (owner.0, owner.1) {
+                        *owner = (root, path);
+                    }
+                })
+                .or_insert((root, path));
+        }
+
+        let collisions: HashSet<PathBuf> = metadata
+            .iter()
+            .filter_map(|(path, meta)| {
+                let (root, owner) = owners.get(&meta.fingerprint)?;
+                if *root >= self.root_of(path) {
+                    return None;
+                }
+
+                warn!(
+                    path = path.display().to_string(),
+                    owner = owner.display().to_string(),
+                    fingerprint = meta.fingerprint.to_string(),
+                    "Certificate is already provided by a previous pki directory, skip it"
+                );
+
+                Some(path.to_owned())
+            })
+            .collect();
+
+        metadata.retain(|path, _| !collisions.contains(path));
+        pki.retain(|path, _| metadata.contains_key(path));
+        metadata
+    }
+
+    /// Warn about names covered by several certificates, Sōzu serves only one
+    /// of them for such a name. Directories holding the same certificate do
+    /// not conflict, as a single one is sent.
+    fn conflicts(metadata: &HashMap<PathBuf, Metadata>) {
+        let mut index: HashMap<&str, HashMap<&Fingerprint, &PathBuf>> = HashMap::new();
+        for (path, meta) in metadata {
+            for name in &meta.names {
+                index
+                    .entry(name)
+                    .or_default()
+                    .entry(&meta.fingerprint)
+                    .and_modify(|owner| *owner = path.min(owner))
+                    .or_insert(path);
+            }
+        }
+
+        for (name, owners) in index {
+            if owners.len() < 2 {
+                continue;
+            }
+
+            let mut paths = owners
+                .values()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>();
+            paths.sort();
+
+            warn!(
+                name = name,
+                paths = paths.join(", "),
+                "Name is covered by several certificates, only one of them is served"
+            );
+
+            CERTIFICATE_SAN_CONFLICT.inc();
+        }
+    }
+
+    /// Share the current state of certificates with the HTTP server
+    fn publish(&self) {
+        CERTIFICATE_MANAGED.set(self.metadata.len() as i64);
+        self.refresh_expiries();
+
+        match self.inventory.write() {
+            Ok(mut inventory) => {
+                inventory.managed.clone_from(&self.metadata);
+                inventory.quarantined.clone_from(&self.quarantined);
+            }
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "Could not share the current state of certificates"
+                );
+            }
+        }
+    }
+
+    /// Update the gauges of the time until managed certificates expire
+    fn refresh_expiries(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or_default();
+
+        let (min_expiry, expiries) = self.expiries(now);
+        CERTIFICATE_EXPIRY.reset();
+        for (name, expiry) in &expiries {
+            CERTIFICATE_EXPIRY.with_label_values(&[name]).set(*expiry);
+        }
+
+        // The gauge is removed if no managed certificate has a known expiration
+        // date, rather than reporting one which is not managed anymore
+        CERTIFICATE_MIN_EXPIRY.reset();
+        if let Some(min_expiry) = min_expiry {
+            CERTIFICATE_MIN_EXPIRY
+                .with_label_values(&[])
+                .set(min_expiry);
+        }
+    }
+
+    /// Returns the number of seconds from `now` until the soonest managed
+    /// certificate expires, and until each certificate expires by name
+    fn expiries(&self, now: i64) -> (Option<i64>, HashMap<&str, i64>) {
+        // Certificates are labelled by their primary name, or their first name
+        // in lexicographic order if it is not known yet, the soonest expiry
+        // wins if several share the same name
+        let mut min_expiry = None;
+        let mut expiries: HashMap<&str, i64> = HashMap::new();
+        for meta in self.metadata.values() {
+            let Some(expires_at) = meta.expires_at else {
+                continue;
+            };
+
+            let expiry = expires_at.saturating_sub(now);
+            min_expiry = Some(min_expiry.map_or(expiry, |min: i64| expiry.min(min)));
+
+            let name = Some(meta.primary_name.as_str())
+                .filter(|name| !name.is_empty())
+                .or_else(|| meta.names.iter().map(String::as_str).min());
+
+            if let Some(name) = name {
+                expiries
+                    .entry(name)
+                    .and_modify(|min| *min = expiry.min(*min))
+                    .or_insert(expiry);
+            }
+        }
+
+        (min_expiry, expiries)
+    }
+
+    /// Skip certificates that are quarantined, the current state of their
+    /// directory is kept. A certificate whose content changed or which is not
+    /// on disk anymore within the given directories, all if none, is released.
+    #[tracing::instrument(skip_all)]
+    fn quarantine(
+        &mut self,
+        pki: &mut HashMap<PathBuf, CertificateAndKey>,
+        mut metadata: HashMap<PathBuf, Metadata>,
+        paths: Option<&HashSet<PathBuf>>,
+    ) -> HashMap<PathBuf, Metadata> {
+        self.quarantined.retain(|path, quarantined| {
+            if paths.is_some_and(|paths| !within(paths, path)) {
+                return true;
+            }
+
+            match metadata.get(path) {
+                Some(meta) if meta.is_same_certificate(quarantined) => true,
+                _ => {
+                    info!(
+                        path = path.display().to_string(),
+                        "Certificate changed on disk, release it from quarantine"
+                    );
+
+                    false
+                }
+            }
+        });
+
+        self.retries.retain(|path, _| {
+            paths.is_some_and(|paths| !within(paths, path)) || metadata.contains_key(path)
+        });
+
+        for path in self.quarantined.keys() {
+            pki.remove(path);
+            match self.metadata.get(path) {
+                Some(meta) => {
+                    metadata.insert(path.to_owned(), meta.to_owned());
+                }
+                None => {
+                    metadata.remove(path);
+                }
+            }
+        }
+
+        metadata
+    }
+
+    /// Count consecutive failures of the certificates that were sent to Sōzu
+    /// and quarantine the ones that failed too many times. Only failures that
+    /// Sōzu answered count, the count of a certificate whose requests got no
+    /// answer is kept as is until they are applied.
+    #[tracing::instrument(skip_all)]
+    fn track(
+        &mut self,
+        attempted: &HashMap<PathBuf, Metadata>,
+        applied: &HashMap<PathBuf, Metadata>,
+        summary: &Summary,
+    ) {
+        // A certificate that Sōzu considers invalid would be rejected again,
+        // whatever the number of retries is
+        for (path, meta) in attempted {
+            if summary.invalid.contains(path) {
+                CERTIFICATE_QUARANTINED.inc();
+                self.retries.remove(path);
+                self.quarantined.insert(path.to_owned(), meta.to_owned());
+            }
+        }
+
+        if 0 == self.config.max_retries {
+            return;
+        }
+
+        for (path, meta) in attempted {
+            if self.quarantined.contains_key(path) {
+                continue;
+            }
+
+            if !summary.rejected.contains(path) {
+                if applied.get(path) == Some(meta) {
+                    self.retries.remove(path);
+                }
+
+                continue;
+            }
+
+            let (fingerprint, failures) = self
+                .retries
+                .entry(path.to_owned())
+                .or_insert_with(|| (meta.fingerprint.to_owned(), 0));
+
+            if *fingerprint != meta.fingerprint {
+                *fingerprint = meta.fingerprint.to_owned();
+                *failures = 0;
+            }
+
+            *failures += 1;
+            if *failures < self.config.max_retries {
+                continue;
+            }
+
+            error!(
+                path = path.display().to_string(),
+                fingerprint = meta.fingerprint.to_string(),
+                failures = *failures,
+                "Sōzu keeps rejecting certificate, quarantine it until it changes on disk"
+            );
+
+            CERTIFICATE_QUARANTINED.inc();
+            self.retries.remove(path);
+            self.quarantined.insert(path.to_owned(), meta.to_owned());
+        }
+    }
+
+    /// Returns directories whose files were still changing during the last
+    /// scans and that should be looked up again
+    pub fn take_unstable(&mut self) -> HashSet<PathBuf> {
+        std::mem::take(&mut self.unstable)
+    }
+
+    /// Returns true if the shutdown has been requested, in which case no new
+    /// requests should be sent to Sōzu
+    fn is_shutting_down(&self) -> bool {
+        let shutting_down = *self.shutdown.borrow();
+        if shutting_down {
+            info!("Shutdown has been requested, do not send requests to Sōzu");
+        }
+
+        shutting_down
+    }
+
+    /// Returns true if this replica may send requests to Sōzu, that is the
+    /// leader lock is not configured or is held, trying to acquire it if asked
+    /// to. A replica which takes over retrieves certificates installed by the
+    /// previous leader, while one which steps down forgets the current state
+    /// of certificates, as the leader keeps changing it.
+    #[tracing::instrument(skip(self))]
+    async fn lead(&mut self, acquire: bool) -> bool {
+        let Some(lock) = &mut self.leader else {
+            return true;
+        };
+
+        let held = if acquire {
+            lock.try_acquire().unwrap_or_else(|err| {
+                error!(
+                    error = err.to_string(),
+                    "Could not acquire leader lock, stay on standby"
+                );
+
+                false
+            })
+        } else {
+            lock.is_held()
+        };
+
+        let path = lock.path().display().to_string();
+        LEADER.set(i64::from(held));
+        match (self.leading.replace(held), held) {
+            (None, true) => {
+                info!(path = path, "Acquired leader lock, send requests to Sōzu");
+            }
+            (Some(false), true) => {
+                info!(
+                    path = path,
+                    "Acquired leader lock, take over sending requests to Sōzu"
+                );
+
+                let (installed, connected) = Self::installed_everywhere(&self.targets).await;
+                self.health.set_connected(connected);
+                self.installed = installed;
+                self.metadata.clear();
+                self.applied.clear();
+                self.retries.clear();
+            }
+            (None, false) => {
+                info!(
+                    path = path,
+                    "Leader lock is held by another replica, stay on standby"
+                );
+
+                self.metadata.clear();
+                self.applied.clear();
+            }
+            (Some(true), false) => {
+                warn!(
+                    path = path,
+                    "Lost leader lock, stop sending requests to Sōzu"
+                );
+
+                self.metadata.clear();
+                self.applied.clear();
+                self.retries.clear();
+            }
+            (Some(_), _) => {}
+        }
+
+        held
+    }
+
+    /// Try to acquire the leader lock, if it is configured and not held yet,
+    /// returns true if this replica may send requests to Sōzu
+    pub async fn try_lead(&mut self) -> bool {
+        self.lead(true).await
+    }
+
+    /// Returns true if the leader lock is configured and was not held on the
+    /// last check, or has not been checked yet
+    pub fn is_standby(&self) -> bool {
+        self.leader.is_some() && Some(true) != self.leading
+    }
+
+    /// Returns true if the leader lock has been lost since it was last checked
+    fn has_lost_lead(&self) -> bool {
+        self.leader.as_ref().is_some_and(|lock| !lock.is_held())
+    }
+
+    /// Returns the index of the pki directory that holds the given path
+    fn root_of(&self, path: &Path) -> usize {
+        self.config
+            .roots()
+            .iter()
+            .position(|root| path.starts_with(root))
+            .unwrap_or(usize::MAX)
+    }
+
+    /// Returns the source of the given path for metrics, the path of the pki
+    /// directory that holds it as configured, or an empty one if not asked to.
+    /// Pki directories sharing the same name, e.g. `/a/pki` and `/b/pki`, are
+    /// still told apart.
+    fn source(&self, path: &Path) -> String {
+        if !self.config.source_label {
+            return String::new();
+        }
+
+        // Archives are named after their file, not their extraction directory
+        self.config
+            .roots()
+            .iter()
+            .zip(&self.config.sozu.pki)
+            .find(|(root, _)| path.starts_with(root))
+            .map(|(_, pki)| pki.display().to_string())
+            .unwrap_or_default()
+    }
+
+    /// Apply a new configuration, clients are recreated if the configuration
+    /// of their Sōzu instance changed and certificates are moved to the new
+    /// listeners
+    #[tracing::instrument(skip_all)]
+    pub async fn reload(&mut self, config: Arc<ConnectorConfiguration>) {
+        let old = std::mem::replace(&mut self.config, config);
+
+        if !old.reads_like(&self.config) {
+            info!("Reading of certificate directories changed, invalidate the cache");
+            self.cache = Cache::default();
+        }
+
+        if old.sozu.instances() != self.config.sozu.instances() {
+            self.retarget().await;
+        }
+
+        if old.listening_address != self.config.listening_address {
+            warn!(
+                address = self.config.listening_address.to_string(),
+                "Listening address of the HTTP server changed, a restart is needed to apply it"
+            );
+        }
+
+        if old.leader_lock != self.config.leader_lock {
+            warn!("Leader lock changed, a restart is needed to apply it");
+        }
+
+        if old.http != self.config.http || old.metrics != self.config.metrics {
+            warn!("Configuration of the HTTP server changed, a restart is needed to apply it");
+        }
+
+        if old.logging != self.config.logging
+            || old.telemetry != self.config.telemetry
+            || old.sentry != self.config.sentry
+        {
+            warn!("Configuration of logging, telemetry or Sentry changed, a restart is needed to apply it");
+        }
+    }
+
+    /// Update Sōzu instances from the configuration, an instance that is not
+    /// configured anymore is left as is and a new one receives every current
+    /// certificate on the next lookup, which also moves certificates between
+    /// listeners as the ones that each listener holds are known
+    #[tracing::instrument(skip_all)]
+    async fn retarget(&mut self) {
+        let mut previous = std::mem::take(&mut self.targets);
+        for instance in self.config.sozu.instances() {
+            if self
+                .targets
+                .iter()
+                .any(|target| target.instance.name == instance.name)
+            {
+                error!(
+                    error = Error::DuplicateInstance(instance.name).to_string(),
+                    "Could not use Sōzu instance"
+                );
+
+                continue;
+            }
+
+            let Some(idx) = previous
+                .iter()
+                .position(|target| target.instance.name == instance.name)
+            else {
+                info!(instance = instance.name, "Start to manage Sōzu instance");
+                match Target::try_new(instance).await {
+                    Ok(target) => {
+                        self.targets.push(target);
+                    }
+                    Err(err) => {
+                        error!(
+                            error = err.to_string(),
+                            "Could not connect to the new Sōzu instance, it is ignored until the next reload"
+                        );
+                    }
+                }
+
+                continue;
+            };
+
+            let mut target = previous.swap_remove(idx);
+            let old = std::mem::replace(&mut target.instance, instance);
+            if old.configuration != target.instance.configuration
+                || old.endpoint != target.instance.endpoint
+            {
+                info!(
+                    instance = target.instance.name,
+                    "Sōzu configuration changed, recreate the client"
+                );
+
+                target.reconnect().await;
+            }
+
+            // Discovered listeners are looked up again, as the configuration of
+            // Sōzu may have changed
+            if old.listener != target.instance.listener || target.instance.listener.is_empty() {
+                match listeners(&target.instance).await {
+                    Ok(listeners) if listeners != target.listeners => {
+                        info!(
+                            instance = target.instance.name,
+                            listeners = format!("{listeners:?}"),
+                            "HTTPS listeners changed, move certificates on the next lookup"
+                        );
+
+                        target.listeners = listeners;
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        error!(
+                            error = err.to_string(),
+                            instance = target.instance.name,
+                            "Could not resolve HTTPS listeners, keep the previous ones"
+                        );
+                    }
+                }
+            }
+
+            // Listeners of Sōzu may have changed too, which scopes the ones of
+            // certificate directories
+            match target.list_listeners().await {
+                Ok((https, _, _)) => target.https = Some(https),
+                Err(err) => {
+                    warn!(
+                        error = err.to_string(),
+                        instance = target.instance.name,
+                        "Could not list listeners of Sōzu, keep the previous ones"
+                    );
+                }
+            }
+
+            self.targets.push(target);
+        }
+
+        for target in previous {
+            info!(
+                instance = target.instance.name,
+                "Stop to manage Sōzu instance, its certificates are left as is"
+            );
+
+            // The instance is not managed anymore, so its status is unknown
+            let _ = SOZU_CLIENT_CONNECTED.remove_label_values(&[&target.instance.name]);
+            self.applied.remove(&target.instance.name);
+        }
+    }
+
+    /// Record the outcome of requests sent to Sōzu and recreate the client if
+    /// the connection is dead.
+    #[tracing::instrument(skip_all)]
+    async fn settle(
+        &mut self,
+        result: Result<(HashMap<PathBuf, Metadata>, Applied, Summary), Error>,
+    ) -> Result<(HashMap<PathBuf, Metadata>, Applied, Summary), Error> {
+        match result {
+            Ok((metadata, applied, summary)) => {
+                if 0 != summary.sent {
+                    self.failures = 0;
+                    self.breaker(true);
+                } else if 0 != summary.failed {
+                    self.failures = self.failures.saturating_add(1);
+                    self.breaker(false);
+                }
+
+                if 0 != summary.sent || 0 != summary.failed {
+                    self.health.set_connected(true);
+                }
+
+                // Responses are not matched with requests, so a late answer
+                // would be read as the one of another request
+                for target in &mut self.targets {
+                    if summary.disconnected.contains(&target.instance.name) {
+                        target.reconnect().await;
+                    }
+                }
+
+                Ok((metadata, applied, summary))
+            }
+            Err(Error::Send(err)) if !err.is_recoverable() => {
+                self.failures = self.failures.saturating_add(1);
+                self.breaker(false);
+                self.health.set_connected(false);
+                for target in &mut self.targets {
+                    target.reconnect().await;
+                }
+
+                Err(Error::Send(err))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Returns the state of the circuit breaker, it is half-open once its
+    /// cooldown has elapsed
+    fn circuit(&self) -> Circuit {
+        match self.circuit {
+            None => Circuit::Closed,
+            Some(until) if Instant::now() < until => Circuit::Open,
+            Some(_) => Circuit::HalfOpen,
+        }
+    }
+
+    /// Open the circuit breaker once requests failed to be sent for too many
+    /// consecutive lookups, or again if the probe failed, and close it once
+    /// requests are sent
+    fn breaker(&mut self, sent: bool) {
+        let threshold = self.config.circuit_breaker_threshold;
+        if 0 == threshold {
+            return;
+        }
+
+        match (self.circuit(), sent) {
+            (Circuit::Closed, true) | (Circuit::Open, _) => {}
+            (Circuit::HalfOpen, true) => {
+                info!("Requests to Sōzu succeed again, close the circuit breaker");
+                self.circuit = None;
+            }
+            (Circuit::Closed, false) if self.failures < threshold => {}
+            (_, false) => {
+                let cooldown = Duration::from_millis(self.config.circuit_breaker_cooldown);
+                warn!(
+                    failures = self.failures,
+                    cooldown = cooldown.as_millis(),
+                    "Requests to Sōzu keep failing, open the circuit breaker and pause sending"
+                );
+
+                self.circuit = Some(Instant::now() + cooldown);
+            }
+        }
+
+        SOZU_CIRCUIT_OPEN.set(self.circuit.is_some() as i64);
+
+        let (circuit, until) = (
+            self.circuit(),
+            self.circuit.map(|until| {
+                millis(SystemTime::now() + until.saturating_duration_since(Instant::now()))
+            }),
+        );
+        self.report(|status| {
+            status.circuit = circuit;
+            status.circuit_cooldown_until = until;
+        });
+    }
+
+    /// Returns the delay to wait before the next lookup if requests keep
+    /// failing to be sent to Sōzu
+    pub fn backoff(&self) -> Option<Duration> {
+        if 0 == self.failures {
+            return None;
+        }
+
+        Some(backoff(
+            Duration::from_millis(self.config.interval),
+            self.failures,
+            Duration::from_millis(self.config.max_backoff),
+        ))
+    }
+
+    /// Send requests to every Sōzu instance to go from the certificates that
+    /// each of its listeners holds to the new metadata, looking only at the
+    /// given directories, all if none. Returns the metadata that they are all
+    /// aware of and the certificates that each listener holds afterwards. A
+    /// request that a listener failed to apply is reverted, so that it is sent
+    /// again to this listener only on the next lookup.
+    #[tracing::instrument(skip_all)]
+    async fn apply(
+        &self,
+        current: &HashMap<PathBuf, Metadata>,
+        mut metadata: HashMap<PathBuf, Metadata>,
+        scope: Option<&HashSet<PathBuf>>,
+        pki: &HashMap<PathBuf, CertificateAndKey>,
+        rate_limit: Option<u64>,
+    ) -> Result<(HashMap<PathBuf, Metadata>, Applied, Summary), Error> {
+        if self.targets.is_empty() {
+            return Ok((metadata, Applied::new(), Summary::default()));
+        }
+
+        debug!("Create diff and messages to send to the proxy");
+        let mut batches = vec![];
+        for target in &self.targets {
+            let (listeners, requests) = self
+                .transition(target, &metadata, scope, pki)
+                .map_err(Error::ComputeMessage)?;
+            batches.push((target, listeners, requests));
+        }
+
+        let diff = certificates::diff::create(current, &metadata);
+        if let Some(sink) = &self.config.diff_sink {
+            if !diff.added.is_empty() || !diff.modified.is_empty() || !diff.deleted.is_empty() {
+                let record = export::Record::new(&diff, current, &metadata);
+                if let Err(err) = export::write(sink, &record).await {
+                    warn!(error = err.to_string(), "Could not export diff");
+                }
+            }
+        }
+
+        let mut summary = Summary {
+            added: diff.added.len(),
+            modified: diff.modified.len(),
+            deleted: diff.deleted.len(),
+            ..Default::default()
+        };
+
+        for (kind, number) in [
+            ("added", diff.added.len()),
+            ("modified", diff.modified.len()),
+            ("deleted", diff.deleted.len()),
+            ("renamed", diff.renamed.len()),
+        ] {
+            CERTIFICATE_DIFF
+                .with_label_values(&[kind])
+                .inc_by(number as u64);
+        }
+
+        // Nothing is sent while the circuit breaker is open, then a single
+        // request probes Sōzu once its cooldown has elapsed
+        let circuit = self.circuit();
+        self.report(|status| status.circuit = circuit);
+        let mut probe = Circuit::HalfOpen == circuit;
+
+        let mut applied = Applied::new();
+        let mut dead = None;
+        for (target, listeners, requests) in batches {
+            let mut transition = Transition {
+                current,
+                metadata: &mut metadata,
+                listeners,
+                pending: HashSet::new(),
+            };
+
+            let err = self
+                .apply_to(
+                    target,
+                    &mut transition,
+                    requests,
+                    (circuit, &mut probe),
+                    &mut summary,
+                    rate_limit,
+                )
+                .await;
+
+            dead = err.or(dead);
+            summary
+                .pending
+                .extend(std::mem::take(&mut transition.pending));
+            applied.insert(target.instance.name.to_owned(), transition.into_applied());
+        }
+
+        match dead {
+            Some(err) if summary.disconnected.len() == self.targets.len() => Err(Error::Send(err)),
+            _ => Ok((metadata, applied, summary)),
+        }
+    }
+
+    /// Returns the certificates that each listener of the given Sōzu instance
+    /// holds and will hold once the new metadata of the given directories, all
+    /// if none, is applied, alongside the requests to send to go from the
+    /// former to the latter.
+    ///
+    /// Listeners are the ones of the instance, the ones overriden by a
+    /// directory and the ones which still hold certificates, which are removed
+    /// from the ones that are not used anymore.
+    fn transition(
+        &self,
+        target: &Target,
+        metadata: &HashMap<PathBuf, Metadata>,
+        scope: Option<&HashSet<PathBuf>>,
+        pki: &HashMap<PathBuf, CertificateAndKey>,
+    ) -> Result<(HashMap<SocketAddr, Held>, message::Requests), message::Error> {
+        let state = self.applied.get(&target.instance.name);
+        let mut addresses = target.listeners.to_owned();
+        let mut others: Vec<_> = state
+            .into_iter()
+            .flat_map(HashMap::keys)
+            .copied()
+            .chain(metadata.values().flat_map(|meta| target.listeners_of(meta)))
+            .filter(|address| !addresses.contains(address))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        others.sort();
+        addresses.extend(others);
+
+        for meta in metadata.values() {
+            if let Some(listener) = meta
+                .listener
+                .filter(|_| target.listeners_of(meta).is_empty())
+            {
+                warn!(
+                    instance = target.instance.name,
+                    path = meta.path.display().to_string(),
+                    listener = listener.to_string(),
+                    "Listener of certificate directory is not an HTTPS listener of Sōzu instance, do not send it there"
+                );
+            }
+        }
+
+        let mut listeners = HashMap::new();
+        let mut requests = vec![];
+        for address in addresses {
+            let (mut before, others): (HashMap<_, _>, HashMap<_, _>) = state
+                .and_then(|state| state.get(&address))
+                .cloned()
+                .unwrap_or_default()
+                .into_iter()
+                .partition(|(path, _)| scope.map_or(true, |scope| within(scope, path)));
+
+            let mut after = HashMap::new();
+            for (path, meta) in metadata {
+                if !target.listeners_of(meta).contains(&address) {
+                    continue;
+                }
+
+                match before.get_mut(path) {
+                    // Only the listener of its directory changed, which is
+                    // still this one, so there is nothing to send
+                    Some(held)
+                        if *meta
+                            == Metadata {
+                                listener: meta.listener,
+                                ..held.to_owned()
+                            } =>
+                    {
+                        held.listener = meta.listener;
+                    }
+                    // Certificates which were not read from disk, e.g.
+                    // quarantined ones, cannot be sent, the listener keeps the
+                    // one that it holds
+                    Some(held) if !pki.contains_key(path) => {
+                        after.insert(path.to_owned(), held.to_owned());
+                        continue;
+                    }
+                    None if !pki.contains_key(path) => continue,
+                    _ => {}
+                }
+
+                after.insert(path.to_owned(), meta.to_owned());
+            }
+
+            let (_, created) = message::create(
+                &[address],
+                self.config.request_order,
+                self.config.send_priority,
+                &before,
+                &after,
+                &others,
+                pki,
+            )?;
+
+            requests.push(created);
+            listeners.insert(
+                address,
+                Held {
+                    before,
+                    after,
+                    others,
+                },
+            );
+        }
+
+        let requests = message::merge(
+            requests,
+            self.config.request_order,
+            self.config.send_priority,
+            metadata,
+        );
+
+        Ok((listeners, requests))
+    }
+
+    /// Send requests to the given Sōzu instance, requests that it did not
+    /// apply are reverted. Returns the error of the connection if it is dead.
+    #[tracing::instrument(skip_all, fields(instance = target.instance.name))]
+    async fn apply_to(
+        &self,
+        target: &Target,
+        transition: &mut Transition<'_>,
+        requests: message::Requests,
+        (circuit, probe): (Circuit, &mut bool),
+        summary: &mut Summary,
+        rate_limit: Option<u64>,
+    ) -> Option<sozu_client::Error> {
+        let instance = target.instance.name.as_str();
+        let mut requests = self.allow(instance, requests, transition);
+        if Circuit::Closed != circuit {
+            let kept = usize::from(*probe);
+            *probe &= requests.is_empty();
+            for (path, request) in requests.drain(kept.min(requests.len())..) {
+                transition.revert(&path, address(&request));
+            }
+
+            debug!(
+                instance = instance,
+                circuit = format!("{circuit:?}"),
+                number = requests.len(),
+                "Circuit breaker is not closed, only send probing requests to the proxy"
+            );
+        }
+        let len = requests.len();
+        debug!(
+            instance = instance,
+            number = len,
+            "Number of requests to send to the proxy"
+        );
+
+        if requests.is_empty() {
+            return None;
+        }
+
+        // Another replica may already be sending requests, those of the
+        // remaining instances are left to it
+        if self.has_lost_lead() {
+            warn!(
+                instance = instance,
+                "Lost leader lock, do not send certificates requests to the proxy"
+            );
+
+            for (path, request) in &requests {
+                transition.revert(path, address(request));
+            }
+
+            return None;
+        }
+
+        info!(
+            instance = instance,
+            number = len,
+            "Send certificates requests to the proxy"
+        );
+
+        if self.config.dry_run {
+            for (idx, (path, request)) in requests.into_iter().enumerate() {
+                let kind = format_request_type(&request);
+                let (names, fingerprint) = transition
+                    .metadata
+                    .get(&path)
+                    .or_else(|| transition.current.get(&path))
+                    .map(|meta| {
+                        (
+                            meta.names.iter().cloned().collect::<Vec<_>>().join(", "),
+                            meta.fingerprint.to_string(),
+                        )
+                    })
+                    .unwrap_or_default();
+
+                info!(
+                    instance = instance,
+                    number = idx + 1,
+                    total = len,
+                    path = path.display().to_string(),
+                    kind = kind,
+                    names = names,
+                    fingerprint = fingerprint,
+                    "Would have sent certificate request to Sōzu (dry-run)"
+                );
+
+                summary.sent += 1;
+                CERTIFICATE_REQUEST_DRYRUN.with_label_values(&[kind]).inc();
+            }
+
+            return None;
+        }
+
+        let sent: Vec<_> = requests
+            .iter()
+            .map(|(path, request)| (path.to_owned(), address(request)))
+            .collect();
+        let checks: Vec<_> = requests
+            .iter()
+            .filter_map(|(path, request)| match request {
+                RequestType::AddCertificate(_) | RequestType::ReplaceCertificate(_) => {
+                    Some((path.to_owned(), address(request)?))
+                }
+                _ => None,
+            })
+            .collect();
+
+        match self
+            .send(target, requests, transition, summary, rate_limit)
+            .await
+        {
+            Ok(()) => {
+                info!(
+                    instance = instance,
+                    number = len,
+                    "Successfully sent certificates requests to the proxy"
+                );
+
+                if self.config.verify_after_send {
+                    self.verify(target, checks, transition, summary).await;
+                }
+
+                None
+            }
+            Err(err) => {
+                // Requests to the other instances are still sent, the ones
+                // of this instance will be sent again on the next lookup
+                error!(
+                    error = err.to_string(),
+                    instance = instance,
+                    "Could not send certificates requests to the proxy"
+                );
+
+                for (path, address) in sent {
+                    transition.revert(&path, address);
+                }
+
+                summary.disconnected.insert(instance.to_owned());
+                Some(err)
+            }
+        }
+    }
+
+    /// Drop requests whose kind is not allowed, their certificates are reverted
+    /// as Sōzu still holds the previous ones
+    fn allow(
+        &self,
+        instance: &str,
+        requests: Vec<(PathBuf, RequestType)>,
+        transition: &mut Transition<'_>,
+    ) -> Vec<(PathBuf, RequestType)> {
+        let mut allowed = vec![];
+        for (path, request) in requests {
+            let operation = match &request {
+                RequestType::AddCertificate(_) => Operation::Add,
+                RequestType::ReplaceCertificate(_) => Operation::Replace,
+                RequestType::RemoveCertificate(_) => Operation::Remove,
+                _ => {
+                    allowed.push((path, request));
+                    continue;
+                }
+            };
+
+            if self.config.allowed_operations.contains(&operation) {
+                allowed.push((path, request));
+                continue;
+            }
+
+            let kind = format_request_type(&request);
+            info!(
+                instance = instance,
+                path = path.display().to_string(),
+                kind = kind,
+                "Do not send certificate request to the proxy, its kind is not allowed"
+            );
+
+            CERTIFICATE_REQUEST_SUPPRESSED
+                .with_label_values(&[kind])
+                .inc();
+            transition.suppress(&path, address(&request));
+        }
+
+        allowed
+    }
+
+    /// Send requests to the given Sōzu instance, requests that it failed to
+    /// apply are reverted. An error is returned if the connection is dead.
+    ///
+    /// Requests are sent at most at the given rate per second, if any, batches
+    /// are not limited as they are a single request.
+    #[tracing::instrument(skip_all, fields(instance = target.instance.name))]
+    async fn send(
+        &self,
+        target: &Target,
+        requests: Vec<(PathBuf, RequestType)>,
+        transition: &mut Transition<'_>,
+        summary: &mut Summary,
+        rate_limit: Option<u64>,
+    ) -> Result<(), sozu_client::Error> {
+        let instance = target.instance.name.as_str();
+        let client = &target.client;
+        let len = requests.len();
+        let concurrency = self.config.send_concurrency.max(1);
+        let request_timeout = Duration::from_millis(self.config.request_timeout);
+        if self.config.batch
+            && 1 < len
+            && self
+                .send_batch(target, &requests, transition, summary)
+                .await?
+        {
+            return Ok(());
+        }
+
+        if let Some(rate) = rate_limit {
+            info!(
+                number = len,
+                rate = rate,
+                "Limit the rate of requests of the first synchronization"
+            );
+        }
+
+        let mut bucket = rate_limit.map(TokenBucket::new);
+        for phase in phases(requests) {
+            // Responses are consumed in the order of requests, so that the
+            // accounting does not depend on the scheduling
+            let mut responses = stream::iter(phase)
+                .map(|(idx, path, request)| {
+                    // Tokens are reserved in the order of requests
+                    let delay = bucket
+                        .as_mut()
+                        .map(TokenBucket::reserve)
+                        .unwrap_or_default();
+
+                    async move {
+                        if !delay.is_zero() {
+                            sleep(delay).await;
+                        }
+
+                        trace!(
+                            number = idx + 1,
+                            total = len,
+                            "Send certificate request to Sōzu"
+                        );
+
+                        // Each request is traced as a child of the lookup
+                        let span = info_span!(
+                            "send",
+                            path = path.display().to_string(),
+                            kind = format_request_type(&request)
+                        );
+
+                        let result = timeout(request_timeout, client.send(request.to_owned()))
+                            .instrument(span)
+                            .await;
+
+                        (idx, path, request, result)
+                    }
+                })
+                .buffered(concurrency);
+
+            while let Some((idx, path, request, result)) = responses.next().await {
+                let kind = format_request_type(&request);
+                let (err, rejected) = match result {
+                    Ok(Ok(_)) => {
+                        summary.sent += 1;
+                        target.set_connected(true);
+                        CERTIFICATE_REQUEST_EMITTED
+                            .with_label_values(&[instance, kind, &self.source(&path)])
+                            .inc();
+
+                        if 0 == idx % 1000 {
+                            info!(
+                                number = idx + 1,
+                                total = len,
+                                "Successfully sent request to Sōzu"
+                            );
+                        }
+
+                        trace!(
+                            number = idx + 1,
+                            total = len,
+                            "Successfully sent request to Sōzu"
+                        );
+
+                        continue;
+                    }
+                    Ok(Err(err)) if is_invalid_certificate(&request, &err) => {
+                        target.set_connected(true);
+                        summary.invalid.insert(path.to_owned());
+                        CERTIFICATE_REJECTED_BY_PROXY
+                            .with_label_values(&[instance])
+                            .inc();
+
+                        error!(
+                            error = err.to_string(),
+                            path = path.display().to_string(),
+                            names = transition
+                                .metadata
+                                .get(&path)
+                                .map(|meta| meta.names.iter().cloned().collect::<Vec<_>>().join(", "))
+                                .unwrap_or_default(),
+                            "Sōzu rejected certificate as invalid, quarantine it until it changes on disk"
+                        );
+
+                        (err.to_string(), true)
+                    }
+                    Ok(Err(err)) if matches!(err, sozu_client::Error::Failure(..)) => {
+                        target.set_connected(true);
+                        (err.to_string(), true)
+                    }
+                    Ok(Err(err)) => {
+                        target.set_connected(false);
+                        CERTIFICATE_REQUEST_EMITTED_ERROR
+                            .with_label_values(&[instance, kind, &self.source(&path)])
+                            .inc();
+
+                        return Err(err);
+                    }
+                    Err(_) => {
+                        target.set_connected(false);
+                        summary.disconnected.insert(instance.to_owned());
+                        let err = format!(
+                            "no answer from Sōzu within {}ms",
+                            request_timeout.as_millis()
+                        );
+
+                        (err, false)
+                    }
+                };
+
+                // This will be retried in the next iteration. Only an answer of
+                // Sōzu counts towards quarantine, a request which got none may
+                // well be applied by a healthy Sōzu.
+                summary.failed += 1;
+                summary.errors.push(format!("{}: {err}", path.display()));
+                if rejected {
+                    summary.rejected.insert(path.to_owned());
+                }
+                transition.revert(&path, address(&request));
+
+                CERTIFICATE_REQUEST_EMITTED_ERROR
+                    .with_label_values(&[instance, kind, &self.source(&path)])
+                    .inc();
+
+                error!(
+                    error = err,
+                    number = idx + 1,
+                    total = len,
+                    path = path.display().to_string(),
+                    kind = kind,
+                    "Could not send certificate request to Sōzu"
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Check that the workers of the given Sōzu instance serve the certificates
+    /// that were sent to it on their listener, the ones that are not served
+    /// are reverted so that they are sent again on the next lookup
+    #[tracing::instrument(skip_all, fields(instance = target.instance.name))]
+    async fn verify(
+        &self,
+        target: &Target,
+        checks: Vec<(PathBuf, SocketAddr)>,
+        transition: &mut Transition<'_>,
+        summary: &mut Summary,
+    ) {
+        let instance = target.instance.name.as_str();
+        let request_timeout = Duration::from_millis(self.config.request_timeout);
+        for (path, address) in checks {
+            // Requests that failed are already reverted
+            let Some(fingerprint) = transition
+                .listeners
+                .get(&address)
+                .and_then(|held| {
+                    held.after
+                        .get(&path)
+                        .filter(|meta| Some(*meta) != held.before.get(&path))
+                })
+                .map(|meta| meta.fingerprint.to_string())
+            else {
+                continue;
+            };
+
+            let request = RequestType::QueryCertificatesFromWorkers(QueryCertificatesFilters {
+                domain: None,
+                fingerprint: Some(fingerprint.to_owned()),
+            });
+
+            let (err, rejected) = match timeout(request_timeout, target.client.send(request)).await
+            {
+                Ok(Ok(response))
+                    if is_served(response.content.as_ref(), &address, &fingerprint) =>
+                {
+                    trace!(
+                        path = path.display().to_string(),
+                        fingerprint = fingerprint,
+                        "Sōzu serves certificate on the listener"
+                    );
+
+                    continue;
+                }
+                Ok(Ok(_)) => ("Sōzu does not serve it on the listener".to_string(), true),
+                Ok(Err(err)) => {
+                    let rejected = matches!(err, sozu_client::Error::Failure(..));
+                    (err.to_string(), rejected)
+                }
+                Err(_) => {
+                    let err = format!(
+                        "no answer from Sōzu within {}ms",
+                        request_timeout.as_millis()
+                    );
+
+                    (err, false)
+                }
+            };
+
+            error!(
+                error = err,
+                instance = instance,
+                path = path.display().to_string(),
+                address = address.to_string(),
+                fingerprint = fingerprint,
+                "Could not verify that certificate is installed in Sōzu, send it again on the next lookup"
+            );
+
+            CERTIFICATE_VERIFY_FAILED
+                .with_label_values(&[instance])
+                .inc();
+
+            summary.failed += 1;
+            summary
+                .errors
+                .push(format!("{}: verification failed, {err}", path.display()));
+            if rejected {
+                summary.rejected.insert(path.to_owned());
+            }
+            transition.revert(&path, Some(address));
+        }
+    }
+
+    /// Send requests to the given Sōzu instance as a single batch. A batch is
+    /// not atomic: if it fails, the state of Sōzu tells which requests were
+    /// applied and the others are reverted. Returns false if the batch could
+    /// not be written, in which case requests should be sent one by one.
+    #[tracing::instrument(skip_all, fields(instance = target.instance.name))]
+    async fn send_batch(
+        &self,
+        target: &Target,
+        requests: &message::Requests,
+        transition: &mut Transition<'_>,
+        summary: &mut Summary,
+    ) -> Result<bool, sozu_client::Error> {
+        let instance = target.instance.name.as_str();
+        let len = requests.len();
+        let request_timeout = Duration::from_millis(self.config.request_timeout);
+        let batch: Vec<_> = requests
+            .iter()
+            .map(|(_, request)| request.to_owned())
+            .collect();
+
+        debug!(
+            number = len,
+            "Send certificates requests to Sōzu as a batch"
+        );
+        let err = match timeout(request_timeout, target.client.send_all(&batch)).await {
+            Ok(Ok(_)) => {
+                summary.sent += len;
+                target.set_connected(true);
+                for (path, request) in requests {
+                    CERTIFICATE_REQUEST_EMITTED
+                        .with_label_values(&[
+                            instance,
+                            format_request_type(request),
+                            &self.source(path),
+                        ])
+                        .inc();
+                }
+
+                info!(number = len, "Successfully sent batch of requests to Sōzu");
+
+                return Ok(true);
+            }
+            Ok(Err(
+                err @ (sozu_client::Error::CreateTempDir(_)
+                | sozu_client::Error::CreateTempFile(_)
+                | sozu_client::Error::Serialize(_)
+                | sozu_client::Error::Write(_)
+                | sozu_client::Error::Flush(_)),
+            )) => {
+                warn!(
+                    error = err.to_string(),
+                    "Could not write batch of requests, send them one by one"
+                );
+
+                return Ok(false);
+            }
+            Ok(Err(err)) if matches!(err, sozu_client::Error::Failure(..)) => {
+                target.set_connected(true);
+                err.to_string()
+            }
+            Ok(Err(err)) => {
+                target.set_connected(false);
+                for (path, request) in requests {
+                    CERTIFICATE_REQUEST_EMITTED_ERROR
+                        .with_label_values(&[
+                            instance,
+                            format_request_type(request),
+                            &self.source(path),
+                        ])
+                        .inc();
+                }
+
+                return Err(err);
+            }
+            Err(_) => {
+                target.set_connected(false);
+                summary.disconnected.insert(instance.to_owned());
+                format!(
+                    "no answer from Sōzu within {}ms",
+                    request_timeout.as_millis()
+                )
+            }
+        };
+
+        error!(
+            error = err,
+            number = len,
+            "Could not send batch of requests to Sōzu"
+        );
+
+        // Sōzu applies requests of a batch one by one, so some of them may
+        // have been applied. Its state tells which ones, they are kept and the
+        // others will be retried in the next iteration.
+        let installed = match timeout(request_timeout, Self::installed(target.client.as_ref()))
+            .await
+        {
+            Ok(Ok(installed)) => Some(installed),
+            Ok(Err(err)) => {
+                warn!(
+                    error = err.to_string(),
+                    "Could not retrieve certificates installed in Sōzu, retry the whole batch"
+                );
+
+                None
+            }
+            Err(_) => {
+                warn!("Could not retrieve certificates installed in Sōzu in time, retry the whole batch");
+                None
+            }
+        };
+
+        // Directories are not marked as rejected, as the faulty one is unknown
+        // and the others must not be quarantined because of it.
+        for (path, request) in requests {
+            if installed
+                .as_ref()
+                .is_some_and(|installed| is_installed(installed, transition, path, request))
+            {
+                summary.sent += 1;
+                CERTIFICATE_REQUEST_EMITTED
+                    .with_label_values(&[
+                        instance,
+                        format_request_type(request),
+                        &self.source(path),
+                    ])
+                    .inc();
+
+                continue;
+            }
+
+            summary.failed += 1;
+            summary.errors.push(format!("{}: {err}", path.display()));
+            transition.revert(path, address(request));
+
+            CERTIFICATE_REQUEST_EMITTED_ERROR
+                .with_label_values(&[instance, format_request_type(request), &self.source(path)])
+                .inc();
+        }
+
+        Ok(true)
+    }
+}
+
+// -----------------------------------------------------------------------------
+// helpers
+
+/// Returns the listener that the given request targets, if any
+fn address(request: &RequestType) -> Option<SocketAddr> {
+    match request {
+        RequestType::AddCertificate(add) => Some(add.address.to_owned().into()),
+        RequestType::ReplaceCertificate(replace) => Some(replace.address.to_owned().into()),
+        RequestType::RemoveCertificate(remove) => Some(remove.address.to_owned().into()),
+        _ => None,
+    }
+}
+
+/// Returns true if Sōzu shows that the given request has been applied, i.e.
+/// the added certificate is served on the listener or the removed one is not
+fn is_installed(
+    installed: &Served,
+    transition: &Transition<'_>,
+    path: &Path,
+    request: &RequestType,
+) -> bool {
+    let Some(address) = address(request) else {
+        return false;
+    };
+
+    let Some(held) = transition.listeners.get(&address) else {
+        return false;
+    };
+
+    let installed = installed.get(&address);
+
+    match request {
+        RequestType::AddCertificate(_) | RequestType::ReplaceCertificate(_) => {
+            held.after.get(path).is_some_and(|meta| {
+                installed.is_some_and(|served| served.contains_key(&meta.fingerprint))
+            })
+        }
+        RequestType::RemoveCertificate(_) => held.before.get(path).is_some_and(|meta| {
+            !installed.is_some_and(|served| served.contains_key(&meta.fingerprint))
+        }),
+        _ => false,
+    }
+}
+
+/// Returns the certificates held by every listener that they belong on, of
+/// every given Sōzu instance
+fn everywhere(applied: &Applied, targets: &[Target]) -> HashMap<PathBuf, Metadata> {
+    let held = |target: &Target, listener: &SocketAddr, path: &Path| {
+        applied
+            .get(&target.instance.name)
+            .and_then(|listeners| listeners.get(listener))
+            .and_then(|metadata| metadata.get(path))
+    };
+
+    let mut metadata = HashMap::new();
+    for (path, meta) in applied.values().flat_map(HashMap::values).flatten() {
+        if metadata.contains_key(path) {
+            continue;
+        }
+
+        let everywhere = targets.iter().all(|target| {
+            target
+                .listeners_of(meta)
+                .iter()
+                .all(|listener| held(target, listener, path) == Some(meta))
+        });
+
+        if everywhere {
+            metadata.insert(path.to_owned(), meta.to_owned());
+        }
+    }
+
+    metadata
+}
+
+/// Restore the current state of the given directory and of the ones sharing
+/// one of its certificates, as they rely on the same requests
+fn revert(
+    current: &HashMap<PathBuf, Metadata>,
+    metadata: &mut HashMap<PathBuf, Metadata>,
+    path: &Path,
+) {
+    let fingerprints: HashSet<Fingerprint> = current
+        .get(path)
+        .into_iter()
+        .chain(metadata.get(path))
+        .map(|meta| meta.fingerprint.to_owned())
+        .collect();
+
+    let paths: HashSet<PathBuf> = current
+        .keys()
+        .chain(metadata.keys())
+        .filter(|path| current.get(*path) != metadata.get(*path))
+        .filter(|path| {
+            current
+                .get(*path)
+                .into_iter()
+                .chain(metadata.get(*path))
+                .any(|meta| fingerprints.contains(&meta.fingerprint))
+        })
+        .cloned()
+        .collect();
+
+    for path in paths {
+        match current.get(&path) {
+            Some(meta) => {
+                metadata.insert(path, meta.to_owned());
+            }
+            None => {
+                metadata.remove(&path);
+            }
+        }
+    }
+}
+
+/// Returns true if Sōzu answered that the certificate of the given request is
+/// invalid, as opposed to a failure which may go away, e.g. a missing listener.
+/// The answer only carries a message, which holds the one of the certificate
+/// errors of Sōzu.
+fn is_invalid_certificate(request: &RequestType, err: &sozu_client::Error) -> bool {
+    if !matches!(
+        request,
+        RequestType::AddCertificate(_) | RequestType::ReplaceCertificate(_)
+    ) {
+        return false;
+    }
+
+    let sozu_client::Error::Failure(_, message, _) = err else {
+        return false;
+    };
+
+    // The message is lowercased by the client
+    [
+        "could not parse pem certificate",
+        "could not parse x509 certificate",
+        "failed to parse tls version",
+        "failed at decoding the hex encoded certificate",
+        "invalid certificate",
+        "invalid private key",
+    ]
+    .iter()
+    .any(|marker| message.contains(marker))
+}
+
+/// Returns true if the given path is one of the given directories or is
+/// nested in one of them
+fn within(directories: &HashSet<PathBuf>, path: &Path) -> bool {
+    directories
+        .iter()
+        .any(|directory| path.starts_with(directory))
+}
+
+/// Split requests into consecutive runs of the same kind, keeping their index.
+///
+/// Requests of a run may be sent concurrently, while runs are sent one after
+/// the other to preserve the configured request order.
+fn phases(requests: message::Requests) -> Vec<Vec<(usize, PathBuf, RequestType)>> {
+    let mut phases: Vec<Vec<(usize, PathBuf, RequestType)>> = vec![];
+    for (idx, (path, request)) in requests.into_iter().enumerate() {
+        match phases.last_mut() {
+            Some(phase)
+                if phase.last().is_some_and(|(_, _, last)| {
+                    format_request_type(last) == format_request_type(&request)
+                }) =>
+            {
+                phase.push((idx, path, request));
+            }
+            _ => phases.push(vec![(idx, path, request)]),
+        }
+    }
+
+    phases
+}
+
+/// Resolve the addresses of the HTTPS listeners of the given instance, the
+/// first HTTPS listener of its Sōzu configuration is used if none is given
+#[tracing::instrument(skip_all, fields(instance = instance.name))]
+pub async fn listeners(instance: &Instance) -> Result<Vec<SocketAddr>, Error> {
+    if !instance.listener.is_empty() {
+        return resolve(&instance.listener).await;
+    }
+
+    let sozu_config =
+        sozu_client::config::try_from(&instance.configuration).map_err(Error::SozuConfiguration)?;
+
+    let addr: SocketAddr = sozu_config
+        .https_listeners
+        .first()
+        .map(|listener| listener.address.to_owned().into())
+        .ok_or_else(|| Error::NoHttpsListener(instance.configuration.to_owned()))?;
+
+    info!(
+        address = addr.to_string(),
+        path = instance.configuration.display().to_string(),
+        "Use the first HTTPS listener of the Sōzu configuration"
+    );
+
+    Ok(vec![addr])
+}
+
+/// Resolve the addresses of the HTTPS listeners, a `host:port` listener is
+/// resolved to all of its addresses
+#[tracing::instrument]
+pub async fn resolve(listeners: &[String]) -> Result<Vec<SocketAddr>, Error> {
+    let mut addrs = vec![];
+    for listener in listeners {
+        if let Ok(addr) = listener.parse::<SocketAddr>() {
+            addrs.push(addr);
+            continue;
+        }
+
+        let resolved: Vec<_> = lookup_host(listener)
+            .await
+            .map_err(|err| Error::ResolveListener(listener.to_owned(), err))?
+            .collect();
+
+        if resolved.is_empty() {
+            return Err(Error::NoListenerAddress(listener.to_owned()));
+        }
+
+        info!(
+            listener = listener,
+            addresses = format!("{resolved:?}"),
+            "Resolved HTTPS listener"
+        );
+
+        addrs.extend(resolved);
+    }
+
+    let mut seen = HashSet::new();
+    addrs.retain(|addr| seen.insert(*addr));
+    Ok(addrs)
+}
+
+/// Load the configuration of the given Sōzu instance and create a client to
+/// its command socket
+#[tracing::instrument(skip_all, fields(instance = instance.name))]
+pub async fn connect(instance: &Instance) -> Result<Client, Error> {
+    // -------------------------------------------------------------------------
+    // Load Sōzu configuration
+    info!(
+        path = instance.configuration.display().to_string(),
+        "Load Sōzu configuration"
+    );
+
+    let sozu_config = Arc::new(
+        sozu_client::config::try_from(&instance.configuration).map_err(Error::SozuConfiguration)?,
+    );
+
+    // -------------------------------------------------------------------------
+    // Create Sōzu client
+    info!("Create Sōzu client");
+    let mut opts = ConnectionProperties::from(&*sozu_config);
+    match &instance.endpoint {
+        None if opts.socket.is_relative() => {
+            opts.socket = canonicalize_command_socket(&instance.configuration, &sozu_config)
+                .map_err(Error::CanonicalizeSocket)?;
+        }
+        None => {}
+        Some(Endpoint {
+            unix: Some(socket),
+            tcp: None,
+        }) => {
+            info!(
+                path = socket.display().to_string(),
+                "Use command socket from the connector configuration"
+            );
+
+            opts.socket = socket.to_owned();
+        }
+        // Refused when validating the configuration, the command channel of
+        // Sōzu is a unix socket
+        Some(_) => return Err(Error::InvalidEndpoint),
+    }
+
+    // The client connects lazily, so check that Sōzu listens on the command
+    // socket rather than waiting for the first request to time out
+    UnixStream::connect(&opts.socket)
+        .await
+        .map_err(|err| Error::Unreachable(opts.socket.to_owned(), err))?;
+
+    Client::try_new(opts).await.map_err(Error::CreateClient)
+}
+
+/// Look up the pki directory a single time and return the summary of requests
+/// sent to Sōzu
+#[tracing::instrument(skip_all)]
+pub async fn lookup_once(config: Arc<ConnectorConfiguration>) -> Result<Summary, Error> {
+    // Nobody will ever ask this watcher to shut down
+    let (_, shutdown) = watch::channel(false);
+    let mut watcher = Watcher::try_new(config, Arc::default(), Arc::default(), shutdown).await?;
+    watcher.lookup().await
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn lookup_every(
+    mut configs: watch::Receiver<Arc<ConnectorConfiguration>>,
+    health: Arc<Health>,
+    inventory: Inventory,
+    shutdown: watch::Receiver<bool>,
+    syncs: mpsc::Receiver<SyncRequest>,
+) -> Result<(), Error> {
+    let config = configs.borrow_and_update().to_owned();
+    let watcher = Watcher::try_new(config, health, inventory, shutdown.to_owned()).await?;
+
+    watch_with(watcher, configs, shutdown, syncs).await
+}
+
+/// Look up certificates with the given watcher until the shutdown, on each
+/// tick, filesystem event or sync request, see [`lookup_every`]
+#[tracing::instrument(skip_all)]
+pub async fn watch_with(
+    mut watcher: Watcher,
+    mut configs: watch::Receiver<Arc<ConnectorConfiguration>>,
+    mut shutdown: watch::Receiver<bool>,
+    mut syncs: mpsc::Receiver<SyncRequest>,
+) -> Result<(), Error> {
+    let mut config = watcher.config.to_owned();
+    let mut ticker = interval(Duration::from_millis(config.interval));
+    let mut standby = standby_ticker(&config);
+
+    // -------------------------------------------------------------------------
+    // Listen to filesystem events, if needed
+    let mut listener = listen(&config).map_err(Error::Events)?;
+    let mut rewatch = interval(events::REWATCH_DELAY);
+    rewatch.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut expiries = interval(EXPIRY_REFRESH_DELAY);
+    expiries.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let mut coalescer = Coalescer::new(Duration::from_millis(config.coalesce));
+    let mut debouncer = Debouncer::new(
+        Duration::from_millis(config.debounce),
+        Duration::from_millis(config.max_debounce),
+    );
+
+    // The first tick completes immediately, which triggers the initial full
+    // lookup whatever the watch mode is.
+    ticker.tick().await;
+    full_lookup(&mut watcher, &mut ticker).await;
+    debouncer.push(watcher.take_unstable());
+
+    loop {
+        if *shutdown.borrow() {
+            info!("Stop to lookup certificates directory");
+            return Ok(());
+        }
+
+        info!("Waiting for next iteration to lookup certificates directory");
+        tokio::select! {
+            // An error means that the sender has been dropped, which also
+            // means that we are shutting down.
+            _ = shutdown.changed() => {
+                info!("Stop to lookup certificates directory");
+                return Ok(());
+            }
+            r = configs.changed() => {
+                if r.is_err() {
+                    info!("Stop to lookup certificates directory");
+                    return Ok(());
+                }
+
+                // -------------------------------------------------------------
+                // Apply the new configuration
+                let new = configs.borrow_and_update().to_owned();
+                let old = std::mem::replace(&mut config, new.to_owned());
+                watcher.reload(new).await;
+
+                if old.sozu.pki != config.sozu.pki
+                    || old.watch_mode != config.watch_mode
+                    || old.layout != config.layout
+                {
+                    listener = match listen(&config) {
+                        Ok(listener) => listener,
+                        Err(err) => {
+                            error!(
+                                error = err.to_string(),
+                                "Could not listen to filesystem events with the new configuration, fall back to polling"
+                            );
+
+                            None
+                        }
+                    };
+                }
+
+                coalescer = Coalescer::new(Duration::from_millis(config.coalesce));
+                debouncer = Debouncer::new(
+                    Duration::from_millis(config.debounce),
+                    Duration::from_millis(config.max_debounce),
+                );
+
+                // Look up with the new configuration whatever the watch mode
+                // is, which also sends certificates to new instances and
+                // listeners
+                ticker = interval(Duration::from_millis(config.interval));
+                standby = standby_ticker(&config);
+                ticker.tick().await;
+                full_lookup(&mut watcher, &mut ticker).await;
+                debouncer.push(watcher.take_unstable());
+            }
+            Some(request) = syncs.recv() => {
+                // -------------------------------------------------------------
+                // Coalesce pending requests into a single lookup
+                let mut requests = vec![request];
+                while let Ok(request) = syncs.try_recv() {
+                    requests.push(request);
+                }
+
+                info!(number = requests.len(), "Lookup certificates directory on demand");
+                let pending = watcher.is_pending();
+                let result = watcher.lookup().await.map_err(|err| err.to_string());
+                if let Err(err) = &result {
+                    warn!(
+                        error = err,
+                        "Could not lookup into pki directory and send updates to Sōzu"
+                    );
+                }
+
+                throttle(&watcher, &mut ticker);
+                retry(&watcher, &mut ticker, pending);
+                debouncer.push(watcher.take_unstable());
+                for request in requests {
+                    // The requester may have gone away, nothing to do then
+                    let _ = request.send(result.to_owned());
+                }
+            }
+            // In events mode, requests left pending are retried by full
+            // lookups, as no event may ever come for their directories
+            _ = ticker.tick(), if WatchMode::Events != config.watch_mode
+                || listener.is_none()
+                || watcher.is_pending() => {
+                full_lookup(&mut watcher, &mut ticker).await;
+                debouncer.push(watcher.take_unstable());
+            }
+            // A standby replica tries to take over on its own, as full lookups
+            // may not happen in events mode
+            _ = standby.tick(), if watcher.is_standby() => {
+                if watcher.try_lead().await {
+                    full_lookup(&mut watcher, &mut ticker).await;
+                    debouncer.push(watcher.take_unstable());
+                }
+            }
+            // Expiry gauges are relative to the current time, they would be
+            // frozen between lookups which may not happen in events mode
+            _ = expiries.tick() => watcher.refresh_expiries(),
+            // Filesystem events do not tell when certificates become valid
+            _ = wait_for(watcher.activation()), if WatchMode::Events == config.watch_mode
+                && listener.is_some() => {
+                let paths = watcher.take_activated();
+                if paths.is_empty() {
+                    continue;
+                }
+
+                info!(number = paths.len(), "Certificates became valid, look up their directories");
+                let pending = watcher.is_pending();
+                if let Err(err) = watcher.lookup_paths(&paths).await {
+                    warn!(
+                        error = err.to_string(),
+                        "Could not lookup directories of certificates which became valid and send updates to Sōzu"
+                    );
+                }
+
+                throttle(&watcher, &mut ticker);
+                retry(&watcher, &mut ticker, pending);
+                debouncer.push(watcher.take_unstable());
+            }
+            change = next_change(&mut listener) => {
+                coalescer.push(change.map_err(Error::Events)?);
+            }
+            // Removed pki directories are watched again from their own branch,
+            // as waiting for the next change may be interrupted by any other
+            _ = rewatch.tick(), if listener.as_ref().is_some_and(EventListener::is_removed) => {
+                if let Some(listener) = listener.as_mut() {
+                    if let Some(change) = listener.rewatch().await {
+                        coalescer.push(change);
+                    }
+                }
+            }
+            _ = wait_for(coalescer.deadline()) => {
+                match coalescer.due() {
+                    Some(Change::All) => {
+                        let pending = watcher.is_pending();
+                        debouncer.clear();
+                        full_lookup(&mut watcher, &mut ticker).await;
+                        retry(&watcher, &mut ticker, pending);
+                        debouncer.push(watcher.take_unstable());
+                    }
+                    Some(Change::Directories(paths)) => debouncer.push(paths),
+                    None => {}
+                }
+            }
+            _ = wait_for(debouncer.deadline()) => {
+                let paths = debouncer.due();
+                if paths.is_empty() {
+                    continue;
+                }
+
+                let pending = watcher.is_pending();
+                if let Err(err) = watcher.lookup_paths(&paths).await {
+                    warn!(
+                        error = err.to_string(),
+                        "Could not lookup changed directories and send updates to Sōzu"
+                    );
+                }
+
+                throttle(&watcher, &mut ticker);
+                retry(&watcher, &mut ticker, pending);
+                debouncer.push(watcher.take_unstable());
+            }
+        }
+    }
+}
+
+/// Ticker of the attempts of a standby replica to acquire the leader lock,
+/// ticks missed while leading are skipped
+fn standby_ticker(config: &ConnectorConfiguration) -> Interval {
+    let mut ticker = interval(Duration::from_millis(config.interval));
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    ticker
+}
+
+/// Listen to filesystem events of the pki directories, if the watch mode
+/// needs it
+fn listen(config: &ConnectorConfiguration) -> Result<Option<EventListener>, events::Error> {
+    match config.watch_mode {
+        WatchMode::Poll => Ok(None),
+        WatchMode::Events | WatchMode::Hybrid => {
+            // Archives are extracted again as a whole on full lookups, their
+            // extraction directory is replaced and cannot be watched
+            let roots = config
+                .sozu
+                .pki
+                .iter()
+                .filter(|path| !Archive::is_archive(path))
+                .cloned()
+                .collect();
+
+            EventListener::try_new(roots, config.max_depth, config.layout.to_owned()).map(Some)
+        }
+    }
+}
+
+/// Look up the whole pki directory and delay the next tick if requests keep
+/// failing to be sent to Sōzu, else spread it using the configured jitter
+#[tracing::instrument(skip_all)]
+async fn full_lookup(watcher: &mut Watcher, ticker: &mut Interval) {
+    if let Err(err) = watcher.lookup().await {
+        warn!(
+            error = err.to_string(),
+            "Could not lookup into pki directory and send updates to Sōzu"
+        );
+    }
+
+    if throttle(watcher, ticker) {
+        return;
+    }
+
+    let mut delay = Duration::from_millis(watcher.config.interval);
+    if 0 != watcher.config.interval_jitter {
+        delay = jitter(
+            delay,
+            watcher.config.interval_jitter,
+            rand::thread_rng().gen(),
+        );
+
+        trace!(
+            delay = delay.as_millis(),
+            "Delay the next lookup with jitter"
+        );
+        ticker.reset_after(delay);
+    }
+
+    if WatchMode::Events == watcher.config.watch_mode && !watcher.is_pending() {
+        watcher.schedule(None);
+    } else {
+        watcher.schedule(Some(delay));
+    }
+}
+
+/// Schedule the next tick after the interval if the last lookup left
+/// requests pending while none were, so that a full lookup retries them even
+/// in events mode, unless it is already delayed by [`throttle`]. Ticks are not
+/// awaited in events mode while nothing is pending, hence the reset.
+fn retry(watcher: &Watcher, ticker: &mut Interval, was_pending: bool) {
+    if was_pending || !watcher.is_pending() || watcher.backoff().is_some() {
+        return;
+    }
+
+    let delay = Duration::from_millis(watcher.config.interval);
+    ticker.reset_after(delay);
+    watcher.schedule(Some(delay));
+}
+
+/// Delay the next tick if requests keep failing to be sent to Sōzu, returns
+/// true if it has been delayed
+fn throttle(watcher: &Watcher, ticker: &mut Interval) -> bool {
+    if let Some(delay) = watcher.backoff() {
+        warn!(
+            delay = delay.as_millis(),
+            "Requests to Sōzu keep failing, delay the next lookup"
+        );
+
+        ticker.reset_after(delay);
+        watcher.schedule(Some(delay));
+        return true;
+    }
+
+    false
+}
+
+/// Deviate the interval by at most the given percentage, capped to 50 so that
+/// lookups never run back to back, in either direction. The sample is a random
+/// number in `[0, 1)`, which is given by the caller so that the source of
+/// randomness can be chosen.
+pub fn jitter(interval: Duration, percent: u64, sample: f64) -> Duration {
+    let ratio = percent.min(50) as f64 / 100.0;
+    interval.mul_f64(1.0 + ratio * (2.0 * sample.clamp(0.0, 1.0) - 1.0))
+}
+
+/// Exponential backoff with jitter starting at base and capped at max, the
+/// delay never goes below base
+pub fn backoff(base: Duration, attempts: u32, max: Duration) -> Duration {
+    let delay = base
+        .saturating_mul(2u32.saturating_pow(attempts.min(16)))
+        .min(max)
+        .max(base);
+
+    // Use a jitter between the half and the whole delay
+    let half = delay / 2;
+    half + half.mul_f64(rand::thread_rng().gen::<f64>())
+}
+
+/// Token bucket holding up to a second of requests at the given rate per
+/// second, a request that does not find a token waits for its refill
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        Self {
+            rate: rate.max(1) as f64,
+            tokens: rate.max(1) as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Take a token, which may be one to come, and returns the delay to wait
+    /// for it
+    fn reserve(&mut self) -> Duration {
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate) - 1.0;
+        self.last = now;
+
+        if 0.0 <= self.tokens {
+            return Duration::ZERO;
+        }
+
+        Duration::from_secs_f64(-self.tokens / self.rate)
+    }
+}
+
+/// Returns true if every worker answering a query of certificates by
+/// fingerprint serves the given fingerprint on the given listener
+fn is_served(content: Option<&ResponseContent>, address: &SocketAddr, fingerprint: &str) -> bool {
+    served(content)
+        .and_then(|mut served| served.remove(address))
+        .is_some_and(|fingerprints| fingerprints.contains(fingerprint))
+}
+
+/// Returns the fingerprints of the certificates that every worker serves on
+/// each listener, from the answer to a query of certificates from workers, or
+/// nothing if it is not such an answer
+fn served(content: Option<&ResponseContent>) -> Option<HashMap<SocketAddr, HashSet<String>>> {
+    let lists = match content.and_then(|content| content.content_type.as_ref()) {
+        Some(ContentType::WorkerResponses(WorkerResponses { map })) => map
+            .values()
+            .map(|content| match &content.content_type {
+                Some(ContentType::CertificatesByAddress(list)) => Some(list),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>(),
+        Some(ContentType::CertificatesByAddress(list)) => Some(vec![list]),
+        _ => None,
+    };
+
+    let mut served: Option<HashMap<SocketAddr, HashSet<String>>> = None;
+    for ListOfCertificatesByAddress { certificates } in lists? {
+        let mut list: HashMap<SocketAddr, HashSet<String>> = HashMap::new();
+        for by_address in certificates {
+            list.entry(by_address.address.to_owned().into())
+                .or_default()
+                .extend(
+                    by_address
+                        .certificate_summaries
+                        .iter()
+                        .map(|summary| summary.fingerprint.to_owned()),
+                );
+        }
+
+        served = Some(match served {
+            Some(mut acc) => {
+                acc.retain(|address, fingerprints| match list.get(address) {
+                    Some(found) => {
+                        fingerprints.retain(|fingerprint| found.contains(fingerprint));
+                        true
+                    }
+                    None => false,
+                });
+
+                acc
+            }
+            None => list,
+        });
+    }
+
+    Some(served.unwrap_or_default())
+}
+
+/// Milliseconds since the epoch of the given time
+fn millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as i64)
+        .unwrap_or_default()
+}
+
+/// Wait for the next change of the listener, if any, else wait forever
+async fn next_change(listener: &mut Option<EventListener>) -> Result<Change, events::Error> {
+    match listener {
+        Some(listener) => listener.next().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Wait until the given deadline, if any, else wait forever
+async fn wait_for(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::{
+        collections::{BTreeMap, VecDeque},
+        sync::Mutex,
+    };
+
+    use sozu_command_lib::{
+        channel::ChannelError,
+        proto::command::{
+            CertificateSummary, CertificatesByAddress, Response, ResponseStatus, TlsVersion,
+        },
+    };
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::svc::{
+        certificates::{
+            archive::tests::tarball,
+            tests::{self_signed, self_signed_from, write_directory},
+        },
+        config::{tests::configuration, ChainVerification, KeyPolicy, LayoutKind, MIN_INTERVAL},
+    };
+
+    /// Answer of the mock sink to a certificate request
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Answer {
+        /// Sōzu applied the request
+        Ok,
+        /// Sōzu failed to apply the request
+        Failure,
+        /// Sōzu never answers
+        Hang,
+        /// The connection to Sōzu is dead
+        Disconnect,
+    }
+
+    #[derive(Default)]
+    struct Record {
+        requests: Vec<RequestType>,
+        installed: Vec<(SocketAddr, String, CertificateAndKey)>,
+        script: VecDeque<Answer>,
+        otherwise: Option<Answer>,
+        unreachable: bool,
+        connections: usize,
+    }
+
+    /// Sink recording certificate requests and answering them as scripted, it
+    /// is also the factory of its own copies which share the same record, so
+    /// that recreated sinks are recorded too. Queries are answered as a Sōzu
+    /// without listeners would do, which serves the certificates that it
+    /// applied.
+    #[derive(Clone, Default)]
+    pub struct Mock {
+        record: Arc<Mutex<Record>>,
+    }
+
+    impl Mock {
+        fn lock(&self) -> std::sync::MutexGuard<'_, Record> {
+            self.record.lock().expect("record to not be poisoned")
+        }
+
+        /// Answer the next certificate requests with the given answers
+        pub fn script(&self, answers: &[Answer]) {
+            self.lock().script.extend(answers);
+        }
+
+        /// Answer certificate requests with the given answer once the script
+        /// is exhausted, instead of applying them
+        pub fn otherwise(&self, answer: Answer) {
+            self.lock().otherwise = Some(answer);
+        }
+
+        /// Returns and forget the certificate requests received so far
+        pub fn take(&self) -> Vec<RequestType> {
+            std::mem::take(&mut self.lock().requests)
+        }
+
+        /// Returns the number of sinks created by the factory
+        pub fn connections(&self) -> usize {
+            self.lock().connections
+        }
+
+        /// Fail queries as if Sōzu could not be reached, certificate requests
+        /// are still answered as scripted
+        pub fn unreachable(&self, unreachable: bool) {
+            self.lock().unreachable = unreachable;
+        }
+
+        async fn answer(&self, request: &RequestType) -> Result<Response, sozu_client::Error> {
+            let content_type = match request {
+                RequestType::QueryCertificatesFromTheState(_) => {
+                    let certs = self
+                        .lock()
+                        .installed
+                        .iter()
+                        .map(|(_, fingerprint, certificate)| {
+                            (fingerprint.to_owned(), certificate.to_owned())
+                        })
+                        .collect();
+
+                    Some(ContentType::CertificatesWithFingerprints(
+                        CertificatesWithFingerprints { certs },
+                    ))
+                }
+                RequestType::QueryCertificatesFromWorkers(_) => {
+                    let mut served: BTreeMap<SocketAddr, Vec<CertificateSummary>> = BTreeMap::new();
+                    for (address, fingerprint, _) in &self.lock().installed {
+                        served
+                            .entry(*address)
+                            .or_default()
+                            .push(CertificateSummary {
+                                domain: String::new(),
+                                fingerprint: fingerprint.to_owned(),
+                            });
+                    }
+
+                    let certificates = served
+                        .into_iter()
+                        .map(|(address, certificate_summaries)| CertificatesByAddress {
+                            address: address.into(),
+                            certificate_summaries,
+                        })
+                        .collect();
+
+                    Some(ContentType::CertificatesByAddress(
+                        ListOfCertificatesByAddress { certificates },
+                    ))
+                }
+                RequestType::ListListeners(_) => {
+                    Some(ContentType::ListenersList(ListenersList::default()))
+                }
+                _ => None,
+            };
+
+            if content_type.is_some() && self.lock().unreachable {
+                return Err(sozu_client::Error::Receive(ChannelError::NothingRead));
+            }
+
+            if content_type.is_some() {
+                return Ok(Response {
+                    status: ResponseStatus::Ok.into(),
+                    message: String::new(),
+                    content: Some(ResponseContent { content_type }),
+                });
+            }
+
+            let answer = {
+                let mut record = self.lock();
+                record.requests.push(request.to_owned());
+                let otherwise = record.otherwise.unwrap_or(Answer::Ok);
+                record.script.pop_front().unwrap_or(otherwise)
+            };
+
+            match answer {
+                Answer::Ok => {
+                    self.apply(request).await;
+                    Ok(Response {
+                        status: ResponseStatus::Ok.into(),
+                        message: String::new(),
+                        content: None,
+                    })
+                }
+                Answer::Failure => Err(sozu_client::Error::Failure(
+                    "FAILURE".to_string(),
+                    "could not apply request".to_string(),
+                    Response::default(),
+                )),
+                Answer::Hang => std::future::pending().await,
+                Answer::Disconnect => Err(sozu_client::Error::Receive(ChannelError::NothingRead)),
+            }
+        }
+    }
+
+    impl Mock {
+        /// Update the certificates served as Sōzu would do on the given
+        /// request
+        async fn apply(&self, request: &RequestType) {
+            let (address, removed, added) = match request {
+                RequestType::AddCertificate(add) => (&add.address, None, Some(&add.certificate)),
+                RequestType::ReplaceCertificate(replace) => (
+                    &replace.address,
+                    Some(&replace.old_fingerprint),
+                    Some(&replace.new_certificate),
+                ),
+                RequestType::RemoveCertificate(remove) => {
+                    (&remove.address, Some(&remove.fingerprint), None)
+                }
+                _ => return,
+            };
+
+            let address = SocketAddr::from(address.to_owned());
+            let added = match added {
+                Some(certificate) => {
+                    let meta = certificates::metadata(PathBuf::new(), certificate, None)
+                        .await
+                        .expect("metadata to be computed");
+
+                    Some((
+                        address,
+                        meta.fingerprint.to_string(),
+                        certificate.to_owned(),
+                    ))
+                }
+                None => None,
+            };
+
+            let mut record = self.lock();
+            record.installed.retain(|(served, fingerprint, _)| {
+                *served != address || Some(fingerprint) != removed
+            });
+            record.installed.extend(added);
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl CertificateSink for Mock {
+        async fn send(&self, request: RequestType) -> Result<Response, sozu_client::Error> {
+            self.answer(&request).await
+        }
+
+        async fn send_all(&self, requests: &[RequestType]) -> Result<Response, sozu_client::Error> {
+            // Sōzu loads every request of a batch and answers once, with an
+            // error if one of them failed
+            let mut response = None;
+            for request in requests {
+                let answer = self.answer(request).await;
+                if response.as_ref().map_or(true, Result::is_ok) {
+                    response = Some(answer);
+                }
+            }
+
+            response.expect("batch to not be empty")
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl SinkFactory for Mock {
+        async fn connect(&self, _instance: &Instance) -> Result<Box<dyn CertificateSink>, Error> {
+            self.lock().connections += 1;
+            Ok(Box::new(self.to_owned()))
+        }
+    }
+
+    /// Returns a watcher looking up the given pki directory with the given
+    /// top-level configuration keys, whose Sōzu instances are the given mock
+    pub async fn watcher(pki: &Path, keys: &str, mock: &Mock) -> Watcher {
+        watcher_with(configuration(pki, keys), &[mock.to_owned()]).await
+    }
+
+    /// Returns a watcher with the given configuration, whose Sōzu instances
+    /// are the given mocks in order, the last one is used for the others
+    pub async fn watcher_with(config: ConnectorConfiguration, mocks: &[Mock]) -> Watcher {
+        let config = Arc::new(config);
+        let mut targets = vec![];
+        for (idx, instance) in config.sozu.instances().into_iter().enumerate() {
+            let mock = mocks.get(idx).or(mocks.last()).expect("at least one mock");
+
+            let listeners = instance
+                .listener
+                .iter()
+                .map(|listener| listener.parse().expect("listener to be an address"))
+                .collect();
+
+            targets.push(
+                Target::with_factory(instance, Arc::new(mock.to_owned()), listeners)
+                    .await
+                    .expect("target to be created"),
+            );
+        }
+
+        let (_, shutdown) = watch::channel(false);
+        Watcher::with_targets(config, targets, Arc::default(), Arc::default(), shutdown).await
+    }
+
+    /// Returns the kinds of the given requests
+    pub fn kinds(requests: &[RequestType]) -> Vec<&str> {
+        requests.iter().map(format_request_type).collect()
+    }
+
+    fn tempdir() -> TempDir {
+        tempfile::tempdir().expect("temporary directory to be created")
+    }
+
+    /// Run the loop of the given watcher until the given condition holds, it
+    /// is checked every few milliseconds and must hold within a few seconds
+    pub async fn watch_until(watcher: Watcher, mut until: impl FnMut() -> bool) {
+        let (_configs, receiver) = watch::channel(watcher.config.to_owned());
+        let (_shutdown, shutdown) = watch::channel(false);
+        let (_syncs, syncs) = mpsc::channel(1);
+
+        let run = watch_with(watcher, receiver, shutdown, syncs);
+        let deadline = sleep(Duration::from_secs(5));
+        tokio::pin!(run, deadline);
+        loop {
+            tokio::select! {
+                result = &mut run => panic!("watcher stopped early, {result:?}"),
+                _ = &mut deadline => panic!("condition did not hold in time"),
+                _ = sleep(Duration::from_millis(10)) => {
+                    if until() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn changes_are_picked_up_once_the_pki_directory_is_re_created() {
+        let dir = tempdir();
+        let pki = dir.path().join("pki");
+        std::fs::create_dir(&pki).expect("pki directory to be created");
+
+        let keys = "watch-mode = \"events\"\ndebounce = 50\ncoalesce = 20";
+        let mock = Mock::default();
+        let watcher = watcher_with(configuration(&pki, keys), std::slice::from_ref(&mock)).await;
+
+        let write = |name: &str| {
+            let (cert, key) = self_signed(None, &[&format!("{name}.com")]);
+            write_directory(&pki, name, &cert, &key);
+        };
+
+        // Each step waits for the requests of the previous one
+        let (mut step, mut idle, mut requests) = (0, 0, vec![]);
+        watch_until(watcher, || {
+            let new = mock.take();
+            idle = if new.is_empty() { idle + 1 } else { 0 };
+            requests.extend(new);
+
+            match step {
+                0 => write("first"),
+                // Only once the directory is quiet, nothing else comes
+                1 if 1 == requests.len() && 20 <= idle => {
+                    std::fs::remove_dir_all(&pki).expect("pki directory to be removed");
+                }
+                // Events of the removed directories came and went
+                2 if 20 <= idle => {
+                    std::fs::create_dir(&pki).expect("pki directory to be re-created");
+                    write("second");
+                }
+                // The re-created directory is looked up as a whole, then
+                // watched again
+                3 if 3 == requests.len() && 20 <= idle => write("third"),
+                4 => return 4 == requests.len(),
+                _ => return false,
+            }
+
+            step += 1;
+            false
+        })
+        .await;
+
+        assert_eq!(
+            vec![
+                "AddCertificate",
+                "AddCertificate",
+                "RemoveCertificate",
+                "AddCertificate"
+            ],
+            kinds(&requests)
+        );
+    }
+
+    #[tokio::test]
+    async fn archive_never_extracted_does_not_hold_back_other_pki_directories() {
+        let dir = tempdir();
+        let pki = dir.path().join("pki");
+        let (cert, key) = self_signed(None, &["example.com"]);
+        write_directory(&pki, "example", &cert, &key);
+
+        let archive = dir.path().join("pki.tar.gz");
+        std::fs::write(&archive, b"not an archive").expect("archive to be written");
+
+        let mut config = configuration(
+            &pki,
+            &format!(
+                "archive = {{ directory = \"{}\" }}",
+                dir.path().join("extractions").display()
+            ),
+        );
+        config.sozu.pki.push(archive.to_owned());
+
+        let mock = Mock::default();
+        let mut watcher = watcher_with(config, std::slice::from_ref(&mock)).await;
+        watcher.lookup().await.expect("lookup to succeed");
+        assert_eq!(vec!["AddCertificate"], kinds(&mock.take()));
+
+        // Its certificates are picked up once it is extracted
+        let (cert, key) = self_signed(None, &["example.org"]);
+        tarball(
+            &archive,
+            &[
+                ("other/other.crt", cert.as_bytes()),
+                ("other/other.key", key.as_bytes()),
+            ],
+        );
+
+        watcher.lookup().await.expect("lookup to succeed");
+        assert_eq!(vec!["AddCertificate"], kinds(&mock.take()));
+    }
+
+    #[tokio::test]
+    async fn vanishing_pki_directory_does_not_remove_certificates() {
+        let dir = tempdir();
+        let pki = dir.path().join("pki");
+        let (cert, key) = self_signed(None, &["example.com"]);
+        let path = write_directory(&pki, "example", &cert, &key);
+
+        let mock = Mock::default();
+        let mut watcher = watcher(&pki, "", &mock).await;
+        watcher.lookup().await.expect("lookup to succeed");
+        assert_eq!(vec!["AddCertificate"], kinds(&mock.take()));
+
+        // A mount hiccup, neither a full lookup nor events remove anything
+        let unmounted = dir.path().join("unmounted");
+        std::fs::rename(&pki, &unmounted).expect("pki directory to be moved");
+        assert!(matches!(
+            watcher.lookup().await,
+            Err(Error::PkiUnavailable(root)) if root == pki
+        ));
+
+        assert!(matches!(
+            watcher
+                .lookup_paths(&HashSet::from([path.to_owned()]))
+                .await,
+            Err(Error::PkiUnavailable(_))
+        ));
+
+        assert!(mock.take().is_empty());
+        assert!(watcher.metadata.contains_key(&path));
+
+        // Nothing changed once it is back
+        std::fs::rename(&unmounted, &pki).expect("pki directory to be restored");
+        watcher.lookup().await.expect("lookup to succeed");
+        assert!(mock.take().is_empty());
+
+        // Whereas an empty pki directory removes its certificates
+        std::fs::remove_dir_all(&path).expect("certificate directory to be removed");
+        watcher.lookup().await.expect("lookup to succeed");
+        assert_eq!(vec!["RemoveCertificate"], kinds(&mock.take()));
+    }
+
+    #[test]
+    fn jitter_spreads_the_interval_within_its_bounds() {
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let interval = Duration::from_millis(1_000);
+        assert_eq!(interval, jitter(interval, 0, 0.9));
+        assert_eq!(Duration::from_millis(900), jitter(interval, 10, 0.0));
+        assert_eq!(interval, jitter(interval, 10, 0.5));
+        assert_eq!(Duration::from_millis(1_100), jitter(interval, 10, 1.0));
+
+        // The percentage is capped and samples are clamped
+        assert_eq!(Duration::from_millis(500), jitter(interval, 90, -1.0));
+        assert_eq!(Duration::from_millis(1_500), jitter(interval, 90, 2.0));
+
+        let mut rng = StdRng::seed_from_u64(42);
+        let delays: Vec<_> = (0..1_000)
+            .map(|_| jitter(interval, 10, rng.gen()))
+            .collect();
+
+        let (min, max) = (Duration::from_millis(900), Duration::from_millis(1_100));
+        assert!(delays.iter().all(|delay| min <= *delay && *delay < max));
+        assert!(delays
+            .iter()
+            .any(|delay| *delay < Duration::from_millis(950)));
+        assert!(delays
+            .iter()
+            .any(|delay| *delay > Duration::from_millis(1_050)));
+    }
+
+    #[tokio::test]
+    async fn directories_are_read_alike_whatever_the_scan_concurrency() {
+        let pki = tempdir();
+        let mut expected = vec![];
+        for idx in 0..16 {
+            let name = format!("example-{idx}.com");
+            let (cert, key) = self_signed(None, &[&name]);
+            expected.push(write_directory(pki.path(), &name, &cert, &key));
+        }
+
+        expected.sort();
+
+        let mut previous: Option<Vec<RequestType>> = None;
+        for concurrency in [1, 3, 64] {
+            let mock = Mock::default();
+            let keys = format!("scan-concurrency = {concurrency}");
+            let mut watcher = watcher(pki.path(), &keys, &mock).await;
+            watcher.lookup().await.expect("lookup to succeed");
+
+            let mut paths: Vec<_> = watcher.metadata.keys().cloned().collect();
+            paths.sort();
+            assert_eq!(expected, paths);
+
+            // Requests are sorted by directory, whatever the order reads end in
+            let requests = mock.take();
+            let mut names: Vec<_> = requests
+                .iter()
+                .filter_map(|request| match request {
+                    RequestType::AddCertificate(add) => Some(add.certificate.names.to_owned()),
+                    _ => None,
+                })
+                .collect();
+
+            assert_eq!(16, names.len());
+            assert!(names.windows(2).all(|pair| pair[0] <= pair[1]));
+            names.sort();
+            names.dedup();
+            assert_eq!(16, names.len());
+
+            if let Some(previous) = previous.replace(requests.to_owned()) {
+                assert_eq!(previous, requests);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn certificates_are_sent_once_they_become_valid_in_events_mode() {
+        let pki = tempdir();
+        let not_before = SystemTime::now() + Duration::from_secs(2);
+        let (cert, key) = self_signed_from(&["example.com"], not_before);
+        write_directory(pki.path(), "example", &cert, &key);
+
+        let config = configuration(pki.path(), r#"watch-mode = "events""#);
+        let mock = Mock::default();
+        let watcher = watcher_with(config, std::slice::from_ref(&mock)).await;
+
+        // It is skipped first, and no event comes for the directory once it is
+        // valid
+        let mut requests = vec![];
+        watch_until(watcher, || {
+            requests.extend(mock.take());
+            !requests.is_empty()
+        })
+        .await;
+
+        assert!(SystemTime::now() >= not_before - Duration::from_secs(1));
+        assert_eq!(
+            vec![("AddCertificate", "127.0.0.1:8443".to_string())],
+            addresses(&requests)
+        );
+    }
+
+    #[tokio::test]
+    async fn expiries_are_relative_to_the_given_time() {
+        let pki = tempdir();
+        let (cert, key) = self_signed(Some("example.com"), &["example.com"]);
+        write_directory(pki.path(), "example", &cert, &key);
+
+        let mock = Mock::default();
+        let mut watcher = watcher(pki.path(), "", &mock).await;
+        watcher.lookup().await.expect("lookup to succeed");
+
+        let expires_at = watcher
+            .metadata
+            .values()
+            .find_map(|meta| meta.expires_at)
+            .expect("expiration date to be known");
+
+        // Without any further lookup, the time left decreases as time passes
+        for now in [expires_at - 3600, expires_at, expires_at + 60] {
+            let (min_expiry, expiries) = watcher.expiries(now);
+            assert_eq!(Some(expires_at - now), min_expiry);
+            assert_eq!(Some(&(expires_at - now)), expiries.get("example.com"));
+        }
+    }
+
+    #[tokio::test]
+    async fn leader_lock_is_acquired_lost_and_taken_over() {
+        let pki = tempdir();
+        let (cert, key) = self_signed(None, &["example.com"]);
+        let path = write_directory(pki.path(), "example", &cert, &key);
+
+        let lock = tempdir();
+        let keys = format!(
+            "leader-lock = \"{}\"",
+            lock.path().join("leader.lock").display()
+        );
+
+        let (first, second) = (Mock::default(), Mock::default());
+        let mut leader = watcher(pki.path(), &keys, &first).await;
+        let mut standby = watcher(pki.path(), &keys, &second).await;
+
+        // The first replica to look up acquires the lock
+        leader.lookup().await.expect("lookup to succeed");
+        standby.lookup().await.expect("lookup to succeed");
+        assert_eq!(vec!["AddCertificate"], kinds(&first.take()));
+        assert!(second.take().is_empty());
+        assert!(!leader.is_standby());
+        assert!(standby.is_standby());
+        assert!(!standby.try_lead().await);
+
+        // Replacing the file of the lock releases it, the standby replica
+        // takes over and the leader loses it
+        std::fs::remove_file(lock.path().join("leader.lock")).expect("lock to be removed");
+        assert!(standby.try_lead().await);
+        assert!(!standby.is_standby());
+
+        leader.lookup().await.expect("lookup to succeed");
+        assert!(leader.is_standby());
+        assert!(leader.metadata.is_empty());
+        assert!(first.take().is_empty());
+
+        // The new leader only sends what its Sōzu instance does not serve
+        standby.lookup().await.expect("lookup to succeed");
+        assert_eq!(vec!["AddCertificate"], kinds(&second.take()));
+        assert!(standby.metadata.contains_key(&path));
+    }
+
+    #[tokio::test]
+    async fn standby_replica_takes_over_in_events_mode() {
+        let pki = tempdir();
+        let (cert, key) = self_signed(None, &["example.com"]);
+        write_directory(pki.path(), "example", &cert, &key);
+
+        let lock = tempdir();
+        let mut config = configuration(
+            pki.path(),
+            &format!(
+                "watch-mode = \"events\"\nleader-lock = \"{}\"",
+                lock.path().join("leader.lock").display()
+            ),
+        );
+        config.interval = MIN_INTERVAL;
+
+        let (first, second) = (Mock::default(), Mock::default());
+        let mut leader = watcher_with(config.to_owned(), std::slice::from_ref(&first)).await;
+        leader.lookup().await.expect("lookup to succeed");
+        let standby = watcher_with(config, std::slice::from_ref(&second)).await;
+
+        // The lock is released once the leader stops, no event comes for the
+        // pki directory
+        let mut leader = Some(leader);
+        let mut requests = vec![];
+        watch_until(standby, || {
+            leader.take();
+            requests.extend(second.take());
+            !requests.is_empty()
+        })
+        .await;
+
+        assert_eq!(vec!["AddCertificate"], kinds(&requests));
+    }
+
+    #[tokio::test]
+    async fn requests_left_pending_are_retried_in_events_mode() {
+        let pki = tempdir();
+        let (cert, key) = self_signed(None, &["example.com"]);
+        write_directory(pki.path(), "example", &cert, &key);
+
+        let mut config = configuration(pki.path(), r#"watch-mode = "events""#);
+        config.interval = MIN_INTERVAL;
+
+        let mock = Mock::default();
+        mock.script(&[Answer::Failure]);
+        let watcher = watcher_with(config, std::slice::from_ref(&mock)).await;
+
+        // No event comes for the directory, only a full lookup retries it
+        let mut requests = vec![];
+        watch_until(watcher, || {
+            requests.extend(mock.take());
+            2 <= requests.len()
+        })
+        .await;
+
+        assert_eq!(vec!["AddCertificate", "AddCertificate"], kinds(&requests));
+    }
+
+    #[tokio::test]
+    async fn installed_certificates_are_only_reconciled_on_the_listeners_serving_them() {
+        let pki = tempdir();
+        let (cert, key) = self_signed(None, &["example.com"]);
+        let path = write_directory(pki.path(), "example", &cert, &key);
+
+        // Another listener serves the certificate
+        let mock = Mock::default();
+        let mut config = configuration(pki.path(), "");
+        config.sozu.listener = vec!["127.0.0.1:9443".to_string()];
+        let mut previous = watcher_with(config, std::slice::from_ref(&mock)).await;
+        previous.lookup().await.expect("lookup to succeed");
+        mock.take();
+
+        let mut restarted = watcher(pki.path(), "", &mock).await;
+        restarted.lookup().await.expect("lookup to succeed");
+        assert_eq!(
+            vec![("AddCertificate", "127.0.0.1:8443".to_string())],
+            addresses(&mock.take())
+        );
+
+        // Once served on the listener, it is not sent again on restart
+        let mut restarted = watcher(pki.path(), "", &mock).await;
+        restarted.lookup().await.expect("lookup to succeed");
+        assert!(mock.take().is_empty());
+        assert!(restarted.metadata.contains_key(&path));
+    }
+
+    #[tokio::test]
+    async fn persisted_state_is_not_trusted_if_sozu_did_not_answer() {
+        let pki = tempdir();
+        let (cert, key) = self_signed(None, &["example.com"]);
+        write_directory(pki.path(), "example", &cert, &key);
+
+        let state = tempdir();
+        let keys = format!("state-file = \"{}\"", state.path().join("state").display());
+
+        let mock = Mock::default();
+        let mut previous = watcher(pki.path(), &keys, &mock).await;
+        previous.lookup().await.expect("lookup to succeed");
+        mock.take();
+
+        // Sōzu serves what the state file records
+        let mut restarted = watcher(pki.path(), &keys, &mock).await;
+        assert!(!restarted.applied.is_empty());
+        restarted.lookup().await.expect("lookup to succeed");
+        assert!(mock.take().is_empty());
+
+        // It may not anymore, e.g. if it restarted too
+        mock.unreachable(true);
+        let mut restarted = watcher(pki.path(), &keys, &mock).await;
+        assert!(restarted.applied.is_empty());
+
+        mock.unreachable(false);
+        restarted.lookup().await.expect("lookup to succeed");
+        assert_eq!(vec!["AddCertificate"], kinds(&mock.take()));
+    }
+
+    #[tokio::test]
+    async fn failed_requests_are_reverted_and_sent_again() {
+        let pki = tempdir();
+        let (cert, key) = self_signed(None, &["example.com"]);
+        let path = write_directory(pki.path(), "example", &cert, &key);
+
+        let mock = Mock::default();
+        mock.script(&[Answer::Failure]);
+        let mut watcher = watcher(pki.path(), "", &mock).await;
+
+        let summary = watcher.lookup().await.expect("lookup to succeed");
+        assert_eq!((0, 1), (summary.sent, summary.failed));
+        assert!(!watcher.metadata.contains_key(&path));
+
+        let summary = watcher.lookup().await.expect("lookup to succeed");
+        assert_eq!((1, 0), (summary.sent, summary.failed));
+        assert!(watcher.metadata.contains_key(&path));
+        assert_eq!(
+            vec!["AddCertificate", "AddCertificate"],
+            kinds(&mock.take())
+        );
+
+        // Nothing is sent once Sōzu holds the certificate
+        watcher.lookup().await.expect("lookup to succeed");
+        assert!(mock.take().is_empty());
+    }
+
+    #[tokio::test]
+    async fn requests_are_sent_in_the_configured_order() {
+        for (order, expected) in [
+            ("add-first", ["AddCertificate", "RemoveCertificate"]),
+            ("remove-first", ["RemoveCertificate", "AddCertificate"]),
+        ] {
+            let pki = tempdir();
+            let (cert, key) = self_signed(None, &["old.example.com"]);
+            let old = write_directory(pki.path(), "old", &cert, &key);
+
+            let mock = Mock::default();
+            let keys = format!("request-order = \"{order}\"");
+            let mut watcher = watcher(pki.path(), &keys, &mock).await;
+            watcher.lookup().await.expect("lookup to succeed");
+            mock.take();
+
+            std::fs::remove_dir_all(&old).expect("directory to be removed");
+            let (cert, key) = self_signed(None, &["new.example.com"]);
+            write_directory(pki.path(), "new", &cert, &key);
+
+            watcher.lookup().await.expect("lookup to succeed");
+            assert_eq!(expected.to_vec(), kinds(&mock.take()), "{order}");
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_of_a_failed_batch_that_sozu_applied_are_kept() {
+        let pki = tempdir();
+        let (cert, key) = self_signed(None, &["a.example.com"]);
+        let applied = write_directory(pki.path(), "a", &cert, &key);
+        let (cert, key) = self_signed(None, &["b.example.com"]);
+        let failed = write_directory(pki.path(), "b", &cert, &key);
+
+        let mock = Mock::default();
+        mock.script(&[Answer::Ok, Answer::Failure]);
+        let mut watcher = watcher(pki.path(), "batch = true", &mock).await;
+
+        let summary = watcher.lookup().await.expect("lookup to succeed");
+        assert_eq!((1, 1), (summary.sent, summary.failed));
+        assert!(watcher.metadata.contains_key(&applied));
+        assert!(!watcher.metadata.contains_key(&failed));
+        mock.take();
+
+        watcher.lookup().await.expect("lookup to succeed");
+        let requests = mock.take();
+        assert_eq!(vec!["AddCertificate"], kinds(&requests));
+        assert!(watcher.metadata.contains_key(&failed));
+    }
+
+    #[tokio::test]
+    async fn certificates_rejected_too_many_times_are_quarantined() {
+        let pki = tempdir();
+        let (cert, key) = self_signed(None, &["example.com"]);
+        let path = write_directory(pki.path(), "example", &cert, &key);
+
+        let mock = Mock::default();
+        mock.otherwise(Answer::Failure);
+        let mut watcher = watcher(pki.path(), "max-retries = 2", &mock).await;
+
+        watcher.lookup().await.expect("lookup to succeed");
+        assert!(!watcher.quarantined.contains_key(&path));
+        watcher.lookup().await.expect("lookup to succeed");
+        assert!(watcher.quarantined.contains_key(&path));
+        assert_eq!(2, mock.take().len());
+
+        watcher.lookup().await.expect("lookup to succeed");
+        assert!(mock.take().is_empty());
+
+        // A new certificate on disk is released from quarantine
+        let (cert, key) = self_signed(None, &["example.com"]);
+        write_directory(pki.path(), "example", &cert, &key);
+        mock.otherwise(Answer::Ok);
+
+        watcher.lookup().await.expect("lookup to succeed");
+        assert!(!watcher.quarantined.contains_key(&path));
+        assert_eq!(vec!["AddCertificate"], kinds(&mock.take()));
+    }
+
+    #[tokio::test]
+    async fn unanswered_requests_do_not_count_towards_quarantine() {
+        let pki = tempdir();
+        let (cert, key) = self_signed(None, &["example.com"]);
+        let path = write_directory(pki.path(), "example", &cert, &key);
+
+        let mock = Mock::default();
+        // Sōzu answers the first request only, which counts once
+        mock.script(&[Answer::Failure]);
+        mock.otherwise(Answer::Hang);
+        let keys = "max-retries = 2\nrequest-timeout = 50";
+        let mut watcher = watcher(pki.path(), keys, &mock).await;
+
+        for _ in 0..3 {
+            let summary = watcher.lookup().await.expect("lookup to succeed");
+            assert_eq!(1, summary.failed);
+        }
+
+        assert!(!watcher.quarantined.contains_key(&path));
+        assert_eq!(Some(1), watcher.retries.get(&path).map(|(_, count)| *count));
+
+        // The count is reset once the certificate is applied
+        mock.otherwise(Answer::Ok);
+        watcher.lookup().await.expect("lookup to succeed");
+        assert!(watcher.metadata.contains_key(&path));
+        assert!(!watcher.retries.contains_key(&path));
+    }
+
+    #[tokio::test]
+    async fn certificate_whose_chain_changed_is_replaced_in_place() {
+        let pki = tempdir();
+        let (cert, key) = self_signed(None, &["example.com"]);
+        write_directory(pki.path(), "example", &cert, &key);
+
+        let mock = Mock::default();
+        let mut watcher = watcher(pki.path(), "", &mock).await;
+        watcher.lookup().await.expect("lookup to succeed");
+        let added = match mock.take().as_slice() {
+            [RequestType::AddCertificate(add)] => add.to_owned(),
+            requests => panic!("expected a single addition, got {:?}", kinds(requests)),
+        };
+
+        // The leaf is the same, only its chain changed
+        let (intermediate, _) = self_signed(Some("Intermediate"), &[]);
+        write_directory(
+            pki.path(),
+            "example",
+            &format!("{cert}{intermediate}"),
+            &key,
+        );
+
+        let summary = watcher.lookup().await.expect("lookup to succeed");
+        assert_eq!((1, 1), (summary.modified, summary.sent));
+        match mock.take().as_slice() {
+            [RequestType::ReplaceCertificate(replace)] => {
+                assert_eq!(added.address, replace.address);
+                assert_eq!(
+                    added.certificate.certificate,
+                    replace.new_certificate.certificate
+                );
+                assert!(added.certificate.certificate_chain.is_empty());
+                assert_eq!(1, replace.new_certificate.certificate_chain.len());
+
+                let fingerprint = watcher
+                    .metadata
+                    .values()
+                    .map(|meta| meta.fingerprint.to_string())
+                    .next();
+                assert_eq!(fingerprint, Some(replace.old_fingerprint.to_owned()));
+            }
+            requests => panic!("expected a single replacement, got {:?}", kinds(requests)),
+        }
+    }
+
+    #[tokio::test]
+    async fn listener_of_a_directory_only_applies_to_instances_having_it() {
+        let pki = tempdir();
+        let (cert, key) = self_signed(None, &["example.com"]);
+        let path = write_directory(pki.path(), "example", &cert, &key);
+        std::fs::write(
+            path.join("options.json"),
+            r#"{"listener": "127.0.0.1:7443"}"#,
+        )
+        .expect("options to be written");
+
+        let mut config = configuration(pki.path(), "");
+        config.sozu.instances.push(Instance {
+            name: "green".to_string(),
+            configuration: PathBuf::from("/etc/sozu/green.toml"),
+            listener: vec!["127.0.0.1:9443".to_string()],
+            endpoint: None,
+        });
+
+        let (default, green) = (Mock::default(), Mock::default());
+        let mut watcher = watcher_with(config, &[default.clone(), green.clone()]).await;
+
+        // Only the default instance has the listener of the directory
+        let override_listener: SocketAddr = "127.0.0.1:7443".parse().expect("address");
+        watcher.targets[0].https = Some(HashSet::from([
+            "127.0.0.1:8443".parse().expect("address"),
+            override_listener,
+        ]));
+        watcher.targets[1].https =
+            Some(HashSet::from(["127.0.0.1:9443".parse().expect("address")]));
+
+        watcher.lookup().await.expect("lookup to succeed");
+        assert_eq!(
+            vec![("AddCertificate", "127.0.0.1:7443".to_string())],
+            addresses(&default.take())
+        );
+        assert!(green.take().is_empty());
+
+        // Nothing is pending on the green instance, so it is not sent again
+        watcher.lookup().await.expect("lookup to succeed");
+        assert!(default.take().is_empty());
+        assert!(green.take().is_empty());
+        assert!(watcher.metadata.contains_key(&path));
+    }
+
+    #[tokio::test]
+    async fn instances_only_receive_again_what_they_failed_to_apply() {
+        let pki = tempdir();
+        let (cert, key) = self_signed(None, &["example.com"]);
+        let path = write_directory(pki.path(), "example", &cert, &key);
+
+        let mut config = configuration(pki.path(), "max-retries = 2");
+        config.sozu.instances.push(Instance {
+            name: "green".to_string(),
+            configuration: PathBuf::from("/etc/sozu/green.toml"),
+            listener: vec!["127.0.0.1:9443".to_string()],
+            endpoint: None,
+        });
+
+        let (default, green) = (Mock::default(), Mock::default());
+        let mut watcher = watcher_with(config, &[default.clone(), green.clone()]).await;
+        watcher.lookup().await.expect("lookup to succeed");
+        assert_eq!(vec!["AddCertificate"], kinds(&default.take()));
+        assert_eq!(vec!["AddCertificate"], kinds(&green.take()));
+        let old = watcher.metadata[&path].fingerprint.to_string();
+
+        // The default instance applies the replacement, the green one does not
+        let (cert, key) = self_signed(None, &["example.com"]);
+        write_directory(pki.path(), "example", &cert, &key);
+        green.script(&[Answer::Failure]);
+
+        let summary = watcher.lookup().await.expect("lookup to succeed");
+        assert_eq!((1, 1), (summary.sent, summary.failed));
+        assert_eq!(vec!["ReplaceCertificate"], kinds(&default.take()));
+        assert_eq!(vec!["ReplaceCertificate"], kinds(&green.take()));
+        assert_eq!(old, watcher.metadata[&path].fingerprint.to_string());
+
+        // Only the green instance receives the replacement again, the default
+        // one would fail to replace a certificate that it does not hold anymore
+        let summary = watcher.lookup().await.expect("lookup to succeed");
+        assert_eq!((1, 0), (summary.sent, summary.failed));
+        assert!(default.take().is_empty());
+        match green.take().as_slice() {
+            [RequestType::ReplaceCertificate(replace)] => {
+                assert_eq!(old, replace.old_fingerprint);
+            }
+            requests => panic!("expected a single replacement, got {:?}", kinds(requests)),
+        }
+
+        assert_ne!(old, watcher.metadata[&path].fingerprint.to_string());
+        assert!(watcher.quarantined.is_empty());
+        assert!(watcher.retries.is_empty());
+    }
+
+    /// Returns the listener of each of the given requests, alongside their kind
+    fn addresses(requests: &[RequestType]) -> Vec<(&str, String)> {
+        requests
+            .iter()
+            .map(|request| {
+                let address = address(request).map(|address| address.to_string());
+                (format_request_type(request), address.unwrap_or_default())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn listeners_only_receive_again_what_they_failed_to_apply() {
+        let pki = tempdir();
+        let (cert, key) = self_signed(None, &["example.com"]);
+        let path = write_directory(pki.path(), "example", &cert, &key);
+
+        let mut config = configuration(pki.path(), "");
+        config.sozu.listener = vec!["127.0.0.1:8443".to_string(), "127.0.0.1:8444".to_string()];
+
+        let mock = Mock::default();
+        mock.script(&[Answer::Ok, Answer::Failure]);
+        let mut watcher = watcher_with(config, std::slice::from_ref(&mock)).await;
+
+        let summary = watcher.lookup().await.expect("lookup to succeed");
+        assert_eq!((1, 1), (summary.sent, summary.failed));
+        assert!(!watcher.metadata.contains_key(&path));
+        mock.take();
+
+        watcher.lookup().await.expect("lookup to succeed");
+        assert_eq!(
+            vec![("AddCertificate", "127.0.0.1:8444".to_string())],
+            addresses(&mock.take())
+        );
+        assert!(watcher.metadata.contains_key(&path));
+
+        let applied = &watcher.applied["default"];
+        assert_eq!(2, applied.len());
+        assert!(applied
+            .values()
+            .all(|metadata| metadata.contains_key(&path)));
+    }
+
+    #[tokio::test]
+    async fn cache_is_invalidated_if_reading_options_change_on_reload() {
+        let pki = tempdir();
+        let (cert, key) = self_signed(None, &["example.com"]);
+        let path = write_directory(pki.path(), "example", &cert, &key);
+
+        let config = configuration(pki.path(), "");
+        let mut watcher = watcher(pki.path(), "", &Mock::default()).await;
+        watcher.lookup().await.expect("lookup to succeed");
+        assert!(watcher.cache.peek(&path).is_some());
+
+        // Options that do not change how directories are read keep the cache
+        let mut unchanged = config.to_owned();
+        unchanged.interval *= 2;
+        watcher.reload(Arc::new(unchanged)).await;
+        assert!(watcher.cache.peek(&path).is_some());
+
+        let changes: [fn(&mut ConnectorConfiguration); 7] = [
+            |config| config.layout.kind = LayoutKind::Certbot,
+            |config| config.default_tls_versions = vec![TlsVersion::TlsV13],
+            |config| config.strict_permissions = true,
+            |config| config.verify_chain = ChainVerification::Reject,
+            |config| config.strict_options = true,
+            |config| config.key_policy = Some(KeyPolicy::default()),
+            |config| config.strict_names = true,
+        ];
+
+        for change in changes {
+            watcher.reload(Arc::new(config.to_owned())).await;
+            watcher.lookup().await.expect("lookup to succeed");
+            assert!(watcher.cache.peek(&path).is_some());
+
+            let mut changed = config.to_owned();
+            change(&mut changed);
+            watcher.reload(Arc::new(changed)).await;
+            assert!(watcher.cache.peek(&path).is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn instances_are_reconnected_on_reload_only_if_their_paths_change() {
+        let pki = tempdir();
+        let config = configuration(pki.path(), "");
+        let mock = Mock::default();
+        let mut watcher = watcher_with(config.to_owned(), std::slice::from_ref(&mock)).await;
+        let connections = mock.connections();
+
+        // The HTTP server keeps its startup configuration
+        let mut changed = config.to_owned();
+        changed.http.exempt = vec!["/metrics".to_string()];
+        changed.metrics.prefix = "connector".to_string();
+        watcher.reload(Arc::new(changed.to_owned())).await;
+        assert_eq!(connections, mock.connections());
+
+        changed.sozu.configuration = PathBuf::from("/etc/sozu/other.toml");
+        watcher.reload(Arc::new(changed)).await;
+        assert_eq!(connections + 1, mock.connections());
+    }
+
+    #[tokio::test]
+    async fn certificates_move_to_the_new_listeners_on_reload() {
+        let pki = tempdir();
+        let (cert, key) = self_signed(None, &["example.com"]);
+        write_directory(pki.path(), "example", &cert, &key);
+
+        let config = configuration(pki.path(), "");
+        let mock = Mock::default();
+        let mut watcher = watcher_with(config.to_owned(), std::slice::from_ref(&mock)).await;
+        watcher.lookup().await.expect("lookup to succeed");
+        mock.take();
+
+        let mut config = config;
+        config.sozu.listener = vec!["127.0.0.1:8444".to_string()];
+        watcher.reload(Arc::new(config)).await;
+
+        watcher.lookup().await.expect("lookup to succeed");
+        assert_eq!(
+            vec![
+                ("AddCertificate", "127.0.0.1:8444".to_string()),
+                ("RemoveCertificate", "127.0.0.1:8443".to_string()),
+            ],
+            addresses(&mock.take())
+        );
+
+        // Nothing is left on the previous listener
+        let addresses: Vec<_> = watcher.applied["default"].keys().collect();
+        assert_eq!(
+            vec![&"127.0.0.1:8444".parse::<SocketAddr>().expect("address")],
+            addresses
+        );
+    }
+
+    #[tokio::test]
+    async fn dead_connections_are_recreated_through_the_factory() {
+        let pki = tempdir();
+        let (cert, key) = self_signed(None, &["example.com"]);
+        write_directory(pki.path(), "example", &cert, &key);
+
+        let mock = Mock::default();
+        mock.script(&[Answer::Disconnect]);
+        let mut watcher = watcher(pki.path(), "", &mock).await;
+        assert_eq!(1, mock.connections());
+
+        assert!(matches!(watcher.lookup().await, Err(Error::Send(_))));
+        assert_eq!(2, mock.connections());
+
+        let summary = watcher.lookup().await.expect("lookup to succeed");
+        assert_eq!(1, summary.sent);
     }
 }
@@ -4,14 +4,21 @@
 
 use std::{
     env::{self, VarError},
+    fmt::{self, Debug, Formatter},
     net::SocketAddr,
-    path::PathBuf,
+    num::NonZeroUsize,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    thread::available_parallelism,
 };
 
-use config::{Config, ConfigError, File};
-use serde::{Deserialize, Serialize};
+use config::{builder::DefaultState, Config, ConfigBuilder, ConfigError, File};
+use glob::{MatchOptions, Pattern, PatternError};
+use serde::{Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use sozu_command_lib::proto::command::TlsVersion;
 
-use crate::svc::logging::SentryContext;
+use crate::svc::logging::{Logging, SentryContext, Telemetry};
 
 // -----------------------------------------------------------------------------
 // Error
@@ -24,23 +31,768 @@ pub enum Error {
     Serialize(ConfigError),
     #[error("failed to retrieve environment variable '{0}', {1}")]
     EnvironmentVariable(&'static str, VarError),
+    #[error("interval of {0}ms is too small, it must be at least {MIN_INTERVAL}ms")]
+    Interval(u64),
+    #[error("metrics prefix '{0}' is not a valid prometheus metric name")]
+    MetricsPrefix(String),
+    #[error("key policy allows no algorithm, 'key-policy.algorithms' must not be empty")]
+    KeyPolicyAlgorithms,
+    #[error(
+        "endpoint of Sōzu instance '{0}' is invalid, exactly one of 'unix' or 'tcp' must be set"
+    )]
+    Endpoint(String),
+    #[error("endpoint '{1}' of Sōzu instance '{0}' is not supported, the command channel of Sōzu is only reachable through a unix socket")]
+    TcpEndpoint(String, String),
+    #[error("pki directory '{0}' is an archive, 'archive.directory' must be set to extract it")]
+    ArchiveDirectory(PathBuf),
 }
 
+// -----------------------------------------------------------------------------
+// Constants
+
+/// Minimum duration in milliseconds between two checks of the pki directory,
+/// a smaller one would keep scanning the disk and flood Sōzu
+pub const MIN_INTERVAL: u64 = 100;
+
+/// Environment variables overriding configuration keys, whatever the files
+/// say, with whether they hold a comma separated list
+pub const ENVIRONMENT: &[(&str, &str, bool)] = &[
+    ("SOZU_PKI_DIR", "sozu.pki", true),
+    ("SOZU_LISTENER", "sozu.listener", true),
+    ("SOZU_SOCKET", "sozu.endpoint.unix", false),
+    ("SOZU_CONFIGURATION", "sozu.configuration", false),
+    ("CONNECTOR_INTERVAL", "interval", false),
+    ("CONNECTOR_LISTENING_ADDRESS", "listening-address", false),
+];
+
 // -----------------------------------------------------------------------------
 // Sōzu
 
 /// Sōzu-related configuration
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 pub struct Sozu {
-    /// Path to pki directory
-    #[serde(rename = "pki")]
-    pub pki: PathBuf,
+    /// Paths to pki directories, either a single path or a list, the first
+    /// one wins when several hold the same certificate
+    #[serde(rename = "pki", deserialize_with = "one_or_many")]
+    pub pki: Vec<PathBuf>,
+    /// Path to configuration file
+    #[serde(rename = "configuration")]
+    pub configuration: PathBuf,
+    /// Listeners socket addresses or `host:port` resolved on startup, either a
+    /// single address or a list, defaults to the first HTTPS listener of the
+    /// configuration of Sōzu
+    #[serde(
+        rename = "listener",
+        alias = "listeners",
+        default,
+        deserialize_with = "one_or_many"
+    )]
+    pub listener: Vec<String>,
+    /// Command endpoint of Sōzu, defaults to the command socket of its
+    /// configuration
+    #[serde(rename = "endpoint", default)]
+    pub endpoint: Option<Endpoint>,
+    /// Additional Sōzu instances receiving the same certificates
+    #[serde(rename = "instances", default)]
+    pub instances: Vec<Instance>,
+}
+
+impl Sozu {
+    /// Sōzu instances to send certificates to, the one described at the top
+    /// level named [`DEFAULT_INSTANCE`] comes first
+    pub fn instances(&self) -> Vec<Instance> {
+        let mut instances = vec![Instance {
+            name: DEFAULT_INSTANCE.to_string(),
+            configuration: self.configuration.to_owned(),
+            listener: self.listener.to_owned(),
+            endpoint: self.endpoint.to_owned(),
+        }];
+
+        instances.extend(self.instances.iter().cloned());
+        instances
+    }
+}
+
+/// Name of the Sōzu instance described at the top level of its section
+pub const DEFAULT_INSTANCE: &str = "default";
+
+/// A Sōzu instance receiving certificates
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Instance {
+    /// Name of the instance, used in logs and metrics
+    #[serde(rename = "name")]
+    pub name: String,
     /// Path to configuration file
     #[serde(rename = "configuration")]
     pub configuration: PathBuf,
-    /// Listener socket address
-    #[serde(rename = "listener")]
-    pub listener: SocketAddr,
+    /// Listeners socket addresses or `host:port` resolved on startup, either a
+    /// single address or a list, defaults to the first HTTPS listener of the
+    /// configuration of Sōzu
+    #[serde(
+        rename = "listener",
+        alias = "listeners",
+        default,
+        deserialize_with = "one_or_many"
+    )]
+    pub listener: Vec<String>,
+    /// Command endpoint of Sōzu, defaults to the command socket of its
+    /// configuration
+    #[serde(rename = "endpoint", default)]
+    pub endpoint: Option<Endpoint>,
+}
+
+/// Command endpoint of Sōzu, exactly one of its fields must be set. Only
+/// `unix` is supported as the command channel of Sōzu is a unix socket,
+/// `tcp` is refused when validating the configuration
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Endpoint {
+    /// Path to the command unix socket
+    #[serde(rename = "unix", default)]
+    pub unix: Option<PathBuf>,
+    /// Address of the command endpoint as `host:port`
+    #[serde(rename = "tcp", default)]
+    pub tcp: Option<String>,
+}
+
+/// Deserialize either a single value or a list of values
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(addr) => vec![addr],
+        OneOrMany::Many(addrs) => addrs,
+    })
+}
+
+// -----------------------------------------------------------------------------
+// WatchMode
+
+/// Strategy used to detect changes in the pki directory
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum WatchMode {
+    /// Scan the whole pki directory at each interval
+    #[default]
+    #[serde(rename = "poll")]
+    Poll,
+    /// Listen to filesystem events and only look up changed directories
+    #[serde(rename = "events")]
+    Events,
+    /// Listen to filesystem events and scan the whole pki directory at each
+    /// interval to reconcile missed events
+    #[serde(rename = "hybrid")]
+    Hybrid,
+}
+
+// -----------------------------------------------------------------------------
+// Mode
+
+/// What the connector does with certificates of the pki directories
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum Mode {
+    /// Add, replace and remove certificates so that Sōzu serves the ones of
+    /// the pki directories
+    #[default]
+    #[serde(rename = "sync")]
+    Sync,
+    /// Only remove certificates that the connector installed, as recorded in
+    /// the state file, and never add or replace any
+    #[serde(rename = "cleanup-only")]
+    CleanupOnly,
+}
+
+// -----------------------------------------------------------------------------
+// Operation
+
+/// Kind of request sent to Sōzu
+#[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum Operation {
+    #[serde(rename = "add")]
+    Add,
+    #[serde(rename = "replace")]
+    Replace,
+    #[serde(rename = "remove")]
+    Remove,
+}
+
+// -----------------------------------------------------------------------------
+// RequestOrder
+
+/// Order in which requests are sent to Sōzu
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum RequestOrder {
+    /// Add new certificates, then replace modified ones and finally remove
+    /// deleted ones, to avoid a coverage gap
+    #[default]
+    #[serde(rename = "add-first")]
+    AddFirst,
+    /// Remove deleted certificates, then add new ones and finally replace
+    /// modified ones
+    #[serde(rename = "remove-first")]
+    RemoveFirst,
+}
+
+// -----------------------------------------------------------------------------
+// SendPriority
+
+/// Order in which certificates are sent to Sōzu within a kind of request, the
+/// kinds of requests themselves are ordered by [`RequestOrder`]
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum SendPriority {
+    /// Send certificates by the path of their directory
+    #[default]
+    #[serde(rename = "path")]
+    Path,
+    /// Send certificates which expire the soonest first, so that they are
+    /// installed if a synchronization is interrupted partway
+    #[serde(rename = "expiry")]
+    Expiry,
+}
+
+// -----------------------------------------------------------------------------
+// ChainVerification
+
+/// Verification of the order and signatures of certificate chains
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum ChainVerification {
+    /// Do not verify certificate chains
+    #[default]
+    #[serde(rename = "off")]
+    Off,
+    /// Log a warning when a certificate chain does not verify
+    #[serde(rename = "warn")]
+    Warn,
+    /// Skip certificates whose chain does not verify
+    #[serde(rename = "reject")]
+    Reject,
+}
+
+// -----------------------------------------------------------------------------
+// Layout
+
+/// Naming convention of files within a certificate directory
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum LayoutKind {
+    /// `{name}.crt` and `{name}.key` where `{name}` is the directory name
+    #[default]
+    #[serde(rename = "sozu-default")]
+    SozuDefault,
+    /// `fullchain.pem` and `privkey.pem` as written by certbot
+    #[serde(rename = "certbot")]
+    Certbot,
+    /// `{name}.pem` holding the certificate, its chain and the private key
+    #[serde(rename = "combined")]
+    Combined,
+    /// `{name}.crt` and `{name}.key` directly within the pki directory, files
+    /// are paired by name rather than gathered in a directory
+    #[serde(rename = "flat")]
+    Flat,
+}
+
+/// Layout of certificate directories, file names are templates in which
+/// `{name}` is replaced by the name of the certificate directory
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct Layout {
+    /// Naming convention of files
+    #[serde(rename = "kind", default)]
+    pub kind: LayoutKind,
+    /// Override the file name of the certificate and its chain
+    #[serde(rename = "certificate")]
+    pub certificate: Option<String>,
+    /// Override the file name of the private key
+    #[serde(rename = "key")]
+    pub key: Option<String>,
+    /// Override the file name of options
+    #[serde(rename = "options")]
+    pub options: Option<String>,
+    /// Override the file name of the PKCS#12 bundle, which is used instead of
+    /// the certificate and the key when it exists
+    #[serde(rename = "pkcs12")]
+    pub pkcs12: Option<String>,
+    /// File name of the certificate chain, when it exists the certificate file
+    /// only holds the leaf certificate
+    #[serde(rename = "chain")]
+    pub chain: Option<String>,
+    /// Override the file name of the der encoded OCSP response
+    #[serde(rename = "ocsp")]
+    pub ocsp: Option<String>,
+    /// Override the file name of the pem encoded Diffie-Hellman parameters
+    #[serde(rename = "dh")]
+    pub dh: Option<String>,
+}
+
+impl Layout {
+    /// Template of the file name of the certificate and its chain
+    pub fn certificate(&self) -> &str {
+        self.certificate.as_deref().unwrap_or(match self.kind {
+            LayoutKind::SozuDefault | LayoutKind::Flat => "{name}.crt",
+            LayoutKind::Certbot => "fullchain.pem",
+            LayoutKind::Combined => "{name}.pem",
+        })
+    }
+
+    /// Template of the file name of the private key, the same as the
+    /// certificate for the combined layout
+    pub fn key(&self) -> &str {
+        self.key.as_deref().unwrap_or(match self.kind {
+            LayoutKind::SozuDefault | LayoutKind::Flat => "{name}.key",
+            LayoutKind::Certbot => "privkey.pem",
+            LayoutKind::Combined => "{name}.pem",
+        })
+    }
+
+    /// Template of the file name of options
+    pub fn options(&self) -> &str {
+        self.options.as_deref().unwrap_or(match self.kind {
+            LayoutKind::Flat => "{name}.options.json",
+            _ => "options.json",
+        })
+    }
+
+    /// Template of the file name of the certificate chain, if it is apart from
+    /// the certificate
+    pub fn chain(&self) -> Option<&str> {
+        self.chain.as_deref()
+    }
+
+    /// Template of the file name of the der encoded OCSP response
+    pub fn ocsp(&self) -> &str {
+        self.ocsp.as_deref().unwrap_or("{name}.ocsp")
+    }
+
+    /// Template of the file name of the pem encoded Diffie-Hellman parameters
+    pub fn dh(&self) -> &str {
+        self.dh.as_deref().unwrap_or("{name}.dh")
+    }
+
+    /// Templates of the file names of the PKCS#12 bundle, by order of priority
+    pub fn pkcs12(&self) -> Vec<&str> {
+        match &self.pkcs12 {
+            Some(pkcs12) => vec![pkcs12],
+            None => vec!["{name}.p12", "{name}.pfx"],
+        }
+    }
+
+    /// Returns true if certificates are files paired by name within a
+    /// directory rather than directories. Their path is then the one of a
+    /// directory named after them, which does not exist, next to their files.
+    pub fn is_flat(&self) -> bool {
+        LayoutKind::Flat == self.kind
+    }
+
+    /// Path to the file of the given template of the certificate at the given
+    /// path, which is next to it for the flat layout and within it otherwise
+    pub fn file(&self, path: &Path, template: &str) -> PathBuf {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let file = template.replace("{name}", &name);
+        match path.parent() {
+            Some(parent) if self.is_flat() => parent.join(file),
+            _ => path.join(file),
+        }
+    }
+
+    /// Retrieve the name of the certificate that the given file belongs to,
+    /// from the templates of the certificate, the key and the PKCS#12 bundle
+    pub fn name_of(&self, file: &str) -> Option<String> {
+        let mut templates = vec![self.certificate(), self.key()];
+        templates.extend(self.pkcs12());
+
+        templates.into_iter().find_map(|template| {
+            let (prefix, suffix) = template.split_once("{name}")?;
+            file.strip_prefix(prefix)?
+                .strip_suffix(suffix)
+                .filter(|name| !name.is_empty())
+                .map(String::from)
+        })
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Patterns
+
+/// Glob patterns matched against paths of certificate directories relative to
+/// their pki directory, compiled once when the configuration is loaded. A
+/// pattern ending with `/**` also comes with the one of the directory itself.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+#[serde(try_from = "Vec<String>", into = "Vec<String>")]
+pub struct Patterns(Vec<(Pattern, Option<Pattern>)>);
+
+impl TryFrom<Vec<String>> for Patterns {
+    type Error = PatternError;
+
+    fn try_from(patterns: Vec<String>) -> Result<Self, Self::Error> {
+        patterns
+            .iter()
+            .map(|pattern| {
+                let directory = match pattern.strip_suffix("/**") {
+                    Some(directory) if !directory.is_empty() => Some(Pattern::new(directory)?),
+                    _ => None,
+                };
+
+                Ok((Pattern::new(pattern)?, directory))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+}
+
+impl From<Patterns> for Vec<String> {
+    fn from(patterns: Patterns) -> Self {
+        patterns
+            .0
+            .iter()
+            .map(|(pattern, _)| pattern.to_string())
+            .collect()
+    }
+}
+
+impl Patterns {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns true if one of the patterns matches the given path or one of
+    /// its ancestors, `*` does not match path separators while `**` does. So
+    /// both `**/archive` and `**/archive/**` match `archive` and everything
+    /// below it.
+    pub fn matches(&self, path: &Path) -> bool {
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+
+        path.ancestors()
+            .filter(|ancestor| !ancestor.as_os_str().is_empty())
+            .any(|ancestor| {
+                self.0.iter().any(|(pattern, directory)| {
+                    pattern.matches_path_with(ancestor, options)
+                        || directory
+                            .as_ref()
+                            .is_some_and(|directory| directory.matches_path_with(ancestor, options))
+                })
+            })
+    }
+}
+
+// -----------------------------------------------------------------------------
+// DomainPatterns
+
+/// Domain name patterns matched against the names of certificates, a `*`
+/// label matches exactly one label, e.g. `*.example.com` matches
+/// `www.example.com` but neither `example.com` nor `a.b.example.com`
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+#[serde(from = "Vec<String>", into = "Vec<String>")]
+pub struct DomainPatterns(Vec<String>);
+
+impl From<Vec<String>> for DomainPatterns {
+    fn from(patterns: Vec<String>) -> Self {
+        Self(
+            patterns
+                .iter()
+                .map(|pattern| pattern.trim_end_matches('.').to_lowercase())
+                .collect(),
+        )
+    }
+}
+
+impl From<DomainPatterns> for Vec<String> {
+    fn from(patterns: DomainPatterns) -> Self {
+        patterns.0
+    }
+}
+
+impl DomainPatterns {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns true if one of the patterns matches the given name, names are
+    /// compared case-insensitively
+    pub fn matches(&self, name: &str) -> bool {
+        let name = name.trim_end_matches('.').to_lowercase();
+
+        self.0.iter().any(|pattern| {
+            let mut labels = name.split('.');
+            let mut patterns = pattern.split('.');
+            loop {
+                match (patterns.next(), labels.next()) {
+                    (None, None) => return true,
+                    (Some("*"), Some(_)) => {}
+                    (Some(pattern), Some(label)) if pattern == label => {}
+                    _ => return false,
+                }
+            }
+        })
+    }
+}
+
+// -----------------------------------------------------------------------------
+// HTTP
+
+/// Credentials expected by the HTTP server
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(tag = "kind")]
+pub enum Auth {
+    /// HTTP Basic authentication
+    #[serde(rename = "basic")]
+    Basic {
+        #[serde(rename = "username")]
+        username: String,
+        #[serde(rename = "password")]
+        password: String,
+    },
+    /// Static bearer token
+    #[serde(rename = "bearer")]
+    Bearer {
+        #[serde(rename = "token")]
+        token: String,
+    },
+}
+
+impl Debug for Auth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Basic { username, .. } => f
+                .debug_struct("Basic")
+                .field("username", username)
+                .field("password", &"<redacted>")
+                .finish(),
+            Self::Bearer { .. } => f
+                .debug_struct("Bearer")
+                .field("token", &"<redacted>")
+                .finish(),
+        }
+    }
+}
+
+/// HTTP server configuration
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Http {
+    /// Serve metrics, probes and on demand lookups, the listening address is
+    /// not bound if disabled
+    #[serde(rename = "enabled", default = "default_http_enabled")]
+    pub enabled: bool,
+    /// Credentials required to reach the endpoints, open if not set
+    #[serde(rename = "auth", default)]
+    pub auth: Option<Auth>,
+    /// Paths reachable without credentials, typically for liveness probes
+    #[serde(rename = "exempt", default = "default_exempt")]
+    pub exempt: Vec<String>,
+    /// Certificate and key of the server, plaintext HTTP is served if not set
+    #[serde(rename = "tls", default)]
+    pub tls: Option<Tls>,
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Self {
+            enabled: default_http_enabled(),
+            auth: None,
+            exempt: default_exempt(),
+            tls: None,
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Metrics
+
+/// Exposition of metrics
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct Metrics {
+    /// Prefix of the names of all metrics, joined with an underscore, names
+    /// are left as is if empty
+    #[serde(rename = "prefix", default)]
+    pub prefix: String,
+}
+
+/// Prefix of the names of the historical metrics, replaced by the configured
+/// prefix, if any, rather than prefixed
+const HISTORICAL_METRICS_PREFIX: &str = "proxy_manager_";
+
+impl Metrics {
+    /// Name of the given metric once prefixed, this is the only place where a
+    /// prefix is added. Names are left as is by default, so that historical
+    /// metrics keep their names.
+    pub fn name(&self, name: &str) -> String {
+        if self.prefix.is_empty() {
+            return name.to_string();
+        }
+
+        let name = name.strip_prefix(HISTORICAL_METRICS_PREFIX).unwrap_or(name);
+
+        format!("{}_{name}", self.prefix)
+    }
+
+    /// Returns true if the prefix may start a prometheus metric name
+    fn is_valid(&self) -> bool {
+        self.prefix.chars().enumerate().all(|(idx, c)| {
+            c.is_ascii_alphabetic() || c == '_' || c == ':' || (0 != idx && c.is_ascii_digit())
+        })
+    }
+}
+
+// -----------------------------------------------------------------------------
+// KeyPolicy
+
+/// Algorithm of the public key of a certificate
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum KeyAlgorithm {
+    #[serde(rename = "rsa")]
+    Rsa,
+    #[serde(rename = "ecdsa")]
+    Ecdsa,
+    #[serde(rename = "ed25519")]
+    Ed25519,
+    #[serde(rename = "ed448")]
+    Ed448,
+}
+
+/// Elliptic curve of an ECDSA public key
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Curve {
+    #[serde(rename = "P-256")]
+    P256,
+    #[serde(rename = "P-384")]
+    P384,
+    #[serde(rename = "P-521")]
+    P521,
+}
+
+/// Public keys that certificates must hold to be sent to Sōzu
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct KeyPolicy {
+    /// Allowed algorithms of public keys
+    #[serde(rename = "algorithms", default = "default_key_policy_algorithms")]
+    pub algorithms: Vec<KeyAlgorithm>,
+    /// Minimum size in bits of RSA public keys
+    #[serde(rename = "rsa-min-bits", default = "default_key_policy_rsa_min_bits")]
+    pub rsa_min_bits: usize,
+    /// Allowed curves of ECDSA public keys
+    #[serde(rename = "curves", default = "default_key_policy_curves")]
+    pub curves: Vec<Curve>,
+}
+
+impl Default for KeyPolicy {
+    fn default() -> Self {
+        Self {
+            algorithms: default_key_policy_algorithms(),
+            rsa_min_bits: default_key_policy_rsa_min_bits(),
+            curves: default_key_policy_curves(),
+        }
+    }
+}
+
+fn default_key_policy_algorithms() -> Vec<KeyAlgorithm> {
+    vec![KeyAlgorithm::Rsa, KeyAlgorithm::Ecdsa]
+}
+
+const fn default_key_policy_rsa_min_bits() -> usize {
+    2048
+}
+
+fn default_key_policy_curves() -> Vec<Curve> {
+    vec![Curve::P256, Curve::P384, Curve::P521]
+}
+
+// -----------------------------------------------------------------------------
+// Archive
+
+/// Extraction of pki directories given as archives
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Archive {
+    /// Private directory in which archives are extracted, it is created with
+    /// restricted permissions if missing. It has no default so that private
+    /// keys are not extracted to a shared temporary directory, and must be set
+    /// if an archive is given as pki directory
+    #[serde(rename = "directory", default)]
+    pub directory: PathBuf,
+    /// Maximum size in bytes of the files extracted from an archive
+    #[serde(rename = "max-size", default = "default_archive_max_size")]
+    pub max_size: u64,
+    /// Maximum number of entries of an archive
+    #[serde(rename = "max-entries", default = "default_archive_max_entries")]
+    pub max_entries: usize,
+}
+
+impl Default for Archive {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::new(),
+            max_size: default_archive_max_size(),
+            max_entries: default_archive_max_entries(),
+        }
+    }
+}
+
+impl Archive {
+    /// Returns true if the given pki path is an archive, from its extension
+    pub fn is_archive(path: &Path) -> bool {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        [".tar.gz", ".tgz", ".zip"]
+            .iter()
+            .any(|extension| name.ends_with(extension))
+    }
+
+    /// Directory in which the given archive is extracted, named after the
+    /// archive and a digest of its path so that archives sharing a name do not
+    /// collide
+    pub fn destination(&self, path: &Path) -> PathBuf {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        let digest = format!("{:x}", Sha256::digest(path.as_os_str().as_bytes()));
+        self.directory.join(format!("{name}-{}", &digest[..16]))
+    }
+}
+
+const fn default_archive_max_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+const fn default_archive_max_entries() -> usize {
+    10_000
+}
+
+/// Certificate and key of the HTTP server, unrelated to the ones sent to Sōzu
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Tls {
+    /// Path to the pem encoded certificate, followed by its chain
+    #[serde(rename = "certificate")]
+    pub certificate: PathBuf,
+    /// Path to the pem encoded private key
+    #[serde(rename = "key")]
+    pub key: PathBuf,
+}
+
+const fn default_http_enabled() -> bool {
+    true
+}
+
+fn default_exempt() -> Vec<String> {
+    vec![
+        "/healthz".to_string(),
+        "/livez".to_string(),
+        "/version".to_string(),
+    ]
 }
 
 // -----------------------------------------------------------------------------
@@ -54,25 +806,249 @@ pub struct ConnectorConfiguration {
     /// Duration between two checks of pki directory
     #[serde(rename = "interval")]
     pub interval: u64,
+    /// Maximum random deviation in percent applied to the interval between
+    /// two full lookups, 0 to disable
+    #[serde(rename = "interval-jitter", default)]
+    pub interval_jitter: u64,
+    /// Maximum delay in milliseconds between two lookups when requests keep
+    /// failing to be sent to Sōzu
+    #[serde(rename = "max-backoff", default = "default_max_backoff")]
+    pub max_backoff: u64,
+    /// Number of consecutive lookups in which requests could not be sent to
+    /// Sōzu after which sending is paused, 0 to never pause
+    #[serde(rename = "circuit-breaker-threshold", default)]
+    pub circuit_breaker_threshold: u32,
+    /// Duration in milliseconds during which sending is paused once the
+    /// circuit breaker opens, a single request then probes Sōzu
+    #[serde(
+        rename = "circuit-breaker-cooldown",
+        default = "default_circuit_breaker_cooldown"
+    )]
+    pub circuit_breaker_cooldown: u64,
+    /// Strategy used to detect changes in the pki directory
+    #[serde(rename = "watch-mode", default)]
+    pub watch_mode: WatchMode,
+    /// Quiet period in milliseconds to wait after the last filesystem event of
+    /// a certificate directory before reading it
+    #[serde(rename = "debounce", default = "default_debounce")]
+    pub debounce: u64,
+    /// Maximum delay in milliseconds between the first filesystem event of a
+    /// certificate directory and its reading
+    #[serde(rename = "max-debounce", default = "default_max_debounce")]
+    pub max_debounce: u64,
+    /// Window in milliseconds over which filesystem events of all certificate
+    /// directories are gathered, so that a burst of events yields a single
+    /// lookup of the affected directories
+    #[serde(rename = "coalesce", default = "default_coalesce")]
+    pub coalesce: u64,
+    /// Depth down to which certificate directories are searched within the
+    /// pki directories, 1 for directly nested ones
+    #[serde(rename = "max-depth", default = "default_max_depth")]
+    pub max_depth: usize,
+    /// Glob patterns of certificate directories to look up, all if empty
+    #[serde(rename = "include", default)]
+    pub include: Patterns,
+    /// Glob patterns of certificate directories to ignore, they win over the
+    /// included ones
+    #[serde(rename = "exclude", default)]
+    pub exclude: Patterns,
+    /// Domain name patterns that every name of a certificate must match, all
+    /// names are allowed if empty
+    #[serde(rename = "san-allow", default)]
+    pub san_allow: DomainPatterns,
+    /// Domain name patterns that no name of a certificate may match, they win
+    /// over the allowed ones
+    #[serde(rename = "san-deny", default)]
+    pub san_deny: DomainPatterns,
+    /// Number of certificate directories read concurrently
+    #[serde(rename = "scan-concurrency", default = "default_scan_concurrency")]
+    pub scan_concurrency: usize,
+    /// Delay in milliseconds during which files of a changed certificate
+    /// directory must not change before being read, 0 to disable the check
+    #[serde(rename = "stability-window", default)]
+    pub stability_window: u64,
+    /// Skip certificates which are expired instead of loading them into Sōzu
+    #[serde(rename = "skip-expired", default)]
+    pub skip_expired: bool,
+    /// Tolerated clock skew in milliseconds when checking the validity period of
+    /// certificates, certificates which are not valid yet are always skipped
+    #[serde(rename = "clock-skew-grace", default)]
+    pub clock_skew_grace: u64,
+    /// Number of consecutive failures after which a certificate is skipped
+    /// until it changes on disk, 0 to retry forever
+    #[serde(rename = "max-retries", default)]
+    pub max_retries: u32,
+    /// Number of requests of the same kind sent concurrently to Sōzu
+    #[serde(rename = "send-concurrency", default = "default_send_concurrency")]
+    pub send_concurrency: usize,
+    /// Maximum number of requests per second sent to Sōzu during the first
+    /// synchronization, when no certificate is known yet, 0 to disable
+    #[serde(rename = "initial-rate-limit", default)]
+    pub initial_rate_limit: u64,
+    /// Send the requests of a lookup to Sōzu as a single batch, which is not
+    /// atomic: the state of Sōzu tells which requests were applied if it fails
+    #[serde(rename = "batch", default)]
+    pub batch: bool,
+    /// Label emitted requests metrics with the path of the pki directory that
+    /// holds the certificate
+    #[serde(rename = "source-label", default)]
+    pub source_label: bool,
+    /// Refuse to load private keys readable by group or others instead of
+    /// only logging them
+    #[serde(rename = "strict-permissions", default)]
+    pub strict_permissions: bool,
+    /// TLS versions of certificates whose directory has no options, using the
+    /// names of the Sōzu configuration such as "TLS_V12"
+    #[serde(rename = "default-tls-versions", default)]
+    pub default_tls_versions: Vec<TlsVersion>,
+    /// Skip certificate directories whose options could not be parsed instead
+    /// of using the default TLS versions
+    #[serde(rename = "strict-options", default)]
+    pub strict_options: bool,
+    /// Skip certificates without any name, neither a common name nor a
+    /// subject alternative name, instead of only logging them
+    #[serde(rename = "strict-names", default)]
+    pub strict_names: bool,
+    /// Order in which requests are sent to Sōzu
+    #[serde(rename = "request-order", default)]
+    pub request_order: RequestOrder,
+    /// Order in which certificates are sent to Sōzu within a kind of request
+    #[serde(rename = "send-priority", default)]
+    pub send_priority: SendPriority,
+    /// What the connector does with certificates
+    #[serde(rename = "mode", default)]
+    pub mode: Mode,
+    /// Kinds of requests that may be sent to Sōzu, the other ones are dropped
+    #[serde(rename = "allowed-operations", default = "default_allowed_operations")]
+    pub allowed_operations: Vec<Operation>,
+    /// Maximum delay in milliseconds to wait for requests in flight to be sent
+    /// to Sōzu on shutdown
+    #[serde(rename = "shutdown-timeout", default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    /// Maximum delay in milliseconds to wait for Sōzu to answer a request, the
+    /// request is then considered as failed
+    #[serde(rename = "request-timeout", default = "default_request_timeout")]
+    pub request_timeout: u64,
+    /// Maximum delay in milliseconds to wait on startup for Sōzu and its
+    /// configuration to be available, 0 to give up at the first failure
+    #[serde(rename = "startup-timeout", default = "default_startup_timeout")]
+    pub startup_timeout: u64,
+    /// Path to the file in which the state of certificates is persisted across
+    /// restarts, nothing is persisted if not set
+    #[serde(rename = "state-file", default)]
+    pub state_file: Option<PathBuf>,
+    /// Path to the file to which the diff computed at each lookup is appended
+    /// as a JSON line, `-` for the standard output, nothing is exported if not
+    /// set
+    #[serde(rename = "diff-sink", default)]
+    pub diff_sink: Option<PathBuf>,
+    /// Path to the file holding the identifier of the process, for supervisors
+    /// that do not track processes on their own
+    #[serde(rename = "pid-file", default)]
+    pub pid_file: Option<PathBuf>,
+    /// Path to a file shared between replicas of the connector, only the one
+    /// holding a lock on it sends requests to Sōzu
+    #[serde(rename = "leader-lock", default)]
+    pub leader_lock: Option<PathBuf>,
+    /// Log requests that would be sent to Sōzu instead of sending them
+    #[serde(rename = "dry-run", default)]
+    pub dry_run: bool,
+    /// Verification of the order and signatures of certificate chains
+    #[serde(rename = "verify-chain", default)]
+    pub verify_chain: ChainVerification,
+    /// Public keys that certificates must hold, certificates that do not
+    /// comply are skipped, nothing is checked if not set
+    #[serde(rename = "key-policy", default)]
+    pub key_policy: Option<KeyPolicy>,
+    /// Query Sōzu after each certificate addition or replacement to check that
+    /// its workers serve it on the listener, which doubles the requests
+    #[serde(rename = "verify-after-send", default)]
+    pub verify_after_send: bool,
+    /// HTTP server configuration
+    #[serde(rename = "http", default)]
+    pub http: Http,
+    /// Layout of certificate directories
+    #[serde(rename = "layout", default)]
+    pub layout: Layout,
     /// Sōzu configuration
     #[serde(rename = "sozu")]
     pub sozu: Sozu,
+    /// Logging configuration
+    #[serde(rename = "logging", default)]
+    pub logging: Logging,
+    /// Exposition of metrics
+    #[serde(rename = "metrics", default)]
+    pub metrics: Metrics,
+    /// Extraction of pki directories given as archives
+    #[serde(rename = "archive", default)]
+    pub archive: Archive,
+    /// Export of spans
+    #[serde(rename = "telemetry", default)]
+    pub telemetry: Telemetry,
     /// Sentry configuration
     #[serde(rename = "sentry")]
     pub sentry: Option<SentryContext>,
 }
 
+const fn default_max_backoff() -> u64 {
+    300_000
+}
+
+const fn default_circuit_breaker_cooldown() -> u64 {
+    300_000
+}
+
+const fn default_debounce() -> u64 {
+    500
+}
+
+const fn default_max_debounce() -> u64 {
+    5_000
+}
+
+const fn default_coalesce() -> u64 {
+    250
+}
+
+const fn default_send_concurrency() -> usize {
+    1
+}
+
+const fn default_shutdown_timeout() -> u64 {
+    10_000
+}
+
+const fn default_request_timeout() -> u64 {
+    30_000
+}
+
+const fn default_startup_timeout() -> u64 {
+    60_000
+}
+
+const fn default_max_depth() -> usize {
+    1
+}
+
+fn default_allowed_operations() -> Vec<Operation> {
+    vec![Operation::Add, Operation::Replace, Operation::Remove]
+}
+
+fn default_scan_concurrency() -> usize {
+    available_parallelism().map(NonZeroUsize::get).unwrap_or(1)
+}
+
 impl TryFrom<PathBuf> for ConnectorConfiguration {
     type Error = Error;
 
     #[tracing::instrument]
     fn try_from(path: PathBuf) -> Result<Self, Self::Error> {
-        Config::builder()
-            .add_source(File::from(path).required(true))
+        with_environment(Config::builder().add_source(File::from(path).required(true)))?
             .build()
             .map_err(Error::Build)?
-            .try_deserialize()
-            .map_err(Error::Serialize)
+            .try_deserialize::<Self>()
+            .map_err(Error::Serialize)?
+            .validate()
     }
 }
 
@@ -81,7 +1057,7 @@ impl ConnectorConfiguration {
     pub fn try_new() -> Result<Self, Error> {
         let homedir = env::var("HOME").map_err(|err| Error::EnvironmentVariable("HOME", err))?;
 
-        Config::builder()
+        let builder = Config::builder()
             .add_source(
                 File::from(PathBuf::from(format!(
                     "/usr/share/{}/config",
@@ -112,10 +1088,237 @@ impl ConnectorConfiguration {
                 )))
                 .required(false),
             )
-            .add_source(File::from(PathBuf::from("config")).required(false))
+            .add_source(File::from(PathBuf::from("config")).required(false));
+
+        with_environment(builder)?
             .build()
             .map_err(Error::Build)?
-            .try_deserialize()
-            .map_err(Error::Serialize)
+            .try_deserialize::<Self>()
+            .map_err(Error::Serialize)?
+            .validate()
+    }
+
+    /// Pki directories to look up, archives are replaced by the directory
+    /// they are extracted to
+    pub fn roots(&self) -> Vec<PathBuf> {
+        self.sozu
+            .pki
+            .iter()
+            .map(|path| {
+                if Archive::is_archive(path) {
+                    self.archive.destination(path)
+                } else {
+                    path.to_owned()
+                }
+            })
+            .collect()
+    }
+
+    /// Returns true if certificate directories are read the same way under
+    /// both configurations, so that what was read under one of them holds
+    /// under the other
+    pub fn reads_like(&self, other: &Self) -> bool {
+        self.layout == other.layout
+            && self.default_tls_versions == other.default_tls_versions
+            && self.strict_permissions == other.strict_permissions
+            && self.verify_chain == other.verify_chain
+            && self.strict_options == other.strict_options
+            && self.key_policy == other.key_policy
+            && self.strict_names == other.strict_names
+    }
+
+    /// Reject values that deserialize but cannot be used, once files and
+    /// environment variables are merged
+    fn validate(self) -> Result<Self, Error> {
+        if self.interval < MIN_INTERVAL {
+            return Err(Error::Interval(self.interval));
+        }
+
+        if !self.metrics.is_valid() {
+            return Err(Error::MetricsPrefix(self.metrics.prefix));
+        }
+
+        // Every certificate would be refused
+        if self
+            .key_policy
+            .as_ref()
+            .is_some_and(|policy| policy.algorithms.is_empty())
+        {
+            return Err(Error::KeyPolicyAlgorithms);
+        }
+
+        if self.archive.directory.as_os_str().is_empty() {
+            if let Some(path) = self.sozu.pki.iter().find(|path| Archive::is_archive(path)) {
+                return Err(Error::ArchiveDirectory(path.to_owned()));
+            }
+        }
+
+        for instance in self.sozu.instances() {
+            match instance.endpoint {
+                None
+                | Some(Endpoint {
+                    unix: Some(_),
+                    tcp: None,
+                }) => {}
+                Some(Endpoint {
+                    unix: None,
+                    tcp: Some(addr),
+                }) => return Err(Error::TcpEndpoint(instance.name, addr)),
+                Some(_) => return Err(Error::Endpoint(instance.name)),
+            }
+        }
+
+        Ok(self)
+    }
+}
+
+/// Layer the environment variables of [`ENVIRONMENT`] that are set over the
+/// given configuration
+fn with_environment(
+    mut builder: ConfigBuilder<DefaultState>,
+) -> Result<ConfigBuilder<DefaultState>, Error> {
+    for (var, key, list) in ENVIRONMENT {
+        let value = match env::var(var) {
+            Ok(value) => value,
+            Err(VarError::NotPresent) => continue,
+            Err(err) => return Err(Error::EnvironmentVariable(var, err)),
+        };
+
+        builder = if *list {
+            let values: Vec<String> = value
+                .split(',')
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(String::from)
+                .collect();
+
+            builder.set_override(*key, values)
+        } else {
+            builder.set_override(*key, value)
+        }
+        .map_err(Error::Build)?;
+    }
+
+    Ok(builder)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use config::FileFormat;
+
+    use super::*;
+
+    /// Parse and validate the given configuration, environment variables are
+    /// ignored so that tests do not depend on it
+    pub fn parse(content: &str) -> Result<ConnectorConfiguration, Error> {
+        Config::builder()
+            .add_source(File::from_str(content, FileFormat::Toml))
+            .build()
+            .map_err(Error::Build)?
+            .try_deserialize::<ConnectorConfiguration>()
+            .map_err(Error::Serialize)?
+            .validate()
+    }
+
+    /// Returns a configuration looking up the given pki directory with the
+    /// given top-level keys, tables must be written inline
+    pub fn configuration(pki: &Path, keys: &str) -> ConnectorConfiguration {
+        parse(&format!(
+            r#"
+            {keys}
+            listening-address = "127.0.0.1:3031"
+            interval = 1_000
+            [sozu]
+            pki = "{}"
+            configuration = "/etc/sozu/config.toml"
+            listener = "127.0.0.1:8443"
+            "#,
+            pki.display()
+        ))
+        .expect("configuration to be valid")
+    }
+
+    const MINIMAL: &str = r#"
+        listening-address = "127.0.0.1:3031"
+        interval = 1_000
+        [sozu]
+        pki = "/etc/pki"
+        configuration = "/etc/sozu/config.toml"
+    "#;
+
+    #[test]
+    fn key_policy_without_algorithm_is_refused() {
+        let content = format!("{MINIMAL}\n[key-policy]\nalgorithms = []\n");
+        assert!(matches!(parse(&content), Err(Error::KeyPolicyAlgorithms)));
+    }
+
+    #[test]
+    fn endpoints_must_be_a_single_unix_socket() {
+        let content = format!("{MINIMAL}\n[sozu.endpoint]\nunix = \"/run/sozu/sozu.sock\"\n");
+        assert!(parse(&content).is_ok());
+
+        let content = format!("{MINIMAL}\n[sozu.endpoint]\ntcp = \"127.0.0.1:9000\"\n");
+        assert!(matches!(
+            parse(&content),
+            Err(Error::TcpEndpoint(name, addr)) if DEFAULT_INSTANCE == name && "127.0.0.1:9000" == addr
+        ));
+
+        let content = format!("{MINIMAL}\n[sozu.endpoint]\n");
+        assert!(matches!(parse(&content), Err(Error::Endpoint(name)) if DEFAULT_INSTANCE == name));
+
+        let content = format!(
+            r#"{MINIMAL}
+            [[sozu.instances]]
+            name = "green"
+            configuration = "/etc/sozu/green.toml"
+            [sozu.instances.endpoint]
+            unix = "/run/sozu/green.sock"
+            tcp = "127.0.0.1:9000"
+            "#
+        );
+        assert!(matches!(parse(&content), Err(Error::Endpoint(name)) if "green" == name));
+    }
+
+    #[test]
+    fn archives_require_a_directory_to_be_extracted_to() {
+        let content = MINIMAL.replace("/etc/pki", "/etc/pki.tar.gz");
+        assert!(matches!(
+            parse(&content),
+            Err(Error::ArchiveDirectory(path)) if Path::new("/etc/pki.tar.gz") == path
+        ));
+
+        let content = format!("{content}\n[archive]\ndirectory = \"/run/sozu-pki-connector\"\n");
+        assert!(parse(&content).is_ok());
+        assert!(parse(MINIMAL).is_ok());
+    }
+
+    #[test]
+    fn metrics_are_prefixed_once() {
+        let config = parse(MINIMAL).expect("configuration");
+        for name in [
+            "proxy_manager_certificate_request_emitted",
+            "http_access_requests_count",
+        ] {
+            assert_eq!(name, config.metrics.name(name));
+        }
+
+        let config =
+            parse(&format!("{MINIMAL}\n[metrics]\nprefix = \"edge\"\n")).expect("configuration");
+        assert_eq!(
+            "edge_certificate_request_emitted",
+            config
+                .metrics
+                .name("proxy_manager_certificate_request_emitted")
+        );
+        assert_eq!(
+            "edge_http_access_requests_count",
+            config.metrics.name("http_access_requests_count")
+        );
+    }
+
+    #[test]
+    fn key_policy_defaults_are_accepted() {
+        let config = parse(&format!("{MINIMAL}\n[key-policy]\n")).expect("configuration");
+        assert_eq!(Some(KeyPolicy::default()), config.key_policy);
     }
 }
@@ -0,0 +1,75 @@
+//! # Health module
+//!
+//! This module provides the health state shared between the watcher and the
+//! HTTP server
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// -----------------------------------------------------------------------------
+// Health
+
+#[derive(Debug, Default)]
+pub struct Health {
+    /// At least one lookup of the pki directory has completed successfully
+    synced: AtomicBool,
+    /// The last exchange with Sōzu succeeded
+    connected: AtomicBool,
+}
+
+impl Health {
+    /// Record whether the last lookup of the pki directory has completed
+    /// successfully
+    pub fn set_synced(&self, synced: bool) {
+        self.synced.store(synced, Ordering::Release);
+    }
+
+    /// Record whether the last exchange with Sōzu succeeded. Once it failed,
+    /// what Sōzu serves is unknown until the next successful lookup.
+    pub fn set_connected(&self, connected: bool) {
+        self.connected.store(connected, Ordering::Release);
+        if !connected {
+            self.set_synced(false);
+        }
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.synced.load(Ordering::Acquire)
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Acquire)
+    }
+
+    /// Returns true if the connector is able to serve its purpose
+    pub fn is_ready(&self) -> bool {
+        self.is_synced() && self.is_connected()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readiness_is_lost_on_failed_lookups_and_disconnects() {
+        let health = Health::default();
+        assert!(!health.is_ready());
+
+        health.set_connected(true);
+        health.set_synced(true);
+        assert!(health.is_ready());
+
+        health.set_synced(false);
+        assert!(!health.is_ready());
+
+        health.set_synced(true);
+        health.set_connected(false);
+        assert!(!health.is_synced());
+
+        // Reconnecting is not enough, a lookup has to complete again
+        health.set_connected(true);
+        assert!(!health.is_ready());
+        health.set_synced(true);
+        assert!(health.is_ready());
+    }
+}
@@ -2,12 +2,23 @@
 //!
 //! This module provides handlers to use with the server implementation
 
-use std::time::SystemTime;
+use std::{sync::Arc, time::SystemTime};
 
-use axum::http::{HeaderValue, Request, Response};
+use axum::{
+    extract::State,
+    http::{HeaderValue, Request, Response},
+};
 use hyper::{Body, StatusCode};
 use prometheus::{Encoder, TextEncoder};
 
+use tokio::sync::{mpsc, oneshot};
+
+use crate::svc::{
+    certificates::watcher::{Inventory, SyncRequest},
+    config::Metrics,
+    health::Health,
+};
+
 // -----------------------------------------------------------------------------
 // Constants
 
@@ -64,16 +75,210 @@ pub async fn healthz(req: Request<Body>) -> Response<Body> {
     res
 }
 
+// -----------------------------------------------------------------------------
+// Readyz
+
+/// Returns 200 if the last lookup has completed and the connection to Sōzu is
+/// healthy, 503 otherwise
+#[tracing::instrument]
+pub async fn readyz(State(health): State<Arc<Health>>, req: Request<Body>) -> Response<Body> {
+    let mut res = healthz(req).await;
+    if !health.is_ready() {
+        let message = serde_json::json!({
+            "message": "Not ready",
+            "synced": health.is_synced(),
+            "connected": health.is_connected(),
+        })
+        .to_string();
+
+        res.headers_mut().insert(
+            hyper::header::CONTENT_LENGTH,
+            HeaderValue::from_str(&message.len().to_string())
+                .expect("constant to be iso8859-1 compliant"),
+        );
+
+        *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        *res.body_mut() = Body::from(message);
+    }
+
+    res
+}
+
+// -----------------------------------------------------------------------------
+// Status
+
+/// Returns the outcome of the last full lookup, the schedule of the next one
+/// and the number of certificates managed
+#[tracing::instrument]
+pub async fn status(
+    State(health): State<Arc<Health>>,
+    State(inventory): State<Inventory>,
+    _req: Request<Body>,
+) -> Response<Body> {
+    let (status, message) = match inventory.read() {
+        Ok(inventory) => {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|duration| duration.as_millis() as i64)
+                .unwrap_or_default();
+
+            let status = &inventory.status;
+            (
+                StatusCode::OK,
+                serde_json::json!({
+                    "synced": health.is_synced(),
+                    "connected": health.is_connected(),
+                    "managed": inventory.managed.len(),
+                    "quarantined": inventory.quarantined.len(),
+                    "interval": status.interval,
+                    "last_scan_started_at": status.last_scan_started_at,
+                    "last_scan_ended_at": status.last_scan_ended_at,
+                    "last_scan_duration": status.last_scan_duration,
+                    "last_scan_error": status.last_scan_error,
+                    "last_summary": status.last_summary,
+                    "next_scan_at": status.next_scan_at,
+                    "next_scan_in": status.next_scan_at.map(|at| (at - now).max(0)),
+                }),
+            )
+        }
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            serde_json::json!({"error": err.to_string()}),
+        ),
+    };
+
+    json(status, message)
+}
+
+// -----------------------------------------------------------------------------
+// Version
+
+/// Returns the version of the connector, the commit and timestamp of its build
+/// and the versions of the Sōzu libraries it is compiled against
+#[tracing::instrument]
+pub async fn version(_req: Request<Body>) -> Response<Body> {
+    json(
+        StatusCode::OK,
+        serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "commit": env!("BUILD_GIT_COMMIT"),
+            "build_timestamp": env!("BUILD_TIMESTAMP"),
+            "sozu_client": env!("BUILD_SOZU_CLIENT_VERSION"),
+            "sozu_command_lib": env!("BUILD_SOZU_COMMAND_LIB_VERSION"),
+        }),
+    )
+}
+
+// -----------------------------------------------------------------------------
+// Sync
+
+/// Trigger an immediate lookup of the pki directories and returns its summary,
+/// concurrent triggers are coalesced into a single lookup
+#[tracing::instrument]
+pub async fn sync(
+    State(syncs): State<mpsc::Sender<SyncRequest>>,
+    _req: Request<Body>,
+) -> Response<Body> {
+    let (tx, rx) = oneshot::channel();
+    let (status, message) = match syncs.send(tx).await {
+        Err(_) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            serde_json::json!({"error": "watcher is not running"}),
+        ),
+        Ok(_) => match rx.await {
+            Ok(Ok(summary)) => (StatusCode::OK, serde_json::json!(summary)),
+            Ok(Err(err)) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                serde_json::json!({ "error": err }),
+            ),
+            Err(_) => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                serde_json::json!({"error": "watcher stopped before the end of the lookup"}),
+            ),
+        },
+    };
+
+    json(status, message)
+}
+
+// -----------------------------------------------------------------------------
+// Certificates
+
+/// List certificates currently managed by the connector and the quarantined
+/// ones, sorted by path
+#[tracing::instrument]
+pub async fn certificates(
+    State(inventory): State<Inventory>,
+    _req: Request<Body>,
+) -> Response<Body> {
+    let (status, message) = match inventory.read() {
+        Ok(inventory) => {
+            let mut certificates = inventory
+                .managed
+                .values()
+                .map(|metadata| (metadata, false))
+                .chain(
+                    inventory
+                        .quarantined
+                        .values()
+                        .map(|metadata| (metadata, true)),
+                )
+                .collect::<Vec<_>>();
+            certificates.sort_by(|(a, x), (b, y)| (&a.path, x).cmp(&(&b.path, y)));
+
+            let certificates = certificates
+                .into_iter()
+                .map(|(metadata, quarantined)| {
+                    let mut names = metadata.names.iter().collect::<Vec<_>>();
+                    names.sort();
+
+                    let mut chain_fingerprints = metadata
+                        .chain_fingerprints
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>();
+                    chain_fingerprints.sort();
+
+                    serde_json::json!({
+                        "path": metadata.path,
+                        "fingerprint": metadata.fingerprint.to_string(),
+                        "names": names,
+                        "chain_fingerprints": chain_fingerprints,
+                        "not_before": metadata.not_before,
+                        "expires_at": metadata.expires_at,
+                        "serial": metadata.serial,
+                        "issuer": metadata.issuer,
+                        "listener": metadata.listener,
+                        "quarantined": quarantined,
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            (StatusCode::OK, serde_json::json!(certificates))
+        }
+        Err(err) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            serde_json::json!({"error": err.to_string()}),
+        ),
+    };
+
+    json(status, message)
+}
+
 // -----------------------------------------------------------------------------
 // Telemetry
 
 #[tracing::instrument]
 /// Retrieve Sōzu internals and connector telemetry
-pub async fn telemetry(_req: Request<Body>) -> Response<Body> {
+pub async fn telemetry(State(config): State<Arc<Metrics>>, _req: Request<Body>) -> Response<Body> {
     let mut res = Response::default();
 
     let encoder = TextEncoder::new();
-    let metrics = prometheus::gather();
+    let mut metrics = prometheus::gather();
+    for family in &mut metrics {
+        let name = config.name(family.get_name());
+        family.set_name(name);
+    }
 
     let mut buf = vec![];
     match encoder.encode(&metrics, &mut buf) {
@@ -118,3 +323,29 @@ pub async fn telemetry(_req: Request<Body>) -> Response<Body> {
 
     res
 }
+
+// -----------------------------------------------------------------------------
+// Helpers
+
+/// Create a response with the given status and json body
+fn json(status: StatusCode, message: serde_json::Value) -> Response<Body> {
+    let message = message.to_string();
+    let mut res = Response::default();
+    let headers = res.headers_mut();
+
+    headers.insert(
+        hyper::header::CONTENT_TYPE,
+        HeaderValue::from_str(mime::APPLICATION_JSON.as_ref())
+            .expect("constant to be iso8859-1 compliant"),
+    );
+
+    headers.insert(
+        hyper::header::CONTENT_LENGTH,
+        HeaderValue::from_str(&message.len().to_string())
+            .expect("buffer size to be iso8859-1 compliant"),
+    );
+
+    *res.status_mut() = status;
+    *res.body_mut() = Body::from(message);
+    res
+}
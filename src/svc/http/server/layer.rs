@@ -3,12 +3,21 @@
 //! This module provides middlewares to give to the server implementation.
 //! It could be seen as interceptor in h2.
 
-use std::{fmt::Debug, time::Instant};
-
-use axum::{http::Request, middleware::Next};
+use std::{fmt::Debug, sync::Arc, time::Instant};
+
+use axum::{
+    extract::State,
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::IntoResponse,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use hyper::StatusCode;
 use once_cell::sync::Lazy;
 use prometheus::{register_int_counter_vec, IntCounterVec};
-use tracing::{info, info_span, Instrument};
+use tracing::{debug, info, info_span, Instrument};
+
+use crate::svc::config::{Auth, Http};
 
 // -----------------------------------------------------------------------------
 // Telemetry
@@ -113,3 +122,78 @@ where
 
     res
 }
+
+// -----------------------------------------------------------------------------
+// Authentication
+
+/// Reject requests without the configured credentials with a 401, paths
+/// listed as exempt are always let through
+#[tracing::instrument(skip_all)]
+pub async fn authenticate<T>(
+    State(http): State<Arc<Http>>,
+    req: Request<T>,
+    next: Next<T>,
+) -> axum::response::Response
+where
+    T: Debug,
+{
+    let Some(auth) = &http.auth else {
+        return next.run(req).await;
+    };
+
+    if http.exempt.iter().any(|path| path == req.uri().path()) {
+        return next.run(req).await;
+    }
+
+    let authorization = req
+        .headers()
+        .get(hyper::header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok());
+
+    if authorization.is_some_and(|authorization| is_authorized(auth, authorization)) {
+        return next.run(req).await;
+    }
+
+    debug!(
+        uri = req.uri().to_string(),
+        "Reject request with missing or invalid credentials"
+    );
+
+    let challenge = match auth {
+        Auth::Basic { .. } => "Basic realm=\"sozu-pki-connector\"",
+        Auth::Bearer { .. } => "Bearer",
+    };
+
+    let mut res = StatusCode::UNAUTHORIZED.into_response();
+    res.headers_mut().insert(
+        hyper::header::WWW_AUTHENTICATE,
+        HeaderValue::from_static(challenge),
+    );
+
+    res
+}
+
+/// Check the value of an authorization header against the configured
+/// credentials
+fn is_authorized(auth: &Auth, authorization: &str) -> bool {
+    let Some((scheme, credentials)) = authorization.trim().split_once(' ') else {
+        return false;
+    };
+
+    match auth {
+        Auth::Basic { username, password } if scheme.eq_ignore_ascii_case("basic") => STANDARD
+            .decode(credentials.trim())
+            .is_ok_and(|credentials| {
+                constant_time_eq(&credentials, format!("{username}:{password}").as_bytes())
+            }),
+        Auth::Bearer { token } if scheme.eq_ignore_ascii_case("bearer") => {
+            constant_time_eq(credentials.trim().as_bytes(), token.as_bytes())
+        }
+        _ => false,
+    }
+}
+
+/// Compare secrets without leaking the position of the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -2,20 +2,28 @@
 //!
 //! This module provide a server implementation with a lite router
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use axum::{
+    extract::FromRef,
     middleware::{self},
-    routing::{any, get},
+    routing::{any, get, post},
     Router,
 };
 use hyper::Server;
 use tracing::info;
 
-use crate::svc::config::ConnectorConfiguration;
+use tokio::{net::TcpListener, sync::mpsc};
+
+use crate::svc::{
+    certificates::watcher::{Inventory, SyncRequest},
+    config::{ConnectorConfiguration, Metrics},
+    health::Health,
+};
 
 pub mod handler;
 pub mod layer;
+pub mod tls;
 
 // -----------------------------------------------------------------------------
 // Error
@@ -24,6 +32,10 @@ pub mod layer;
 pub enum Error {
     #[error("failed to bind server, {0}")]
     Bind(hyper::Error),
+    #[error("failed to bind tls server, {0}")]
+    BindTls(std::io::Error),
+    #[error("failed to load certificate of the server, {0}")]
+    Tls(tls::Error),
     #[error("failed to serve content, {0}")]
     Serve(hyper::Error),
     #[error("failed to create client, {0}")]
@@ -32,21 +44,65 @@ pub enum Error {
     CanonicalizeSocket(sozu_client::config::Error),
 }
 
+// -----------------------------------------------------------------------------
+// Context
+
+/// State shared between the watcher and the handlers
+#[derive(Clone, Debug)]
+pub struct Context {
+    pub health: Arc<Health>,
+    pub inventory: Inventory,
+    pub syncs: mpsc::Sender<SyncRequest>,
+    pub metrics: Arc<Metrics>,
+}
+
+impl FromRef<Context> for Inventory {
+    fn from_ref(context: &Context) -> Self {
+        context.inventory.to_owned()
+    }
+}
+
+impl FromRef<Context> for Arc<Health> {
+    fn from_ref(context: &Context) -> Self {
+        context.health.to_owned()
+    }
+}
+
+impl FromRef<Context> for Arc<Metrics> {
+    fn from_ref(context: &Context) -> Self {
+        context.metrics.to_owned()
+    }
+}
+
+impl FromRef<Context> for mpsc::Sender<SyncRequest> {
+    fn from_ref(context: &Context) -> Self {
+        context.syncs.to_owned()
+    }
+}
+
 // -----------------------------------------------------------------------------
 // helpers
 
 #[tracing::instrument(skip_all)]
-pub async fn serve(config: Arc<ConnectorConfiguration>) -> Result<(), Error> {
+pub async fn serve(config: Arc<ConnectorConfiguration>, context: Context) -> Result<(), Error> {
     // -------------------------------------------------------------------------
     // Create router
     let router = Router::new()
         .route("/healthz", get(handler::healthz))
         .route("/livez", get(handler::healthz))
-        .route("/readyz", get(handler::healthz))
-        .route("/status", get(handler::healthz))
+        .route("/readyz", get(handler::readyz))
+        .route("/status", get(handler::status))
         .route("/metrics", get(handler::telemetry))
+        .route("/version", get(handler::version))
+        .route("/sync", post(handler::sync))
+        .route("/certificates", get(handler::certificates))
         .fallback(any(handler::not_found))
-        .layer(middleware::from_fn(layer::access));
+        .layer(middleware::from_fn_with_state(
+            Arc::new(config.http.to_owned()),
+            layer::authenticate,
+        ))
+        .layer(middleware::from_fn(layer::access))
+        .with_state(context);
 
     // -------------------------------------------------------------------------
     // Serve router
@@ -55,11 +111,115 @@ pub async fn serve(config: Arc<ConnectorConfiguration>) -> Result<(), Error> {
         "Begin to listen on address"
     );
 
-    Server::try_bind(&config.listening_address)
-        .map_err(Error::Bind)?
+    let Some(tls) = &config.http.tls else {
+        Server::try_bind(&config.listening_address)
+            .map_err(Error::Bind)?
+            .serve(router.into_make_service())
+            .await
+            .map_err(Error::Serve)?;
+
+        return Ok(());
+    };
+
+    info!(
+        path = tls.certificate.display().to_string(),
+        "Serve over TLS"
+    );
+
+    let resolver = Arc::new(tls::Resolver::try_new(tls).await.map_err(Error::Tls)?);
+    tokio::spawn(tls::watch(
+        resolver.to_owned(),
+        tls.to_owned(),
+        Duration::from_millis(config.interval),
+    ));
+
+    let listener = TcpListener::bind(&config.listening_address)
+        .await
+        .map_err(Error::BindTls)?;
+
+    Server::builder(tls::incoming(listener, resolver))
         .serve(router.into_make_service())
         .await
         .map_err(Error::Serve)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::svc::{
+        certificates::{
+            tests::{self_signed, write_directory},
+            watcher::tests::{watcher, Mock},
+        },
+        config::tests::configuration,
+    };
+
+    /// Send a GET request to the server listening on the given address,
+    /// returns the response if any
+    async fn get(addr: SocketAddr, path: &str) -> Option<String> {
+        let mut stream = tokio::net::TcpStream::connect(addr).await.ok()?;
+        let request =
+            format!("GET {path} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.ok()?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.ok()?;
+        Some(response)
+    }
+
+    #[tokio::test]
+    async fn metrics_keep_their_historical_names_by_default() {
+        let pki = tempfile::tempdir().expect("temporary directory to be created");
+        let (certificate, key) = self_signed(None, &["example.com"]);
+        write_directory(pki.path(), "example", &certificate, &key);
+
+        let mut config = configuration(pki.path(), "");
+        config.listening_address = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("port to be available");
+
+        // Emit requests to Sōzu so that the metrics of the watcher are set
+        let mock = Mock::default();
+        let mut watcher = watcher(pki.path(), "", &mock).await;
+        watcher.lookup().await.expect("lookup to succeed");
+
+        let (syncs, _rx) = mpsc::channel(1);
+        let context = Context {
+            health: Arc::default(),
+            inventory: Inventory::default(),
+            syncs,
+            metrics: Arc::new(config.metrics.to_owned()),
+        };
+
+        let addr = config.listening_address;
+        tokio::spawn(serve(Arc::new(config), context));
+        while get(addr, "/healthz").await.is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let response = get(addr, "/metrics").await.expect("metrics to be exposed");
+        let names: Vec<_> = response
+            .lines()
+            .filter_map(|line| line.strip_prefix("# TYPE "))
+            .filter_map(|line| line.split_whitespace().next())
+            .collect();
+
+        for name in [
+            "proxy_manager_certificate_request_emitted",
+            "http_access_requests_count",
+            "http_access_requests_duration",
+        ] {
+            assert!(names.contains(&name), "{name} in {names:?}");
+        }
+
+        assert!(!names
+            .iter()
+            .any(|name| name.starts_with("proxy_manager_http")));
+    }
+}
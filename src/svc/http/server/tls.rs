@@ -0,0 +1,360 @@
+//! # Tls module
+//!
+//! This module provides the TLS termination of the HTTP server, its own
+//! certificate is reloaded when its files change
+
+use std::{
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    task::Poll,
+    time::{Duration, SystemTime},
+};
+
+use hyper::server::accept::{self, Accept};
+use tokio::{
+    fs,
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+use tokio_rustls::{
+    rustls::{
+        server::{ClientHello, ResolvesServerCert},
+        sign::{self, CertifiedKey},
+        Certificate, PrivateKey, ServerConfig,
+    },
+    server::TlsStream,
+    TlsAcceptor,
+};
+use tracing::{debug, error, info, warn};
+use x509_parser::{error::X509Error, prelude::parse_x509_certificate};
+use zeroize::Zeroizing;
+
+use crate::svc::{certificates::key, config::Tls};
+
+// -----------------------------------------------------------------------------
+// Constants
+
+/// Maximum duration of a TLS handshake, a client that does not complete it in
+/// time is disconnected
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of connections whose handshake is completed waiting to be served
+pub const BACKLOG: usize = 128;
+
+// -----------------------------------------------------------------------------
+// Error
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to read '{0}', {1}")]
+    Read(PathBuf, io::Error),
+    #[error("failed to parse pem file '{0}', {1}")]
+    Parse(PathBuf, io::Error),
+    #[error("file '{0}' does not hold any certificate")]
+    NoCertificate(PathBuf),
+    #[error("file '{0}' does not hold any private key")]
+    NoKey(PathBuf),
+    #[error("failed to use private key '{0}', {1}")]
+    Key(PathBuf, sign::SignError),
+    #[error("failed to parse certificate '{0}', {1}")]
+    ParseCertificate(PathBuf, x509_parser::nom::Err<X509Error>),
+    #[error("failed to derive public key from private key '{0}', {1}")]
+    PublicKey(PathBuf, key::Error),
+    #[error("private key '{0}' does not match the certificate")]
+    KeyCertificateMismatch(PathBuf),
+}
+
+// -----------------------------------------------------------------------------
+// Resolver
+
+/// Provide the current certificate of the server to TLS handshakes
+pub struct Resolver {
+    certified: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.certified
+            .read()
+            .ok()
+            .map(|certified| certified.to_owned())
+    }
+}
+
+impl Resolver {
+    #[tracing::instrument]
+    pub async fn try_new(tls: &Tls) -> Result<Self, Error> {
+        Ok(Self {
+            certified: RwLock::new(Arc::new(load(tls).await?)),
+        })
+    }
+
+    fn set(&self, certified: CertifiedKey) {
+        if let Ok(mut guard) = self.certified.write() {
+            *guard = Arc::new(certified);
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Helpers
+
+/// Read the certificate, its chain and the private key of the server, the
+/// private key has to belong to the certificate
+#[tracing::instrument]
+async fn load(tls: &Tls) -> Result<CertifiedKey, Error> {
+    let mut certificates = vec![];
+    for item in parse(&tls.certificate, &read(&tls.certificate).await?)? {
+        if let rustls_pemfile::Item::X509Certificate(der) = item {
+            certificates.push(Certificate(der));
+        }
+    }
+
+    if certificates.is_empty() {
+        return Err(Error::NoCertificate(tls.certificate.to_owned()));
+    }
+
+    let data = read(&tls.key).await?;
+    let key = parse(&tls.key, &data)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| Error::NoKey(tls.key.to_owned()))?;
+
+    // Files may be replaced one after the other, a certificate is only used
+    // along with its own private key
+    let (_, x509) = parse_x509_certificate(&certificates[0].0)
+        .map_err(|err| Error::ParseCertificate(tls.certificate.to_owned(), err))?;
+    let pem = std::str::from_utf8(&data).map_err(|_| Error::NoKey(tls.key.to_owned()))?;
+    match key::matches(&x509, pem).map_err(|err| Error::PublicKey(tls.key.to_owned(), err))? {
+        Some(true) => {}
+        Some(false) => return Err(Error::KeyCertificateMismatch(tls.key.to_owned())),
+        None => {
+            debug!(
+                path = tls.key.display().to_string(),
+                "Kind of private key is not supported, skip the check against the certificate"
+            );
+        }
+    }
+
+    let key = sign::any_supported_type(&key).map_err(|err| Error::Key(tls.key.to_owned(), err))?;
+    Ok(CertifiedKey::new(certificates, key))
+}
+
+/// Read the given file, its content is zeroed once dropped as it may hold a
+/// private key
+async fn read(path: &Path) -> Result<Zeroizing<Vec<u8>>, Error> {
+    Ok(Zeroizing::new(
+        fs::read(path)
+            .await
+            .map_err(|err| Error::Read(path.to_owned(), err))?,
+    ))
+}
+
+/// Parse all pem blocks of the given file content
+fn parse(path: &Path, data: &[u8]) -> Result<Vec<rustls_pemfile::Item>, Error> {
+    rustls_pemfile::read_all(&mut BufReader::new(data))
+        .map_err(|err| Error::Parse(path.to_owned(), err))
+}
+
+/// Modification time and size of the files of the server certificate
+async fn stamp(tls: &Tls) -> Vec<Option<(SystemTime, u64)>> {
+    let mut acc = vec![];
+    for path in [&tls.certificate, &tls.key] {
+        acc.push(
+            fs::metadata(path)
+                .await
+                .ok()
+                .and_then(|metadata| Some((metadata.modified().ok()?, metadata.len()))),
+        );
+    }
+
+    acc
+}
+
+/// Check the files of the server certificate at each interval and reload them
+/// once they changed, the previous certificate is kept if they are invalid.
+/// Invalid files are read again at the next interval, as they may be in the
+/// middle of being written.
+#[tracing::instrument(skip(resolver))]
+pub async fn watch(resolver: Arc<Resolver>, tls: Tls, interval: Duration) {
+    let mut previous = stamp(&tls).await;
+    loop {
+        sleep(interval).await;
+
+        let current = stamp(&tls).await;
+        if current == previous {
+            continue;
+        }
+
+        match load(&tls).await {
+            Ok(certified) => {
+                resolver.set(certified);
+                info!(
+                    path = tls.certificate.display().to_string(),
+                    "Reloaded certificate of the HTTP server"
+                );
+
+                previous = current;
+            }
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    "Could not reload certificate of the HTTP server, keep the previous one"
+                );
+            }
+        }
+    }
+}
+
+/// Accept connections on the given listener and perform their TLS handshake,
+/// handshakes are performed concurrently so that a slow client does not hold
+/// back the others
+#[tracing::instrument(skip_all)]
+pub fn incoming(
+    listener: TcpListener,
+    resolver: Arc<Resolver>,
+) -> impl Accept<Conn = TlsStream<TcpStream>, Error = io::Error> {
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver);
+
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    let (tx, mut rx) = mpsc::channel(BACKLOG);
+
+    tokio::spawn(async move {
+        while !tx.is_closed() {
+            let (stream, addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    warn!(
+                        error = err.to_string(),
+                        "Could not accept connection on the HTTP server"
+                    );
+
+                    // Typically too many opened files, let some be closed
+                    sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+
+            let acceptor = acceptor.to_owned();
+            let tx = tx.to_owned();
+            tokio::spawn(async move {
+                match timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        // The server is gone, there is nobody left to serve it
+                        let _ = tx.send(stream).await;
+                    }
+                    Ok(Err(err)) => {
+                        debug!(
+                            error = err.to_string(),
+                            addr = addr.to_string(),
+                            "Could not complete TLS handshake"
+                        );
+                    }
+                    Err(_) => {
+                        debug!(addr = addr.to_string(), "TLS handshake timed out");
+                    }
+                }
+            });
+        }
+    });
+
+    accept::poll_fn(move |cx| match rx.poll_recv(cx) {
+        Poll::Ready(stream) => Poll::Ready(stream.map(Ok)),
+        Poll::Pending => Poll::Pending,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::svc::certificates::tests::self_signed;
+
+    /// Returns the der encoded certificate currently provided by the resolver
+    fn current(resolver: &Resolver) -> Vec<u8> {
+        let certified = resolver
+            .certified
+            .read()
+            .expect("lock to not be poisoned")
+            .to_owned();
+
+        certified.cert[0].0.to_owned()
+    }
+
+    fn der(certificate: &str) -> Vec<u8> {
+        match rustls_pemfile::read_one(&mut BufReader::new(certificate.as_bytes())) {
+            Ok(Some(rustls_pemfile::Item::X509Certificate(der))) => der,
+            _ => panic!("certificate to be pem encoded"),
+        }
+    }
+
+    #[tokio::test]
+    async fn mismatching_key_is_rejected() {
+        let dir = tempfile::tempdir().expect("temporary directory to be created");
+        let tls = Tls {
+            certificate: dir.path().join("server.crt"),
+            key: dir.path().join("server.key"),
+        };
+
+        let (certificate, _) = self_signed(Some("first.example.com"), &["first.example.com"]);
+        let (_, key) = self_signed(Some("second.example.com"), &["second.example.com"]);
+        std::fs::write(&tls.certificate, certificate).expect("certificate to be written");
+        std::fs::write(&tls.key, key).expect("key to be written");
+
+        assert!(matches!(
+            Resolver::try_new(&tls).await,
+            Err(Error::KeyCertificateMismatch(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn previous_certificate_is_kept_until_its_key_is_replaced() {
+        let dir = tempfile::tempdir().expect("temporary directory to be created");
+        let tls = Tls {
+            certificate: dir.path().join("server.crt"),
+            key: dir.path().join("server.key"),
+        };
+
+        let (first, first_key) = self_signed(Some("first.example.com"), &["first.example.com"]);
+        std::fs::write(&tls.certificate, &first).expect("certificate to be written");
+        std::fs::write(&tls.key, first_key).expect("key to be written");
+
+        let resolver = Arc::new(Resolver::try_new(&tls).await.expect("resolver to load"));
+        let watcher = tokio::spawn(watch(
+            resolver.to_owned(),
+            tls.to_owned(),
+            Duration::from_millis(10),
+        ));
+
+        // The certificate is replaced before its key, the previous pair is kept
+        // in the meantime
+        let (second, second_key) = self_signed(Some("second.example.com"), &["second.example.com"]);
+        std::fs::write(&tls.certificate, &second).expect("certificate to be written");
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(der(&first), current(&resolver));
+
+        std::fs::write(&tls.key, second_key).expect("key to be written");
+        let mut reloaded = false;
+        for _ in 0..100 {
+            sleep(Duration::from_millis(10)).await;
+            if der(&second) == current(&resolver) {
+                reloaded = true;
+                break;
+            }
+        }
+
+        watcher.abort();
+        assert!(reloaded);
+    }
+}
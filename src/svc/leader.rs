@@ -0,0 +1,111 @@
+//! # Leader module
+//!
+//! This module provides a lock on a file shared between replicas of the
+//! connector, so that only one of them sends requests to Sōzu
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    os::unix::{fs::MetadataExt, io::AsRawFd},
+    path::{Path, PathBuf},
+    process,
+};
+
+use tracing::debug;
+
+// -----------------------------------------------------------------------------
+// Error
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to open leader lock '{0}', {1}")]
+    Open(PathBuf, io::Error),
+    #[error("failed to lock leader lock '{0}', {1}")]
+    Lock(PathBuf, io::Error),
+}
+
+// -----------------------------------------------------------------------------
+// LeaderLock
+
+/// Exclusive advisory lock on a file, it is released when dropped or when the
+/// process exits. The file itself is left in place, removing it would let
+/// another replica lock a new file while the previous one is still locked.
+#[derive(Debug)]
+pub struct LeaderLock {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl LeaderLock {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_owned(),
+            file: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns true if the lock is held, that is the file that is locked is
+    /// still the one at the path. It is lost if the file has been removed or
+    /// replaced, as another replica may then lock the new one.
+    pub fn is_held(&self) -> bool {
+        let Some(file) = &self.file else {
+            return false;
+        };
+
+        match (file.metadata(), std::fs::metadata(&self.path)) {
+            (Ok(locked), Ok(current)) => {
+                locked.dev() == current.dev() && locked.ino() == current.ino()
+            }
+            _ => false,
+        }
+    }
+
+    /// Try to acquire the lock without waiting, returns true if it is held
+    #[tracing::instrument(skip(self), fields(path = self.path.display().to_string()))]
+    pub fn try_acquire(&mut self) -> Result<bool, Error> {
+        if self.is_held() {
+            return Ok(true);
+        }
+
+        // Release a lock on a file which is not at the path anymore
+        self.file = None;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&self.path)
+            .map_err(|err| Error::Open(self.path.to_owned(), err))?;
+
+        // SAFETY: the descriptor is owned by the file which outlives the call
+        if 0 != unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } {
+            let err = io::Error::last_os_error();
+            if io::ErrorKind::WouldBlock == err.kind() {
+                debug!("Leader lock is held by another replica");
+                return Ok(false);
+            }
+
+            return Err(Error::Lock(self.path.to_owned(), err));
+        }
+
+        // Help operators to find the leader, this is not relied upon
+        if let Err(err) = file
+            .set_len(0)
+            .and_then(|_| writeln!(file, "{}", process::id()))
+        {
+            debug!(
+                error = err.to_string(),
+                "Could not write pid to leader lock"
+            );
+        }
+
+        self.file = Some(file);
+
+        // The file may have been replaced between its opening and its locking
+        Ok(self.is_held())
+    }
+}
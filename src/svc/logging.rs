@@ -4,9 +4,22 @@
 
 use std::borrow::Cow;
 
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    runtime::TokioCurrentThread,
+    trace::{self, Tracer},
+    Resource,
+};
 use serde::{Deserialize, Serialize};
-use tracing::Level;
-use tracing_subscriber::prelude::*;
+use tracing::{Level, Subscriber};
+use tracing_opentelemetry::OpenTelemetryLayer;
+use tracing_subscriber::{
+    fmt::{writer::MakeWriterExt, MakeWriter},
+    prelude::*,
+    registry::LookupSpan,
+    Layer,
+};
 
 // -----------------------------------------------------------------------------
 // Error enumeration
@@ -17,6 +30,43 @@ pub enum Error {
     GlobalDefaultSubscriber(tracing::subscriber::SetGlobalDefaultError),
     #[error("failed to create a tracing registry")]
     TracingRegistry(tracing_subscriber::util::TryInitError),
+    #[error("failed to create OTLP trace exporter, {0}")]
+    Otlp(opentelemetry::trace::TraceError),
+}
+
+// -----------------------------------------------------------------------------
+// Logging
+
+/// Format of log lines
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum LogFormat {
+    /// Human readable log lines
+    #[default]
+    #[serde(rename = "pretty")]
+    Pretty,
+    /// One JSON object per log line, structured fields are JSON keys
+    #[serde(rename = "json")]
+    Json,
+}
+
+/// Configuration of the logging system
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct Logging {
+    /// Format of log lines
+    #[serde(rename = "format", default)]
+    pub format: LogFormat,
+}
+
+// -----------------------------------------------------------------------------
+// Telemetry
+
+/// Configuration of the export of spans
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct Telemetry {
+    /// Endpoint of the OpenTelemetry collector to which spans are exported
+    /// over OTLP (HTTP), nothing is exported if not set
+    #[serde(rename = "otlp-endpoint", default)]
+    pub otlp_endpoint: Option<String>,
 }
 
 // -----------------------------------------------------------------------------
@@ -54,31 +104,63 @@ impl SentryContext {
 pub struct LoggingInitGuard {
     #[allow(dead_code)]
     sentry_guard: Option<sentry::ClientInitGuard>,
+    /// Spans are exported over OTLP and must be flushed on drop
+    otlp: bool,
 }
 
 impl From<Option<sentry::ClientInitGuard>> for LoggingInitGuard {
     #[tracing::instrument(skip_all)]
     fn from(sentry_guard: Option<sentry::ClientInitGuard>) -> Self {
-        Self { sentry_guard }
+        Self {
+            sentry_guard,
+            otlp: false,
+        }
+    }
+}
+
+impl Drop for LoggingInitGuard {
+    fn drop(&mut self) {
+        if self.otlp {
+            opentelemetry::global::shutdown_tracer_provider();
+        }
     }
 }
 
 // -----------------------------------------------------------------------------
 // Initialize logging system functions
 
-/// Initialize the local logger
+/// Initialize the local logger and the export of spans, if configured
 #[tracing::instrument]
-pub fn initialize(verbosity: usize) -> Result<(), Error> {
-    tracing::subscriber::set_global_default(
-        tracing_subscriber::fmt()
-            .with_max_level(level(verbosity))
-            .with_thread_names(true)
-            .with_line_number(true)
-            .with_thread_ids(true)
-            .with_target(true)
-            .finish(),
-    )
-    .map_err(Error::GlobalDefaultSubscriber)
+pub fn initialize(
+    verbosity: usize,
+    format: LogFormat,
+    telemetry: &Telemetry,
+) -> Result<LoggingInitGuard, Error> {
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level(verbosity))
+        .with_thread_names(true)
+        .with_line_number(true)
+        .with_thread_ids(true)
+        .with_target(true);
+
+    match format {
+        LogFormat::Pretty => {
+            tracing::subscriber::set_global_default(builder.finish().with(otlp(telemetry)?))
+        }
+        LogFormat::Json => tracing::subscriber::set_global_default(
+            builder
+                .json()
+                .flatten_event(true)
+                .finish()
+                .with(otlp(telemetry)?),
+        ),
+    }
+    .map_err(Error::GlobalDefaultSubscriber)?;
+
+    Ok(LoggingInitGuard {
+        sentry_guard: None,
+        otlp: telemetry.otlp_endpoint.is_some(),
+    })
 }
 
 /// Initialize the local logger and the sentry hook.
@@ -87,38 +169,37 @@ pub fn initialize(verbosity: usize) -> Result<(), Error> {
 /// leave the main scope:
 ///
 /// ```
-/// use functions_sdk::logging::{initialize_with_sentry, SentryContext};
+/// use functions_sdk::logging::{initialize_with_sentry, LogFormat, SentryContext, Telemetry};
 ///
 /// let _logging_guard_to_keep_around = initialize_with_sentry(
 ///     2,
 ///     SentryContext::new(
 ///         "https://something@glitchtip.corp.clever-cloud.com/xx",
 ///         "development",
-///     )
+///     ),
+///     LogFormat::Pretty,
+///     &Telemetry::default(),
 /// ).expect("Could not initialize logging together with the sentry hook");
 /// ```
 #[tracing::instrument]
 pub fn initialize_with_sentry(
     verbosity: usize,
     sentry_ctx: SentryContext,
+    format: LogFormat,
+    telemetry: &Telemetry,
 ) -> Result<LoggingInitGuard, Error> {
-    let format_layer = tracing_subscriber::fmt::Layer::new()
-        .with_writer(std::io::stdout.with_max_level(level(verbosity)))
-        .with_thread_names(true)
-        .with_line_number(true)
-        .with_thread_ids(true)
-        .with_target(true);
-
-    let sentry_guard =
+    let mut guard =
         LoggingInitGuard::from(sentry_initialize(sentry_ctx.dsn, sentry_ctx.environment));
 
     tracing_subscriber::registry()
-        .with(format_layer)
+        .with(format_layer(format, verbosity, std::io::stdout))
         .with(sentry_tracing::layer())
+        .with(otlp(telemetry)?)
         .try_init()
         .map_err(Error::TracingRegistry)?;
 
-    Ok(sentry_guard)
+    guard.otlp = telemetry.otlp_endpoint.is_some();
+    Ok(guard)
 }
 
 // -----------------------------------------------------------------------------
@@ -134,6 +215,59 @@ pub const fn level(verbosity: usize) -> Level {
     }
 }
 
+/// Create the layer writing log lines in the given format to the given writer
+fn format_layer<S, W>(
+    format: LogFormat,
+    verbosity: usize,
+    writer: W,
+) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::Layer::new()
+        .with_writer(writer.with_max_level(level(verbosity)))
+        .with_thread_names(true)
+        .with_line_number(true)
+        .with_thread_ids(true)
+        .with_target(true);
+
+    match format {
+        LogFormat::Pretty => layer.boxed(),
+        LogFormat::Json => layer.json().flatten_event(true).boxed(),
+    }
+}
+
+/// Create the layer exporting spans over OTLP, if an endpoint is configured.
+///
+/// Spans are exported in batches on the current thread runtime of tokio.
+fn otlp<S>(telemetry: &Telemetry) -> Result<Option<OpenTelemetryLayer<S, Tracer>>, Error>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let Some(endpoint) = &telemetry.otlp_endpoint else {
+        return Ok(None);
+    };
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(
+            trace::config().with_resource(Resource::new(vec![KeyValue::new(
+                "service.name",
+                env!("CARGO_PKG_NAME"),
+            )])),
+        )
+        .install_batch(TokioCurrentThread)
+        .map_err(Error::Otlp)?;
+
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
 fn sentry_initialize(dsn: String, environment: String) -> Option<sentry::ClientInitGuard> {
     if dsn.is_empty() {
         return None;
@@ -148,3 +282,67 @@ fn sentry_initialize(dsn: String, environment: String) -> Option<sentry::ClientI
         },
     )))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use tracing::{info, warn};
+
+    use super::*;
+
+    /// Log lines written so far
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().expect("buffer to be available").extend(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_lines_hold_structured_fields_alongside_sentry() {
+        let buffer = Buffer::default();
+        let writer = buffer.to_owned();
+        let subscriber = tracing_subscriber::registry()
+            .with(format_layer(LogFormat::Json, 2, move || writer.to_owned()))
+            .with(sentry_tracing::layer());
+
+        tracing::subscriber::with_default(subscriber, || {
+            info!(
+                path = "/etc/pki/example",
+                fingerprint = "ab:cd",
+                names = "example.com, www.example.com",
+                "Certificate has been added"
+            );
+
+            warn!(error = "permission denied", "Could not read certificate");
+            tracing::debug!("Filtered out by the verbosity");
+        });
+
+        let output = String::from_utf8(buffer.0.lock().expect("buffer").to_owned())
+            .expect("output to be utf-8");
+
+        let lines: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("line to be valid json"))
+            .collect();
+
+        assert_eq!(2, lines.len());
+        assert_eq!("INFO", lines[0]["level"]);
+        assert_eq!("Certificate has been added", lines[0]["message"]);
+        assert_eq!("/etc/pki/example", lines[0]["path"]);
+        assert_eq!("ab:cd", lines[0]["fingerprint"]);
+        assert_eq!("example.com, www.example.com", lines[0]["names"]);
+        assert_eq!("permission denied", lines[1]["error"]);
+    }
+}
@@ -4,5 +4,8 @@
 
 pub mod certificates;
 pub mod config;
+pub mod health;
 pub mod http;
+pub mod leader;
 pub mod logging;
+pub mod pid;
@@ -0,0 +1,105 @@
+//! # Pid module
+//!
+//! This module provides a pid file for process supervisors that do not track
+//! processes on their own
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+};
+
+use tracing::{debug, error, info, warn};
+
+// -----------------------------------------------------------------------------
+// Error
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to read pid file '{0}', {1}")]
+    Read(PathBuf, io::Error),
+    #[error("pid file '{0}' belongs to process {1} which is still running")]
+    Running(PathBuf, u32),
+    #[error("failed to write pid file '{0}', {1}")]
+    Write(PathBuf, io::Error),
+}
+
+// -----------------------------------------------------------------------------
+// PidFile
+
+/// Pid file holding the identifier of the current process, it is removed when
+/// dropped
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Write the identifier of the current process to the given path. A pid
+    /// file left behind by a process which is not running anymore is
+    /// overwritten, while a running one prevents to start.
+    #[tracing::instrument]
+    pub fn create(path: &Path) -> Result<Self, Error> {
+        match fs::read_to_string(path) {
+            Ok(content) => match content.trim().parse::<u32>() {
+                Ok(pid) if pid != process::id() && is_running(pid) => {
+                    return Err(Error::Running(path.to_owned(), pid));
+                }
+                Ok(pid) => {
+                    warn!(
+                        path = path.display().to_string(),
+                        pid = pid,
+                        "Overwrite stale pid file"
+                    );
+                }
+                Err(_) => {
+                    warn!(
+                        path = path.display().to_string(),
+                        "Overwrite pid file which does not hold a process identifier"
+                    );
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(Error::Read(path.to_owned(), err)),
+        }
+
+        fs::write(path, format!("{}\n", process::id()))
+            .map_err(|err| Error::Write(path.to_owned(), err))?;
+
+        info!(
+            path = path.display().to_string(),
+            pid = process::id(),
+            "Wrote pid file"
+        );
+
+        Ok(Self {
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        match fs::remove_file(&self.path) {
+            Ok(_) => {
+                debug!(path = self.path.display().to_string(), "Removed pid file");
+            }
+            Err(err) => {
+                error!(
+                    error = err.to_string(),
+                    path = self.path.display().to_string(),
+                    "Could not remove pid file"
+                );
+            }
+        }
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Helpers
+
+/// Returns true if a process with the given identifier is running, relying on
+/// the procfs of Linux
+fn is_running(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}